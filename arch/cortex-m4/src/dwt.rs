@@ -0,0 +1,36 @@
+//! ARM Data Watchpoint and Trace unit
+//!
+//! Only the cycle counter is used, as a cheap timestamp source for kernel
+//! tracing.
+//!
+//! http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0439b/BABJFFGJ.html
+
+use kernel::common::VolatileCell;
+
+#[repr(C, packed)]
+struct DwtRegisters {
+    ctrl: VolatileCell<u32>,
+    cyccnt: VolatileCell<u32>,
+}
+
+const DWT_BASE: usize = 0xE0001000;
+
+/// Debug Exception and Monitor Control Register, part of the debug block of
+/// the SCB. The DWT is only clocked while its TRCENA bit is set.
+const DEMCR: *const VolatileCell<u32> = 0xE000EDFC as *const VolatileCell<u32>;
+
+static mut DWT: *mut DwtRegisters = DWT_BASE as *mut DwtRegisters;
+
+/// Start the cycle counter from 0.
+pub unsafe fn enable_cycle_counter() {
+    let demcr = (*DEMCR).get();
+    (*DEMCR).set(demcr | 1 << 24);
+    (*DWT).cyccnt.set(0);
+    let ctrl = (*DWT).ctrl.get();
+    (*DWT).ctrl.set(ctrl | 1);
+}
+
+/// Core clock cycles since the counter was enabled, wrapping at 2^32.
+pub fn cycle_count() -> u32 {
+    unsafe { (*DWT).cyccnt.get() }
+}
@@ -9,6 +9,7 @@
 #[macro_use(debug)]
 extern crate kernel;
 
+pub mod dwt;
 pub mod mpu;
 pub mod systick;
 pub mod scb;
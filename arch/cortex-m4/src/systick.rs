@@ -1,5 +1,6 @@
 //! ARM SysTick peripheral.
 
+use core::cmp;
 use kernel;
 use kernel::common::VolatileCell;
 
@@ -24,17 +25,23 @@ impl SysTick {
 impl kernel::SysTick for SysTick {
     fn set_timer(&self, us: u32) {
         let tenms = self.calibration.get() & 0xffffff;
-        let reload = tenms * us / 10000;
+        let reload = cmp::min(tenms as u64 * us as u64 / 10000, 0xffffff);
 
         self.value.set(0);
-        self.reload.set(reload);
+        self.reload.set(reload as u32);
     }
 
     fn value(&self) -> u32 {
         let tenms = self.calibration.get() & 0xffffff;
         let value = self.value.get() & 0xffffff;
 
-        value * 10000 / tenms
+        (value as u64 * 10000 / tenms as u64) as u32
+    }
+
+    fn max_timer_us(&self) -> u32 {
+        let tenms = self.calibration.get() & 0xffffff;
+
+        (0xffffff as u64 * 10000 / tenms as u64) as u32
     }
 
     fn overflowed(&self) -> bool {
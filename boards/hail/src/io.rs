@@ -1,5 +1,6 @@
 use core::fmt::*;
 use kernel::hil::uart::{self, UART};
+use kernel::fault_record;
 use kernel::process;
 use sam4l;
 
@@ -37,6 +38,9 @@ impl Write for Writer {
 #[no_mangle]
 #[lang="panic_fmt"]
 pub unsafe extern "C" fn panic_fmt(args: Arguments, file: &'static str, line: u32) -> ! {
+    // Keep a record of the panic for after the reset.
+    fault_record::record_panic(file, line);
+
     // XXX Replace with something like kernel::begin_panic()
     // XXX Maybe place that call at panic_fmt, as it's called first
     // XXX Better to cancel the transaction rather than hope we wait long enough
@@ -9,6 +9,7 @@
 
 extern crate capsules;
 extern crate compiler_builtins;
+extern crate cortexm4;
 #[allow(unused_imports)]
 #[macro_use(debug,static_init)]
 extern crate kernel;
@@ -16,6 +17,7 @@ extern crate sam4l;
 
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_i2c::{MuxI2C, I2CDevice};
+use capsules::virtual_uart::{MuxUart, UartDevice};
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
 use kernel::Platform;
 use kernel::hil;
@@ -38,6 +40,22 @@ const NUM_PROCS: usize = 4;
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
 
+// Largest grant region, in bytes, a process may use. `None` for no limit.
+const GRANT_LIMIT: Option<usize> = None;
+
+// Ed25519 public keys whose signatures on apps are trusted, and which apps
+// the kernel agrees to load. With `IfPresent`, apps with a wrong hash are
+// refused. Use `Required` to only run apps signed with one of the keys.
+const APP_SIGNING_KEYS: [[u8; 32]; 0] = [];
+const APP_VERIFICATION: kernel::process::AppVerification =
+    kernel::process::AppVerification::IfPresent(&APP_SIGNING_KEYS);
+
+// How the kernel picks which process to run next.
+static mut SCHEDULER: kernel::scheduler::RoundRobin = kernel::scheduler::RoundRobin::new();
+
+// Flash page buffer for installing apps at runtime.
+static mut APP_LOADER_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+
 // RAM to be shared by all application processes.
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 49152] = [0; 49152];
@@ -49,7 +67,7 @@ static mut PROCESSES: [Option<kernel::Process<'static>>; NUM_PROCS] = [None, Non
 /// A structure representing this platform that holds references to all
 /// capsules for this platform.
 struct Hail {
-    console: &'static capsules::console::Console<'static, sam4l::usart::USART>,
+    console: &'static capsules::console::Console<'static, UartDevice<'static>>,
     gpio: &'static capsules::gpio::GPIO<'static, sam4l::gpio::GPIOPin>,
     alarm: &'static capsules::alarm::AlarmDriver<'static,
                                                  VirtualMuxAlarm<'static,
@@ -66,6 +84,9 @@ struct Hail {
     button: &'static capsules::button::Button<'static, sam4l::gpio::GPIOPin>,
     rng: &'static capsules::rng::SimpleRng<'static, sam4l::trng::Trng<'static>>,
     ipc: kernel::ipc::IPC,
+    ipc_message: kernel::ipc_message::IPCMessage,
+    process_manager: kernel::process_manager::ProcessManager,
+    app_loader: &'static capsules::app_loader::AppLoader<'static, sam4l::flashcalw::FLASHCALW>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
     dac: &'static capsules::dac::Dac<'static>,
     aes: &'static capsules::symmetric_encryption::Crypto<'static, sam4l::aes::Aes>,
@@ -101,6 +122,9 @@ impl Platform for Hail {
             capsules::dac::DRIVER_NUM => f(Some(self.dac)),
 
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc_message::DRIVER_NUM => f(Some(&self.ipc_message)),
+            kernel::process_manager::DRIVER_NUM => f(Some(&self.process_manager)),
+            capsules::app_loader::DRIVER_NUM => f(Some(self.app_loader)),
             _ => f(None),
         }
     }
@@ -161,6 +185,34 @@ unsafe fn set_pin_primary_functions() {
     PB[15].configure(None); //... D1
 }
 
+/// Stream the kernel trace over the serial port, if the board is built with
+/// the `trace` feature.
+#[cfg(feature = "trace")]
+unsafe fn setup_trace(uart_mux: &'static MuxUart<'static>,
+                      mux_alarm: &'static MuxAlarm<'static, sam4l::ast::Ast>) {
+    let trace_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+    trace_uart.setup();
+    let trace_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm));
+    let trace = static_init!(
+        capsules::trace_uart::TraceUart<'static, UartDevice,
+                                        VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        capsules::trace_uart::TraceUart::new(trace_uart, trace_alarm,
+                                             &mut capsules::trace_uart::BUF));
+    hil::uart::UART::set_client(trace_uart, trace);
+    trace_alarm.set_client(trace);
+
+    // Timestamps are core clock cycles.
+    cortexm4::dwt::enable_cycle_counter();
+    kernel::trace::set_clock(cortexm4::dwt::cycle_count, 48000000);
+    trace.start();
+}
+
+#[cfg(not(feature = "trace"))]
+unsafe fn setup_trace(_uart_mux: &'static MuxUart<'static>,
+                      _mux_alarm: &'static MuxAlarm<'static, sam4l::ast::Ast>) {}
+
 /// Reset Handler.
 ///
 /// This symbol is loaded into vector table by the SAM4L chip crate.
@@ -183,13 +235,61 @@ pub unsafe fn reset_handler() {
 
     let mut chip = sam4l::chip::Sam4l::new();
 
+    // Share the serial port between the console driver and the process
+    // console.
+    let uart_mux = static_init!(
+        MuxUart<'static>,
+        MuxUart::new(&sam4l::usart::USART0, 115200));
+    hil::uart::UART::set_client(&sam4l::usart::USART0, uart_mux);
+    uart_mux.initialize();
+
+    let console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+    console_uart.setup();
     let console = static_init!(
-        capsules::console::Console<sam4l::usart::USART>,
-        capsules::console::Console::new(&sam4l::usart::USART0,
+        capsules::console::Console<UartDevice>,
+        capsules::console::Console::new(console_uart,
                      115200,
                      &mut capsules::console::WRITE_BUF,
                      kernel::Grant::create()));
-    hil::uart::UART::set_client(&sam4l::usart::USART0, console);
+    hil::uart::UART::set_client(console_uart, console);
+
+    // Kernel shell for inspecting processes.
+    let process_console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+    process_console_uart.setup();
+    let process_console = static_init!(
+        capsules::process_console::ProcessConsole<UartDevice>,
+        capsules::process_console::ProcessConsole::new(process_console_uart,
+                     &mut capsules::process_console::WRITE_BUF,
+                     &mut capsules::process_console::READ_BUF,
+                     &mut capsules::process_console::COMMAND_BUF));
+    hil::uart::UART::set_client(process_console_uart, process_console);
+
+    extern "C" {
+        /// Beginning and end of the ROM region containing app images.
+        ///
+        /// These symbols are defined in the linker script.
+        static _sapps: u8;
+        static _eapps: u8;
+    }
+
+    // The app allowed to control other processes and to install and remove
+    // apps: the first app in flash.
+    let process_manager = kernel::process::Privileged::FlashAddress(&_sapps as *const u8 as usize);
+
+    // Installs and removes apps at runtime, for the process manager or over
+    // the process console.
+    let app_loader = static_init!(
+        capsules::app_loader::AppLoader<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::app_loader::AppLoader::new(&mut sam4l::flashcalw::FLASH_CONTROLLER,
+                     &mut APP_LOADER_PAGE,
+                     &_sapps as *const u8 as usize,
+                     &_eapps as *const u8 as usize,
+                     process_manager,
+                     kernel::Grant::create()));
+    hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, app_loader);
+    sam4l::flashcalw::FLASH_CONTROLLER.configure();
+    process_console.set_loader(app_loader);
+    capsules::app_loader::Loader::set_client(app_loader, process_console);
 
     // Create the Nrf51822Serialization driver for passing BLE commands
     // over UART to the nRF51822 radio.
@@ -206,6 +306,7 @@ pub unsafe fn reset_handler() {
         MuxAlarm<'static, sam4l::ast::Ast>,
         MuxAlarm::new(&sam4l::ast::AST));
     ast.configure(mux_alarm);
+    setup_trace(uart_mux, mux_alarm);
 
     let sensors_i2c = static_init!(MuxI2C<'static>, MuxI2C::new(&sam4l::i2c::I2C1));
     sam4l::i2c::I2C1.set_master_client(sensors_i2c);
@@ -400,6 +501,9 @@ pub unsafe fn reset_handler() {
         button: button,
         rng: rng,
         ipc: kernel::ipc::IPC::new(),
+        ipc_message: kernel::ipc_message::IPCMessage::new(),
+        process_manager: kernel::process_manager::ProcessManager::new(process_manager),
+        app_loader: app_loader,
         crc: crc,
         dac: dac,
         aes: aes,
@@ -419,21 +523,18 @@ pub unsafe fn reset_handler() {
     kernel::debug::assign_console_driver(Some(hail.console), kc);
 
     hail.nrf51822.initialize();
+    process_console.initialize();
 
     // Uncomment to measure overheads for TakeCell and MapCell:
     // test_take_map_cell::test_take_map_cell();
 
     // debug!("Initialization complete. Entering main loop");
 
-    extern "C" {
-        /// Beginning of the ROM region containing app images.
-        ///
-        /// This symbol is defined in the linker script.
-        static _sapps: u8;
-    }
     kernel::process::load_processes(&_sapps as *const u8,
                                     &mut APP_MEMORY,
                                     &mut PROCESSES,
-                                    FAULT_RESPONSE);
-    kernel::main(&hail, &mut chip, &mut PROCESSES, &hail.ipc);
+                                    FAULT_RESPONSE,
+                                    GRANT_LIMIT,
+                                    APP_VERIFICATION);
+    kernel::main(&hail, &mut chip, &mut PROCESSES, &hail.ipc, &SCHEDULER);
 }
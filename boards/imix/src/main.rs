@@ -4,6 +4,7 @@
 
 extern crate capsules;
 extern crate compiler_builtins;
+extern crate cortexm4;
 #[macro_use(debug, static_init)]
 extern crate kernel;
 extern crate sam4l;
@@ -13,6 +14,7 @@ use capsules::ieee802154::mac::Mac;
 use capsules::rf233::RF233;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_i2c::{I2CDevice, MuxI2C};
+use capsules::virtual_uart::{MuxUart, UartDevice};
 use capsules::virtual_spi::{VirtualSpiMasterDevice, MuxSpiMaster};
 use kernel::hil;
 use kernel::hil::Controller;
@@ -41,6 +43,22 @@ const NUM_PROCS: usize = 2;
 // how should the kernel respond when a process faults
 const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
 
+// Largest grant region, in bytes, a process may use. `None` for no limit.
+const GRANT_LIMIT: Option<usize> = None;
+
+// Ed25519 public keys whose signatures on apps are trusted, and which apps
+// the kernel agrees to load. With `IfPresent`, apps with a wrong hash are
+// refused. Use `Required` to only run apps signed with one of the keys.
+const APP_SIGNING_KEYS: [[u8; 32]; 0] = [];
+const APP_VERIFICATION: kernel::process::AppVerification =
+    kernel::process::AppVerification::IfPresent(&APP_SIGNING_KEYS);
+
+// How the kernel picks which process to run next.
+static mut SCHEDULER: kernel::scheduler::RoundRobin = kernel::scheduler::RoundRobin::new();
+
+// Flash page buffer for installing apps at runtime.
+static mut APP_LOADER_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 16384] = [0; 16384];
 
@@ -51,7 +69,7 @@ type RF233Device = capsules::rf233::RF233<'static,
                                           VirtualSpiMasterDevice<'static, sam4l::spi::Spi>>;
 
 struct Imix {
-    console: &'static capsules::console::Console<'static, sam4l::usart::USART>,
+    console: &'static capsules::console::Console<'static, UartDevice<'static>>,
     gpio: &'static capsules::gpio::GPIO<'static, sam4l::gpio::GPIOPin>,
     alarm: &'static AlarmDriver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
@@ -62,6 +80,9 @@ struct Imix {
     button: &'static capsules::button::Button<'static, sam4l::gpio::GPIOPin>,
     spi: &'static capsules::spi::Spi<'static, VirtualSpiMasterDevice<'static, sam4l::spi::Spi>>,
     ipc: kernel::ipc::IPC,
+    ipc_message: kernel::ipc_message::IPCMessage,
+    process_manager: kernel::process_manager::ProcessManager,
+    app_loader: &'static capsules::app_loader::AppLoader<'static, sam4l::flashcalw::FLASHCALW>,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
@@ -107,6 +128,9 @@ impl kernel::Platform for Imix {
             capsules::usb_user::DRIVER_NUM => f(Some(self.usb_driver)),
             capsules::ieee802154::DRIVER_NUM => f(Some(self.radio_driver)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc_message::DRIVER_NUM => f(Some(&self.ipc_message)),
+            kernel::process_manager::DRIVER_NUM => f(Some(&self.process_manager)),
+            capsules::app_loader::DRIVER_NUM => f(Some(self.app_loader)),
             _ => f(None),
         }
     }
@@ -179,6 +203,34 @@ unsafe fn set_pin_primary_functions() {
     PC[31].configure(None); //... D2          -- GPIO Pin
 }
 
+/// Stream the kernel trace over the serial port, if the board is built with
+/// the `trace` feature.
+#[cfg(feature = "trace")]
+unsafe fn setup_trace(uart_mux: &'static MuxUart<'static>,
+                      mux_alarm: &'static MuxAlarm<'static, sam4l::ast::Ast>) {
+    let trace_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+    trace_uart.setup();
+    let trace_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm));
+    let trace = static_init!(
+        capsules::trace_uart::TraceUart<'static, UartDevice,
+                                        VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        capsules::trace_uart::TraceUart::new(trace_uart, trace_alarm,
+                                             &mut capsules::trace_uart::BUF));
+    hil::uart::UART::set_client(trace_uart, trace);
+    trace_alarm.set_client(trace);
+
+    // Timestamps are core clock cycles.
+    cortexm4::dwt::enable_cycle_counter();
+    kernel::trace::set_clock(cortexm4::dwt::cycle_count, 48000000);
+    trace.start();
+}
+
+#[cfg(not(feature = "trace"))]
+unsafe fn setup_trace(_uart_mux: &'static MuxUart<'static>,
+                      _mux_alarm: &'static MuxAlarm<'static, sam4l::ast::Ast>) {}
+
 #[no_mangle]
 pub unsafe fn reset_handler() {
     sam4l::init();
@@ -202,15 +254,64 @@ pub unsafe fn reset_handler() {
 
     // # CONSOLE
 
+    // Share the serial port between the console driver and the process
+    // console.
+    let uart_mux = static_init!(
+        MuxUart<'static>,
+        MuxUart::new(&sam4l::usart::USART3, 115200));
+    hil::uart::UART::set_client(&sam4l::usart::USART3, uart_mux);
+    uart_mux.initialize();
+
+    let console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+    console_uart.setup();
     let console = static_init!(
-        capsules::console::Console<sam4l::usart::USART>,
-        capsules::console::Console::new(&sam4l::usart::USART3,
+        capsules::console::Console<UartDevice>,
+        capsules::console::Console::new(console_uart,
                      115200,
                      &mut capsules::console::WRITE_BUF,
                      kernel::Grant::create()));
-    hil::uart::UART::set_client(&sam4l::usart::USART3, console);
+    hil::uart::UART::set_client(console_uart, console);
     console.initialize();
 
+    // Kernel shell for inspecting processes.
+    let process_console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+    process_console_uart.setup();
+    let process_console = static_init!(
+        capsules::process_console::ProcessConsole<UartDevice>,
+        capsules::process_console::ProcessConsole::new(process_console_uart,
+                     &mut capsules::process_console::WRITE_BUF,
+                     &mut capsules::process_console::READ_BUF,
+                     &mut capsules::process_console::COMMAND_BUF));
+    hil::uart::UART::set_client(process_console_uart, process_console);
+
+    extern "C" {
+        /// Beginning and end of the ROM region containing app images.
+        ///
+        /// These symbols are defined in the linker script.
+        static _sapps: u8;
+        static _eapps: u8;
+    }
+
+    // The app allowed to control other processes and to install and remove
+    // apps: the first app in flash.
+    let process_manager = kernel::process::Privileged::FlashAddress(&_sapps as *const u8 as usize);
+
+    // Installs and removes apps at runtime, for the process manager or over
+    // the process console.
+    let app_loader = static_init!(
+        capsules::app_loader::AppLoader<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::app_loader::AppLoader::new(&mut sam4l::flashcalw::FLASH_CONTROLLER,
+                     &mut APP_LOADER_PAGE,
+                     &_sapps as *const u8 as usize,
+                     &_eapps as *const u8 as usize,
+                     process_manager,
+                     kernel::Grant::create()));
+    hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, app_loader);
+    sam4l::flashcalw::FLASH_CONTROLLER.configure();
+    process_console.set_loader(app_loader);
+    capsules::app_loader::Loader::set_client(app_loader, process_console);
+    process_console.initialize();
+
     // Attach the kernel debug interface to this console
     let kc = static_init!(
         capsules::console::App,
@@ -225,6 +326,7 @@ pub unsafe fn reset_handler() {
         MuxAlarm<'static, sam4l::ast::Ast>,
         MuxAlarm::new(&sam4l::ast::AST));
     ast.configure(mux_alarm);
+    setup_trace(uart_mux, mux_alarm);
 
     let virtual_alarm1 = static_init!(
         VirtualMuxAlarm<'static, sam4l::ast::Ast>,
@@ -464,6 +566,9 @@ pub unsafe fn reset_handler() {
         crc: crc,
         spi: spi_syscalls,
         ipc: kernel::ipc::IPC::new(),
+        ipc_message: kernel::ipc_message::IPCMessage::new(),
+        process_manager: kernel::process_manager::ProcessManager::new(process_manager),
+        app_loader: app_loader,
         ninedof: ninedof,
         radio_driver: radio_driver,
         usb_driver: usb_driver,
@@ -477,13 +582,11 @@ pub unsafe fn reset_handler() {
     rf233.start();
 
     debug!("Initialization complete. Entering main loop");
-    extern "C" {
-        /// Beginning of the ROM region containing app images.
-        static _sapps: u8;
-    }
     kernel::process::load_processes(&_sapps as *const u8,
                                     &mut APP_MEMORY,
                                     &mut PROCESSES,
-                                    FAULT_RESPONSE);
-    kernel::main(&imix, &mut chip, &mut PROCESSES, &imix.ipc);
+                                    FAULT_RESPONSE,
+                                    GRANT_LIMIT,
+                                    APP_VERIFICATION);
+    kernel::main(&imix, &mut chip, &mut PROCESSES, &imix.ipc, &SCHEDULER);
 }
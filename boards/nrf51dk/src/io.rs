@@ -57,8 +57,12 @@ pub unsafe extern "C" fn rust_begin_unwind(_args: Arguments,
                                            _file: &'static str,
                                            _line: usize)
                                            -> ! {
+    use kernel::fault_record;
     use kernel::hil::gpio::Pin;
     use kernel::process;
+    // Keep a record of the panic for after the reset.
+    fault_record::record_panic(_file, _line as u32);
+
     // The nRF51 DK LEDs (see back of board)
     const LED1_PIN: usize = 21;
     const LED2_PIN: usize = 22;
@@ -76,6 +76,16 @@ const BUTTON4_PIN: usize = 20;
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
 
+// Largest grant region, in bytes, a process may use. `None` for no limit.
+const GRANT_LIMIT: Option<usize> = None;
+
+// Which apps the kernel agrees to load, see `kernel::process::AppVerification`.
+const APP_VERIFICATION: kernel::process::AppVerification =
+    kernel::process::AppVerification::Off;
+
+// How the kernel picks which process to run next.
+static mut SCHEDULER: kernel::scheduler::RoundRobin = kernel::scheduler::RoundRobin::new();
+
 // Number of concurrent processes this platform supports.
 const NUM_PROCS: usize = 1;
 
@@ -293,9 +303,12 @@ pub unsafe fn reset_handler() {
     kernel::process::load_processes(&_sapps as *const u8,
                                     &mut APP_MEMORY,
                                     &mut PROCESSES,
-                                    FAULT_RESPONSE);
+                                    FAULT_RESPONSE,
+                                    GRANT_LIMIT,
+                                    APP_VERIFICATION);
     kernel::main(&platform,
                  &mut chip,
                  &mut PROCESSES,
-                 &kernel::ipc::IPC::new());
+                 &kernel::ipc::IPC::new(),
+                 &SCHEDULER);
 }
@@ -56,7 +56,11 @@ macro_rules! println {
 #[lang = "panic_fmt"]
 pub unsafe extern "C" fn panic_fmt(args: Arguments, file: &'static str, line: u32) -> ! {
     use kernel::hil::gpio::Pin;
+    use kernel::fault_record;
     use kernel::process;
+    // Keep a record of the panic for after the reset.
+    fault_record::record_panic(file, line);
+
     // The nRF52 DK LEDs (see back of board)
     const LED1_PIN: usize = 17;
     const LED2_PIN: usize = 18;
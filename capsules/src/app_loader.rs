@@ -0,0 +1,791 @@
+//! Install and remove apps while the board is running.
+//!
+//! Processes are normally found once, at boot, by walking the TBF headers in
+//! flash. The app loader writes a new TBF into free application flash, checks
+//! it and starts it in an empty slot of the process array without a reboot.
+//! It can also stop a process and erase its TBF again. Images are passed in
+//! chunks, either by a privileged process through the syscall interface or by
+//! the kernel, for example the process console over UART, through the
+//! `Loader` trait. Only one install or uninstall runs at a time.
+//!
+//! Installing
+//! ----------
+//!
+//! Images must be a power of two in size and at least one flash page, which
+//! is what `elf2tbf` produces. They are placed at an address that is a
+//! multiple of their size so that the MPU can protect them, in the first
+//! padding TBF or the free flash after the last TBF that has room. Padding
+//! TBFs are written around the image where needed so that the kernel still
+//! finds every app when it walks the headers at the next boot.
+//!
+//! The header of the image only goes to flash once the rest of it has been
+//! written, so a reset while the image is received leaves the apps in flash
+//! as they were. An image that needs new padding in front of it is only
+//! linked into the list of TBFs once the kernel has checked it. An image that
+//! starts at a padding TBF or at the end of the list is part of the list as
+//! soon as its header is written, before the check. A reset between the two
+//! leaves an unchecked image in flash, which is then only kept from running
+//! by the kernel checking every app with its `AppVerification` again at boot.
+//! An image that the kernel refuses, for example because it is not signed on
+//! a board that requires signatures, is replaced with padding.
+//!
+//! Uninstalling
+//! ------------
+//!
+//! Uninstalling stops the process, removes it from the process array,
+//! replaces its header with padding and erases the rest of its flash. Its
+//! slot in the process array and its memory are only reused after the next
+//! reboot, so every uninstall leaves one install less until then.
+//!
+//! The loader expects flash to be mapped at address 0, so that page `n`
+//! starts at address `n * page_size`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! pub static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! let app_loader = static_init!(
+//!     capsules::app_loader::AppLoader<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::app_loader::AppLoader::new(
+//!         &mut sam4l::flashcalw::FLASH_CONTROLLER,
+//!         &mut PAGEBUFFER,
+//!         &_sapps as *const u8 as usize,
+//!         &_eapps as *const u8 as usize,
+//!         kernel::process::Privileged::FlashAddress(&_sapps as *const u8 as usize),
+//!         kernel::Grant::create()));
+//! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, app_loader);
+//! ```
+
+use core::cell::Cell;
+use core::slice;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::process::{self, TbfEntry};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x00010003;
+
+/// Size of the header of a padding TBF.
+const PADDING_HEADER_SIZE: usize = 16;
+
+/// Interface for kernel users of the app loader.
+pub trait Loader {
+    fn set_client(&self, client: &'static LoaderClient);
+
+    /// Size of a flash page. Chunks passed to `write` must not cross a page
+    /// boundary.
+    fn page_size(&self) -> usize;
+
+    /// Find room for a TBF of `size` bytes and start installing it.
+    fn setup(&self, size: usize) -> ReturnCode;
+
+    /// Pass the next chunk of the TBF. Chunks that complete a flash page are
+    /// written to flash and `write_done` is called once that is done, the
+    /// next chunk must wait for it. Other chunks are only buffered and the
+    /// next one can be passed right away, there is no callback for them.
+    fn write(&self, data: &[u8]) -> ReturnCode;
+
+    /// Check the TBF once all of it has been written, link it into the list
+    /// of TBFs and start it. `finish_done` is called with the index of the
+    /// new process.
+    fn finish(&self) -> ReturnCode;
+
+    /// Give up on the current install. Nothing of it will be found at boot.
+    fn abort(&self) -> ReturnCode;
+
+    /// Stop the process at `idx` and erase its TBF. `uninstall_done` is
+    /// called once the flash has been erased.
+    fn uninstall(&self, idx: usize) -> ReturnCode;
+}
+
+/// Implement `LoaderClient` to receive callbacks from a `Loader`.
+pub trait LoaderClient {
+    fn write_done(&self, result: ReturnCode);
+    fn finish_done(&self, result: ReturnCode);
+    fn uninstall_done(&self, result: ReturnCode);
+}
+
+/// Who started the install or uninstall that is in progress.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Owner {
+    Kernel,
+    App(AppId),
+}
+
+/// Operations that complete with a callback. The values are the command
+/// numbers passed to the callback of a process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Write = 3,
+    Finish = 4,
+    Uninstall = 6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the next chunk of the image.
+    Receiving,
+    /// Writing a complete page of the image.
+    WritingPage,
+    /// Writing the padding header after the image.
+    Trailing,
+    /// Writing the first page of the image again, with its real header.
+    Header,
+    /// Writing the padding header that links the image into the list of
+    /// TBFs.
+    Link,
+    /// Replacing a refused image with padding. Holds the error to report.
+    Rollback(ReturnCode),
+    /// Writing padding over the header of an uninstalled app.
+    RemoveHeader,
+    /// Erasing the rest of an uninstalled app.
+    Erasing,
+}
+
+/// Where an image goes and which padding headers it needs.
+#[derive(Clone, Copy, Debug)]
+struct Install {
+    address: usize,
+    size: usize,
+    /// Padding header for the rest of the padding TBF after the image, as
+    /// address and total size.
+    trailing: Option<(usize, usize)>,
+    /// Padding header for the space between the last TBF before the image
+    /// and the image. The image is part of the list of TBFs once it has been
+    /// written.
+    link: Option<(usize, usize)>,
+    /// Total size of the padding header kept at the start of the image
+    /// while it is written, if the image replaces part of a padding TBF.
+    placeholder: Option<usize>,
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            buffer: None,
+        }
+    }
+}
+
+pub struct AppLoader<'a, F: hil::flash::Flash + 'static> {
+    driver: &'a F,
+    pagebuffer: TakeCell<'static, F::Page>,
+    page_size: usize,
+    /// Flash available to apps.
+    flash_start: usize,
+    flash_end: usize,
+    /// The process that may use the syscall interface.
+    manager: process::Privileged,
+    apps: Grant<App>,
+    client: Cell<Option<&'static LoaderClient>>,
+    owner: Cell<Option<Owner>>,
+    state: Cell<State>,
+    install: Cell<Option<Install>>,
+    /// Bytes of the image received so far.
+    received: Cell<usize>,
+    /// The real header of the image while a placeholder is in flash.
+    header: Cell<[u8; PADDING_HEADER_SIZE]>,
+    /// Next page to erase and the page after the last one.
+    erase_pages: Cell<(usize, usize)>,
+}
+
+impl<'a, F: hil::flash::Flash + 'a> AppLoader<'a, F> {
+    pub fn new(driver: &'a F,
+               pagebuffer: &'static mut F::Page,
+               flash_start: usize,
+               flash_end: usize,
+               manager: process::Privileged,
+               grant: Grant<App>)
+               -> AppLoader<'a, F> {
+        let page_size = pagebuffer.as_mut().len();
+        AppLoader {
+            driver: driver,
+            pagebuffer: TakeCell::new(pagebuffer),
+            page_size: page_size,
+            flash_start: flash_start,
+            flash_end: flash_end,
+            manager: manager,
+            apps: grant,
+            client: Cell::new(None),
+            owner: Cell::new(None),
+            state: Cell::new(State::Idle),
+            install: Cell::new(None),
+            received: Cell::new(0),
+            header: Cell::new([0; PADDING_HEADER_SIZE]),
+            erase_pages: Cell::new((0, 0)),
+        }
+    }
+
+    /// Whether `appid` is the process allowed to install and remove apps.
+    fn is_manager(&self, appid: AppId) -> bool {
+        let procs = unsafe { &process::PROCS };
+        procs.get(appid.idx())
+            .and_then(|p| p.as_ref())
+            .map_or(false, |p| p.is_privileged(self.manager))
+    }
+
+    /// Make `owner` the owner of a new operation, unless another one is in
+    /// progress. An install whose process has died is abandoned.
+    fn claim(&self, owner: Owner) -> ReturnCode {
+        if let Some(Owner::App(appid)) = self.owner.get() {
+            let procs = unsafe { &process::PROCS };
+            let alive = procs.get(appid.idx())
+                .and_then(|p| p.as_ref())
+                .map_or(false, |p| p.current_state() != process::State::Fault);
+            if !alive && self.state.get() == State::Receiving {
+                self.reset();
+            }
+        }
+        if self.owner.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.owner.set(Some(owner));
+        ReturnCode::SUCCESS
+    }
+
+    fn reset(&self) {
+        self.owner.set(None);
+        self.state.set(State::Idle);
+        self.install.set(None);
+        self.received.set(0);
+    }
+
+    /// Whether a padding header at `address` can be written with a single
+    /// page write.
+    fn header_fits(&self, address: usize) -> bool {
+        address % self.page_size + PADDING_HEADER_SIZE <= self.page_size
+    }
+
+    /// Find the first place in application flash where an image of `size`
+    /// bytes fits, aligned to its size.
+    fn find_region(&self, size: usize) -> Option<Install> {
+        let mut address = self.flash_start;
+        while address < self.flash_end {
+            match unsafe { process::tbf_entry(address as *const u8) } {
+                Some(TbfEntry::App(total_size)) => address += total_size,
+                Some(TbfEntry::Padding(total_size)) => {
+                    let start = align_up(address, size);
+                    let end = address + total_size;
+                    if start + size <= end && self.gap_usable(address, start) {
+                        return Some(Install {
+                            address: start,
+                            size: size,
+                            trailing: if start + size < end {
+                                Some((start + size, end - start - size))
+                            } else {
+                                None
+                            },
+                            link: if start > address {
+                                Some((address, start - address))
+                            } else {
+                                None
+                            },
+                            placeholder: Some(end - start),
+                        });
+                    }
+                    address = end;
+                }
+                None => break,
+            }
+        }
+
+        // Free flash after the last TBF.
+        let start = align_up(address, size);
+        if start + size <= self.flash_end && self.gap_usable(address, start) {
+            Some(Install {
+                address: start,
+                size: size,
+                trailing: None,
+                link: if start > address {
+                    Some((address, start - address))
+                } else {
+                    None
+                },
+                placeholder: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the space from `address` to `start` is either empty or can
+    /// be filled with padding.
+    fn gap_usable(&self, address: usize, start: usize) -> bool {
+        start == address || (start - address > PADDING_HEADER_SIZE && self.header_fits(address))
+    }
+
+    /// Replace the 16 bytes at `address` in flash with `header`, keeping the
+    /// rest of the page.
+    fn write_header(&self, address: usize, header: [u8; PADDING_HEADER_SIZE]) -> ReturnCode {
+        self.pagebuffer.take().map_or(ReturnCode::ERESERVE, |pagebuffer| {
+            let page = address / self.page_size;
+            let offset = address % self.page_size;
+            let flash = unsafe {
+                slice::from_raw_parts((page * self.page_size) as *const u8, self.page_size)
+            };
+            pagebuffer.as_mut().copy_from_slice(flash);
+            pagebuffer.as_mut()[offset..offset + PADDING_HEADER_SIZE].copy_from_slice(&header);
+            self.driver.write_page(page, pagebuffer)
+        })
+    }
+
+    /// Start a flash write for the install and move to `state`, or give up
+    /// if the flash refuses it.
+    fn step(&self, state: State, result: ReturnCode) {
+        if result == ReturnCode::SUCCESS {
+            self.state.set(state);
+        } else {
+            self.done(Operation::Finish, ReturnCode::FAIL);
+        }
+    }
+
+    fn setup_install(&self, size: usize) -> ReturnCode {
+        if size < self.page_size || !size.is_power_of_two() {
+            return ReturnCode::EINVAL;
+        }
+        match self.find_region(size) {
+            None => ReturnCode::ENOMEM,
+            Some(install) => {
+                self.install.set(Some(install));
+                self.received.set(0);
+                self.state.set(State::Receiving);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    /// Buffer a chunk of the image and write the page if it is complete.
+    /// Returns whether a page write was started.
+    fn write_chunk(&self, data: &[u8]) -> Result<bool, ReturnCode> {
+        let install = match (self.state.get(), self.install.get()) {
+            (State::Receiving, Some(install)) => install,
+            (State::WritingPage, _) => return Err(ReturnCode::EBUSY),
+            _ => return Err(ReturnCode::EINVAL),
+        };
+        let received = self.received.get();
+        let offset = received % self.page_size;
+        if received + data.len() > install.size || offset + data.len() > self.page_size {
+            return Err(ReturnCode::ESIZE);
+        }
+
+        self.pagebuffer.take().map_or(Err(ReturnCode::ERESERVE), |pagebuffer| {
+            pagebuffer.as_mut()[offset..offset + data.len()].copy_from_slice(data);
+            self.received.set(received + data.len());
+            if offset + data.len() < self.page_size {
+                self.pagebuffer.replace(pagebuffer);
+                return Ok(false);
+            }
+
+            let page_address = install.address + received - offset;
+            if page_address == install.address {
+                // Keep the header out of flash until the image is complete.
+                let mut header = [0; PADDING_HEADER_SIZE];
+                header.copy_from_slice(&pagebuffer.as_mut()[..PADDING_HEADER_SIZE]);
+                self.header.set(header);
+                let placeholder = install.placeholder
+                    .map_or([0xff; PADDING_HEADER_SIZE], padding_header);
+                pagebuffer.as_mut()[..PADDING_HEADER_SIZE].copy_from_slice(&placeholder);
+            }
+            match self.driver.write_page(page_address / self.page_size, pagebuffer) {
+                ReturnCode::SUCCESS => {
+                    self.state.set(State::WritingPage);
+                    Ok(true)
+                }
+                err => Err(err),
+            }
+        })
+    }
+
+    fn finish_install(&self) -> ReturnCode {
+        let install = match (self.state.get(), self.install.get()) {
+            (State::Receiving, Some(install)) => install,
+            (State::Idle, _) => return ReturnCode::EINVAL,
+            _ => return ReturnCode::EBUSY,
+        };
+        if self.received.get() != install.size {
+            return ReturnCode::ESIZE;
+        }
+        match install.trailing {
+            Some((address, total_size)) => {
+                let result = self.write_header(address, padding_header(total_size));
+                if result == ReturnCode::SUCCESS {
+                    self.state.set(State::Trailing);
+                }
+                result
+            }
+            None => {
+                let result = self.write_header(install.address, self.header.get());
+                if result == ReturnCode::SUCCESS {
+                    self.state.set(State::Header);
+                }
+                result
+            }
+        }
+    }
+
+    /// The image is complete in flash. Check it before it becomes part of
+    /// the list of TBFs.
+    fn check_image(&self, install: Install) {
+        let result = unsafe { process::check_app(install.address as *const u8, install.size) };
+        if result != ReturnCode::SUCCESS {
+            self.rollback(install, result);
+            return;
+        }
+        match install.link {
+            Some((address, total_size)) => {
+                self.step(State::Link, self.write_header(address, padding_header(total_size)))
+            }
+            None => self.start_process(install),
+        }
+    }
+
+    fn start_process(&self, install: Install) {
+        match unsafe { process::load_process(install.address as *const u8) } {
+            Ok(appid) => {
+                self.done(Operation::Finish,
+                          ReturnCode::SuccessWithValue { value: appid.idx() })
+            }
+            Err(err) => self.rollback(install, err),
+        }
+    }
+
+    /// Turn a refused image into padding so that it is not loaded at boot.
+    fn rollback(&self, install: Install, err: ReturnCode) {
+        let result = self.write_header(install.address, padding_header(install.size));
+        self.step(State::Rollback(err), result);
+    }
+
+    fn uninstall_process(&self, idx: usize) -> ReturnCode {
+        let procs = unsafe { &process::PROCS };
+        let start = match procs.get(idx) {
+            None => return ReturnCode::EINVAL,
+            Some(&None) => return ReturnCode::ENODEVICE,
+            Some(&Some(ref p)) => p.flash_start() as usize,
+        };
+        if !self.header_fits(start) {
+            return ReturnCode::ENOSUPPORT;
+        }
+
+        let text = match unsafe { process::unload_process(idx) } {
+            Ok(text) => text,
+            Err(err) => return err,
+        };
+        let end = start + text.len();
+        self.erase_pages.set((start / self.page_size + 1, end / self.page_size));
+        let result = self.write_header(start, padding_header(text.len()));
+        if result == ReturnCode::SUCCESS {
+            self.state.set(State::RemoveHeader);
+        }
+        result
+    }
+
+    fn erase_next_page(&self) {
+        let (next, end) = self.erase_pages.get();
+        if next >= end {
+            self.done(Operation::Uninstall, ReturnCode::SUCCESS);
+            return;
+        }
+        self.erase_pages.set((next + 1, end));
+        self.state.set(State::Erasing);
+        if self.driver.erase_page(next) != ReturnCode::SUCCESS {
+            self.done(Operation::Uninstall, ReturnCode::FAIL);
+        }
+    }
+
+    /// Report the end of an operation to its owner. Anything other than a
+    /// successful page write ends the install or uninstall.
+    fn done(&self, operation: Operation, result: ReturnCode) {
+        let owner = self.owner.get();
+        if operation == Operation::Write && result == ReturnCode::SUCCESS {
+            self.state.set(State::Receiving);
+        } else {
+            self.reset();
+        }
+
+        match owner {
+            Some(Owner::App(appid)) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.callback.map(|mut cb| cb.schedule(From::from(result), operation as usize, 0));
+                });
+            }
+            Some(Owner::Kernel) => {
+                self.client.get().map(|client| match operation {
+                    Operation::Write => client.write_done(result),
+                    Operation::Finish => client.finish_done(result),
+                    Operation::Uninstall => client.uninstall_done(result),
+                });
+            }
+            None => {}
+        }
+    }
+
+    fn setup_for(&self, owner: Owner, size: usize) -> ReturnCode {
+        let result = self.claim(owner);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let result = self.setup_install(size);
+        if result != ReturnCode::SUCCESS {
+            self.reset();
+        }
+        result
+    }
+
+    fn finish_for(&self, owner: Owner) -> ReturnCode {
+        if self.owner.get() != Some(owner) {
+            return ReturnCode::EINVAL;
+        }
+        self.finish_install()
+    }
+
+    fn abort_for(&self, owner: Owner) -> ReturnCode {
+        if self.owner.get() != Some(owner) {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Receiving {
+            return ReturnCode::EBUSY;
+        }
+        self.reset();
+        ReturnCode::SUCCESS
+    }
+
+    fn uninstall_for(&self, owner: Owner, idx: usize) -> ReturnCode {
+        let result = self.claim(owner);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        let result = self.uninstall_process(idx);
+        if result != ReturnCode::SUCCESS {
+            self.reset();
+        }
+        result
+    }
+}
+
+/// Round `address` up to a multiple of `align`, which is a power of two.
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+/// The header of a padding TBF of `total_size` bytes.
+fn padding_header(total_size: usize) -> [u8; PADDING_HEADER_SIZE] {
+    let version = 2 | (PADDING_HEADER_SIZE as u32) << 16;
+    let flags = 0;
+    let checksum = version ^ total_size as u32 ^ flags;
+    let words = [version, total_size as u32, flags, checksum];
+    let mut header = [0; PADDING_HEADER_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        for j in 0..4 {
+            header[i * 4 + j] = (word >> (8 * j)) as u8;
+        }
+    }
+    header
+}
+
+impl<'a, F: hil::flash::Flash + 'a> Loader for AppLoader<'a, F> {
+    fn set_client(&self, client: &'static LoaderClient) {
+        self.client.set(Some(client));
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn setup(&self, size: usize) -> ReturnCode {
+        self.setup_for(Owner::Kernel, size)
+    }
+
+    fn write(&self, data: &[u8]) -> ReturnCode {
+        if self.owner.get() != Some(Owner::Kernel) {
+            return ReturnCode::EINVAL;
+        }
+        match self.write_chunk(data) {
+            Ok(_) => ReturnCode::SUCCESS,
+            Err(err) => err,
+        }
+    }
+
+    fn finish(&self) -> ReturnCode {
+        self.finish_for(Owner::Kernel)
+    }
+
+    fn abort(&self) -> ReturnCode {
+        self.abort_for(Owner::Kernel)
+    }
+
+    fn uninstall(&self, idx: usize) -> ReturnCode {
+        self.uninstall_for(Owner::Kernel, idx)
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for AppLoader<'a, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+        let state = self.state.get();
+        if error != hil::flash::Error::CommandComplete {
+            let operation = match state {
+                State::WritingPage => Operation::Write,
+                State::RemoveHeader => Operation::Uninstall,
+                _ => Operation::Finish,
+            };
+            self.done(operation, ReturnCode::FAIL);
+            return;
+        }
+
+        let install = match self.install.get() {
+            Some(install) => install,
+            None => {
+                if state == State::RemoveHeader {
+                    self.erase_next_page();
+                }
+                return;
+            }
+        };
+        match state {
+            State::WritingPage => self.done(Operation::Write, ReturnCode::SUCCESS),
+            State::Trailing => {
+                self.step(State::Header,
+                          self.write_header(install.address, self.header.get()))
+            }
+            State::Header => self.check_image(install),
+            State::Link => self.start_process(install),
+            State::Rollback(err) => self.done(Operation::Finish, err),
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.done(Operation::Uninstall, ReturnCode::FAIL);
+        } else if self.state.get() == State::Erasing {
+            self.erase_next_page();
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'a> Driver for AppLoader<'a, F> {
+    /// Setup the buffer chunks of an image are written from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer with the next chunk of the image.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        if !self.is_manager(appid) {
+            return ReturnCode::EPERM;
+        }
+        match allow_num {
+            0 => {
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.buffer = Some(slice);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup the callback for completed operations.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called with the result and the number of the command that
+    ///        completed.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        if !self.is_manager(callback.app_id()) {
+            return ReturnCode::EPERM;
+        }
+        match subscribe_num {
+            0 => {
+                self.apps
+                    .enter(callback.app_id(), |app, _| {
+                        app.callback = Some(callback);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Install and uninstall apps.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Return the flash page size.
+    /// - `2`: Start installing an image of `data` bytes.
+    /// - `3`: Write the first `data` bytes of the allowed buffer as the next
+    ///        chunk of the image.
+    /// - `4`: Check and start the installed image. The callback gets the
+    ///        index of the new process.
+    /// - `5`: Abort the install.
+    /// - `6`: Stop the process at index `data` and erase its image.
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        if !self.is_manager(appid) {
+            return ReturnCode::EPERM;
+        }
+        let owner = Owner::App(appid);
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => ReturnCode::SuccessWithValue { value: self.page_size },
+            2 => self.setup_for(owner, data),
+            3 => {
+                if self.owner.get() != Some(owner) {
+                    return ReturnCode::EINVAL;
+                }
+                let result = self.apps
+                    .enter(appid, |app, _| {
+                        app.buffer.as_ref().map_or(Err(ReturnCode::ERESERVE), |buffer| {
+                            if data > buffer.len() {
+                                Err(ReturnCode::EINVAL)
+                            } else {
+                                self.write_chunk(&buffer.as_ref()[..data])
+                            }
+                        })
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                match result {
+                    // The chunk is buffered, let the process send the next.
+                    Ok(false) => {
+                        let _ = self.apps.enter(appid, |app, _| {
+                            app.callback.map(|mut cb| {
+                                cb.schedule(From::from(ReturnCode::SUCCESS),
+                                            Operation::Write as usize,
+                                            0)
+                            });
+                        });
+                        ReturnCode::SUCCESS
+                    }
+                    Ok(true) => ReturnCode::SUCCESS,
+                    Err(err) => err,
+                }
+            }
+            4 => self.finish_for(owner),
+            5 => self.abort_for(owner),
+            6 => {
+                // Removing the caller would free the process the kernel is
+                // running right now.
+                if data == appid.idx() {
+                    return ReturnCode::EINVAL;
+                }
+                self.uninstall_for(owner, data)
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,2132 @@
+//! FAT16 and FAT32 filesystem on an SD card, for apps.
+//!
+//! Apps open, read, write, list and delete files on a card formatted by a
+//! laptop, and the laptop can read what they wrote. The first FAT16 or FAT32
+//! partition in the MBR partition table is used, or the whole card if it has
+//! no partition table.
+//!
+//! Apps name files by path, with `/` between the directories, and use the
+//! long names a laptop shows as well as the 8.3 short names. Names are
+//! matched without regard to case. New files get a long name too if their
+//! name is not a valid upper case 8.3 name, and a short name of the form
+//! `NAME~1.EXT`. Names are ASCII, other characters read as `?`.
+//!
+//! Open files are identified by a file descriptor, an index into the
+//! `MAX_OPEN_FILES` files every app can have open, which are kept in its
+//! grant. Directories are opened like files and listed an entry at a time.
+//!
+//! All sector reads and writes go through a one sector cache, which is
+//! written back before any other sector is read and when an operation
+//! completes. One operation runs at a time, every app can have one more
+//! waiting. The FATs and directory entries are updated after every write, so
+//! the card can be pulled once the last callback came. There is no clock,
+//! files are dated 1980-01-01.
+//!
+//! Not supported: creating and deleting directories, FAT12, and sectors of
+//! other than 512 bytes.
+//!
+//! Usage
+//! -----
+//!
+//! The filesystem takes the place of `SDCardDriver` as the client of the
+//! `SDCard`.
+//!
+//! ```rust
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::fat::FatFs::new(sdcard,
+//!                               kernel::Grant::create(),
+//!                               &mut capsules::fat::BUFFER));
+//! sdcard.set_client(fat);
+//! sdcard.detect_changes();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::bytes::{get_u16, get_u32, put_u16, put_u32};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use sdcard::{SDCard, SDCardClient};
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x50004;
+
+/// Files every app can have open at once.
+pub const MAX_OPEN_FILES: usize = 4;
+pub const MAX_PATH_LEN: usize = 128;
+/// Longest name of a new file. Longer names are listed cut short and cannot
+/// be opened.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Flags to open files with.
+pub const OPEN_WRITE: usize = 1;
+/// Create the file if it does not exist.
+pub const OPEN_CREATE: usize = 2;
+/// Write at the end of the file, wherever the position is.
+pub const OPEN_APPEND: usize = 4;
+/// Empty the file, if it is opened for writing.
+pub const OPEN_TRUNCATE: usize = 8;
+
+/// Sector cache, assigned in board `main.rs` files
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+const SECTOR_LEN: usize = 512;
+const ENTRY_LEN: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = 16;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First byte of a free directory entry. Entries after one that starts with
+/// 0 were never used.
+const FREE_ENTRY: u8 = 0xE5;
+/// Order flag of the first long name entry, which holds the end of the name.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Flags of short names that read in lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+/// Where the 13 characters of a long name entry are.
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// 1980-01-01, the earliest date FAT can store.
+const FAT_DATE: u16 = (1 << 5) | 1;
+/// Short names to try for a file with a long name: `NAME~1` to `NAME~4`,
+/// then as Windows does `NA1234~1` to `NA1234~9` with a hash of the long
+/// name, so many files with the same start still get a short name.
+const TAILS: u8 = 13;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Layout of the mounted filesystem. Sectors are numbered from the start of
+/// the card.
+#[derive(Clone, Copy)]
+struct Volume {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    num_fats: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    // The root directory of FAT16, before the data.
+    root_start: u32,
+    root_entries: u32,
+    // The first cluster of the root directory of FAT32.
+    root_cluster: u32,
+    // Where cluster 2, the first, starts.
+    data_start: u32,
+    clusters: u32,
+    // FAT32 sector with the count of free clusters, relative to the start of
+    // the partition.
+    fs_info: u32,
+}
+
+const NO_VOLUME: Volume = Volume {
+    fat_type: FatType::Fat16,
+    sectors_per_cluster: 1,
+    num_fats: 0,
+    fat_start: 0,
+    fat_sectors: 0,
+    root_start: 0,
+    root_entries: 0,
+    root_cluster: 0,
+    data_start: 0,
+    clusters: 0,
+    fs_info: 0,
+};
+
+impl Volume {
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_LEN as u32
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    fn is_end_of_chain(&self, entry: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => entry >= 0xFFF8,
+            FatType::Fat32 => entry >= 0x0FFFFFF8,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    /// The first cluster of the root directory, 0 for the root directory
+    /// of FAT16 which is not in a cluster.
+    fn root(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => self.root_cluster,
+        }
+    }
+
+    fn fat_entry_len(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// Sector and offset of the entry of `cluster` in the FAT `copy`.
+    fn fat_location(&self, cluster: u32, copy: u32) -> (u32, usize) {
+        let offset = cluster as usize * self.fat_entry_len();
+        (self.fat_start + copy * self.fat_sectors + (offset / SECTOR_LEN) as u32, offset % SECTOR_LEN)
+    }
+
+    fn get_fat(&self, sector: &[u8], offset: usize) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => get_u16(&sector[offset..]) as u32,
+            FatType::Fat32 => get_u32(&sector[offset..]) & 0x0FFFFFFF,
+        }
+    }
+
+    fn put_fat(&self, sector: &mut [u8], offset: usize, entry: u32) {
+        match self.fat_type {
+            FatType::Fat16 => put_u16(&mut sector[offset..], entry as u16),
+            FatType::Fat32 => {
+                // The top four bits are reserved and kept.
+                let reserved = get_u32(&sector[offset..]) & 0xF0000000;
+                put_u32(&mut sector[offset..], reserved | entry);
+            }
+        }
+    }
+
+    fn entry_cluster(&self, entry: &[u8]) -> u32 {
+        let low = get_u16(&entry[26..]) as u32;
+        match self.fat_type {
+            FatType::Fat16 => low,
+            FatType::Fat32 => (get_u16(&entry[20..]) as u32) << 16 | low,
+        }
+    }
+}
+
+/// An open file or directory.
+#[derive(Clone, Copy)]
+struct File {
+    first_cluster: u32,
+    size: u32,
+    // In bytes for files, in directory entries for directories.
+    position: u32,
+    // The cluster `position` is in and its index in the chain, so reading
+    // on does not follow the chain from the start.
+    cluster: u32,
+    cluster_index: u32,
+    // Where the directory entry is, to update the size and first cluster.
+    entry_sector: u32,
+    entry_offset: usize,
+    flags: usize,
+    directory: bool,
+    // Files opened before the card was changed are no longer valid.
+    generation: u32,
+}
+
+/// A directory entry found by a lookup.
+#[derive(Clone, Copy)]
+struct Entry {
+    // Index of the short name entry in its directory, and of the first of
+    // its long name entries.
+    index: u32,
+    first_index: u32,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    sector: u32,
+    offset: usize,
+}
+
+const NO_ENTRY: Entry = Entry {
+    index: 0,
+    first_index: 0,
+    attributes: 0,
+    cluster: 0,
+    size: 0,
+    sector: 0,
+    offset: 0,
+};
+
+/// Where a walk through a directory is.
+#[derive(Clone, Copy)]
+struct Scan {
+    // First cluster of the directory, 0 for the root directory of FAT16.
+    first: u32,
+    // The entry the walk is at, the cluster it is in and the index of that
+    // cluster in the chain.
+    index: u32,
+    cluster: u32,
+    cluster_index: u32,
+    // The long name being put together from the entries before a short
+    // name entry.
+    long_name: bool,
+    long_next: u8,
+    long_checksum: u8,
+    long_start: u32,
+    long_len: usize,
+    // Free entries seen so far in a row, and the first row long enough for
+    // a new file.
+    free_start: u32,
+    free_len: u32,
+    free_found: Option<u32>,
+    // Past the entry that ends the directory.
+    ended: bool,
+    // Short names of the new file that are taken, one bit for each of
+    // `TAILS`.
+    tails: u16,
+}
+
+impl Scan {
+    fn new(first: u32) -> Scan {
+        Scan {
+            first: first,
+            index: 0,
+            cluster: first,
+            cluster_index: 0,
+            long_name: false,
+            long_next: 0,
+            long_checksum: 0,
+            long_start: 0,
+            long_len: 0,
+            free_start: 0,
+            free_len: 0,
+            free_found: None,
+            ended: false,
+            tails: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ScanMode {
+    /// Looking for the name of the path being looked up.
+    Lookup,
+    /// Listing a directory, stopping at the next entry.
+    List,
+    /// Freeing the entries of a deleted file, up to `last`.
+    Erase { last: u32 },
+    /// Writing the entries of a new file, `written` of them so far.
+    Create { written: u32 },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scanned {
+    More,
+    Found,
+    End,
+}
+
+enum DirSector {
+    Sector(u32),
+    End,
+    Wait,
+}
+
+enum Seek {
+    Found,
+    /// Past the last cluster of the file.
+    End,
+    /// The chain of the file is broken.
+    Broken,
+    Wait,
+}
+
+/// What the SD card is busy with.
+#[derive(Clone, Copy, PartialEq)]
+enum Io {
+    Idle,
+    Initializing,
+    Reading(u32),
+    Writing,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Idle,
+    Initialize,
+    ReadMbr,
+    ReadBootSector { start: u32 },
+    ResetFsInfo { sector: u32 },
+    /// Look up the next name of the path.
+    NextName,
+    /// Walk the directory entries, as `scan_mode` says.
+    Scan,
+    /// Look for a free cluster from `cluster`, with `left` to look at.
+    Allocate { cluster: u32, left: u32 },
+    /// Zero a cluster allocated for a directory, from `sector` on.
+    ZeroCluster { sector: u32 },
+    /// Mark the allocated cluster as the end of a chain, or with `link`
+    /// link it to the end of the chain, in the FAT `copy`.
+    UpdateFat { link: bool, copy: u32 },
+    Allocated,
+    /// Free the chain of clusters from `cluster`, in the FAT `copy`.
+    FreeChain { cluster: u32, copy: u32 },
+    ReadData,
+    WriteData,
+    UpdateEntry,
+    /// Write the cache back and call the app.
+    Flush,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Open = 1,
+    Read = 3,
+    Write = 4,
+    ReadDirectory = 5,
+    Delete = 6,
+}
+
+#[derive(Clone, Copy)]
+struct Request {
+    appid: AppId,
+    operation: Operation,
+    fd: usize,
+    // Bytes to read or write, or the length of the path for open and
+    // delete.
+    len: usize,
+    flags: usize,
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    data: Option<AppSlice<Shared, u8>>,
+    files: [Option<File>; MAX_OPEN_FILES],
+    pending: Option<Request>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            path: None,
+            data: None,
+            files: [None; MAX_OPEN_FILES],
+            pending: None,
+        }
+    }
+}
+
+pub struct FatFs<'a, A: hil::time::Alarm + 'a> {
+    sdcard: &'a SDCard<'a, A>,
+    apps: Grant<App>,
+    buffer: TakeCell<'static, [u8]>,
+    // The sector in `buffer`, and whether it was changed since it was read.
+    cached: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    io: Cell<Io>,
+    volume: Cell<Volume>,
+    mounted: Cell<bool>,
+    // Changes with the card.
+    generation: Cell<u32>,
+    // Where to start looking for a free cluster.
+    next_free: Cell<u32>,
+    step: Cell<Step>,
+    // The request being served, one at a time, and how it turned out.
+    request: Cell<Option<Request>>,
+    outcome: Cell<Result<usize, ReturnCode>>,
+    // The file being read, written, listed, created or deleted.
+    file: Cell<File>,
+    // Bytes read or written so far, and still to go.
+    done: Cell<usize>,
+    remaining: Cell<usize>,
+    path: Cell<[u8; MAX_PATH_LEN]>,
+    path_len: Cell<usize>,
+    // The name of the path being looked up.
+    name_start: Cell<usize>,
+    name_end: Cell<usize>,
+    scan: Cell<Scan>,
+    scan_mode: Cell<ScanMode>,
+    long_name: Cell<[u8; MAX_NAME_LEN]>,
+    found: Cell<Entry>,
+    // Whether the name looked up is a new file to create if it is not
+    // found, its short name and how many entries it needs.
+    creating: Cell<bool>,
+    short_name: Cell<[u8; 11]>,
+    name_hash: Cell<u16>,
+    entries_needed: Cell<u32>,
+    // The cluster allocated, and the one to link it to, 0 for none.
+    allocated: Cell<u32>,
+    link_from: Cell<u32>,
+    // The next cluster of the chain being freed.
+    free_next: Cell<u32>,
+}
+
+impl<'a, A: hil::time::Alarm + 'a> FatFs<'a, A> {
+    pub fn new(sdcard: &'a SDCard<'a, A>,
+               grant: Grant<App>,
+               buffer: &'static mut [u8; 512])
+               -> FatFs<'a, A> {
+        FatFs {
+            sdcard: sdcard,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            cached: Cell::new(None),
+            dirty: Cell::new(false),
+            io: Cell::new(Io::Idle),
+            volume: Cell::new(NO_VOLUME),
+            mounted: Cell::new(false),
+            generation: Cell::new(0),
+            next_free: Cell::new(2),
+            step: Cell::new(Step::Idle),
+            request: Cell::new(None),
+            outcome: Cell::new(Ok(0)),
+            file: Cell::new(File {
+                first_cluster: 0,
+                size: 0,
+                position: 0,
+                cluster: 0,
+                cluster_index: 0,
+                entry_sector: 0,
+                entry_offset: 0,
+                flags: 0,
+                directory: false,
+                generation: 0,
+            }),
+            done: Cell::new(0),
+            remaining: Cell::new(0),
+            path: Cell::new([0; MAX_PATH_LEN]),
+            path_len: Cell::new(0),
+            name_start: Cell::new(0),
+            name_end: Cell::new(0),
+            scan: Cell::new(Scan::new(0)),
+            scan_mode: Cell::new(ScanMode::Lookup),
+            long_name: Cell::new([0; MAX_NAME_LEN]),
+            found: Cell::new(NO_ENTRY),
+            creating: Cell::new(false),
+            short_name: Cell::new([b' '; 11]),
+            name_hash: Cell::new(0),
+            entries_needed: Cell::new(1),
+            allocated: Cell::new(0),
+            link_from: Cell::new(0),
+            free_next: Cell::new(0),
+        }
+    }
+
+    // Check so see if we are doing something. If not, go ahead and do this
+    // request. If so, this is queued and will be run when the pending
+    // request completes.
+    fn enqueue(&self,
+               appid: AppId,
+               operation: Operation,
+               fd: usize,
+               len: usize,
+               flags: usize)
+               -> ReturnCode {
+        let request = Request {
+            appid: appid,
+            operation: operation,
+            fd: fd,
+            len: len,
+            flags: flags,
+        };
+
+        let start = self.apps
+            .enter(appid, |app, _| {
+                match operation {
+                    Operation::Open | Operation::Delete => {
+                        let path_buf_len = app.path.as_ref().map_or(0, |path| path.len());
+                        if len == 0 || len > MAX_PATH_LEN || len > path_buf_len {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                        if operation == Operation::Open &&
+                           app.files.iter().all(|file| file.is_some()) {
+                            return Err(ReturnCode::ENOMEM);
+                        }
+                    }
+                    _ => {
+                        if fd >= MAX_OPEN_FILES || app.files[fd].is_none() {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                    }
+                }
+
+                if self.request.get().is_none() {
+                    Ok(Some(request))
+                } else if app.pending.is_some() {
+                    // No more room in the queue, nowhere to store this
+                    // request.
+                    Err(ReturnCode::EBUSY)
+                } else {
+                    app.pending = Some(request);
+                    Ok(None)
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match start {
+            Ok(request) => {
+                request.map(|request| self.start(request));
+                ReturnCode::SUCCESS
+            }
+            Err(err) => err,
+        }
+    }
+
+    fn start(&self, request: Request) {
+        self.request.set(Some(request));
+        self.done.set(0);
+        self.remaining.set(0);
+
+        // Take the path or the file now, the app may change them before the
+        // request is served.
+        let generation = self.generation.get();
+        let prepared = self.apps
+            .enter(request.appid, |app, _| {
+                if request.operation == Operation::Open || request.operation == Operation::Delete {
+                    return app.path.as_ref().map_or(Err(ReturnCode::EINVAL), |app_path| {
+                        if app_path.len() < request.len {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                        let mut path = [0; MAX_PATH_LEN];
+                        path[0..request.len].copy_from_slice(&app_path.as_ref()[0..request.len]);
+                        let mut len = request.len;
+                        while len > 0 && path[len - 1] == b'/' {
+                            len -= 1;
+                        }
+                        self.path.set(path);
+                        self.path_len.set(len);
+                        Ok(())
+                    });
+                }
+
+                let mut file = match app.files[request.fd] {
+                    Some(file) => file,
+                    None => return Err(ReturnCode::EINVAL),
+                };
+                if file.generation != generation {
+                    return Err(ReturnCode::ECANCEL);
+                }
+                match request.operation {
+                    Operation::Read if file.directory => return Err(ReturnCode::EINVAL),
+                    Operation::Write if file.directory => return Err(ReturnCode::EINVAL),
+                    Operation::Write if file.flags & OPEN_WRITE == 0 => {
+                        return Err(ReturnCode::EPERM);
+                    }
+                    Operation::ReadDirectory if !file.directory => return Err(ReturnCode::EINVAL),
+                    _ => {}
+                }
+                if request.operation == Operation::Write && file.flags & OPEN_APPEND != 0 {
+                    file.position = file.size;
+                }
+                let data_len = app.data.as_ref().map_or(0, |data| data.len());
+                let mut remaining = cmp::min(request.len, data_len);
+                if request.operation == Operation::Write {
+                    // Files end at 4 GB.
+                    remaining = cmp::min(remaining, (0xFFFFFFFF - file.position) as usize);
+                }
+                self.remaining.set(remaining);
+                self.file.set(file);
+                Ok(())
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match prepared {
+            Err(err) => self.fail(err),
+            Ok(()) if self.buffer.is_none() => self.fail(ReturnCode::ENOMEM),
+            Ok(()) if self.mounted.get() => self.begin(),
+            Ok(()) => self.step.set(Step::Initialize),
+        }
+        self.run();
+    }
+
+    /// The first step of the request, once the card is mounted.
+    fn begin(&self) {
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return self.step.set(Step::Idle),
+        };
+        match request.operation {
+            Operation::Open | Operation::Delete => {
+                self.scan.set(Scan::new(self.volume.get().root()));
+                self.name_end.set(0);
+                self.step.set(Step::NextName);
+            }
+            Operation::Read => self.step.set(Step::ReadData),
+            Operation::Write => self.step.set(Step::WriteData),
+            Operation::ReadDirectory => {
+                let file = self.file.get();
+                let mut scan = Scan::new(file.first_cluster);
+                scan.index = file.position;
+                if file.cluster != 0 {
+                    scan.cluster = file.cluster;
+                    scan.cluster_index = file.cluster_index;
+                }
+                self.scan.set(scan);
+                self.scan_mode.set(ScanMode::List);
+                self.step.set(Step::Scan);
+            }
+        }
+    }
+
+    /// Carry on with the request until it has to wait for the card.
+    fn run(&self) {
+        while self.io.get() == Io::Idle {
+            match self.step.get() {
+                Step::Idle => return,
+                Step::Initialize => self.initialize(),
+                Step::ReadMbr => self.read_mbr(),
+                Step::ReadBootSector { start } => self.read_boot_sector(start),
+                Step::ResetFsInfo { sector } => self.reset_fs_info(sector),
+                Step::NextName => self.next_name(),
+                Step::Scan => self.scan(),
+                Step::Allocate { cluster, left } => self.allocate_from(cluster, left),
+                Step::ZeroCluster { sector } => self.zero_cluster(sector),
+                Step::UpdateFat { link, copy } => self.update_fat(link, copy),
+                Step::Allocated => self.allocated(),
+                Step::FreeChain { cluster, copy } => self.free_chain(cluster, copy),
+                Step::ReadData => self.read_data(),
+                Step::WriteData => self.write_data(),
+                Step::UpdateEntry => self.update_entry(),
+                Step::Flush => {
+                    if self.dirty.get() {
+                        self.flush();
+                    } else {
+                        self.deliver();
+                    }
+                }
+            }
+        }
+    }
+
+    fn complete(&self, outcome: Result<usize, ReturnCode>) {
+        self.outcome.set(outcome);
+        self.step.set(Step::Flush);
+    }
+
+    fn fail(&self, err: ReturnCode) {
+        self.complete(Err(err));
+    }
+
+    fn deliver(&self) {
+        self.step.set(Step::Idle);
+        let outcome = self.outcome.get();
+        let file = self.file.get();
+        self.request.take().map(|request| {
+            let _ = self.apps.enter(request.appid, |app, _| {
+                let result = match outcome {
+                    Ok(value) => {
+                        match request.operation {
+                            Operation::Open => {
+                                match app.files.iter().position(|file| file.is_none()) {
+                                    Some(fd) => {
+                                        app.files[fd] = Some(file);
+                                        fd
+                                    }
+                                    None => usize::from(ReturnCode::ENOMEM),
+                                }
+                            }
+                            Operation::Delete => value,
+                            _ => {
+                                // Unless the app closed it in the meantime.
+                                if app.files[request.fd].is_some() {
+                                    app.files[request.fd] = Some(file);
+                                }
+                                value
+                            }
+                        }
+                    }
+                    Err(err) => usize::from(err),
+                };
+                app.callback.map(|mut cb| cb.schedule(result, request.operation as usize, 0));
+            });
+        });
+        self.check_queue();
+    }
+
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let request = cntr.enter(|app, _| app.pending.take());
+            if let Some(request) = request {
+                self.start(request);
+                break;
+            }
+        }
+    }
+
+    // The sector cache.
+
+    /// Run `f` on `sector`. If the sector is not in the cache, start reading
+    /// it and return `None`, the step is run again once it is read.
+    fn sector<F, R>(&self, sector: u32, f: F) -> Option<R>
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        self.with_sector(sector, true, f)
+    }
+
+    /// Like `sector`, but if not `read` the sector is about to be written
+    /// whole and is not read first.
+    fn with_sector<F, R>(&self, sector: u32, read: bool, f: F) -> Option<R>
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        if self.cached.get() != Some(sector) {
+            if self.dirty.get() {
+                self.flush();
+                return None;
+            }
+            if read {
+                self.load(sector);
+                return None;
+            }
+            self.cached.set(Some(sector));
+        }
+        let result = self.buffer.map(|buffer| f(&mut buffer[0..SECTOR_LEN]));
+        if result.is_none() {
+            self.fail(ReturnCode::ENOMEM);
+        }
+        result
+    }
+
+    fn load(&self, sector: u32) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.cached.set(None);
+                self.io.set(Io::Reading(sector));
+                let (rc, buffer) = self.sdcard.read_blocks(buffer, sector, 1);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                if rc != ReturnCode::SUCCESS {
+                    self.io_error(rc);
+                }
+            }
+            None => self.io_error(ReturnCode::ENOMEM),
+        }
+    }
+
+    fn flush(&self) {
+        match (self.cached.get(), self.buffer.take()) {
+            (Some(sector), Some(buffer)) => {
+                self.io.set(Io::Writing);
+                let (rc, buffer) = self.sdcard.write_blocks(buffer, sector, 1);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                if rc != ReturnCode::SUCCESS {
+                    self.io_error(rc);
+                }
+            }
+            (_, buffer) => {
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                self.dirty.set(false);
+            }
+        }
+    }
+
+    /// Give up on the request after the card failed. What was not written
+    /// yet is lost.
+    fn io_error(&self, err: ReturnCode) {
+        self.sdcard.take_buffer().map(|buffer| self.buffer.replace(buffer));
+        self.io.set(Io::Idle);
+        self.cached.set(None);
+        self.dirty.set(false);
+        if self.request.get().is_some() {
+            self.fail(err);
+        }
+    }
+
+    /// The FAT entry of `cluster`, `None` while its sector is read.
+    fn fat_entry(&self, cluster: u32) -> Option<u32> {
+        let volume = self.volume.get();
+        let (sector, offset) = volume.fat_location(cluster, 0);
+        self.sector(sector, |buffer| volume.get_fat(buffer, offset))
+    }
+
+    // Mounting.
+
+    fn initialize(&self) {
+        if self.sdcard.is_initialized() {
+            return self.step.set(Step::ReadMbr);
+        }
+        let rc = self.sdcard.initialize();
+        if rc == ReturnCode::SUCCESS {
+            self.io.set(Io::Initializing);
+        } else {
+            self.fail(rc);
+        }
+    }
+
+    fn read_mbr(&self) {
+        match self.sector(0, |buffer| partition_start(buffer)) {
+            None => {}
+            Some(None) => self.fail(ReturnCode::ENOSUPPORT),
+            Some(Some(start)) => self.step.set(Step::ReadBootSector { start: start }),
+        }
+    }
+
+    fn read_boot_sector(&self, start: u32) {
+        match self.sector(start, |buffer| parse_boot_sector(buffer, start)) {
+            None => {}
+            Some(None) => self.fail(ReturnCode::ENOSUPPORT),
+            Some(Some(volume)) => {
+                self.volume.set(volume);
+                if volume.fat_type == FatType::Fat32 && volume.fs_info != 0 &&
+                   volume.fs_info != 0xFFFF {
+                    self.step.set(Step::ResetFsInfo { sector: start + volume.fs_info });
+                } else {
+                    self.mounted_volume();
+                }
+            }
+        }
+    }
+
+    /// Clusters are allocated without keeping count, so mark the count of
+    /// free clusters of FAT32 as unknown for laptops to count them again.
+    fn reset_fs_info(&self, sector: u32) {
+        let reset = self.sector(sector, |buffer| {
+            if get_u32(&buffer[0..]) == 0x41615252 && get_u32(&buffer[484..]) == 0x61417272 &&
+               (get_u32(&buffer[488..]) != 0xFFFFFFFF || get_u32(&buffer[492..]) != 0xFFFFFFFF) {
+                put_u32(&mut buffer[488..], 0xFFFFFFFF);
+                put_u32(&mut buffer[492..], 0xFFFFFFFF);
+                self.dirty.set(true);
+            }
+        });
+        if reset.is_some() {
+            self.mounted_volume();
+        }
+    }
+
+    fn mounted_volume(&self) {
+        self.mounted.set(true);
+        self.next_free.set(2);
+        self.begin();
+    }
+
+    // Looking up paths.
+
+    fn next_name(&self) {
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return self.step.set(Step::Idle),
+        };
+        let path = self.path.get();
+        let len = self.path_len.get();
+        let mut start = self.name_end.get();
+        while start < len && path[start] == b'/' {
+            start += 1;
+        }
+        if start == len {
+            // The path names the root directory.
+            if request.operation != Operation::Open || request.flags & OPEN_WRITE != 0 {
+                return self.fail(ReturnCode::EINVAL);
+            }
+            let root = self.volume.get().root();
+            self.file.set(self.new_file(root, 0, true, 0, 0));
+            return self.complete(Ok(0));
+        }
+        let end = path[start..len].iter().position(|c| *c == b'/').map_or(len, |i| start + i);
+        self.name_start.set(start);
+        self.name_end.set(end);
+
+        let name = &path[start..end];
+        let creating = end == len && request.operation == Operation::Open &&
+                       request.flags & OPEN_CREATE != 0 &&
+                       valid_name(name);
+        self.creating.set(creating);
+        if creating {
+            let (short_name, exact) = short_name(name);
+            self.short_name.set(short_name);
+            self.name_hash.set(name_hash(name));
+            self.entries_needed.set(if exact {
+                1
+            } else {
+                ((name.len() + 12) / 13 + 1) as u32
+            });
+        }
+        self.scan_mode.set(ScanMode::Lookup);
+        self.step.set(Step::Scan);
+    }
+
+    /// The sector of the entry the scan is at, following the chain of the
+    /// directory as far as needed.
+    fn dir_sector(&self) -> DirSector {
+        let volume = self.volume.get();
+        let mut scan = self.scan.get();
+        if scan.first == 0 {
+            return if scan.index < volume.root_entries {
+                DirSector::Sector(volume.root_start + scan.index / ENTRIES_PER_SECTOR)
+            } else {
+                DirSector::End
+            };
+        }
+        let per_cluster = volume.sectors_per_cluster * ENTRIES_PER_SECTOR;
+        while scan.index / per_cluster > scan.cluster_index {
+            match self.fat_entry(scan.cluster) {
+                None => return DirSector::Wait,
+                Some(next) if volume.is_cluster(next) => {
+                    scan.cluster = next;
+                    scan.cluster_index += 1;
+                    self.scan.set(scan);
+                }
+                Some(_) => return DirSector::End,
+            }
+        }
+        DirSector::Sector(volume.cluster_sector(scan.cluster) +
+                          (scan.index % per_cluster) / ENTRIES_PER_SECTOR)
+    }
+
+    /// Walk the entries of one sector of the directory.
+    fn scan(&self) {
+        let sector = match self.dir_sector() {
+            DirSector::Sector(sector) => sector,
+            DirSector::End => return self.scan_done(Scanned::End),
+            DirSector::Wait => return,
+        };
+        let scanned = self.sector(sector, |buffer| {
+            let mut scan = self.scan.get();
+            let mut scanned = Scanned::More;
+            for i in (scan.index % ENTRIES_PER_SECTOR) as usize..ENTRIES_PER_SECTOR as usize {
+                let offset = i * ENTRY_LEN;
+                scanned = self.visit(&mut scan, &mut buffer[offset..offset + ENTRY_LEN], sector, offset);
+                if scanned != Scanned::More {
+                    break;
+                }
+                scan.index += 1;
+            }
+            self.scan.set(scan);
+            scanned
+        });
+        match scanned {
+            Some(Scanned::More) | None => {}
+            Some(scanned) => self.scan_done(scanned),
+        }
+    }
+
+    fn visit(&self, scan: &mut Scan, entry: &mut [u8], sector: u32, offset: usize) -> Scanned {
+        match self.scan_mode.get() {
+            ScanMode::Lookup => self.visit_lookup(scan, entry, sector, offset),
+            ScanMode::List => self.visit_list(scan, entry),
+            ScanMode::Erase { last } => {
+                entry[0] = FREE_ENTRY;
+                self.dirty.set(true);
+                if scan.index == last {
+                    Scanned::Found
+                } else {
+                    Scanned::More
+                }
+            }
+            ScanMode::Create { written } => {
+                let short_name = self.short_name.get();
+                let needed = self.entries_needed.get();
+                self.dirty.set(true);
+                if written + 1 < needed {
+                    let path = self.path.get();
+                    let name = &path[self.name_start.get()..self.name_end.get()];
+                    let order = (needed - 1 - written) as u8;
+                    write_long_entry(entry, name, order, written == 0, checksum(&short_name));
+                    self.scan_mode.set(ScanMode::Create { written: written + 1 });
+                    Scanned::More
+                } else {
+                    write_short_entry(entry, &short_name);
+                    self.file.set(self.new_file(0, 0, false, sector, offset));
+                    Scanned::Found
+                }
+            }
+        }
+    }
+
+    fn visit_lookup(&self, scan: &mut Scan, entry: &[u8], sector: u32, offset: usize) -> Scanned {
+        let creating = self.creating.get();
+        if scan.ended || entry[0] == 0 {
+            // Only free entries from here on, which a new file can use.
+            if !creating {
+                return Scanned::End;
+            }
+            scan.ended = true;
+            self.count_free(scan);
+            return Scanned::More;
+        }
+        if entry[0] == FREE_ENTRY {
+            scan.long_name = false;
+            self.count_free(scan);
+            return Scanned::More;
+        }
+        scan.free_len = 0;
+        if self.long_name_part(scan, entry) {
+            return Scanned::More;
+        }
+        let long_name = self.has_long_name(scan, entry);
+        scan.long_name = false;
+        if entry[11] & ATTR_VOLUME_ID != 0 {
+            return Scanned::More;
+        }
+
+        if creating {
+            let short_name = self.short_name.get();
+            let hash = self.name_hash.get();
+            for tail in 0..TAILS {
+                if entry[0..11] == with_tail(&short_name, hash, tail)[..] {
+                    scan.tails |= 1 << tail;
+                }
+            }
+        }
+
+        let path = self.path.get();
+        let name = &path[self.name_start.get()..self.name_end.get()];
+        let (short, short_len) = short_name_display(entry);
+        let matched = same_name(&short[0..short_len], name) ||
+                      long_name && scan.long_len <= MAX_NAME_LEN &&
+                      same_name(&self.long_name.get()[0..scan.long_len], name);
+        if !matched {
+            return Scanned::More;
+        }
+        self.found.set(Entry {
+            index: scan.index,
+            first_index: if long_name { scan.long_start } else { scan.index },
+            attributes: entry[11],
+            cluster: self.volume.get().entry_cluster(entry),
+            size: get_u32(&entry[28..]),
+            sector: sector,
+            offset: offset,
+        });
+        Scanned::Found
+    }
+
+    fn visit_list(&self, scan: &mut Scan, entry: &[u8]) -> Scanned {
+        if entry[0] == 0 {
+            return Scanned::End;
+        }
+        if entry[0] == FREE_ENTRY {
+            scan.long_name = false;
+            return Scanned::More;
+        }
+        if self.long_name_part(scan, entry) {
+            return Scanned::More;
+        }
+        let long_name = self.has_long_name(scan, entry);
+        scan.long_name = false;
+        // Skip the volume label and the "." and ".." entries.
+        if entry[11] & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+            return Scanned::More;
+        }
+
+        let mut name = [0; MAX_NAME_LEN];
+        let name_len = if long_name {
+            name = self.long_name.get();
+            cmp::min(scan.long_len, MAX_NAME_LEN)
+        } else {
+            let (short, short_len) = short_name_display(entry);
+            name[0..short_len].copy_from_slice(&short[0..short_len]);
+            short_len
+        };
+
+        // Record: | size: u32 | attributes: u8 | name |
+        let mut record = [0; 5 + MAX_NAME_LEN];
+        put_u32(&mut record[0..], get_u32(&entry[28..]));
+        record[4] = entry[11];
+        record[5..5 + name_len].copy_from_slice(&name[0..name_len]);
+        self.request.get().map(|request| {
+            let _ = self.apps.enter(request.appid, |app, _| {
+                app.data.as_mut().map(|data| {
+                    let len = cmp::min(data.len(), 5 + name_len);
+                    data.as_mut()[0..len].copy_from_slice(&record[0..len]);
+                });
+            });
+        });
+        self.done.set(name_len);
+        Scanned::Found
+    }
+
+    /// Put a long name entry into the name being put together. Returns
+    /// false if `entry` is not one.
+    fn long_name_part(&self, scan: &mut Scan, entry: &[u8]) -> bool {
+        if entry[11] & 0x3F != ATTR_LONG_NAME {
+            return false;
+        }
+        let order = entry[0] & 0x1F;
+        if entry[0] & LAST_LONG_ENTRY != 0 {
+            scan.long_name = order != 0;
+            scan.long_start = scan.index;
+            scan.long_checksum = entry[13];
+            scan.long_len = order as usize * 13;
+        } else if !scan.long_name || order != scan.long_next || entry[13] != scan.long_checksum {
+            scan.long_name = false;
+        }
+        if scan.long_name {
+            let mut name = self.long_name.get();
+            for (i, at) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let index = (order as usize - 1) * 13 + i;
+                match get_u16(&entry[*at..]) {
+                    0 => {
+                        scan.long_len = cmp::min(scan.long_len, index);
+                        break;
+                    }
+                    c if index < MAX_NAME_LEN => name[index] = if c < 0x80 { c as u8 } else { b'?' },
+                    _ => {}
+                }
+            }
+            self.long_name.set(name);
+            scan.long_next = order - 1;
+        }
+        true
+    }
+
+    /// Whether the long name put together belongs to the short name
+    /// `entry`.
+    fn has_long_name(&self, scan: &Scan, entry: &[u8]) -> bool {
+        scan.long_name && scan.long_next == 0 && checksum(&entry[0..11]) == scan.long_checksum
+    }
+
+    fn count_free(&self, scan: &mut Scan) {
+        if scan.free_len == 0 {
+            scan.free_start = scan.index;
+        }
+        scan.free_len += 1;
+        if scan.free_found.is_none() && scan.free_len >= self.entries_needed.get() {
+            scan.free_found = Some(scan.free_start);
+        }
+    }
+
+    fn scan_done(&self, scanned: Scanned) {
+        let scan = self.scan.get();
+        match (self.scan_mode.get(), scanned) {
+            (ScanMode::Lookup, Scanned::Found) => self.found(),
+            (ScanMode::Lookup, _) => self.not_found(),
+            (ScanMode::List, scanned) => {
+                let mut file = self.file.get();
+                file.position = if scanned == Scanned::Found {
+                    scan.index + 1
+                } else {
+                    scan.index
+                };
+                file.cluster = scan.cluster;
+                file.cluster_index = scan.cluster_index;
+                self.file.set(file);
+                let done = if scanned == Scanned::Found { self.done.get() } else { 0 };
+                self.complete(Ok(done));
+            }
+            (ScanMode::Erase { .. }, Scanned::Found) => {
+                let cluster = self.file.get().first_cluster;
+                self.step.set(Step::FreeChain {
+                    cluster: cluster,
+                    copy: 0,
+                });
+            }
+            (ScanMode::Create { .. }, Scanned::Found) => self.complete(Ok(0)),
+            // The directory ended before the entries did.
+            _ => self.fail(ReturnCode::FAIL),
+        }
+    }
+
+    fn found(&self) {
+        let entry = self.found.get();
+        if self.name_end.get() < self.path_len.get() {
+            if entry.attributes & ATTR_DIRECTORY == 0 {
+                return self.fail(ReturnCode::FAIL);
+            }
+            // ".." of a directory in the root directory says cluster 0.
+            let first = if entry.cluster == 0 {
+                self.volume.get().root()
+            } else {
+                entry.cluster
+            };
+            self.scan.set(Scan::new(first));
+            return self.step.set(Step::NextName);
+        }
+        match self.request.get().map(|request| request.operation) {
+            Some(Operation::Open) => self.open_found(entry),
+            _ => self.delete_found(entry),
+        }
+    }
+
+    fn not_found(&self) {
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return self.step.set(Step::Idle),
+        };
+        if request.operation != Operation::Open || request.flags & OPEN_CREATE == 0 ||
+           self.name_end.get() < self.path_len.get() {
+            return self.fail(ReturnCode::FAIL);
+        }
+        if !self.creating.get() {
+            return self.fail(ReturnCode::EINVAL);
+        }
+
+        let scan = self.scan.get();
+        if self.entries_needed.get() > 1 {
+            match (0..TAILS).find(|tail| scan.tails & (1 << *tail) == 0) {
+                Some(tail) => {
+                    let short_name = with_tail(&self.short_name.get(), self.name_hash.get(), tail);
+                    self.short_name.set(short_name);
+                }
+                None => return self.fail(ReturnCode::FAIL),
+            }
+        }
+        self.scan_mode.set(ScanMode::Create { written: 0 });
+        match scan.free_found {
+            Some(index) => {
+                let mut create = Scan::new(scan.first);
+                create.index = index;
+                self.scan.set(create);
+                self.step.set(Step::Scan);
+            }
+            // The root directory of FAT16 cannot grow.
+            None if scan.first == 0 => self.fail(ReturnCode::ENOMEM),
+            None => {
+                // The scan is at the start of the cluster the directory
+                // needs, the entries of the new file start in it or in the
+                // free entries just before it.
+                self.link_from.set(scan.cluster);
+                self.allocate();
+            }
+        }
+    }
+
+    fn new_file(&self,
+                first_cluster: u32,
+                size: u32,
+                directory: bool,
+                entry_sector: u32,
+                entry_offset: usize)
+                -> File {
+        File {
+            first_cluster: first_cluster,
+            size: size,
+            position: 0,
+            cluster: first_cluster,
+            cluster_index: 0,
+            entry_sector: entry_sector,
+            entry_offset: entry_offset,
+            flags: self.request.get().map_or(0, |request| request.flags),
+            directory: directory,
+            generation: self.generation.get(),
+        }
+    }
+
+    /// Whether any app has the file of `entry` open, or only open for
+    /// writing if `writing`. Open files keep their own copy of the first
+    /// cluster and size of the file, which must not change under them.
+    fn is_open(&self, entry: &Entry, writing: bool) -> bool {
+        let generation = self.generation.get();
+        self.apps.iter().any(|cntr| {
+            cntr.enter(|app, _| {
+                app.files.iter().any(|file| {
+                    file.map_or(false, |file| {
+                        file.generation == generation && !file.directory &&
+                        file.entry_sector == entry.sector &&
+                        file.entry_offset == entry.offset &&
+                        (!writing || file.flags & OPEN_WRITE != 0)
+                    })
+                })
+            })
+        })
+    }
+
+    fn open_found(&self, entry: Entry) {
+        let flags = self.request.get().map_or(0, |request| request.flags);
+        let directory = entry.attributes & ATTR_DIRECTORY != 0;
+        if flags & OPEN_WRITE != 0 {
+            if directory {
+                return self.fail(ReturnCode::EINVAL);
+            }
+            if entry.attributes & ATTR_READ_ONLY != 0 {
+                return self.fail(ReturnCode::EPERM);
+            }
+            // A file has at most one writer, and is only emptied while
+            // nobody else has it open.
+            if self.is_open(&entry, flags & OPEN_TRUNCATE == 0) {
+                return self.fail(ReturnCode::EBUSY);
+            }
+        }
+
+        if directory {
+            let first = if entry.cluster == 0 {
+                self.volume.get().root()
+            } else {
+                entry.cluster
+            };
+            self.file.set(self.new_file(first, 0, true, entry.sector, entry.offset));
+        } else if flags & OPEN_WRITE != 0 && flags & OPEN_TRUNCATE != 0 {
+            // Empty the entry first, like a delete, then free the chain.
+            self.file.set(self.new_file(0, 0, false, entry.sector, entry.offset));
+            self.free_next.set(entry.cluster);
+            return self.step.set(Step::UpdateEntry);
+        } else {
+            self.file.set(self.new_file(entry.cluster, entry.size, false, entry.sector, entry.offset));
+        }
+        self.complete(Ok(0));
+    }
+
+    fn delete_found(&self, entry: Entry) {
+        if entry.attributes & ATTR_DIRECTORY != 0 {
+            return self.fail(ReturnCode::EINVAL);
+        }
+        if entry.attributes & ATTR_READ_ONLY != 0 {
+            return self.fail(ReturnCode::EPERM);
+        }
+        if self.is_open(&entry, false) {
+            return self.fail(ReturnCode::EBUSY);
+        }
+
+        // Free the entries first, a reset before the clusters are freed only
+        // loses the space.
+        self.file.set(self.new_file(entry.cluster, 0, false, entry.sector, entry.offset));
+        let mut erase = Scan::new(self.scan.get().first);
+        erase.index = entry.first_index;
+        self.scan.set(erase);
+        self.scan_mode.set(ScanMode::Erase { last: entry.index });
+        self.step.set(Step::Scan);
+    }
+
+    // Clusters.
+
+    fn allocate(&self) {
+        let volume = self.volume.get();
+        self.step.set(Step::Allocate {
+            cluster: self.next_free.get(),
+            left: volume.clusters + (SECTOR_LEN / volume.fat_entry_len()) as u32,
+        });
+    }
+
+    /// Look for a free cluster in the FAT sector of `cluster`.
+    fn allocate_from(&self, cluster: u32, left: u32) {
+        if left == 0 {
+            return self.fail(ReturnCode::ENOMEM);
+        }
+        let volume = self.volume.get();
+        let cluster = if volume.is_cluster(cluster) { cluster } else { 2 };
+        let (sector, offset) = volume.fat_location(cluster, 0);
+        let entry_len = volume.fat_entry_len();
+        let in_sector = ((SECTOR_LEN - offset) / entry_len) as u32;
+        let free = self.sector(sector, |buffer| {
+            (0..in_sector).find(|i| {
+                volume.is_cluster(cluster + i) &&
+                volume.get_fat(buffer, offset + *i as usize * entry_len) == 0
+            })
+        });
+        match free {
+            None => {}
+            Some(Some(i)) => {
+                self.allocated.set(cluster + i);
+                self.next_free.set(cluster + i + 1);
+                let writing = self.request.get().map_or(false, |request| {
+                    request.operation == Operation::Write
+                });
+                if writing {
+                    self.step.set(Step::UpdateFat {
+                        link: false,
+                        copy: 0,
+                    });
+                } else {
+                    // A directory must not get a cluster of old entries.
+                    self.step.set(Step::ZeroCluster { sector: 0 });
+                }
+            }
+            Some(None) => {
+                self.step.set(Step::Allocate {
+                    cluster: cluster + in_sector,
+                    left: left.saturating_sub(in_sector),
+                });
+            }
+        }
+    }
+
+    fn zero_cluster(&self, sector: u32) {
+        let volume = self.volume.get();
+        if sector == volume.sectors_per_cluster {
+            return self.step.set(Step::UpdateFat {
+                link: false,
+                copy: 0,
+            });
+        }
+        let first = volume.cluster_sector(self.allocated.get());
+        let zeroed = self.with_sector(first + sector, false, |buffer| {
+            for byte in buffer.iter_mut() {
+                *byte = 0;
+            }
+            self.dirty.set(true);
+        });
+        if zeroed.is_some() {
+            self.step.set(Step::ZeroCluster { sector: sector + 1 });
+        }
+    }
+
+    /// Mark the allocated cluster as the end of its chain, then link it to
+    /// the end of the chain, in every FAT.
+    fn update_fat(&self, link: bool, copy: u32) {
+        let volume = self.volume.get();
+        let (cluster, entry) = if link {
+            (self.link_from.get(), self.allocated.get())
+        } else {
+            (self.allocated.get(), volume.end_of_chain())
+        };
+        let (sector, offset) = volume.fat_location(cluster, copy);
+        let updated = self.sector(sector, |buffer| {
+            volume.put_fat(buffer, offset, entry);
+            self.dirty.set(true);
+        });
+        if updated.is_none() {
+            return;
+        }
+        if copy + 1 < volume.num_fats {
+            self.step.set(Step::UpdateFat {
+                link: link,
+                copy: copy + 1,
+            });
+        } else if !link && self.link_from.get() != 0 {
+            self.step.set(Step::UpdateFat {
+                link: true,
+                copy: 0,
+            });
+        } else {
+            self.step.set(Step::Allocated);
+        }
+    }
+
+    fn allocated(&self) {
+        let cluster = self.allocated.get();
+        let writing = self.request
+            .get()
+            .map_or(false, |request| request.operation == Operation::Write);
+        if writing {
+            let mut file = self.file.get();
+            if file.first_cluster == 0 {
+                file.first_cluster = cluster;
+                file.cluster_index = 0;
+            } else {
+                file.cluster_index += 1;
+            }
+            file.cluster = cluster;
+            self.file.set(file);
+            self.step.set(Step::WriteData);
+        } else {
+            // The directory has a new cluster for the entries of a new file.
+            // They start in the free entries at the end of the old last
+            // cluster, if there are any: a scan stops at the first entry
+            // that ends the directory, so none may be left before the new
+            // file.
+            let scan = self.scan.get();
+            let mut create = Scan::new(scan.first);
+            create.index = if scan.free_len > 0 {
+                scan.free_start
+            } else {
+                scan.index
+            };
+            self.scan.set(create);
+            self.step.set(Step::Scan);
+        }
+    }
+
+    fn free_chain(&self, cluster: u32, copy: u32) {
+        let volume = self.volume.get();
+        if !volume.is_cluster(cluster) {
+            return self.complete(Ok(0));
+        }
+        let (sector, offset) = volume.fat_location(cluster, copy);
+        let next = self.sector(sector, |buffer| {
+            let next = volume.get_fat(buffer, offset);
+            volume.put_fat(buffer, offset, 0);
+            self.dirty.set(true);
+            next
+        });
+        match next {
+            None => {}
+            Some(next) => {
+                if copy == 0 {
+                    self.free_next.set(next);
+                }
+                if copy + 1 < volume.num_fats {
+                    self.step.set(Step::FreeChain {
+                        cluster: cluster,
+                        copy: copy + 1,
+                    });
+                } else {
+                    self.next_free.set(cmp::min(self.next_free.get(), cluster));
+                    self.step.set(Step::FreeChain {
+                        cluster: self.free_next.get(),
+                        copy: 0,
+                    });
+                }
+            }
+        }
+    }
+
+    // File data.
+
+    /// Follow the chain of `file` to the cluster of its position.
+    fn seek_cluster(&self, file: &mut File) -> Seek {
+        let volume = self.volume.get();
+        if file.first_cluster == 0 {
+            return Seek::End;
+        }
+        if !volume.is_cluster(file.first_cluster) {
+            return Seek::Broken;
+        }
+        let index = file.position / volume.cluster_bytes();
+        if !volume.is_cluster(file.cluster) || index < file.cluster_index {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < index {
+            match self.fat_entry(file.cluster) {
+                None => return Seek::Wait,
+                Some(next) if volume.is_cluster(next) => {
+                    file.cluster = next;
+                    file.cluster_index += 1;
+                }
+                Some(next) if volume.is_end_of_chain(next) => return Seek::End,
+                Some(_) => return Seek::Broken,
+            }
+        }
+        Seek::Found
+    }
+
+    /// The sector of the position of `file`, the offset in it and the
+    /// bytes of the next `len` in it.
+    fn data_location(&self, file: &File, len: usize) -> (u32, usize, usize) {
+        let volume = self.volume.get();
+        let sector = volume.cluster_sector(file.cluster) +
+                     (file.position % volume.cluster_bytes()) / SECTOR_LEN as u32;
+        let offset = file.position as usize % SECTOR_LEN;
+        (sector, offset, cmp::min(len, SECTOR_LEN - offset))
+    }
+
+    fn read_data(&self) {
+        let mut file = self.file.get();
+        let remaining = cmp::min(self.remaining.get(),
+                                 file.size.saturating_sub(file.position) as usize);
+        if remaining == 0 {
+            return self.complete(Ok(self.done.get()));
+        }
+        let seek = self.seek_cluster(&mut file);
+        self.file.set(file);
+        match seek {
+            Seek::Found => {}
+            Seek::End => return self.complete(Ok(self.done.get())),
+            Seek::Broken => return self.fail(ReturnCode::FAIL),
+            Seek::Wait => return,
+        }
+
+        let (sector, offset, len) = self.data_location(&file, remaining);
+        let done = self.done.get();
+        let appid = match self.request.get() {
+            Some(request) => request.appid,
+            None => return self.step.set(Step::Idle),
+        };
+        let read = self.sector(sector, |buffer| {
+            // The app may have allowed a shorter buffer since.
+            let _ = self.apps.enter(appid, |app, _| {
+                app.data.as_mut().map(|data| {
+                    let end = cmp::min(done + len, data.len());
+                    if end > done {
+                        data.as_mut()[done..end].copy_from_slice(&buffer[offset..offset + end - done]);
+                    }
+                });
+            });
+        });
+        if read.is_some() {
+            file.position += len as u32;
+            self.file.set(file);
+            self.done.set(done + len);
+            self.remaining.set(self.remaining.get() - len);
+        }
+    }
+
+    fn write_data(&self) {
+        let remaining = self.remaining.get();
+        if remaining == 0 {
+            return self.step.set(Step::UpdateEntry);
+        }
+        let mut file = self.file.get();
+        let seek = self.seek_cluster(&mut file);
+        self.file.set(file);
+        match seek {
+            Seek::Found => {}
+            Seek::End => {
+                self.link_from.set(if file.first_cluster == 0 {
+                    0
+                } else {
+                    file.cluster
+                });
+                return self.allocate();
+            }
+            Seek::Broken => return self.fail(ReturnCode::FAIL),
+            Seek::Wait => return,
+        }
+
+        let (sector, offset, len) = self.data_location(&file, remaining);
+        let done = self.done.get();
+        let appid = match self.request.get() {
+            Some(request) => request.appid,
+            None => return self.step.set(Step::Idle),
+        };
+        // A sector written whole need not be read.
+        let whole = offset == 0 && len == SECTOR_LEN;
+        let written = self.with_sector(sector, !whole, |buffer| {
+            for byte in buffer[offset..offset + len].iter_mut() {
+                *byte = 0;
+            }
+            let _ = self.apps.enter(appid, |app, _| {
+                app.data.as_ref().map(|data| {
+                    let end = cmp::min(done + len, data.len());
+                    if end > done {
+                        buffer[offset..offset + end - done].copy_from_slice(&data.as_ref()[done..end]);
+                    }
+                });
+            });
+            self.dirty.set(true);
+        });
+        if written.is_some() {
+            file.position += len as u32;
+            file.size = cmp::max(file.size, file.position);
+            self.file.set(file);
+            self.done.set(done + len);
+            self.remaining.set(remaining - len);
+        }
+    }
+
+    /// Write the size and first cluster of the file to its directory entry.
+    /// A file opened with `OPEN_TRUNCATE` then has its old chain freed.
+    fn update_entry(&self) {
+        let file = self.file.get();
+        let updated = self.sector(file.entry_sector, |buffer| {
+            let entry = &mut buffer[file.entry_offset..file.entry_offset + ENTRY_LEN];
+            put_u16(&mut entry[20..], (file.first_cluster >> 16) as u16);
+            put_u16(&mut entry[26..], file.first_cluster as u16);
+            put_u32(&mut entry[28..], file.size);
+            entry[11] |= ATTR_ARCHIVE;
+            self.dirty.set(true);
+        });
+        if updated.is_none() {
+            return;
+        }
+        let truncating = self.request
+            .get()
+            .map_or(false, |request| request.operation == Operation::Open);
+        if truncating {
+            self.step.set(Step::FreeChain {
+                cluster: self.free_next.get(),
+                copy: 0,
+            });
+        } else {
+            self.complete(Ok(self.done.get()));
+        }
+    }
+
+    // Commands that complete right away.
+
+    fn close(&self, appid: AppId, fd: usize) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if fd >= MAX_OPEN_FILES || app.files[fd].is_none() {
+                    return ReturnCode::EINVAL;
+                }
+                app.files[fd] = None;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn seek(&self, appid: AppId, fd: usize, position: usize) -> ReturnCode {
+        let generation = self.generation.get();
+        self.apps
+            .enter(appid, |app, _| {
+                let mut file = match app.files.get(fd).and_then(|file| *file) {
+                    Some(file) => file,
+                    None => return ReturnCode::EINVAL,
+                };
+                if file.generation != generation {
+                    return ReturnCode::ECANCEL;
+                }
+                // Directories can only go back to the start.
+                let end = if file.directory { 0 } else { file.size as usize };
+                if position > end {
+                    return ReturnCode::EINVAL;
+                }
+                file.position = position as u32;
+                if file.directory {
+                    file.cluster = file.first_cluster;
+                    file.cluster_index = 0;
+                }
+                app.files[fd] = Some(file);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn size(&self, appid: AppId, fd: usize) -> ReturnCode {
+        let generation = self.generation.get();
+        self.apps
+            .enter(appid, |app, _| {
+                match app.files.get(fd).and_then(|file| *file) {
+                    Some(file) if file.generation != generation => ReturnCode::ECANCEL,
+                    Some(file) => ReturnCode::SuccessWithValue { value: file.size as usize },
+                    None => ReturnCode::EINVAL,
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+/// The first sector of the filesystem: of the first FAT partition in the
+/// partition table, or sector 0 if the card has no partition table.
+fn partition_start(mbr: &[u8]) -> Option<u32> {
+    if get_u16(&mbr[510..]) != 0xAA55 {
+        return None;
+    }
+    // A boot sector says what it is, a partition table does not.
+    if (mbr[0] == 0xEB || mbr[0] == 0xE9) &&
+       (mbr[54..57] == b"FAT"[..] || mbr[82..87] == b"FAT32"[..]) {
+        return Some(0);
+    }
+    for i in 0..4 {
+        let partition = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        match partition[4] {
+            // FAT16 and FAT32, with CHS or LBA addresses.
+            0x04 | 0x06 | 0x0E | 0x0B | 0x0C => return Some(get_u32(&partition[8..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_boot_sector(sector: &[u8], start: u32) -> Option<Volume> {
+    let sectors_per_cluster = sector[13] as u32;
+    let reserved = get_u16(&sector[14..]) as u32;
+    let num_fats = sector[16] as u32;
+    let root_entries = get_u16(&sector[17..]) as u32;
+    let total = match get_u16(&sector[19..]) {
+        0 => get_u32(&sector[32..]),
+        total => total as u32,
+    };
+    let fat_sectors = match get_u16(&sector[22..]) {
+        0 => get_u32(&sector[36..]),
+        fat_sectors => fat_sectors as u32,
+    };
+    if get_u16(&sector[510..]) != 0xAA55 || get_u16(&sector[11..]) as usize != SECTOR_LEN ||
+       !sectors_per_cluster.is_power_of_two() || reserved == 0 || num_fats == 0 ||
+       fat_sectors == 0 {
+        return None;
+    }
+
+    let root_sectors = (root_entries * ENTRY_LEN as u32 + SECTOR_LEN as u32 - 1) / SECTOR_LEN as u32;
+    let metadata = reserved + num_fats * fat_sectors + root_sectors;
+    if total <= metadata {
+        return None;
+    }
+    let clusters = (total - metadata) / sectors_per_cluster;
+    let fat_type = if clusters < 4085 {
+        // FAT12
+        return None;
+    } else if clusters < 65525 {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    };
+    let entries_per_fat = match fat_type {
+        FatType::Fat16 => fat_sectors * SECTOR_LEN as u32 / 2,
+        FatType::Fat32 => fat_sectors * SECTOR_LEN as u32 / 4,
+    };
+
+    let fat_start = start + reserved;
+    let root_start = fat_start + num_fats * fat_sectors;
+    Some(Volume {
+        fat_type: fat_type,
+        sectors_per_cluster: sectors_per_cluster,
+        num_fats: num_fats,
+        fat_start: fat_start,
+        fat_sectors: fat_sectors,
+        root_start: root_start,
+        root_entries: root_entries,
+        root_cluster: get_u32(&sector[44..]),
+        data_start: root_start + root_sectors,
+        clusters: cmp::min(clusters, entries_per_fat - 2),
+        fs_info: get_u16(&sector[48..]) as u32,
+    })
+}
+
+/// Whether a new file can be called `name`.
+fn valid_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN &&
+    name.iter().all(|c| *c >= 0x20 && *c < 0x80 && !b"\"*/:<>?\\|".contains(c)) &&
+    name.iter().any(|c| *c != b'.' && *c != b' ') &&
+    name[name.len() - 1] != b'.' && name[name.len() - 1] != b' '
+}
+
+/// The short name of a new file called `name`, padded with spaces, and
+/// whether it is `name` itself. If not, the file needs a long name and the
+/// short name a `~N` tail.
+fn short_name(name: &[u8]) -> ([u8; 11], bool) {
+    let (base, extension) = match name.iter().rposition(|c| *c == b'.') {
+        Some(dot) if dot > 0 => (&name[0..dot], &name[dot + 1..]),
+        _ => (name, &name[0..0]),
+    };
+    let mut short = [b' '; 11];
+    let mut exact = base.len() <= 8 && extension.len() <= 3;
+    let mut len = 0;
+    for c in base.iter() {
+        if *c == b' ' || *c == b'.' {
+            exact = false;
+        } else if len < 8 {
+            short[len] = short_char(*c, &mut exact);
+            len += 1;
+        }
+    }
+    if len == 0 {
+        short[0] = b'_';
+    }
+    len = 0;
+    for c in extension.iter() {
+        if *c == b' ' || *c == b'.' {
+            exact = false;
+        } else if len < 3 {
+            short[8 + len] = short_char(*c, &mut exact);
+            len += 1;
+        }
+    }
+    (short, exact)
+}
+
+fn short_char(c: u8, exact: &mut bool) -> u8 {
+    let upper = to_upper(c);
+    if upper != c {
+        *exact = false;
+    }
+    if (upper >= b'A' && upper <= b'Z') || (upper >= b'0' && upper <= b'9') ||
+       b"!#$%&'()-@^_`{}~".contains(&upper) {
+        upper
+    } else {
+        *exact = false;
+        b'_'
+    }
+}
+
+/// `short` with the tail number `tail` of `TAILS`.
+fn with_tail(short: &[u8; 11], hash: u16, tail: u8) -> [u8; 11] {
+    let mut tailed = *short;
+    let base_len = short[0..8].iter().position(|c| *c == b' ').unwrap_or(8);
+    let mut at = cmp::min(base_len, 6);
+    let mut n = tail + 1;
+    if tail >= 4 {
+        at = cmp::min(base_len, 2);
+        for shift in [12, 8, 4, 0].iter() {
+            tailed[at] = b"0123456789ABCDEF"[(hash >> *shift) as usize & 0xF];
+            at += 1;
+        }
+        n = tail - 3;
+    }
+    tailed[at] = b'~';
+    tailed[at + 1] = b'0' + n;
+    for c in tailed[at + 2..8].iter_mut() {
+        *c = b' ';
+    }
+    tailed
+}
+
+/// FNV-1a of `name` in upper case, folded to 16 bits.
+fn name_hash(name: &[u8]) -> u16 {
+    let hash = name.iter().fold(0x811C9DC5u32, |hash, c| {
+        (hash ^ to_upper(*c) as u32).wrapping_mul(0x01000193)
+    });
+    (hash >> 16) as u16 ^ hash as u16
+}
+
+/// The name of a short name entry as it reads, `NAME.EXT`.
+fn short_name_display(entry: &[u8]) -> ([u8; 12], usize) {
+    let mut name = [0; 12];
+    let base_len = entry[0..8].iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+    let extension_len = entry[8..11].iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+    for i in 0..base_len {
+        name[i] = display_char(entry[i], entry[12] & LOWER_CASE_BASE != 0);
+    }
+    let mut len = base_len;
+    if extension_len > 0 {
+        name[len] = b'.';
+        len += 1;
+        for i in 0..extension_len {
+            name[len] = display_char(entry[8 + i], entry[12] & LOWER_CASE_EXTENSION != 0);
+            len += 1;
+        }
+    }
+    (name, len)
+}
+
+fn display_char(c: u8, lower: bool) -> u8 {
+    if c >= 0x80 {
+        b'?'
+    } else if lower && c >= b'A' && c <= b'Z' {
+        c + 32
+    } else {
+        c
+    }
+}
+
+fn to_upper(c: u8) -> u8 {
+    if c >= b'a' && c <= b'z' { c - 32 } else { c }
+}
+
+fn same_name(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| to_upper(*a) == to_upper(*b))
+}
+
+/// Checksum of a short name, kept in its long name entries.
+fn checksum(short: &[u8]) -> u8 {
+    short[0..11].iter().fold(0u8, |sum, c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c))
+}
+
+/// Long name entry `order` of `name`, counting from 1. `last` for the first
+/// entry in the directory, which holds the end of the name.
+fn write_long_entry(entry: &mut [u8], name: &[u8], order: u8, last: bool, checksum: u8) {
+    for byte in entry.iter_mut() {
+        *byte = 0;
+    }
+    entry[0] = if last { order | LAST_LONG_ENTRY } else { order };
+    entry[11] = ATTR_LONG_NAME;
+    entry[13] = checksum;
+    for (i, at) in LONG_NAME_OFFSETS.iter().enumerate() {
+        let index = (order as usize - 1) * 13 + i;
+        // The name ends with a 0, and the rest is filled with 0xFFFF.
+        let c = if index < name.len() {
+            name[index] as u16
+        } else if index == name.len() {
+            0
+        } else {
+            0xFFFF
+        };
+        put_u16(&mut entry[*at..], c);
+    }
+}
+
+fn write_short_entry(entry: &mut [u8], short_name: &[u8; 11]) {
+    for byte in entry.iter_mut() {
+        *byte = 0;
+    }
+    entry[0..11].copy_from_slice(short_name);
+    entry[11] = ATTR_ARCHIVE;
+    // Created, accessed and modified.
+    put_u16(&mut entry[16..], FAT_DATE);
+    put_u16(&mut entry[18..], FAT_DATE);
+    put_u16(&mut entry[24..], FAT_DATE);
+}
+
+/// Handle callbacks from SDCard
+impl<'a, A: hil::time::Alarm + 'a> SDCardClient for FatFs<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {
+        // Whatever card is in now, it is not the one that was mounted.
+        self.mounted.set(false);
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.cached.set(None);
+        self.dirty.set(false);
+    }
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if self.io.get() == Io::Initializing {
+            self.io.set(Io::Idle);
+            self.step.set(Step::ReadMbr);
+            self.run();
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        self.buffer.replace(data);
+        if let Io::Reading(sector) = self.io.get() {
+            self.cached.set(Some(sector));
+        }
+        self.io.set(Io::Idle);
+        self.run();
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.dirty.set(false);
+        self.io.set(Io::Idle);
+        self.run();
+    }
+
+    fn error(&self, _error: u32) {
+        self.sdcard.take_buffer().map(|buffer| self.buffer.replace(buffer));
+        if self.io.get() != Io::Idle {
+            self.io_error(ReturnCode::FAIL);
+            self.run();
+        }
+    }
+}
+
+/// Provide an interface for userland.
+impl<'a, A: hil::time::Alarm + 'a> Driver for FatFs<'a, A> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The path to open or delete.
+    /// - `1`: The data to write, or where reads and directory listings put
+    ///        what they read.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.path = Some(slice),
+                    1 => app.data = Some(slice),
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when an open, read, write, directory read or delete
+    ///        completes, with the result and the number of the command.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => {
+                self.apps
+                    .enter(callback.app_id(), |app, _| {
+                        app.callback = Some(callback);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Open the path of `data` bytes with the `OPEN_` flags `arg2`.
+    ///        The callback gets the file descriptor, or FAIL if the path
+    ///        does not exist. A file can be open for writing only once, and
+    ///        can only be truncated when it is not open at all, EBUSY
+    ///        otherwise.
+    /// - `2`: Close the file descriptor `data`.
+    /// - `3`: Read up to `arg2` bytes from the file descriptor `data`. The
+    ///        callback gets the number of bytes read, 0 at the end.
+    /// - `4`: Write `arg2` bytes to the file descriptor `data`. The callback
+    ///        gets the number of bytes written.
+    /// - `5`: Read the next entry of the directory `data`. The callback gets
+    ///        the length of its name, 0 at the end.
+    /// - `6`: Delete the file at the path of `data` bytes, EBUSY if it is
+    ///        open.
+    /// - `7`: Move the file descriptor `data` to the position `arg2`.
+    /// - `8`: Return the size of the file descriptor `data`.
+    fn command(&self, command_num: usize, data: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue(appid, Operation::Open, 0, data, arg2),
+            2 => self.close(appid, data),
+            3 => self.enqueue(appid, Operation::Read, data, arg2, 0),
+            4 => self.enqueue(appid, Operation::Write, data, arg2, 0),
+            5 => self.enqueue(appid, Operation::ReadDirectory, data, 0, 0),
+            6 => self.enqueue(appid, Operation::Delete, 0, data, 0),
+            7 => self.seek(appid, data, arg2),
+            8 => self.size(appid, data),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
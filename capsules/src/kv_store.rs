@@ -0,0 +1,1171 @@
+//! Key-value store for apps, kept in a log in flash.
+//!
+//! Apps store values of up to `MAX_VALUE_LEN` bytes under keys of up to
+//! `MAX_KEY_LEN` bytes. Every app has a namespace of its own, so apps cannot
+//! see or change each other's keys. The namespace is the storage key of the
+//! app, a SHA-256 hash of its package name and the key that signed it (see
+//! `kernel::process::Process::storage_key`), which keeps its keys across
+//! reboots and updates of the app. Apps without a package name cannot use the
+//! store.
+//!
+//! The store spans a range of flash pages, accessed through `hil::flash`,
+//! usually a `virtual_flash::FlashUser`. Records are only ever added to a log:
+//! setting a key adds a record with the new value, and deleting a key adds a
+//! record that marks it deleted. The newest record of a key is the one that
+//! counts.
+//!
+//! Page format, all multi-byte fields little endian:
+//!
+//! ```text
+//! 0       4     8          10     12         14    16
+//! +-------+-----+----------+------+----------+-----+---------+---------+
+//! | magic | seq | replaces | used | reserved | crc | record  | ...     |
+//! +-------+-----+----------+------+----------+-----+---------+---------+
+//!
+//! record: | key len: u8 | flags: u8 | value len: u16 | namespace: 32 bytes |
+//!         | key | value | crc: u16 |, padded to a multiple of four bytes
+//! ```
+//!
+//! `seq` grows with every page written, so it orders the pages from the
+//! oldest to the newest, `used` is the number of bytes of records after the
+//! header and both CRCs are CRC-16/CCITT of what comes before them in the
+//! header or record.
+//!
+//! Surviving a reset
+//! -----------------
+//!
+//! Records go to the open page, the newest one. A flash page can only be
+//! written whole, and a reset while it is being written can leave it with
+//! anything, so the open page is never written in place. The open page with
+//! the new record is written to a free page instead, and `replaces` of the
+//! copy names the page it replaces. Only once the copy is written is the old
+//! page erased. A page whose header or records do not check out, or that does
+//! not have all the records `used` says, is ignored. So after a reset either
+//! the old or the new copy is found, and if both are, the copy wins.
+//!
+//! When the open page is full the next record starts a new one.
+//!
+//! Garbage collection
+//! ------------------
+//!
+//! When a record needs a new page and only `RESERVED_PAGES` pages are free,
+//! setting or deleting a key first collects the oldest full page: the records in it that no newer record
+//! replaced are added to the log again, and then it is erased. Deleted keys
+//! are dropped, nothing older than the oldest page can need hiding. A reset
+//! during collection at worst leaves records in the log twice, the newer
+//! copy hides the older one. If a set or delete still does not fit once every
+//! full page had its turn, it fails with `ENOMEM`.
+//!
+//! Finding a key reads the pages from the newest to the oldest, and
+//! collection and iteration read all newer pages for every record they come
+//! across, so the store is meant for configuration and other small data that
+//! changes now and then.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! storage_volume!(KV_STORE, 8);
+//!
+//! let kv_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash));
+//! pub static mut KV_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! pub static mut KV_WALK_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStore<'static,
+//!         capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::kv_store::KVStore::new(
+//!         kv_flash,
+//!         kernel::Grant::create(),
+//!         (&KV_STORE as *const _ as usize) / 512, // First page of the store.
+//!         KV_STORE.len() / 512,                   // Number of pages.
+//!         &mut KV_PAGE,
+//!         &mut KV_WALK_PAGE));
+//! hil::flash::HasClient::set_client(kv_flash, kv_store);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::bytes::{crc16, get_u16, get_u32, put_u16, put_u32};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x50003;
+
+/// Most pages a store can span.
+pub const MAX_PAGES: usize = 32;
+pub const MAX_KEY_LEN: usize = 32;
+pub const MAX_VALUE_LEN: usize = 256;
+
+/// "TKV2" in little endian.
+const PAGE_MAGIC: u32 = 0x32564B54;
+const PAGE_HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 36;
+const RECORD_CRC_LEN: usize = 2;
+
+/// `replaces` of a page that is not the copy of another.
+const NO_PAGE: u16 = 0xFFFF;
+
+/// Record flag of a deleted key.
+const DELETED: u8 = 0x01;
+
+/// Free pages garbage collection needs: one to start a new page and one to
+/// copy the open page to. A store needs at least two more pages than this,
+/// one to collect and one to stay open.
+const RESERVED_PAGES: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum PageState {
+    /// Erased, or nothing valid in it.
+    Free,
+    /// Full, or replaced as the open page by a new page.
+    Sealed,
+    /// The page new records go to.
+    Open,
+    /// Replaced by a copy, to be erased.
+    Stale,
+}
+
+#[derive(Clone, Copy)]
+struct PageInfo {
+    state: PageState,
+    seq: u32,
+    replaces: u16,
+    /// Bytes of records after the header.
+    used: usize,
+}
+
+const FREE_PAGE: PageInfo = PageInfo {
+    state: PageState::Free,
+    seq: 0,
+    replaces: NO_PAGE,
+    used: 0,
+};
+
+impl PageInfo {
+    fn in_log(&self) -> bool {
+        self.state == PageState::Sealed || self.state == PageState::Open
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Get = 1,
+    Set = 2,
+    Delete = 3,
+    Iterate = 4,
+}
+
+#[derive(Clone, Copy)]
+struct Request {
+    appid: AppId,
+    operation: Operation,
+    namespace: [u8; 32],
+    key_len: usize,
+    value_len: usize,
+    cursor: usize,
+}
+
+/// What the flash operation in progress is for.
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Reading every page to find the log.
+    Mount(usize),
+    /// Erasing a page found replaced by a copy.
+    MountErase(usize),
+    /// Looking for the key of the request, from the newest page down.
+    Lookup(usize),
+    /// Reading the page garbage collection or iteration walks through.
+    WalkRead,
+    /// Reading the pages newer than the walked record, to see whether one of
+    /// them replaced it.
+    Check(usize),
+    /// Reading the open page to add a record to it.
+    AppendRead,
+    /// Writing the open page with the new record to a free page.
+    AppendWrite,
+    /// Erasing the page the copy replaced.
+    AppendErase(usize),
+    /// Erasing the page garbage collection emptied.
+    CollectErase(usize),
+}
+
+/// A valid record in a page.
+#[derive(Clone, Copy)]
+struct Record {
+    offset: usize,
+    namespace: [u8; 32],
+    flags: u8,
+    key_len: usize,
+    value_len: usize,
+}
+
+impl Record {
+    fn key_start(&self) -> usize {
+        self.offset + RECORD_HEADER_LEN
+    }
+
+    fn value_start(&self) -> usize {
+        self.key_start() + self.key_len
+    }
+
+    fn len(&self) -> usize {
+        record_len(self.key_len, self.value_len)
+    }
+
+    fn deleted(&self) -> bool {
+        self.flags & DELETED != 0
+    }
+
+    fn is(&self, page: &[u8], namespace: &[u8; 32], key: &[u8]) -> bool {
+        self.namespace == *namespace && &page[self.key_start()..self.value_start()] == key
+    }
+}
+
+/// The valid records of a page, in the order they were added.
+struct Records<'b> {
+    page: &'b [u8],
+    offset: usize,
+    end: usize,
+}
+
+impl<'b> Records<'b> {
+    fn new(page: &'b [u8], offset: usize, end: usize) -> Records<'b> {
+        Records {
+            page: page,
+            offset: offset,
+            end: cmp::min(end, page.len()),
+        }
+    }
+}
+
+impl<'b> Iterator for Records<'b> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        read_record(self.page, self.offset, self.end).map(|record| {
+            self.offset += record.len();
+            record
+        })
+    }
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    pending: Option<Request>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            key: None,
+            value: None,
+            pending: None,
+        }
+    }
+}
+
+pub struct KVStore<'a, F: hil::flash::Flash + 'static> {
+    driver: &'a F,
+    apps: Grant<App>,
+    // For looking up keys, checking records and writing pages.
+    page: TakeCell<'static, F::Page>,
+    // For the page garbage collection or iteration walks through.
+    walk: TakeCell<'static, F::Page>,
+    page_len: usize,
+    // The pages of the store, in flash page numbers.
+    first_page: usize,
+    num_pages: usize,
+    pages: Cell<[PageInfo; MAX_PAGES]>,
+    // Whether `pages` was read from the flash.
+    mounted: Cell<bool>,
+    next_seq: Cell<u32>,
+    state: Cell<State>,
+    // The request being served, one at a time.
+    request: Cell<Option<Request>>,
+    key: Cell<[u8; MAX_KEY_LEN]>,
+    // Sequence number of the page last read by a lookup or check.
+    scan_seq: Cell<Option<u32>>,
+    // Whether the walk is garbage collection, else iteration.
+    collecting: Cell<bool>,
+    // Free pages when the current collection started.
+    collect_free: Cell<usize>,
+    // Collections since the last one that freed a page.
+    fruitless_collections: Cell<usize>,
+    walk_page: Cell<usize>,
+    // The record the walk is at, and where the next one starts.
+    walk_record: Cell<usize>,
+    walk_offset: Cell<usize>,
+    walk_namespace: Cell<[u8; 32]>,
+    walk_key: Cell<[u8; MAX_KEY_LEN]>,
+    walk_key_len: Cell<usize>,
+    // The open page being written, and the page it replaces.
+    append_page: Cell<usize>,
+    append_info: Cell<PageInfo>,
+    append_replaces: Cell<Option<usize>>,
+}
+
+impl<'a, F: hil::flash::Flash + 'a> KVStore<'a, F> {
+    /// Panics if the store spans fewer than `RESERVED_PAGES + 2` pages.
+    pub fn new(driver: &'a F,
+               grant: Grant<App>,
+               first_page: usize,
+               num_pages: usize,
+               page: &'static mut F::Page,
+               walk: &'static mut F::Page)
+               -> KVStore<'a, F> {
+        let page_len = page.as_mut().len();
+        if cmp::min(num_pages, MAX_PAGES) < RESERVED_PAGES + 2 {
+            panic!("KVStore needs at least {} pages", RESERVED_PAGES + 2);
+        }
+        KVStore {
+            driver: driver,
+            apps: grant,
+            page: TakeCell::new(page),
+            walk: TakeCell::new(walk),
+            page_len: page_len,
+            first_page: first_page,
+            num_pages: cmp::min(num_pages, MAX_PAGES),
+            pages: Cell::new([FREE_PAGE; MAX_PAGES]),
+            mounted: Cell::new(false),
+            next_seq: Cell::new(1),
+            state: Cell::new(State::Idle),
+            request: Cell::new(None),
+            key: Cell::new([0; MAX_KEY_LEN]),
+            scan_seq: Cell::new(None),
+            collecting: Cell::new(false),
+            collect_free: Cell::new(0),
+            fruitless_collections: Cell::new(0),
+            walk_page: Cell::new(0),
+            walk_record: Cell::new(0),
+            walk_offset: Cell::new(0),
+            walk_namespace: Cell::new([0; 32]),
+            walk_key: Cell::new([0; MAX_KEY_LEN]),
+            walk_key_len: Cell::new(0),
+            append_page: Cell::new(0),
+            append_info: Cell::new(FREE_PAGE),
+            append_replaces: Cell::new(None),
+        }
+    }
+
+    fn page_info(&self, index: usize) -> PageInfo {
+        self.pages.get()[index]
+    }
+
+    fn set_page_info(&self, index: usize, info: PageInfo) {
+        let mut pages = self.pages.get();
+        pages[index] = info;
+        self.pages.set(pages);
+    }
+
+    fn set_page_state(&self, index: usize, state: PageState) {
+        let mut info = self.page_info(index);
+        info.state = state;
+        self.set_page_info(index, info);
+    }
+
+    fn free_pages(&self) -> usize {
+        self.pages.get()[0..self.num_pages].iter().filter(|page| page.state == PageState::Free).count()
+    }
+
+    fn open_page(&self) -> Option<usize> {
+        self.pages.get()[0..self.num_pages].iter().position(|page| page.state == PageState::Open)
+    }
+
+    /// A free page, the first after the last one written so writes go round
+    /// all pages.
+    fn free_page(&self) -> Option<usize> {
+        let pages = self.pages.get();
+        let start = self.append_page.get() + 1;
+        (0..self.num_pages)
+            .map(|i| (start + i) % self.num_pages)
+            .find(|i| pages[*i].state == PageState::Free)
+    }
+
+    /// The page of the log with the lowest sequence number above `seq`, or
+    /// if not `newer` the highest below it. Without a `seq`, the oldest or
+    /// the newest page.
+    fn next_page(&self, seq: Option<u32>, newer: bool) -> Option<usize> {
+        let pages = self.pages.get();
+        let mut next: Option<usize> = None;
+        for (i, page) in pages[0..self.num_pages].iter().enumerate() {
+            let after = seq.map_or(true, |seq| if newer { page.seq > seq } else { page.seq < seq });
+            let closer = next.map_or(true, |next| if newer {
+                page.seq < pages[next].seq
+            } else {
+                page.seq > pages[next].seq
+            });
+            if page.in_log() && after && closer {
+                next = Some(i);
+            }
+        }
+        next
+    }
+
+    fn read_page(&self, index: usize, state: State) {
+        let buffer = if state == State::WalkRead {
+            self.walk.take()
+        } else {
+            self.page.take()
+        };
+        buffer.map(|buffer| {
+            self.state.set(state);
+            self.driver.read_page(self.first_page + index, buffer);
+        });
+    }
+
+    fn erase_page(&self, index: usize, state: State) {
+        self.state.set(state);
+        self.driver.erase_page(self.first_page + index);
+    }
+
+    // Check so see if we are doing something. If not, go ahead and do this
+    // request. If so, this is queued and will be run when the pending
+    // request completes.
+    fn enqueue(&self,
+               appid: AppId,
+               operation: Operation,
+               key_len: usize,
+               value_len: usize,
+               cursor: usize)
+               -> ReturnCode {
+        let namespace = match appid.get_storage_key() {
+            Some(namespace) => namespace,
+            None => return ReturnCode::ENOSUPPORT,
+        };
+        let request = Request {
+            appid: appid,
+            operation: operation,
+            namespace: namespace,
+            key_len: key_len,
+            value_len: value_len,
+            cursor: cursor,
+        };
+
+        let start = self.apps
+            .enter(appid, |app, _| {
+                let key_buf_len = app.key.as_ref().map_or(0, |key| key.len());
+                let value_buf_len = app.value.as_ref().map_or(0, |value| value.len());
+                if operation != Operation::Iterate &&
+                   (key_len == 0 || key_len > MAX_KEY_LEN || key_len > key_buf_len) {
+                    return Err(ReturnCode::EINVAL);
+                }
+                if operation == Operation::Set {
+                    if value_len > MAX_VALUE_LEN || value_len > value_buf_len {
+                        return Err(ReturnCode::EINVAL);
+                    }
+                    if PAGE_HEADER_LEN + record_len(key_len, value_len) > self.page_len {
+                        return Err(ReturnCode::ESIZE);
+                    }
+                }
+
+                if self.request.get().is_none() {
+                    Ok(Some(request))
+                } else if app.pending.is_some() {
+                    // No more room in the queue, nowhere to store this
+                    // request.
+                    Err(ReturnCode::EBUSY)
+                } else {
+                    app.pending = Some(request);
+                    Ok(None)
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match start {
+            Ok(request) => {
+                request.map(|request| self.start(request));
+                ReturnCode::SUCCESS
+            }
+            Err(err) => err,
+        }
+    }
+
+    fn start(&self, request: Request) {
+        self.request.set(Some(request));
+        self.fruitless_collections.set(0);
+
+        // Take the key now, the app may change its buffer before the request
+        // is served.
+        let copied = self.apps
+            .enter(request.appid, |app, _| {
+                app.key.as_ref().map_or(request.operation == Operation::Iterate, |app_key| {
+                    if app_key.len() < request.key_len {
+                        return false;
+                    }
+                    let mut key = [0; MAX_KEY_LEN];
+                    key[0..request.key_len].copy_from_slice(&app_key.as_ref()[0..request.key_len]);
+                    self.key.set(key);
+                    true
+                })
+            })
+            .unwrap_or(false);
+        if !copied {
+            return self.finish_error(ReturnCode::EINVAL);
+        }
+        self.run();
+    }
+
+    /// Serve the request, or carry on with it once the log is read.
+    fn run(&self) {
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return,
+        };
+        if !self.mounted.get() {
+            return self.mount();
+        }
+        match request.operation {
+            Operation::Get | Operation::Delete => {
+                self.scan_seq.set(None);
+                self.lookup_next();
+            }
+            Operation::Set => self.append(),
+            Operation::Iterate => self.iterate(request.cursor),
+        }
+    }
+
+    fn finish(&self, result: usize, extra: usize) {
+        self.collecting.set(false);
+        self.request.take().map(|request| {
+            let _ = self.apps.enter(request.appid, |app, _| {
+                app.callback.map(|mut cb| cb.schedule(result, request.operation as usize, extra));
+            });
+        });
+        self.check_queue();
+    }
+
+    fn finish_error(&self, err: ReturnCode) {
+        self.finish(usize::from(err), 0);
+    }
+
+    /// Give up on the request after a flash error. The pages are read again
+    /// for the next request, in case what is in flash is not what we think.
+    fn flash_error(&self) {
+        self.mounted.set(false);
+        self.finish_error(ReturnCode::FAIL);
+    }
+
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let request = cntr.enter(|app, _| app.pending.take());
+            if let Some(request) = request {
+                self.start(request);
+                break;
+            }
+        }
+    }
+
+    // Reading the log.
+
+    fn mount(&self) {
+        self.pages.set([FREE_PAGE; MAX_PAGES]);
+        self.next_seq.set(1);
+        if self.num_pages == 0 {
+            self.mounted.set(true);
+            return self.run();
+        }
+        self.read_page(0, State::Mount(0));
+    }
+
+    fn mount_read(&self, index: usize) {
+        let info = self.page.map_or(FREE_PAGE, |page| parse_page(page.as_mut()));
+        self.set_page_info(index, info);
+        if info.state != PageState::Free {
+            self.next_seq.set(cmp::max(self.next_seq.get(), info.seq.wrapping_add(1)));
+        }
+        if index + 1 < self.num_pages {
+            return self.read_page(index + 1, State::Mount(index + 1));
+        }
+
+        // Pages with a newer copy were about to be erased when the chip
+        // reset, and the newest page is the open one.
+        let mut pages = self.pages.get();
+        for i in 0..self.num_pages {
+            let replaced = pages[i].replaces as usize;
+            if pages[i].state != PageState::Free && replaced < self.num_pages &&
+               pages[replaced].state != PageState::Free &&
+               pages[replaced].seq < pages[i].seq {
+                pages[replaced].state = PageState::Stale;
+            }
+        }
+        self.pages.set(pages);
+        self.next_page(None, false).map(|newest| self.set_page_state(newest, PageState::Open));
+        self.mount_erase();
+    }
+
+    fn mount_erase(&self) {
+        let stale = self.pages.get()[0..self.num_pages]
+            .iter()
+            .position(|page| page.state == PageState::Stale);
+        match stale {
+            Some(index) => self.erase_page(index, State::MountErase(index)),
+            None => {
+                self.mounted.set(true);
+                self.run();
+            }
+        }
+    }
+
+    // Get and delete.
+
+    fn lookup_next(&self) {
+        match self.next_page(self.scan_seq.get(), false) {
+            Some(index) => {
+                self.scan_seq.set(Some(self.page_info(index).seq));
+                self.read_page(index, State::Lookup(index));
+            }
+            None => self.finish_error(ReturnCode::FAIL),
+        }
+    }
+
+    fn lookup_read(&self, index: usize) {
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return,
+        };
+        let key = self.key.get();
+        let end = PAGE_HEADER_LEN + self.page_info(index).used;
+        let found = self.page
+            .map(|page| {
+                let page = page.as_mut();
+                let found = Records::new(page, PAGE_HEADER_LEN, end)
+                    .filter(|record| record.is(page, &request.namespace, &key[0..request.key_len]))
+                    .last();
+                found.map(|record| if request.operation == Operation::Get && !record.deleted() {
+                    let value = &page[record.value_start()..record.value_start() + record.value_len];
+                    let _ = self.apps.enter(request.appid, |app, _| {
+                        app.value.as_mut().map(|app_value| {
+                            let len = cmp::min(app_value.len(), value.len());
+                            app_value.as_mut()[0..len].copy_from_slice(&value[0..len]);
+                        });
+                    });
+                });
+                found
+            })
+            .unwrap_or(None);
+
+        match found {
+            None => self.lookup_next(),
+            Some(record) => {
+                if record.deleted() {
+                    self.finish_error(ReturnCode::FAIL);
+                } else if request.operation == Operation::Get {
+                    self.finish(record.value_len, 0);
+                } else {
+                    self.append();
+                }
+            }
+        }
+    }
+
+    // Set, delete and garbage collection.
+
+    /// The length of the record `append` adds.
+    fn append_len(&self) -> usize {
+        if self.collecting.get() {
+            return self.walk_offset.get() - self.walk_record.get();
+        }
+        self.request.get().map_or(0, |request| match request.operation {
+            Operation::Set => record_len(request.key_len, request.value_len),
+            _ => record_len(request.key_len, 0),
+        })
+    }
+
+    /// Add the record of the request, or when collecting the walked record,
+    /// to the log. Makes room first if the record needs a new page and only
+    /// the reserved pages are free.
+    fn append(&self) {
+        let len = self.append_len();
+        let open = self.open_page();
+        match open {
+            Some(open) if PAGE_HEADER_LEN + self.page_info(open).used + len <= self.page_len => {
+                self.append_replaces.set(Some(open));
+                self.read_page(open, State::AppendRead);
+            }
+            _ if !self.collecting.get() && self.free_pages() <= RESERVED_PAGES => self.collect(),
+            _ => {
+                // The record goes to a new page.
+                open.map(|open| self.set_page_state(open, PageState::Sealed));
+                self.append_replaces.set(None);
+                self.page.map(|page| for byte in page.as_mut().iter_mut() {
+                    *byte = 0xFF;
+                });
+                self.append_write();
+            }
+        }
+    }
+
+    /// Write the page in the page buffer, with the record added, to a free
+    /// page.
+    fn append_write(&self) {
+        let target = match self.free_page() {
+            Some(target) => target,
+            None => return self.finish_error(ReturnCode::ENOMEM),
+        };
+        let replaces = self.append_replaces.get();
+        let used = replaces.map_or(0, |open| self.page_info(open).used);
+        let len = self.append_len();
+
+        let added = self.page.map_or(false, |page| {
+            let page = page.as_mut();
+            let start = PAGE_HEADER_LEN + used;
+            if !self.write_record(&mut page[start..start + len]) {
+                return false;
+            }
+            let info = PageInfo {
+                state: PageState::Open,
+                seq: self.next_seq.get(),
+                replaces: replaces.map_or(NO_PAGE, |open| open as u16),
+                used: used + len,
+            };
+            write_page_header(page, info);
+            self.append_info.set(info);
+            true
+        });
+        if !added {
+            return self.finish_error(ReturnCode::EINVAL);
+        }
+
+        self.append_page.set(target);
+        self.page.take().map(|page| {
+            self.state.set(State::AppendWrite);
+            self.driver.write_page(self.first_page + target, page);
+        });
+    }
+
+    /// Fill `record` with the record to add. Fails if the app no longer has
+    /// the value to set.
+    fn write_record(&self, record: &mut [u8]) -> bool {
+        if self.collecting.get() {
+            let start = self.walk_record.get();
+            return self.walk.map_or(false, |walk| {
+                record.copy_from_slice(&walk.as_mut()[start..start + record.len()]);
+                true
+            });
+        }
+
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return false,
+        };
+        let value_len = if request.operation == Operation::Set {
+            request.value_len
+        } else {
+            0
+        };
+        let key_end = RECORD_HEADER_LEN + request.key_len;
+        let value_end = key_end + value_len;
+        for byte in record.iter_mut() {
+            *byte = 0xFF;
+        }
+        record[0] = request.key_len as u8;
+        record[1] = if request.operation == Operation::Delete {
+            DELETED
+        } else {
+            0
+        };
+        put_u16(&mut record[2..4], value_len as u16);
+        record[4..RECORD_HEADER_LEN].copy_from_slice(&request.namespace);
+        record[RECORD_HEADER_LEN..key_end].copy_from_slice(&self.key.get()[0..request.key_len]);
+        if value_len > 0 {
+            let copied = self.apps
+                .enter(request.appid, |app, _| {
+                    app.value.as_ref().map_or(false, |value| {
+                        if value.len() < value_len {
+                            return false;
+                        }
+                        record[key_end..value_end].copy_from_slice(&value.as_ref()[0..value_len]);
+                        true
+                    })
+                })
+                .unwrap_or(false);
+            if !copied {
+                return false;
+            }
+        }
+        let crc = crc16(&record[0..value_end]);
+        put_u16(&mut record[value_end..value_end + RECORD_CRC_LEN], crc);
+        true
+    }
+
+    fn append_written(&self) {
+        let target = self.append_page.get();
+        let info = self.append_info.get();
+        self.set_page_info(target, info);
+        self.next_seq.set(info.seq.wrapping_add(1));
+        match self.append_replaces.get() {
+            Some(old) => {
+                self.set_page_state(old, PageState::Stale);
+                self.erase_page(old, State::AppendErase(old));
+            }
+            None => self.append_done(),
+        }
+    }
+
+    fn append_done(&self) {
+        if self.collecting.get() {
+            self.walk_next();
+        } else {
+            self.finish(0, 0);
+        }
+    }
+
+    /// Start collecting the oldest full page.
+    fn collect(&self) {
+        let victim = match self.next_page(None, true) {
+            Some(oldest) if self.page_info(oldest).state == PageState::Sealed => oldest,
+            _ => return self.finish_error(ReturnCode::ENOMEM),
+        };
+        // Every full page had its turn without freeing anything, all that is
+        // in them is still needed.
+        if self.fruitless_collections.get() > self.num_pages {
+            return self.finish_error(ReturnCode::ENOMEM);
+        }
+        self.collecting.set(true);
+        self.collect_free.set(self.free_pages());
+        self.start_walk(victim, PAGE_HEADER_LEN);
+    }
+
+    fn collect_done(&self) {
+        self.collecting.set(false);
+        if self.free_pages() > self.collect_free.get() {
+            self.fruitless_collections.set(0);
+        } else {
+            self.fruitless_collections.set(self.fruitless_collections.get() + 1);
+        }
+        self.append();
+    }
+
+    // Walking through a page for garbage collection or iteration.
+
+    fn iterate(&self, cursor: usize) {
+        // The cursor is the page the last key was found in, plus one, and
+        // where the next record starts in it.
+        let (index, offset) = if cursor == 0 {
+            match self.next_page(None, true) {
+                Some(oldest) => (oldest, PAGE_HEADER_LEN),
+                None => return self.finish_error(ReturnCode::FAIL),
+            }
+        } else {
+            ((cursor >> 16) - 1, cursor & 0xFFFF)
+        };
+        // The page was collected or replaced by a copy since.
+        if index >= self.num_pages || !self.page_info(index).in_log() || offset < PAGE_HEADER_LEN {
+            return self.finish_error(ReturnCode::ECANCEL);
+        }
+        self.start_walk(index, offset);
+    }
+
+    fn start_walk(&self, index: usize, offset: usize) {
+        self.walk_page.set(index);
+        self.walk_offset.set(offset);
+        self.read_page(index, State::WalkRead);
+    }
+
+    /// Look at the next record of the walked page. Records the walk is not
+    /// after, or that a later record in the same page replaced, are
+    /// skipped. For the others, the newer pages are checked.
+    fn walk_next(&self) {
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return,
+        };
+        let collecting = self.collecting.get();
+        let end = PAGE_HEADER_LEN + self.page_info(self.walk_page.get()).used;
+
+        loop {
+            let offset = self.walk_offset.get();
+            let candidate = self.walk
+                .map(|walk| {
+                    let walk = walk.as_mut();
+                    read_record(walk, offset, end).map(|record| {
+                        let wanted = !record.deleted() &&
+                                     (collecting || record.namespace == request.namespace);
+                        let key = &walk[record.key_start()..record.value_start()];
+                        let replaced = Records::new(walk, offset + record.len(), end)
+                            .any(|later| later.is(walk, &record.namespace, key));
+                        if wanted && !replaced {
+                            let mut walk_key = [0; MAX_KEY_LEN];
+                            walk_key[0..record.key_len].copy_from_slice(key);
+                            self.walk_key.set(walk_key);
+                            self.walk_key_len.set(record.key_len);
+                            self.walk_namespace.set(record.namespace);
+                        }
+                        (record, wanted && !replaced)
+                    })
+                })
+                .unwrap_or(None);
+
+            match candidate {
+                None => break,
+                Some((record, wanted)) => {
+                    self.walk_record.set(offset);
+                    self.walk_offset.set(offset + record.len());
+                    if wanted {
+                        self.scan_seq.set(Some(self.page_info(self.walk_page.get()).seq));
+                        return self.check_next();
+                    }
+                }
+            }
+        }
+
+        // The end of the page.
+        if collecting {
+            let victim = self.walk_page.get();
+            return self.erase_page(victim, State::CollectErase(victim));
+        }
+        let seq = self.page_info(self.walk_page.get()).seq;
+        match self.next_page(Some(seq), true) {
+            Some(next) => self.start_walk(next, PAGE_HEADER_LEN),
+            // No more keys.
+            None => self.finish_error(ReturnCode::FAIL),
+        }
+    }
+
+    /// Read the next newer page to see whether it has the walked key.
+    fn check_next(&self) {
+        match self.next_page(self.scan_seq.get(), true) {
+            Some(index) => {
+                self.scan_seq.set(Some(self.page_info(index).seq));
+                self.read_page(index, State::Check(index));
+            }
+            // Nothing replaced the record.
+            None => self.walk_found(),
+        }
+    }
+
+    fn check_read(&self, index: usize) {
+        let end = PAGE_HEADER_LEN + self.page_info(index).used;
+        let namespace = self.walk_namespace.get();
+        let key = self.walk_key.get();
+        let key = &key[0..self.walk_key_len.get()];
+        let replaced = self.page.map_or(false, |page| {
+            let page = page.as_mut();
+            Records::new(page, PAGE_HEADER_LEN, end).any(|record| record.is(page, &namespace, key))
+        });
+        if replaced {
+            self.walk_next();
+        } else {
+            self.check_next();
+        }
+    }
+
+    /// The walked record is the newest of its key.
+    fn walk_found(&self) {
+        if self.collecting.get() {
+            return self.append();
+        }
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return,
+        };
+        let key = self.walk_key.get();
+        let key_len = self.walk_key_len.get();
+        let _ = self.apps.enter(request.appid, |app, _| {
+            app.key.as_mut().map(|app_key| {
+                let len = cmp::min(app_key.len(), key_len);
+                app_key.as_mut()[0..len].copy_from_slice(&key[0..len]);
+            });
+        });
+        let cursor = (self.walk_page.get() + 1) << 16 | self.walk_offset.get();
+        self.finish(key_len, cursor);
+    }
+}
+
+/// The length of a record, padded.
+fn record_len(key_len: usize, value_len: usize) -> usize {
+    (RECORD_HEADER_LEN + key_len + value_len + RECORD_CRC_LEN + 3) & !3
+}
+
+/// The record at `offset`, if it is whole, ends before `end` and its CRC
+/// matches.
+fn read_record(page: &[u8], offset: usize, end: usize) -> Option<Record> {
+    if offset + RECORD_HEADER_LEN > end {
+        return None;
+    }
+    let mut namespace = [0; 32];
+    namespace.copy_from_slice(&page[offset + 4..offset + RECORD_HEADER_LEN]);
+    let record = Record {
+        offset: offset,
+        key_len: page[offset] as usize,
+        flags: page[offset + 1],
+        value_len: get_u16(&page[offset + 2..offset + 4]) as usize,
+        namespace: namespace,
+    };
+    if record.key_len == 0 || record.key_len > MAX_KEY_LEN || record.value_len > MAX_VALUE_LEN ||
+       offset + record.len() > end {
+        return None;
+    }
+    let crc_start = record.value_start() + record.value_len;
+    if crc16(&page[offset..crc_start]) != get_u16(&page[crc_start..crc_start + RECORD_CRC_LEN]) {
+        return None;
+    }
+    Some(record)
+}
+
+/// What the header of a page says, if the page is valid.
+fn parse_page(page: &[u8]) -> PageInfo {
+    if page.len() < PAGE_HEADER_LEN || get_u32(&page[0..4]) != PAGE_MAGIC ||
+       get_u16(&page[14..16]) != crc16(&page[0..14]) {
+        return FREE_PAGE;
+    }
+    let used = get_u16(&page[10..12]) as usize;
+    let end = PAGE_HEADER_LEN + used;
+    // A page that was cut short by a reset does not have all its records.
+    let last = Records::new(page, PAGE_HEADER_LEN, end)
+        .fold(PAGE_HEADER_LEN, |_, record| record.offset + record.len());
+    if last != end {
+        return FREE_PAGE;
+    }
+    PageInfo {
+        state: PageState::Sealed,
+        seq: get_u32(&page[4..8]),
+        replaces: get_u16(&page[8..10]),
+        used: used,
+    }
+}
+
+fn write_page_header(page: &mut [u8], info: PageInfo) {
+    put_u32(&mut page[0..4], PAGE_MAGIC);
+    put_u32(&mut page[4..8], info.seq);
+    put_u16(&mut page[8..10], info.replaces);
+    put_u16(&mut page[10..12], info.used as u16);
+    put_u16(&mut page[12..14], 0xFFFF);
+    let crc = crc16(&page[0..14]);
+    put_u16(&mut page[14..16], crc);
+}
+
+impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for KVStore<'a, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        if state == State::WalkRead {
+            self.walk.replace(buffer);
+        } else {
+            self.page.replace(buffer);
+        }
+        if error != hil::flash::Error::CommandComplete {
+            return self.flash_error();
+        }
+
+        match state {
+            State::Mount(index) => self.mount_read(index),
+            State::Lookup(index) => self.lookup_read(index),
+            State::WalkRead => self.walk_next(),
+            State::Check(index) => self.check_read(index),
+            State::AppendRead => self.append_write(),
+            _ => {}
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.state.set(State::Idle);
+        self.page.replace(buffer);
+        if error != hil::flash::Error::CommandComplete {
+            return self.flash_error();
+        }
+        self.append_written();
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        // A page that failed to erase stays stale until the log is read
+        // again.
+        if error != hil::flash::Error::CommandComplete {
+            return self.flash_error();
+        }
+
+        match state {
+            State::MountErase(index) => {
+                self.set_page_state(index, PageState::Free);
+                self.mount_erase();
+            }
+            State::AppendErase(index) => {
+                self.set_page_state(index, PageState::Free);
+                self.append_done();
+            }
+            State::CollectErase(index) => {
+                self.set_page_state(index, PageState::Free);
+                self.collect_done();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Provide an interface for userland.
+impl<'a, F: hil::flash::Flash + 'a> Driver for KVStore<'a, F> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The key. Iteration puts the keys it finds here.
+    /// - `1`: The value to set, or where get puts the value.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = Some(slice),
+                    1 => app.value = Some(slice),
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when a get, set, delete or iteration completes, with the
+    ///        result, the number of the command and for iteration the cursor
+    ///        of the next key.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => {
+                self.apps
+                    .enter(callback.app_id(), |app, _| {
+                        app.callback = Some(callback);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Get the value of the key of `data` bytes. The callback gets the
+    ///        length of the value, or FAIL if the key is not set.
+    /// - `2`: Set the key of `data` bytes to the value of `arg2` bytes.
+    /// - `3`: Delete the key of `data` bytes. The callback gets FAIL if the
+    ///        key is not set.
+    /// - `4`: Find the next key of the app after the cursor `data`, 0 to
+    ///        start. The callback gets the length of the key and the cursor
+    ///        to pass next, FAIL if there are no more keys or ECANCEL if the
+    ///        store changed too much to carry on.
+    fn command(&self, command_num: usize, data: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue(appid, Operation::Get, data, 0, 0),
+            2 => self.enqueue(appid, Operation::Set, data, arg2, 0),
+            3 => self.enqueue(appid, Operation::Delete, data, 0, 0),
+            4 => self.enqueue(appid, Operation::Iterate, 0, 0, data),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
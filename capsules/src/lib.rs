@@ -16,12 +16,15 @@ pub mod led;
 pub mod nrf51822_serialization;
 pub mod tmp006;
 pub mod sdcard;
+pub mod fat;
 pub mod si7021;
 pub mod spi;
 pub mod virtual_alarm;
 pub mod virtual_flash;
+pub mod wear_leveling;
 pub mod virtual_i2c;
 pub mod virtual_spi;
+pub mod virtual_uart;
 pub mod adc;
 pub mod dac;
 pub mod i2c_master_slave_driver;
@@ -41,7 +44,11 @@ pub mod max17205;
 pub mod pca9544a;
 pub mod nonvolatile_to_pages;
 pub mod nonvolatile_storage_driver;
+pub mod kv_store;
 pub mod app_flash_driver;
+pub mod app_loader;
+pub mod process_console;
+pub mod trace_uart;
 pub mod usb;
 pub mod usb_user;
 pub mod usbc_client;
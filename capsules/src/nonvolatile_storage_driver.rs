@@ -1,13 +1,35 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! Each application gets its own region of the memory provided to userland,
+//! and its reads and writes are relative to and bounded by that region. The
+//! region is allocated the first time the app reads or writes. Its size is
+//! set by the `Nonvolatile Storage` element of the TBF header of the app, or
+//! is the default size given to `new()` if the app does not have one.
 //!
-//! However, the kernel accessible memory does not have to be the same range
-//! as the userspace accessible address space. The kernel memory can overlap
-//! if desired, or can be a completely separate range.
+//! Allocations are recorded in a table at the start of the userspace region
+//! so that apps find their data again after a reboot. Apps are identified by
+//! their storage key, a SHA-256 hash of their package name and the key that
+//! signed them (see `kernel::process::Process::storage_key`). Apps without a
+//! package name cannot use the storage. The table has room for
+//! `MAX_APP_REGIONS` apps, and regions are never freed. The table is:
+//!
+//! ```text
+//! 0       4         8                                   8+40*MAX_APP_REGIONS
+//! +-------+---------+-----------------------------------+
+//! | magic | version | MAX_APP_REGIONS entries           |
+//! +-------+---------+-----------------------------------+
+//!
+//! entry: | storage key (32 bytes) | offset | length |, offsets relative to
+//! the userspace region, unused entries have length 0
+//! ```
+//!
+//! with every other field a little endian u32. If the magic number or version
+//! is wrong, for example on a new chip, the table is treated as empty.
+//!
+//! The kernel accessible memory does not have to be the same range as the
+//! userspace accessible address space. The kernel memory can overlap if
+//! desired, or can be a completely separate range. If it overlaps, the kernel
+//! must not write over the table.
 //!
 //! Here is a diagram of the expected stack with this capsule:
 //! Boxes are components and between the boxes are the traits that are the
@@ -42,6 +64,8 @@
 //!         3000,                        // The byte start address for the userspace
 //!                                      // accessible memory region.
 //!         2000,                        // The length of the userspace region.
+//!         256,                         // The default bytes allocated to an
+//!                                      // app.
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
@@ -52,11 +76,43 @@
 use core::cell::Cell;
 use core::cmp;
 use kernel::{AppId, AppSlice, Callback, Grant, Driver, ReturnCode, Shared};
+use kernel::common::bytes::{get_u32, put_u32};
 use kernel::common::take_cell::TakeCell;
 use kernel::hil;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// How many apps can have a region.
+pub const MAX_APP_REGIONS: usize = 8;
+
+/// "TNVS" in little endian.
+const TABLE_MAGIC: u32 = 0x53564E54;
+const TABLE_VERSION: u32 = 2;
+const TABLE_HEADER_LEN: usize = 8;
+const TABLE_ENTRY_LEN: usize = 40;
+
+/// Bytes of the region table at the start of the userspace region.
+pub const TABLE_LEN: usize = TABLE_HEADER_LEN + MAX_APP_REGIONS * TABLE_ENTRY_LEN;
+
+/// The region of the userspace memory that belongs to one app.
+#[derive(Clone, Copy, Default)]
+struct AppRegion {
+    /// Storage key of the app.
+    key: [u8; 32],
+    /// From the start of the userspace region.
+    offset: usize,
+    /// 0 if the entry is unused.
+    length: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TableState {
+    /// Not read from the storage yet.
+    Unloaded,
+    Loading,
+    Loaded,
+}
+
 #[derive(Clone,Copy,PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -69,6 +125,8 @@ pub enum NonvolatileCommand {
 pub enum NonvolatileUser {
     App { app_id: AppId },
     Kernel,
+    /// This capsule, reading or writing the region table.
+    Table,
 }
 
 pub struct App {
@@ -112,6 +170,13 @@ pub struct NonvolatileStorage<'a> {
     userspace_start_address: usize,
     // How many bytes allocated to userspace.
     userspace_length: usize,
+    // How many bytes an app gets if its TBF header does not say.
+    default_app_length: usize,
+    // Copy of the region table.
+    table: Cell<[AppRegion; MAX_APP_REGIONS]>,
+    table_state: Cell<TableState>,
+    // Whether the copy has regions that are not written to the storage yet.
+    table_dirty: Cell<bool>,
     // The first byte that is accessible from the kernel.
     kernel_start_address: usize,
     // How many bytes allocated to kernel.
@@ -137,6 +202,7 @@ impl<'a> NonvolatileStorage<'a> {
                grant: Grant<App>,
                userspace_start_address: usize,
                userspace_length: usize,
+               default_app_length: usize,
                kernel_start_address: usize,
                kernel_length: usize,
                buffer: &'static mut [u8])
@@ -148,6 +214,10 @@ impl<'a> NonvolatileStorage<'a> {
             current_user: Cell::new(None),
             userspace_start_address: userspace_start_address,
             userspace_length: userspace_length,
+            default_app_length: default_app_length,
+            table: Cell::new([AppRegion::default(); MAX_APP_REGIONS]),
+            table_state: Cell::new(TableState::Unloaded),
+            table_dirty: Cell::new(false),
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
             kernel_client: Cell::new(None),
@@ -173,11 +243,24 @@ impl<'a> NonvolatileStorage<'a> {
         match command {
             NonvolatileCommand::UserspaceRead |
             NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length || length > self.userspace_length ||
-                   offset + length > self.userspace_length {
-                    return ReturnCode::EINVAL;
+                // Userspace sees memory that starts at address 0 of its own
+                // region. The region is only known once the table is loaded,
+                // until then the command is queued and checked when it runs.
+                // Apps without a region yet are checked against the size
+                // their region will have.
+                let appid = match app_id {
+                    Some(appid) => appid,
+                    None => return ReturnCode::FAIL,
+                };
+                if appid.get_storage_key().is_none() {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                if self.table_state.get() == TableState::Loaded {
+                    let region_length = self.find_region(appid)
+                        .map_or_else(|| self.new_region_length(appid), |region| region.length);
+                    if !in_region(offset, length, region_length) {
+                        return ReturnCode::EINVAL;
+                    }
                 }
             }
             NonvolatileCommand::KernelRead |
@@ -222,30 +305,15 @@ impl<'a> NonvolatileStorage<'a> {
                             let active_len = cmp::min(length, allow_buf_len);
 
                             // First need to determine if we can execute this or must
-                            // queue it.
-                            if self.current_user.get().is_none() {
+                            // queue it. Apps also wait for the table to be read, or
+                            // for a new region to be recorded in it.
+                            if self.current_user.get().is_none() &&
+                               self.table_state.get() == TableState::Loaded &&
+                               !self.table_dirty.get() &&
+                               self.find_region(appid).is_some() {
                                 // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user.set(Some(NonvolatileUser::App { app_id: appid }));
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    app.buffer_write.as_mut().map(|app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
-                                        // Check that the internal buffer and the buffer that was
-                                        // allowed are long enough.
-                                        let write_len = cmp::min(active_len, kernel_buffer.len());
-
-                                        let d = &mut app_buffer.as_mut()[0..write_len];
-                                        for (i, c) in kernel_buffer[0..write_len].iter_mut()
-                                                                                 .enumerate() {
-                                            *c = d[i];
-                                        }
-                                    });
-                                    });
-                                }
-
-                                self.userspace_call_driver(command, offset, active_len)
+                                // Execute the command.
+                                self.userspace_call_driver(app, appid, command, offset, active_len)
                             } else {
                                 // Some app is using the storage, we must wait.
                                 if app.pending_command == true {
@@ -258,6 +326,20 @@ impl<'a> NonvolatileStorage<'a> {
                                     app.command = command;
                                     app.offset = offset;
                                     app.length = active_len;
+
+                                    // If nothing is using the storage, the
+                                    // table is what we are waiting for: it
+                                    // must be read, or the new region of the
+                                    // app recorded in it.
+                                    if self.current_user.get().is_none() {
+                                        if self.table_state.get() == TableState::Loaded {
+                                            if let Err(err) = self.app_region(appid) {
+                                                app.pending_command = false;
+                                                return err;
+                                            }
+                                        }
+                                        self.table_call_driver();
+                                    }
                                     ReturnCode::SUCCESS
                                 }
                             }
@@ -304,27 +386,46 @@ impl<'a> NonvolatileStorage<'a> {
 
     }
 
+    // Start a userspace command. Fails if it is not within the region of the
+    // app. The table must be loaded.
     fn userspace_call_driver(&self,
+                             app: &mut App,
+                             appid: AppId,
                              command: NonvolatileCommand,
                              offset: usize,
                              length: usize)
                              -> ReturnCode {
+        let region = match self.app_region(appid) {
+            Ok(region) => region,
+            Err(err) => return err,
+        };
+        if !in_region(offset, length, region.length) {
+            return ReturnCode::EINVAL;
+        }
 
         // Calculate where we want to actually read from in the physical
         // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let physical_address = self.userspace_start_address + region.offset + offset;
 
         self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
             // Check that the internal buffer and the buffer that was
             // allowed are long enough.
             let active_len = cmp::min(length, buffer.len());
 
-            // self.current_app.set(Some(appid));
+            self.current_user.set(Some(NonvolatileUser::App { app_id: appid }));
             match command {
                 NonvolatileCommand::UserspaceRead => {
                     self.driver.read(buffer, physical_address, active_len)
                 }
                 NonvolatileCommand::UserspaceWrite => {
+                    // Need to copy bytes if this is a write!
+                    app.buffer_write.as_mut().map(|app_buffer| {
+                        let write_len = cmp::min(active_len, app_buffer.len());
+                        let d = &app_buffer.as_mut()[0..write_len];
+                        for (i, c) in buffer[0..write_len].iter_mut().enumerate() {
+                            *c = d[i];
+                        }
+                    });
                     self.driver.write(buffer, physical_address, active_len)
                 }
                 _ => ReturnCode::FAIL,
@@ -332,6 +433,101 @@ impl<'a> NonvolatileStorage<'a> {
         })
     }
 
+    // Read the table if it is not loaded yet, or write it if it has new
+    // regions.
+    fn table_call_driver(&self) {
+        self.buffer.take().map(|buffer| if self.table_state.get() != TableState::Loaded {
+            self.table_state.set(TableState::Loading);
+            self.current_user.set(Some(NonvolatileUser::Table));
+            self.driver.read(buffer, self.userspace_start_address, TABLE_LEN);
+        } else {
+            put_u32(&mut buffer[0..4], TABLE_MAGIC);
+            put_u32(&mut buffer[4..8], TABLE_VERSION);
+            let entries = buffer[TABLE_HEADER_LEN..TABLE_LEN].chunks_mut(TABLE_ENTRY_LEN);
+            for (entry, region) in entries.zip(self.table.get().iter()) {
+                entry[0..32].copy_from_slice(&region.key);
+                put_u32(&mut entry[32..36], region.offset as u32);
+                put_u32(&mut entry[36..40], region.length as u32);
+            }
+            self.table_dirty.set(false);
+            self.current_user.set(Some(NonvolatileUser::Table));
+            self.driver.write(buffer, self.userspace_start_address, TABLE_LEN);
+        });
+    }
+
+    // Fill the copy of the table from what was read from the storage.
+    // Entries that do not fit in the userspace region are dropped.
+    fn load_table(&self, buffer: &[u8]) {
+        let mut table = [AppRegion::default(); MAX_APP_REGIONS];
+        if buffer.len() >= TABLE_LEN && get_u32(&buffer[0..4]) == TABLE_MAGIC &&
+           get_u32(&buffer[4..8]) == TABLE_VERSION {
+            let entries = buffer[TABLE_HEADER_LEN..TABLE_LEN].chunks(TABLE_ENTRY_LEN);
+            for (region, entry) in table.iter_mut().zip(entries) {
+                let offset = get_u32(&entry[32..36]) as usize;
+                let length = get_u32(&entry[36..40]) as usize;
+                if offset >= TABLE_LEN &&
+                   offset.checked_add(length).map_or(false, |end| end <= self.userspace_length) {
+                    let mut key = [0; 32];
+                    key.copy_from_slice(&entry[0..32]);
+                    *region = AppRegion {
+                        key: key,
+                        offset: offset,
+                        length: length,
+                    };
+                }
+            }
+        }
+        self.table.set(table);
+    }
+
+    // The region the table has for the app, if there is one.
+    fn find_region(&self, appid: AppId) -> Option<AppRegion> {
+        appid.get_storage_key().and_then(|key| {
+            self.table.get().iter().cloned().find(|region| region.length > 0 && region.key == key)
+        })
+    }
+
+    // How many bytes a new region of the app gets.
+    fn new_region_length(&self, appid: AppId) -> usize {
+        appid.get_nonvolatile_storage_size().unwrap_or(self.default_app_length)
+    }
+
+    // The region of the app, allocated after the last region if the app does
+    // not have one yet. The table must be loaded. New regions make the table
+    // dirty, they must be written to the storage before they are used.
+    fn app_region(&self, appid: AppId) -> Result<AppRegion, ReturnCode> {
+        let key = match appid.get_storage_key() {
+            Some(key) => key,
+            None => return Err(ReturnCode::ENOSUPPORT),
+        };
+        if let Some(region) = self.find_region(appid) {
+            return Ok(region);
+        }
+
+        let mut table = self.table.get();
+        let length = self.new_region_length(appid);
+        let offset = table.iter()
+            .filter(|region| region.length > 0)
+            .map(|region| region.offset + region.length)
+            .fold(TABLE_LEN, cmp::max);
+        if length == 0 || offset + length > self.userspace_length {
+            return Err(ReturnCode::ENOMEM);
+        }
+
+        let region = AppRegion {
+            key: key,
+            offset: offset,
+            length: length,
+        };
+        match table.iter_mut().find(|entry| entry.length == 0) {
+            Some(entry) => *entry = region,
+            None => return Err(ReturnCode::ENOMEM),
+        }
+        self.table.set(table);
+        self.table_dirty.set(true);
+        Ok(region)
+    }
+
     fn check_queue(&self) {
         // Check if there are any pending events.
         if self.kernel_pending_command.get() {
@@ -357,10 +553,30 @@ impl<'a> NonvolatileStorage<'a> {
             // If the kernel is not requesting anything, check all of the apps.
             for cntr in self.apps.iter() {
                 let started_command = cntr.enter(|app, _| if app.pending_command {
+                    let appid = app.appid();
+                    if self.table_state.get() != TableState::Loaded {
+                        self.table_call_driver();
+                        return true;
+                    }
+                    let allocated = self.app_region(appid);
+                    if allocated.is_ok() && self.table_dirty.get() {
+                        // Record the new region before the app uses it.
+                        self.table_call_driver();
+                        return true;
+                    }
+
                     app.pending_command = false;
-                    self.current_user.set(Some(NonvolatileUser::App { app_id: app.appid() }));
-                    self.userspace_call_driver(app.command, app.offset, app.length) ==
-                    ReturnCode::SUCCESS
+                    let (command, offset, length) = (app.command, app.offset, app.length);
+                    let result = self.userspace_call_driver(app, appid, command, offset, length);
+                    if result != ReturnCode::SUCCESS {
+                        // Tell the app its command will not complete.
+                        let callback = match command {
+                            NonvolatileCommand::UserspaceRead => app.callback_read,
+                            _ => app.callback_write,
+                        };
+                        callback.map(|mut cb| cb.schedule(usize::from(result), 0, 0));
+                    }
+                    result == ReturnCode::SUCCESS
                 } else {
                     false
                 });
@@ -372,6 +588,12 @@ impl<'a> NonvolatileStorage<'a> {
     }
 }
 
+/// Whether `length` bytes at `offset` are within a region of `region_length`
+/// bytes.
+fn in_region(offset: usize, length: usize, region_length: usize) -> bool {
+    offset < region_length && length <= region_length && offset + length <= region_length
+}
+
 /// This is the callback client for the underlying physical storage driver.
 impl<'a> hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'a> {
     fn read_done(&self, buffer: &'static mut [u8], length: usize) {
@@ -403,6 +625,14 @@ impl<'a> hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStora
                         app.callback_read.map(|mut cb| cb.schedule(length, 0, 0));
                     });
                 }
+                NonvolatileUser::Table => {
+                    if self.table_state.get() == TableState::Loading {
+                        let read_len = cmp::min(buffer.len(), length);
+                        self.load_table(&buffer[0..read_len]);
+                        self.table_state.set(TableState::Loaded);
+                    }
+                    self.buffer.replace(buffer);
+                }
             }
         });
 
@@ -428,6 +658,9 @@ impl<'a> hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStora
                         app.callback_write.map(|mut cb| cb.schedule(length, 0, 0));
                     });
                 }
+                NonvolatileUser::Table => {
+                    self.buffer.replace(buffer);
+                }
             }
         });
 
@@ -499,7 +732,8 @@ impl<'a> Driver for NonvolatileStorage<'a> {
     /// ### `command_num`
     ///
     /// - `0`: Return SUCCESS if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes available to the app. If the app
+    ///        does not have a region yet, this is the size it will get.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
@@ -508,8 +742,13 @@ impl<'a> Driver for NonvolatileStorage<'a> {
         match command_num {
             0 => /* This driver exists. */ ReturnCode::SUCCESS,
 
-            // How many bytes are accessible from userspace.
-            1 => ReturnCode::SuccessWithValue { value: self.userspace_length },
+            // How many bytes are accessible from this app.
+            1 => {
+                let length = self.find_region(appid).map_or_else(|| {
+                    appid.get_nonvolatile_storage_size().unwrap_or(self.default_app_length)
+                }, |region| region.length);
+                ReturnCode::SuccessWithValue { value: length }
+            }
 
             // Issue a read
             2 => {
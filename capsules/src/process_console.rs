@@ -0,0 +1,534 @@
+//! Kernel shell for inspecting and controlling processes over a UART.
+//!
+//! The process console reads lines from the UART and answers commands that
+//! show the state of the processes on the board, without having to crash the
+//! board to get the panic output.
+//!
+//! Commands
+//! --------
+//!
+//! - `help`: List the commands.
+//! - `list`: One line per process with its state, syscall count, CPU time
+//!   and last syscall, and how many app images failed verification.
+//! - `status <app>`: Statistics, memory map and registers of a process.
+//! - `fault <app>`: Fault status registers of a process.
+//! - `memory <app>`: Memory map of a process.
+//! - `stop <app>`: Stop a process.
+//! - `start <app>`: Resume a stopped process or restart a faulted one.
+//! - `install <size>`: Install a new app, see below.
+//! - `uninstall <app>`: Stop a process and erase its app from flash.
+//! - `crash`: The record of the last process fault or kernel panic, which
+//!   survives a reset. `crash clear` forgets it.
+//! - `power`: How often and how long the chip was active and in each sleep
+//!   state since boot.
+//!
+//! `<app>` is either the index of the process or its package name.
+//!
+//! Installing apps
+//! ---------------
+//!
+//! `install` and `uninstall` need an app loader, see `set_loader`. After
+//! `install <size>` the console answers with the page size and then treats
+//! everything it receives as the TBF, without echoing it. The sender must
+//! wait for a `.` after every page before sending the next one. Once the
+//! whole TBF has arrived it is checked and started, and the console goes
+//! back to reading commands.
+//!
+//! Setup
+//! -----
+//!
+//! The process console usually shares the serial port with the console
+//! driver through a `virtual_uart::MuxUart`.
+//!
+//! ```rust
+//! let process_console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+//! process_console_uart.setup();
+//! let process_console = static_init!(
+//!     capsules::process_console::ProcessConsole<UartDevice>,
+//!     capsules::process_console::ProcessConsole::new(
+//!         process_console_uart,
+//!         &mut capsules::process_console::WRITE_BUF,
+//!         &mut capsules::process_console::READ_BUF,
+//!         &mut capsules::process_console::COMMAND_BUF));
+//! hil::uart::UART::set_client(process_console_uart, process_console);
+//! process_console.set_loader(app_loader);
+//! capsules::app_loader::Loader::set_client(app_loader, process_console);
+//! process_console.initialize();
+//! ```
+//!
+//! Output that does not fit in `WRITE_BUF` is generated again for every
+//! chunk that is sent, so a process that runs while a long answer is being
+//! printed can make the chunks slightly inconsistent.
+
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::str;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::fault_record;
+use kernel::power;
+use kernel::hil::uart::{self, UART, Client};
+use kernel::process::{self, State};
+use app_loader::{Loader, LoaderClient};
+
+pub static mut WRITE_BUF: [u8; 256] = [0; 256];
+pub static mut READ_BUF: [u8; 1] = [0; 1];
+pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
+
+const PROMPT: &'static str = "tock$ ";
+
+#[derive(Copy, Clone, PartialEq)]
+enum Command {
+    Help,
+    List,
+    Status(usize),
+    Fault(usize),
+    Memory(usize),
+    FaultRecord,
+    Power,
+    Message(&'static str),
+    /// Ready to receive an image of the given size in pages of the given
+    /// size.
+    Ready(usize, usize),
+    /// A page of the image has been written. Printed without a prompt.
+    Progress,
+    Installed(usize),
+    Failed(ReturnCode),
+}
+
+/// Writes the part of a formatted output that starts `skip` bytes in into
+/// `buffer`, dropping everything before and after.
+struct ChunkWriter<'b> {
+    buffer: &'b mut [u8],
+    skip: usize,
+    position: usize,
+    len: usize,
+}
+
+impl<'b> Write for ChunkWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.position >= self.skip && self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+            self.position += 1;
+        }
+        Ok(())
+    }
+}
+
+pub struct ProcessConsole<'a, U: UART + 'a> {
+    uart: &'a U,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    command_buffer: TakeCell<'static, [u8]>,
+    command_index: Cell<usize>,
+    /// The command whose answer is being printed and how many bytes of the
+    /// answer have been sent so far.
+    output: Cell<Option<(Command, usize)>>,
+    /// The answer to print once the current one is done.
+    pending: Cell<Option<Command>>,
+    loader: Cell<Option<&'a Loader>>,
+    /// Bytes of the image being installed that have not arrived yet.
+    install_remaining: Cell<Option<usize>>,
+}
+
+impl<'a, U: UART> ProcessConsole<'a, U> {
+    pub fn new(uart: &'a U,
+               tx_buffer: &'static mut [u8],
+               rx_buffer: &'static mut [u8],
+               command_buffer: &'static mut [u8])
+               -> ProcessConsole<'a, U> {
+        ProcessConsole {
+            uart: uart,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            command_buffer: TakeCell::new(command_buffer),
+            command_index: Cell::new(0),
+            output: Cell::new(None),
+            pending: Cell::new(None),
+            loader: Cell::new(None),
+            install_remaining: Cell::new(None),
+        }
+    }
+
+    /// Enable the `install` and `uninstall` commands.
+    pub fn set_loader(&self, loader: &'a Loader) {
+        self.loader.set(Some(loader));
+    }
+
+    /// Print the prompt and start listening for commands.
+    pub fn initialize(&self) {
+        self.rx_buffer.take().map(|buffer| self.uart.receive(buffer, 1));
+        self.respond(Command::Message(""));
+    }
+
+    /// Start printing the answer to `command`, or once the answer that is
+    /// being printed is done. Only one answer waits, and only a waiting
+    /// progress dot is replaced by a later answer.
+    fn respond(&self, command: Command) {
+        if self.output.get().is_none() {
+            self.output.set(Some((command, 0)));
+            self.send_next_chunk();
+        } else if self.pending.get().map_or(true, |pending| pending == Command::Progress) {
+            self.pending.set(Some(command));
+        }
+    }
+
+    /// Send the next part of the current answer if the UART is idle.
+    fn send_next_chunk(&self) {
+        let (command, sent) = match self.output.get() {
+            Some(output) => output,
+            None => return,
+        };
+        self.tx_buffer.take().map(|buffer| {
+            let len = {
+                let mut writer = ChunkWriter {
+                    buffer: &mut buffer[..],
+                    skip: sent,
+                    position: 0,
+                    len: 0,
+                };
+                self.write_answer(command, &mut writer);
+                if command != Command::Progress {
+                    let _ = writer.write_str(PROMPT);
+                }
+                writer.len
+            };
+            if len == 0 {
+                self.tx_buffer.replace(buffer);
+                self.output.set(self.pending.take().map(|command| (command, 0)));
+                if self.output.get().is_some() {
+                    self.send_next_chunk();
+                }
+            } else {
+                self.output.set(Some((command, sent + len)));
+                self.uart.transmit(buffer, len);
+            }
+        });
+    }
+
+    /// Echo typed characters back, unless an answer is being printed.
+    fn echo(&self, bytes: &[u8]) {
+        if self.output.get().is_none() {
+            self.tx_buffer.take().map(|buffer| {
+                for (dst, src) in buffer.iter_mut().zip(bytes.iter()) {
+                    *dst = *src;
+                }
+                self.uart.transmit(buffer, bytes.len());
+            });
+        }
+    }
+
+    fn write_answer<W: Write>(&self, command: Command, writer: &mut W) {
+        let procs = unsafe { &mut process::PROCS };
+        match command {
+            Command::Help => {
+                let _ = writer.write_str("Commands: help list status fault memory stop start \
+                                          install uninstall crash power\r\n");
+            }
+            Command::List => {
+                let _ = writer.write_str(" PID  Name                 State      Syscalls  CPU (ms)  Last Syscall\r\n");
+                for (i, slot) in procs.iter().enumerate() {
+                    slot.as_ref().map(|p| {
+                        let _ = writer.write_fmt(format_args!(" {:<4} {:<20} {:<10} {:<9} {:<9} ",
+                                                              i,
+                                                              p.package_name,
+                                                              state_str(p.current_state()),
+                                                              p.syscall_count(),
+                                                              p.cpu_time_us() / 1000));
+                        let _ = match p.last_syscall() {
+                            Some(syscall) => writer.write_fmt(format_args!("{:?}\r\n", syscall)),
+                            None => writer.write_str("None\r\n"),
+                        };
+                    });
+                }
+                if let (refused, Some(reason)) = process::refused_apps() {
+                    let _ = writer.write_fmt(format_args!("Refused apps: {}, last: {}\r\n",
+                                                          refused,
+                                                          reason));
+                }
+            }
+            Command::Status(idx) => {
+                procs[idx].as_mut().map(|p| unsafe { p.statistics_str(writer) });
+            }
+            Command::Fault(idx) => {
+                procs[idx].as_mut().map(|p| unsafe { p.fault_str(writer) });
+                let _ = writer.write_str("\r\n");
+            }
+            Command::Memory(idx) => {
+                procs[idx].as_ref().map(|p| unsafe { p.memory_map_str(writer) });
+            }
+            Command::FaultRecord => {
+                match fault_record::last() {
+                    Some(record) => record.fault_str(writer),
+                    None => {
+                        let _ = writer.write_str("No fault recorded.\r\n");
+                    }
+                }
+            }
+            Command::Power => {
+                let _ = writer.write_str(" State        Entries     Time (ms)\r\n");
+                for state in power::POWER_STATES.iter() {
+                    let residency = power::residency(*state);
+                    let _ = writer.write_fmt(format_args!(" {:<12} {:<11} {}\r\n",
+                                                          state.name(),
+                                                          residency.entries,
+                                                          residency.time_us / 1000));
+                }
+            }
+            Command::Message(message) => {
+                let _ = writer.write_str(message);
+            }
+            Command::Ready(size, page_size) => {
+                let _ = writer.write_fmt(format_args!("Send {} bytes, {} at a time.\r\n",
+                                                      size,
+                                                      page_size));
+            }
+            Command::Progress => {
+                let _ = writer.write_str(".");
+            }
+            Command::Installed(idx) => {
+                let _ = writer.write_fmt(format_args!("\r\nInstalled as process {}.\r\n", idx));
+            }
+            Command::Failed(err) => {
+                let _ = writer.write_fmt(format_args!("\r\nFailed: {:?}.\r\n", err));
+            }
+        }
+    }
+
+    /// Parse and run the command in `line`, returning what to print.
+    fn execute(&self, line: &str) -> Command {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Command::Message(""),
+        };
+        let target = if name == "install" || name == "crash" || name == "power" {
+            None
+        } else {
+            words.next().map(find_process)
+        };
+
+        match (name, target) {
+            ("help", _) => Command::Help,
+            ("list", _) => Command::List,
+            ("power", _) => Command::Power,
+            ("crash", _) => {
+                match words.next() {
+                    None => Command::FaultRecord,
+                    Some("clear") => {
+                        fault_record::clear();
+                        Command::Message("Fault record cleared.\r\n")
+                    }
+                    Some(_) => Command::Message("Usage: crash [clear]\r\n"),
+                }
+            }
+            (_, Some(None)) => Command::Message("No such process.\r\n"),
+            ("status", Some(Some(idx))) => Command::Status(idx),
+            ("fault", Some(Some(idx))) => Command::Fault(idx),
+            ("memory", Some(Some(idx))) => Command::Memory(idx),
+            ("stop", Some(Some(idx))) => {
+                let procs = unsafe { &mut process::PROCS };
+                procs[idx].as_mut().map_or(Command::Message(""), |p| {
+                    match p.current_state() {
+                        State::Stopped => Command::Message("Process is already stopped.\r\n"),
+                        State::Fault => Command::Message("Process has faulted.\r\n"),
+                        _ => {
+                            p.stop();
+                            Command::Message("Process stopped.\r\n")
+                        }
+                    }
+                })
+            }
+            ("start", Some(Some(idx))) => {
+                let procs = unsafe { &mut process::PROCS };
+                procs[idx].as_mut().map_or(Command::Message(""), |p| {
+                    match p.current_state() {
+                        State::Stopped => {
+                            p.resume();
+                            Command::Message("Process resumed.\r\n")
+                        }
+                        State::Fault => {
+                            unsafe {
+                                p.restart();
+                            }
+                            Command::Message("Process restarted.\r\n")
+                        }
+                        _ => Command::Message("Process is already running.\r\n"),
+                    }
+                })
+            }
+            ("uninstall", Some(Some(idx))) => {
+                self.loader.get().map_or(Command::Message("No app loader.\r\n"), |loader| {
+                    match loader.uninstall(idx) {
+                        // Answered when the flash has been erased.
+                        ReturnCode::SUCCESS => Command::Message(""),
+                        err => Command::Failed(err),
+                    }
+                })
+            }
+            ("install", _) => {
+                let size = words.next().and_then(|size| size.parse::<usize>().ok());
+                match (self.loader.get(), size) {
+                    (None, _) => Command::Message("No app loader.\r\n"),
+                    (_, None) => Command::Message("Missing or invalid size.\r\n"),
+                    (Some(loader), Some(size)) => {
+                        match loader.setup(size) {
+                            ReturnCode::SUCCESS => {
+                                self.install_remaining.set(Some(size));
+                                Command::Ready(size, loader.page_size())
+                            }
+                            err => Command::Failed(err),
+                        }
+                    }
+                }
+            }
+            ("status", None) | ("fault", None) | ("memory", None) | ("stop", None) |
+            ("start", None) | ("uninstall", None) => {
+                Command::Message("Missing process name or index.\r\n")
+            }
+            _ => Command::Message("Unknown command, try `help`.\r\n"),
+        }
+    }
+
+    /// Pass one received byte of an image to the loader. Returns whether the
+    /// byte completed a page, in which case nothing more must be received
+    /// until the page has been written.
+    fn receive_image_byte(&self, byte: u8) -> bool {
+        let remaining = self.install_remaining.get().unwrap_or(0);
+        self.loader.get().map_or(false, |loader| {
+            match loader.write(&[byte]) {
+                ReturnCode::SUCCESS => {
+                    self.install_remaining.set(Some(remaining - 1));
+                    (remaining - 1) % loader.page_size() == 0
+                }
+                err => {
+                    loader.abort();
+                    self.install_remaining.set(None);
+                    self.respond(Command::Failed(err));
+                    false
+                }
+            }
+        })
+    }
+
+    /// Start receiving again after waiting for the loader.
+    fn resume_receive(&self) {
+        self.rx_buffer.take().map(|buffer| self.uart.receive(buffer, 1));
+    }
+
+    /// Handle one received character.
+    fn receive_char(&self, c: u8) {
+        self.command_buffer.map(|command| {
+            let index = self.command_index.get();
+            match c {
+                b'\r' | b'\n' => {
+                    self.command_index.set(0);
+                    self.echo(b"\r\n");
+                    let answer = match str::from_utf8(&command[..index]) {
+                        Ok(line) => self.execute(line),
+                        Err(_) => Command::Message("Invalid characters in command.\r\n"),
+                    };
+                    self.respond(answer);
+                }
+                // Backspace and delete.
+                0x08 | 0x7f => {
+                    if index > 0 {
+                        self.command_index.set(index - 1);
+                        self.echo(b"\x08 \x08");
+                    }
+                }
+                _ => {
+                    if index < command.len() {
+                        command[index] = c;
+                        self.command_index.set(index + 1);
+                        self.echo(&[c]);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Find a process by index or package name.
+fn find_process(name: &str) -> Option<usize> {
+    let procs = unsafe { &process::PROCS };
+    let by_index = name.parse::<usize>()
+        .ok()
+        .and_then(|idx| procs.get(idx).and_then(|p| p.as_ref()).map(|_| idx));
+    by_index.or_else(|| {
+        procs.iter().position(|slot| slot.as_ref().map_or(false, |p| p.package_name == name))
+    })
+}
+
+fn state_str(state: State) -> &'static str {
+    match state {
+        State::Unstarted => "Unstarted",
+        State::Running => "Running",
+        State::Yielded => "Yielded",
+        State::Fault => "Fault",
+        State::Stopped => "Stopped",
+    }
+}
+
+impl<'a, U: UART> Client for ProcessConsole<'a, U> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.tx_buffer.replace(buffer);
+        self.send_next_chunk();
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        if rx_len > 0 && error == uart::Error::CommandComplete {
+            if self.install_remaining.get().is_some() {
+                if self.receive_image_byte(buffer[0]) {
+                    self.rx_buffer.replace(buffer);
+                    return;
+                }
+            } else {
+                self.receive_char(buffer[0]);
+            }
+        }
+        self.uart.receive(buffer, 1);
+    }
+}
+
+impl<'a, U: UART> LoaderClient for ProcessConsole<'a, U> {
+    fn write_done(&self, result: ReturnCode) {
+        match (result, self.install_remaining.get()) {
+            (ReturnCode::SUCCESS, Some(0)) => {
+                // The receiver stays off until the app has been started.
+                let result = self.loader.get().map_or(ReturnCode::FAIL, |loader| loader.finish());
+                if result != ReturnCode::SUCCESS {
+                    self.finish_done(result);
+                }
+            }
+            (ReturnCode::SUCCESS, Some(_)) => {
+                self.respond(Command::Progress);
+                self.resume_receive();
+            }
+            (err, _) => {
+                self.install_remaining.set(None);
+                self.respond(Command::Failed(err));
+                self.resume_receive();
+            }
+        }
+    }
+
+    fn finish_done(&self, result: ReturnCode) {
+        self.install_remaining.set(None);
+        match result {
+            ReturnCode::SuccessWithValue { value } => self.respond(Command::Installed(value)),
+            err => self.respond(Command::Failed(err)),
+        }
+        self.resume_receive();
+    }
+
+    fn uninstall_done(&self, result: ReturnCode) {
+        match result {
+            ReturnCode::SUCCESS => self.respond(Command::Message("App uninstalled.\r\n")),
+            err => self.respond(Command::Failed(err)),
+        }
+    }
+}
@@ -1,6 +1,9 @@
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
 //! This allows initialization and block reads or writes on top of SPI.
+//! Transfers of more than one block use the multiple block commands, so the
+//! card streams all of the blocks after a single command. Byte addressed
+//! (SDSC) and block addressed (SDHC and SDXC) cards are supported.
 //!
 //! Usage
 //! -----
@@ -64,6 +67,7 @@ pub struct SDCard<'a, A: hil::time::Alarm + 'a> {
 
     is_initialized: Cell<bool>,
     card_type: Cell<SDCardType>,
+    block_count: Cell<u64>,
 
     detect_pin: Cell<Option<&'static hil::gpio::Pin>>,
 
@@ -73,6 +77,12 @@ pub struct SDCard<'a, A: hil::time::Alarm + 'a> {
     client: Cell<Option<&'static SDCardClient>>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
+
+    poll_count: Cell<u8>,
+    transfer_count: Cell<u32>,
+    transfer_start: Cell<u32>,
+    write_failed: Cell<bool>,
+    statistics: Cell<Statistics>,
 }
 
 /// SD card command codes
@@ -91,6 +101,7 @@ enum SDCmd {
     CMD25_WriteMultiple = 25, //        Write multiple blocks
     CMD55_ManufSpecificCommand = 55, // Next command will be manufacturer specific
     CMD58_ReadOCR = 58, //              Read operation condition register (OCR)
+    ACMD23_SetWriteEraseCount = 0x80 + 23, // Pre-erase blocks of a multiple block write
     ACMD41_ManufSpecificInit = 0x80 + 41, // Manufacturer specific Init
 }
 
@@ -128,10 +139,12 @@ enum SpiState {
     ReceivedBlock { count: u32 },
     ReadBlocksComplete,
 
+    PreEraseBlocks { address: u32, count: u32 },
     StartWriteBlocks { count: u32 },
-    WriteBlockResponse,
-    WriteBlockBusy,
-    WaitWriteBlockBusy,
+    WriteBlockResponse { count: u32 },
+    WriteBlockBusy { count: u32 },
+    WaitWriteBlockBusy { count: u32 },
+    StopWriteBlocks,
 }
 
 /// Alarm states
@@ -148,7 +161,7 @@ enum AlarmState {
     WaitForDataBlock,
     WaitForDataBlocks { count: u32 },
 
-    WaitForWriteBusy,
+    WaitForWriteBusy { count: u32 },
 }
 
 /// Error codes returned if an SD card transaction fails
@@ -175,6 +188,25 @@ enum SDCardType {
 const SUCCESS_STATUS: u8 = 0x00;
 const INITIALIZING_STATUS: u8 = 0x01;
 const DATA_TOKEN: u8 = 0xFE;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xFC;
+const STOP_TRANSMISSION_TOKEN: u8 = 0xFD;
+
+/// Number of times to poll the card for a data block or for the end of a
+/// write before waiting 1 ms
+const MAX_POLLS: u8 = 32;
+
+/// Transfer statistics, counted from boot or the last reset
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct Statistics {
+    /// Blocks read successfully
+    pub blocks_read: u32,
+    /// Blocks written successfully
+    pub blocks_written: u32,
+    /// Time spent on successful reads, in alarm ticks
+    pub read_ticks: u64,
+    /// Time spent on successful writes, in alarm ticks
+    pub write_ticks: u64,
+}
 
 /// Callback functions from SDCard
 pub trait SDCardClient {
@@ -228,12 +260,18 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
             alarm_count: Cell::new(0),
             is_initialized: Cell::new(false),
             card_type: Cell::new(SDCardType::Uninitialized),
+            block_count: Cell::new(0),
             detect_pin: Cell::new(pin),
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
             client: Cell::new(None),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
+            poll_count: Cell::new(0),
+            transfer_count: Cell::new(0),
+            transfer_start: Cell::new(0),
+            write_failed: Cell::new(false),
+            statistics: Cell::new(Statistics::default()),
         }
     }
 
@@ -389,6 +427,85 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         (r1, r2, r3)
     }
 
+    /// poll the card again while it is not ready to send a data block or
+    /// still busy writing one
+    /// Polls follow each other immediately, since the card is usually ready
+    /// within a few bytes. After MAX_POLLS of them, wait 1 ms and try again
+    fn poll_again(&self,
+                  state: SpiState,
+                  alarm_state: AlarmState,
+                  write_buffer: &'static mut [u8],
+                  read_buffer: &'static mut [u8]) {
+        let polls = self.poll_count.get();
+        if polls < MAX_POLLS {
+            self.poll_count.set(polls + 1);
+            self.state.set(state);
+            self.read_bytes(write_buffer, read_buffer, 1);
+        } else {
+            // replace buffers
+            self.txbuffer.replace(write_buffer);
+            self.rxbuffer.replace(read_buffer);
+
+            // try again after 1 ms
+            self.alarm_state.set(alarm_state);
+            let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
+            let tics = self.alarm.now().wrapping_add(interval);
+            self.alarm.set_alarm(tics);
+        }
+    }
+
+    /// send the next block of the client buffer as a data packet
+    fn write_block(&self,
+                   token: u8,
+                   write_buffer: &'static mut [u8],
+                   read_buffer: &'static mut [u8]) {
+        let offset = self.client_offset.get();
+        let bytes_written = self.client_buffer.map_or(0, |buffer| {
+            // copy over data from client buffer
+            // Limit to minimum length between write_buffer, what is left of
+            // buffer, and 512 (block size)
+            for (write_byte, &client_byte) in
+                write_buffer.iter_mut().skip(1).zip(buffer.iter().skip(offset)).take(512) {
+                *write_byte = client_byte;
+            }
+
+            // calculate number of bytes written
+            cmp::min(buffer.len().saturating_sub(offset), 512)
+        });
+        self.client_offset.set(offset + 512);
+
+        // set a known value for remaining bytes
+        for write_byte in write_buffer.iter_mut()
+            .skip(1)
+            .skip(bytes_written)
+            .take(512 - bytes_written) {
+            *write_byte = 0xFF;
+        }
+
+        // set up remainder of data packet
+        write_buffer[0] = token;
+        write_buffer[513] = 0xFF; // dummy CRC
+        write_buffer[514] = 0xFF; // dummy CRC
+
+        // write data packet
+        self.write_bytes(write_buffer, read_buffer, 515);
+    }
+
+    /// add the transfer that just completed to the statistics
+    fn record_transfer(&self, write: bool) {
+        let ticks = self.alarm.now().wrapping_sub(self.transfer_start.get()) as u64;
+        let blocks = self.transfer_count.get();
+        let mut statistics = self.statistics.get();
+        if write {
+            statistics.blocks_written = statistics.blocks_written.wrapping_add(blocks);
+            statistics.write_ticks += ticks;
+        } else {
+            statistics.blocks_read = statistics.blocks_read.wrapping_add(blocks);
+            statistics.read_ticks += ticks;
+        }
+        self.statistics.set(statistics);
+    }
+
     /// updates SD card state on SPI transaction returns
     fn process_spi_states(&self,
                           write_buffer: &'static mut [u8],
@@ -508,16 +625,25 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
                 if r1 == SUCCESS_STATUS {
                     if (r7 & 0x40000000) != 0x00000000 {
+                        // SDHC or SDXC card, blocks are always 512 bytes
                         self.card_type.set(SDCardType::SDv2BlockAddressable);
+
+                        // Read CSD register
+                        // Note that the receive length needs to be increased
+                        //  here to capture the 16-byte register (plus some
+                        //  slack)
+                        self.state.set(SpiState::InitComplete);
+                        self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
                     } else {
+                        // byte addressed SDv2 card, set blocksize to 512
                         self.card_type.set(SDCardType::SDv2);
+                        self.state.set(SpiState::InitSetBlocksize);
+                        self.send_command(SDCmd::CMD16_SetBlockSize,
+                                          512,
+                                          write_buffer,
+                                          read_buffer,
+                                          10);
                     }
-
-                    // Read CSD register
-                    // Note that the receive length needs to be increased here
-                    //  to capture the 16-byte register (plus some slack)
-                    self.state.set(SpiState::InitComplete);
-                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -668,7 +794,10 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     for buf in read_buffer.windows(12) {
                         if buf[0] == DATA_TOKEN {
                             // get total size from CSD
-                            if (buf[1] & 0xC0) == 0x00 {
+                            // MMC cards use their own CSD versions, with the
+                            //  size in the same place as in CSD version 1.0
+                            let csd_version = buf[1] & 0xC0;
+                            if self.card_type.get() == SDCardType::MMC || csd_version == 0x00 {
                                 // CSD version 1.0
                                 let c_size = (((buf[7] & 0x03) as u32) << 10) |
                                              (((buf[8] & 0xFF) as u32) << 2) |
@@ -680,8 +809,9 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                                 let block_count = (c_size + 1) * (1 << (c_size_mult + 2));
                                 let block_len = 1 << read_bl_len;
                                 total_size = block_count as u64 * block_len as u64;
-                            } else {
-                                // CSD version 2.0
+                            } else if csd_version == 0x40 {
+                                // CSD version 2.0, SDHC and SDXC cards
+                                // c_size is 22 bits, up to 2 TB
                                 let c_size = (((buf[8] & 0x3F) as u32) << 16) |
                                              (((buf[9] & 0xFF) as u32) << 8) |
                                              ((buf[10] & 0xFF) as u32);
@@ -697,6 +827,8 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.rxbuffer.replace(read_buffer);
 
                     // initialization complete
+                    // A block count of 0 means the size is unknown
+                    self.block_count.set(total_size / 512);
                     self.state.set(SpiState::Idle);
                     self.is_initialized.set(true);
 
@@ -720,6 +852,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
+                    self.poll_count.set(0);
                     if count <= 1 {
                         // check for data block to be ready
                         self.state.set(SpiState::WaitReadBlock);
@@ -750,16 +883,10 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.read_bytes(write_buffer, read_buffer, 512 + 2);
                 } else if read_buffer[0] == 0xFF {
                     // line is idling high, data is not ready
-
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForDataBlock);
-                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.poll_again(SpiState::WaitReadBlock,
+                                    AlarmState::WaitForDataBlock,
+                                    write_buffer,
+                                    read_buffer);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -780,6 +907,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
                 // read finished, perform callback
                 self.state.set(SpiState::Idle);
+                self.record_transfer(false);
                 self.rxbuffer.map(|read_buffer| {
                     self.client_buffer.take().map(move |buffer| {
                         // copy data to user buffer
@@ -807,16 +935,10 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.read_bytes(write_buffer, read_buffer, 512 + 2);
                 } else if read_buffer[0] == 0xFF {
                     // line is idling high, data is not ready
-
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForDataBlocks { count: count });
-                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.poll_again(SpiState::WaitReadBlocks { count: count },
+                                    AlarmState::WaitForDataBlocks { count: count },
+                                    write_buffer,
+                                    read_buffer);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -853,6 +975,8 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     self.send_command(SDCmd::CMD12_StopRead, 0x0, write_buffer, read_buffer, 10);
                 } else {
                     // check for next data block to be ready
+                    // The card streams the next block without another command
+                    self.poll_count.set(0);
                     self.state.set(SpiState::WaitReadBlocks { count: count - 1 });
                     self.read_bytes(write_buffer, read_buffer, 1);
                 }
@@ -860,13 +984,16 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
             SpiState::ReadBlocksComplete => {
                 // check response
-                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+                // The card keeps sending data while the stop command goes
+                //  out, and a stuff byte follows it, so skip those bytes
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, &read_buffer[9..18]);
 
                 if r1 == SUCCESS_STATUS {
                     // replace buffers
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
                     self.state.set(SpiState::Idle);
+                    self.record_transfer(false);
 
                     // read finished, perform callback
                     self.client_buffer.take().map(move |buffer| {
@@ -887,53 +1014,28 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 }
             }
 
+            SpiState::PreEraseBlocks { address, count } => {
+                // the pre-erase count is only a hint, so continue with the
+                //  write whatever the response
+                self.state.set(SpiState::StartWriteBlocks { count: count });
+                self.send_command(SDCmd::CMD25_WriteMultiple,
+                                  address,
+                                  write_buffer,
+                                  read_buffer,
+                                  10);
+            }
+
             SpiState::StartWriteBlocks { count } => {
                 // check response
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    if count <= 1 {
-                        let bytes_written = self.client_buffer.map_or(0, |buffer| {
-                            // copy over data from client buffer
-                            // Limit to minimum length between write_buffer,
-                            // buffer, and 512 (block size)
-                            for (write_byte, &client_byte) in
-                                write_buffer.iter_mut().skip(1).zip(buffer.iter()).take(512) {
-                                *write_byte = client_byte;
-                            }
-
-                            // calculate number of bytes written
-                            cmp::min(write_buffer.len(), cmp::min(buffer.len(), 512))
-                        });
-
-                        // set a known value for remaining bytes
-                        for write_byte in write_buffer.iter_mut()
-                            .skip(1)
-                            .skip(bytes_written)
-                            .take(512) {
-                            *write_byte = 0xFF;
-                        }
-
-                        // set up remainder of data packet
-                        write_buffer[0] = DATA_TOKEN; // Data token
-                        write_buffer[513] = 0xFF; // dummy CRC
-                        write_buffer[514] = 0xFF; // dummy CRC
-
-                        // write data packet
-                        self.state.set(SpiState::WriteBlockResponse);
-                        self.write_bytes(write_buffer, read_buffer, 515);
+                    // send the first block
+                    self.state.set(SpiState::WriteBlockResponse { count: count });
+                    if self.transfer_count.get() > 1 {
+                        self.write_block(WRITE_MULTIPLE_TOKEN, write_buffer, read_buffer);
                     } else {
-                        // multi-block SD card writes are unimplemented
-                        // This should have returned an error already, but if
-                        // we got here somehow, return an error and quit now
-                        self.txbuffer.replace(write_buffer);
-                        self.rxbuffer.replace(read_buffer);
-                        self.state.set(SpiState::Idle);
-                        self.alarm_state.set(AlarmState::Idle);
-                        self.alarm_count.set(0);
-                        self.client
-                            .get()
-                            .map(move |client| { client.error(ErrorCode::WriteFailure as u32); });
+                        self.write_block(DATA_TOKEN, write_buffer, read_buffer);
                     }
                 } else {
                     // error, send callback and quit
@@ -948,17 +1050,26 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 }
             }
 
-            SpiState::WriteBlockResponse => {
+            SpiState::WriteBlockResponse { count } => {
                 // Get data packet
-                self.state.set(SpiState::WriteBlockBusy);
+                self.state.set(SpiState::WriteBlockBusy { count: count });
                 self.read_bytes(write_buffer, read_buffer, 1);
             }
 
-            SpiState::WriteBlockBusy => {
+            SpiState::WriteBlockBusy { count } => {
                 if (read_buffer[0] & 0x1F) == 0x05 {
                     // check if sd card is busy
-                    self.state.set(SpiState::WaitWriteBlockBusy);
+                    self.poll_count.set(0);
+                    self.state.set(SpiState::WaitWriteBlockBusy { count: count });
                     self.read_bytes(write_buffer, read_buffer, 1);
+                } else if self.transfer_count.get() > 1 {
+                    // block rejected. Terminate multiple write and report
+                    //  the error once the card is no longer busy
+                    self.write_failed.set(true);
+                    write_buffer[0] = STOP_TRANSMISSION_TOKEN;
+                    write_buffer[1] = 0xFF;
+                    self.state.set(SpiState::StopWriteBlocks);
+                    self.write_bytes(write_buffer, read_buffer, 2);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -972,32 +1083,59 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 }
             }
 
-            SpiState::WaitWriteBlockBusy => {
+            SpiState::WaitWriteBlockBusy { count } => {
                 // check if line is still held low (busy state)
                 if read_buffer[0] != 0x00 {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // read finished, perform callback
-                    self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
-                    self.client_buffer.take().map(move |buffer| {
-                        self.client.get().map(move |client| { client.write_done(buffer); });
-                    });
-                } else {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
+                    if count > 1 {
+                        // send the next block of a multiple block write
+                        self.state.set(SpiState::WriteBlockResponse { count: count - 1 });
+                        self.write_block(WRITE_MULTIPLE_TOKEN, write_buffer, read_buffer);
+                    } else if count == 1 && self.transfer_count.get() > 1 {
+                        // all blocks written. Terminate multiple write, the
+                        //  card is busy again after the byte following the
+                        //  token
+                        write_buffer[0] = STOP_TRANSMISSION_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::StopWriteBlocks);
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    } else if self.write_failed.get() {
+                        // error, send callback and quit
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+                        self.state.set(SpiState::Idle);
+                        self.alarm_state.set(AlarmState::Idle);
+                        self.client
+                            .get()
+                            .map(move |client| { client.error(ErrorCode::WriteFailure as u32); });
+                    } else {
+                        // replace buffers
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
 
-                    // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForWriteBusy);
-                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                        // write finished, perform callback
+                        self.state.set(SpiState::Idle);
+                        self.record_transfer(true);
+                        self.client_buffer.take().map(move |buffer| {
+                            self.client.get().map(move |client| { client.write_done(buffer); });
+                        });
+                    }
+                } else {
+                    self.poll_again(SpiState::WaitWriteBlockBusy { count: count },
+                                    AlarmState::WaitForWriteBusy { count: count },
+                                    write_buffer,
+                                    read_buffer);
                 }
             }
 
+            SpiState::StopWriteBlocks => {
+                // wait for the card to finish programming
+                self.poll_count.set(0);
+                self.state.set(SpiState::WaitWriteBlockBusy { count: 0 });
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
+
+
             SpiState::Idle => {
                 // receiving an event from Idle means something was killed
 
@@ -1097,6 +1235,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // wait until ready and then read data block, then done
+                        self.poll_count.set(0);
                         self.state.set(SpiState::WaitReadBlock);
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
@@ -1110,6 +1249,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // wait until ready and then read data block, then done
+                        self.poll_count.set(0);
                         self.state.set(SpiState::WaitReadBlocks { count: count });
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
@@ -1118,12 +1258,13 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
-            AlarmState::WaitForWriteBusy => {
+            AlarmState::WaitForWriteBusy { count } => {
                 // check card initialization again
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // check if sd card is busy
-                        self.state.set(SpiState::WaitWriteBlockBusy);
+                        self.poll_count.set(0);
+                        self.state.set(SpiState::WaitWriteBlockBusy { count: count });
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
                 });
@@ -1183,94 +1324,170 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         }
     }
 
-    pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
+    /// convert a sector number to the address used by read and write
+    /// commands
+    /// Returns an error if the card is not ready, or if `count` blocks from
+    /// `sector` go past the end of the card
+    fn transfer_address(&self, sector: u32, count: u32) -> Result<u32, ReturnCode> {
         // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer.take().map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                        // save the user buffer for later
-                        self.client_buffer.replace(buffer);
-                        self.client_offset.set(0);
-
-                        // convert block address to byte address for non-block
-                        //  access cards
-                        let mut address = sector;
-                        if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                            address *= 512;
-                        }
+        if !self.is_installed() {
+            // sd card not installed
+            return Err(ReturnCode::EUNINSTALLED);
+        }
+        if !self.is_initialized() {
+            // sd card not initialized
+            return Err(ReturnCode::ERESERVE);
+        }
 
-                        self.state.set(SpiState::StartReadBlocks { count: count });
-                        if count == 1 {
-                            self.send_command(SDCmd::CMD17_ReadSingle,
-                                              address,
-                                              txbuffer,
-                                              rxbuffer,
-                                              10);
-                        } else {
-                            self.send_command(SDCmd::CMD18_ReadMultiple,
-                                              address,
-                                              txbuffer,
-                                              rxbuffer,
-                                              10);
-                        }
+        // a block count of 0 means the card did not report its size
+        let block_count = self.block_count.get();
+        if count == 0 || (block_count != 0 && sector as u64 + count as u64 > block_count) {
+            return Err(ReturnCode::EINVAL);
+        }
 
-                        // command started successfully
-                        ReturnCode::SUCCESS
-                    })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
+        if self.card_type.get() == SDCardType::SDv2BlockAddressable {
+            Ok(sector)
         } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
+            // convert block address to byte address for non-block access
+            //  cards
+            sector.checked_mul(512).ok_or(ReturnCode::EINVAL)
         }
     }
 
-    pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer.take().map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                        // save the user buffer for later
-                        self.client_buffer.replace(buffer);
-                        self.client_offset.set(0);
-
-                        // convert block address to byte address for non-block
-                        //  access cards
-                        let mut address = sector;
-                        if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                            address *= 512;
-                        }
+    /// Check that a transfer of `count` blocks from `sector` using `buffer`
+    /// can start now, and return the address to use in its command
+    fn start_transfer(&self, buffer: &[u8], sector: u32, count: u32) -> Result<u32, ReturnCode> {
+        if self.state.get() != SpiState::Idle {
+            // another transfer is running
+            return Err(ReturnCode::EBUSY);
+        }
+        if (buffer.len() as u64) < count as u64 * 512 {
+            return Err(ReturnCode::EINVAL);
+        }
+        if self.txbuffer.is_none() || self.rxbuffer.is_none() {
+            return Err(ReturnCode::ENOMEM);
+        }
+        self.transfer_address(sector, count)
+    }
 
-                        self.state.set(SpiState::StartWriteBlocks { count: count });
-                        if count == 1 {
-                            self.send_command(SDCmd::CMD24_WriteSingle,
-                                              address,
-                                              txbuffer,
-                                              rxbuffer,
-                                              10);
-
-                            // command started successfully
-                            ReturnCode::SUCCESS
-                        } else {
-                            // can't write multiple blocks yet
-                            ReturnCode::ENOSUPPORT
-                        }
-                    })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
+    /// Read `count` blocks starting at `sector` into `buffer`
+    /// More than one block is read with a single multiple block command.
+    /// `buffer` must hold at least `count` blocks. If the read cannot start,
+    /// the buffer is returned along with the error
+    pub fn read_blocks(&self,
+                       buffer: &'static mut [u8],
+                       sector: u32,
+                       count: u32)
+                       -> (ReturnCode, Option<&'static mut [u8]>) {
+        let address = match self.start_transfer(buffer, sector, count) {
+            Ok(address) => address,
+            Err(err) => return (err, Some(buffer)),
+        };
+
+        self.txbuffer.take().map_or((ReturnCode::ENOMEM, None), |txbuffer| {
+            self.rxbuffer.take().map_or((ReturnCode::ENOMEM, None), move |rxbuffer| {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+                self.transfer_count.set(count);
+                self.transfer_start.set(self.alarm.now());
+
+                self.state.set(SpiState::StartReadBlocks { count: count });
+                if count == 1 {
+                    self.send_command(SDCmd::CMD17_ReadSingle, address, txbuffer, rxbuffer, 10);
+                } else {
+                    self.send_command(SDCmd::CMD18_ReadMultiple, address, txbuffer, rxbuffer, 10);
+                }
+
+                // command started successfully
+                (ReturnCode::SUCCESS, None)
+            })
+        })
+    }
+
+    /// Write `count` blocks from `buffer` starting at `sector`
+    /// More than one block is written with a single multiple block command.
+    /// SD cards are told the number of blocks first, so they can erase them
+    /// ahead of the data. `buffer` must hold at least `count` blocks. If the
+    /// write cannot start, the buffer is returned along with the error
+    pub fn write_blocks(&self,
+                        buffer: &'static mut [u8],
+                        sector: u32,
+                        count: u32)
+                        -> (ReturnCode, Option<&'static mut [u8]>) {
+        let address = match self.start_transfer(buffer, sector, count) {
+            Ok(address) => address,
+            Err(err) => return (err, Some(buffer)),
+        };
+
+        self.txbuffer.take().map_or((ReturnCode::ENOMEM, None), |txbuffer| {
+            self.rxbuffer.take().map_or((ReturnCode::ENOMEM, None), move |rxbuffer| {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+                self.transfer_count.set(count);
+                self.transfer_start.set(self.alarm.now());
+                self.write_failed.set(false);
+
+                if count == 1 {
+                    self.state.set(SpiState::StartWriteBlocks { count: count });
+                    self.send_command(SDCmd::CMD24_WriteSingle, address, txbuffer, rxbuffer, 10);
+                } else if self.card_type.get() == SDCardType::MMC {
+                    // MMC cards have no pre-erase command
+                    self.state.set(SpiState::StartWriteBlocks { count: count });
+                    self.send_command(SDCmd::CMD25_WriteMultiple,
+                                      address,
+                                      txbuffer,
+                                      rxbuffer,
+                                      10);
+                } else {
+                    // set the number of blocks to pre-erase, then write
+                    self.state.set(SpiState::SendManufSpecificCmd {
+                        cmd: SDCmd::ACMD23_SetWriteEraseCount,
+                        arg: count & 0x7FFFFF,
+                    });
+                    self.after_state.set(SpiState::PreEraseBlocks {
+                        address: address,
+                        count: count,
+                    });
+                    self.send_command(SDCmd::CMD55_ManufSpecificCommand,
+                                      0x0,
+                                      txbuffer,
+                                      rxbuffer,
+                                      10);
+                }
+
+                // command started successfully
+                (ReturnCode::SUCCESS, None)
+            })
+        })
+    }
+
+    /// Take back the buffer of a read or write that failed after it started.
+    /// The buffer is not passed to `SDCardClient::error`, it stays here until
+    /// taken.
+    pub fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        self.client_buffer.take()
+    }
+
+    /// Number of 512 byte blocks on the card, 0 if it is not initialized or
+    /// did not report its size
+    pub fn block_count(&self) -> u64 {
+        if self.is_initialized() {
+            self.block_count.get()
         } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
+            0
         }
     }
+
+    /// Transfer statistics since boot or the last `reset_statistics`
+    pub fn statistics(&self) -> Statistics {
+        self.statistics.get()
+    }
+
+    pub fn reset_statistics(&self) {
+        self.statistics.set(Statistics::default());
+    }
 }
 
 /// Handle callbacks from the SPI peripheral
@@ -1352,7 +1569,8 @@ impl Default for App {
 }
 
 /// Buffer for SD card driver, assigned in board `main.rs` files
-pub static mut KERNEL_BUFFER: [u8; 512] = [0; 512];
+/// Its length limits how many blocks one read or write command can transfer
+pub static mut KERNEL_BUFFER: [u8; 2048] = [0; 2048];
 
 /// Functions for SDCardDriver
 impl<'a, A: hil::time::Alarm + 'a> SDCardDriver<'a, A> {
@@ -1360,9 +1578,10 @@ impl<'a, A: hil::time::Alarm + 'a> SDCardDriver<'a, A> {
     ///
     /// sdcard - SDCard interface to provide application access to
     /// kernel_buf - buffer used to hold SD card blocks, must be at least 512
-    ///     bytes in length
+    ///     bytes in length. Each additional 512 bytes allow one more block
+    ///     per read or write command
     pub fn new(sdcard: &'a SDCard<'a, A>,
-               kernel_buf: &'static mut [u8; 512])
+               kernel_buf: &'static mut [u8])
                -> SDCardDriver<'a, A> {
 
         // return new SDCardDriver
@@ -1396,7 +1615,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCardClient for SDCardDriver<'a, A> {
 
             let mut read_len: usize = 0;
             self.kernel_buf.map(|data| {
-                app.read_buffer.as_mut().map(move |read_buffer| {
+                app.read_buffer.as_mut().map(|read_buffer| {
 
                     // copy bytes to user buffer
                     // Limit to minimum length between read_buffer, data, and
@@ -1424,6 +1643,8 @@ impl<'a, A: hil::time::Alarm + 'a> SDCardClient for SDCardDriver<'a, A> {
     }
 
     fn error(&self, error: u32) {
+        self.sdcard.take_buffer().map(|buffer| self.kernel_buf.replace(buffer));
+
         self.app.map(|app| { app.callback.map(|mut cb| { cb.schedule(4, error as usize, 0); }); });
     }
 }
@@ -1460,7 +1681,10 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
         }
     }
 
-    fn command(&self, command_num: usize, data: usize, _: usize, _: AppId) -> ReturnCode {
+    fn command(&self, command_num: usize, data: usize, count: usize, _: AppId) -> ReturnCode {
+        // a count of 0 transfers a single block
+        let count = cmp::max(count, 1);
+
         match command_num {
             // check if present
             0 => ReturnCode::SUCCESS,
@@ -1474,33 +1698,67 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
             // initialize
             2 => self.sdcard.initialize(),
 
-            // read_block
+            // read_blocks
             3 => {
                 self.kernel_buf.take().map_or(ReturnCode::EBUSY, |kernel_buf| {
-                    self.sdcard.read_blocks(kernel_buf, data as u32, 1)
+                    if count > kernel_buf.len() / 512 {
+                        self.kernel_buf.replace(kernel_buf);
+                        return ReturnCode::ESIZE;
+                    }
+
+                    let (rc, buffer) = self.sdcard.read_blocks(kernel_buf, data as u32, count as u32);
+                    buffer.map(|buffer| self.kernel_buf.replace(buffer));
+                    rc
                 })
             }
 
-            // write_block
+            // write_blocks
             4 => {
                 self.app.map_or(ReturnCode::ENOMEM, |app| {
                     app.write_buffer.as_mut().map_or(ReturnCode::ENOMEM, |write_buffer| {
                         self.kernel_buf.take().map_or(ReturnCode::EBUSY, |kernel_buf| {
+                            if count > kernel_buf.len() / 512 ||
+                               count > write_buffer.len() / 512 {
+                                self.kernel_buf.replace(kernel_buf);
+                                return ReturnCode::ESIZE;
+                            }
+
                             // copy over write data from application
-                            // Limit to minimum length between kernel_buf,
-                            // write_buffer, and 512 (block size)
                             for (kernel_byte, &write_byte) in
-                                kernel_buf.iter_mut().zip(write_buffer.iter()).take(512) {
+                                kernel_buf.iter_mut().zip(write_buffer.iter()).take(count * 512) {
                                 *kernel_byte = write_byte;
                             }
 
                             // begin writing
-                            self.sdcard.write_blocks(kernel_buf, data as u32, 1)
+                            let (rc, buffer) =
+                                self.sdcard.write_blocks(kernel_buf, data as u32, count as u32);
+                            buffer.map(|buffer| self.kernel_buf.replace(buffer));
+                            rc
                         })
                     })
                 })
             }
 
+            // statistics
+            5 => {
+                let statistics = self.sdcard.statistics();
+                let frequency = <A::Frequency>::frequency() as u64;
+                let value = match data {
+                    0 => statistics.blocks_read as usize,
+                    1 => statistics.blocks_written as usize,
+                    2 => (statistics.read_ticks * 1000 / frequency) as usize,
+                    3 => (statistics.write_ticks * 1000 / frequency) as usize,
+                    _ => return ReturnCode::EINVAL,
+                };
+                ReturnCode::SuccessWithValue { value: value }
+            }
+
+            // reset_statistics
+            6 => {
+                self.sdcard.reset_statistics();
+                ReturnCode::SUCCESS
+            }
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
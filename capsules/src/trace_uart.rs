@@ -0,0 +1,130 @@
+//! Streams kernel trace events over a UART.
+//!
+//! Periodically drains the kernel trace ring (see `kernel::trace`) and sends
+//! the events in frames that `tools/trace_decode.py` decodes. Nothing is sent
+//! unless the kernel was built with its `trace` feature.
+//!
+//! Frame format, all multi-byte fields little endian:
+//!
+//! ```text
+//! 0      1      2       3         4         8      12              12+16*count
+//! +------+------+-------+---------+---------+------+---------------+----------+
+//! | 0x7E | 'T'  | count | version | clock hz| lost | count events  | checksum |
+//! +------+------+-------+---------+---------+------+---------------+----------+
+//! ```
+//!
+//! `lost` is the number of events the kernel dropped since boot because the
+//! ring was full, and `checksum` is the XOR of all bytes after the magic.
+//!
+//! The UART traffic of the capsule itself raises interrupts that show up in
+//! the trace, so it is best to give the trace a UART of its own, or at least
+//! keep other output on a shared one quiet.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let trace_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+//! trace_uart.setup();
+//! let trace_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let trace = static_init!(
+//!     capsules::trace_uart::TraceUart<'static, UartDevice,
+//!                                     VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::trace_uart::TraceUart::new(trace_uart, trace_alarm,
+//!                                          &mut capsules::trace_uart::BUF));
+//! hil::uart::UART::set_client(trace_uart, trace);
+//! trace_alarm.set_client(trace);
+//! cortexm4::dwt::enable_cycle_counter();
+//! kernel::trace::set_clock(cortexm4::dwt::cycle_count, 48000000);
+//! trace.start();
+//! ```
+
+use kernel::common::bytes::put_u32;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::time::{self, Frequency};
+use kernel::hil::uart::{self, UART};
+use kernel::trace;
+
+/// Version of the frame format.
+const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 12;
+
+/// Events in a full frame.
+pub const EVENTS_PER_FRAME: usize = 15;
+
+/// Milliseconds between drains of the trace ring.
+const INTERVAL_MS: u32 = 100;
+
+pub static mut BUF: [u8; HEADER_LEN + EVENTS_PER_FRAME * trace::EVENT_LEN + 1] =
+    [0; HEADER_LEN + EVENTS_PER_FRAME * trace::EVENT_LEN + 1];
+
+pub struct TraceUart<'a, U: UART + 'a, A: time::Alarm + 'a> {
+    uart: &'a U,
+    alarm: &'a A,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, U: UART + 'a, A: time::Alarm + 'a> TraceUart<'a, U, A> {
+    pub fn new(uart: &'a U, alarm: &'a A, buffer: &'static mut [u8]) -> TraceUart<'a, U, A> {
+        TraceUart {
+            uart: uart,
+            alarm: alarm,
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// Start draining the trace ring.
+    pub fn start(&self) {
+        self.set_alarm();
+    }
+
+    fn set_alarm(&self) {
+        let interval = INTERVAL_MS * <A::Frequency>::frequency() / 1000;
+        let tics = self.alarm.now().wrapping_add(interval);
+        self.alarm.set_alarm(tics);
+    }
+
+    /// Send a frame with the events in the ring, if there are any and the
+    /// previous frame has been sent.
+    fn send_frame(&self) {
+        if trace::pending() == 0 {
+            return;
+        }
+        self.buffer.take().map(|buffer| {
+            let events = trace::drain(&mut buffer[HEADER_LEN..buffer.len() - 1]);
+            let end = HEADER_LEN + events;
+            buffer[0] = 0x7E;
+            buffer[1] = b'T';
+            buffer[2] = (events / trace::EVENT_LEN) as u8;
+            buffer[3] = VERSION;
+            put_u32(&mut buffer[4..8], trace::clock_hz());
+            put_u32(&mut buffer[8..12], trace::lost());
+            buffer[end] = buffer[2..end].iter().fold(0, |checksum, byte| checksum ^ byte);
+            self.uart.transmit(buffer, end + 1);
+        });
+    }
+}
+
+impl<'a, U: UART + 'a, A: time::Alarm + 'a> time::Client for TraceUart<'a, U, A> {
+    fn fired(&self) {
+        self.send_frame();
+        self.set_alarm();
+    }
+}
+
+impl<'a, U: UART + 'a, A: time::Alarm + 'a> uart::Client for TraceUart<'a, U, A> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.buffer.replace(buffer);
+        // Keep going while the ring fills faster than the alarm drains it,
+        // but leave partial frames for the alarm so the traffic of the
+        // capsule itself does not keep the UART busy forever.
+        if trace::pending() >= EVENTS_PER_FRAME {
+            self.send_frame();
+        }
+    }
+
+    fn receive_complete(&self, _buffer: &'static mut [u8], _rx_len: usize, _error: uart::Error) {}
+}
@@ -0,0 +1,167 @@
+//! Virtualize a UART to enable multiple users of the same serial port.
+//!
+//! Transmissions from all devices are queued and sent one after another.
+//! Only one device can receive at a time, a receive requested while another
+//! device is receiving starts once that reception completes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let uart_mux = static_init!(
+//!     MuxUart<'static>,
+//!     MuxUart::new(&sam4l::usart::USART3, 115200));
+//! hil::uart::UART::set_client(&sam4l::usart::USART3, uart_mux);
+//! uart_mux.initialize();
+//!
+//! let console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+//! console_uart.setup();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::hil::uart;
+
+/// The Mux struct manages multiple UART users. Each user may have at most one
+/// outstanding transmission and one outstanding reception.
+pub struct MuxUart<'a> {
+    uart: &'a uart::UART,
+    baud_rate: u32,
+    devices: List<'a, UartDevice<'a>>,
+    inflight_tx: Cell<Option<&'a UartDevice<'a>>>,
+    inflight_rx: Cell<Option<&'a UartDevice<'a>>>,
+}
+
+impl<'a> uart::Client for MuxUart<'a> {
+    fn transmit_complete(&self, tx_buffer: &'static mut [u8], error: uart::Error) {
+        self.inflight_tx.get().map(move |device| {
+            self.inflight_tx.set(None);
+            device.transmit_complete(tx_buffer, error);
+        });
+        self.do_next_op();
+    }
+
+    fn receive_complete(&self, rx_buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        self.inflight_rx.get().map(move |device| {
+            self.inflight_rx.set(None);
+            device.receive_complete(rx_buffer, rx_len, error);
+        });
+        self.do_next_op();
+    }
+}
+
+impl<'a> MuxUart<'a> {
+    pub const fn new(uart: &'a uart::UART, baud_rate: u32) -> MuxUart<'a> {
+        MuxUart {
+            uart: uart,
+            baud_rate: baud_rate,
+            devices: List::new(),
+            inflight_tx: Cell::new(None),
+            inflight_rx: Cell::new(None),
+        }
+    }
+
+    /// Configure the underlying UART. Devices on the mux share these
+    /// settings, `init` on a `UartDevice` has no effect.
+    pub fn initialize(&self) {
+        self.uart.init(uart::UARTParams {
+            baud_rate: self.baud_rate,
+            stop_bits: uart::StopBits::One,
+            parity: uart::Parity::None,
+            hw_flow_control: false,
+        });
+    }
+
+    fn do_next_op(&self) {
+        if self.inflight_tx.get().is_none() {
+            let mnode = self.devices.iter().find(|node| node.tx_len.get().is_some());
+            mnode.map(|node| {
+                node.tx_buffer.take().map(|buffer| {
+                    let len = node.tx_len.get().unwrap_or(0);
+                    node.tx_len.set(None);
+                    self.inflight_tx.set(Some(node));
+                    self.uart.transmit(buffer, len);
+                });
+            });
+        }
+        if self.inflight_rx.get().is_none() {
+            let mnode = self.devices.iter().find(|node| node.rx_len.get().is_some());
+            mnode.map(|node| {
+                node.rx_buffer.take().map(|buffer| {
+                    let len = node.rx_len.get().unwrap_or(0);
+                    node.rx_len.set(None);
+                    self.inflight_rx.set(Some(node));
+                    self.uart.receive(buffer, len);
+                });
+            });
+        }
+    }
+}
+
+pub struct UartDevice<'a> {
+    mux: &'a MuxUart<'a>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<Option<usize>>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<Option<usize>>,
+    next: ListLink<'a, UartDevice<'a>>,
+    client: Cell<Option<&'static uart::Client>>,
+}
+
+impl<'a> UartDevice<'a> {
+    pub const fn new(mux: &'a MuxUart<'a>) -> UartDevice<'a> {
+        UartDevice {
+            mux: mux,
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(None),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(None),
+            next: ListLink::empty(),
+            client: Cell::new(None),
+        }
+    }
+
+    /// Attach this device to its mux. Must be called once before the device
+    /// is used.
+    pub fn setup(&'a self) {
+        self.mux.devices.push_head(self);
+    }
+}
+
+impl<'a> uart::Client for UartDevice<'a> {
+    fn transmit_complete(&self, tx_buffer: &'static mut [u8], error: uart::Error) {
+        self.client.get().map(move |client| client.transmit_complete(tx_buffer, error));
+    }
+
+    fn receive_complete(&self, rx_buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        self.client.get().map(move |client| client.receive_complete(rx_buffer, rx_len, error));
+    }
+}
+
+impl<'a> ListNode<'a, UartDevice<'a>> for UartDevice<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, UartDevice<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> hil::uart::UART for UartDevice<'a> {
+    fn set_client(&self, client: &'static uart::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self, _params: uart::UARTParams) {}
+
+    fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
+        self.tx_buffer.replace(tx_data);
+        self.tx_len.set(Some(tx_len));
+        self.mux.do_next_op();
+    }
+
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        self.rx_buffer.replace(rx_buffer);
+        self.rx_len.set(Some(rx_len));
+        self.mux.do_next_op();
+    }
+}
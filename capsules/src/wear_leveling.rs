@@ -0,0 +1,567 @@
+//! Wear leveling and bad page management for flash.
+//!
+//! Flash pages wear out after a number of erases. A user that rewrites the
+//! same few pages, for example a data logger, wears those pages out long
+//! before the rest of the flash. `WearLeveling` sits between such a user and
+//! the flash, usually a `virtual_flash::FlashUser`, and gives the user
+//! logical pages that move around a range of physical pages:
+//!
+//!   * Every write or erase of a logical page goes to the spare physical page
+//!     with the fewest erases, and the page the logical page was on becomes
+//!     a spare. Writes and erases are spread over all pages of the range
+//!     that are not holding data.
+//!
+//!   * Written pages are read back. A page that does not read back what was
+//!     written, or that the flash reports an error for, is retired: it is
+//!     never used again, and the write goes to the next spare. Once all
+//!     spares are retired, writes fail with `FlashError`.
+//!
+//!   * Which physical page each logical page is on, how often every page was
+//!     erased and which pages are retired is kept in a table in flash. The
+//!     first pages of the range hold copies of the table, which are written
+//!     in turn. A new copy has a higher sequence number, and at boot the
+//!     newest valid copy wins. A table page that fails is retired like any
+//!     other page.
+//!
+//! The table is only written once a write or erase has completed, and the
+//! data is not written over the page it replaces. So a reset before the
+//! write completed leaves the logical page as it was, and after that it has
+//! the new data.
+//!
+//! Every write counts as an erase, as flash controllers usually erase a page
+//! before writing it. Each write or erase of a logical page costs one write of
+//! a data page and one of a table page, so the table pages wear as fast as
+//! the spares if there is one more spare page than there are table pages.
+//!
+//! The table format, all multi-byte fields little endian:
+//!
+//! ```text
+//! 0       4     8       10              12
+//! +-------+-----+-------+---------------+-----------------------+-----+
+//! | magic | seq | pages | logical pages | `pages` entries       | crc |
+//! +-------+-----+-------+---------------+-----------------------+-----+
+//!
+//! entry: | logical page: u16 | erases: u32 |, one per physical page
+//! ```
+//!
+//! where the logical page is `0xFFFF` for spare pages, `0xFFFE` for retired
+//! pages and `0xFFFD` for pages holding the table, and `crc` is the
+//! CRC-16/CCITT of everything before it. A table for a different number of
+//! pages is ignored, so changing the range, the number of table pages or of
+//! spares loses the data in it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let logger_flash = static_init!(
+//!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::virtual_flash::FlashUser::new(mux_flash));
+//! pub static mut WEAR_LEVELING_PAGE: sam4l::flashcalw::Sam4lPage =
+//!     sam4l::flashcalw::Sam4lPage::new();
+//! let wear_leveling = static_init!(
+//!     capsules::wear_leveling::WearLeveling<'static,
+//!         capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::wear_leveling::WearLeveling::new(
+//!         logger_flash,
+//!         (&LOG as *const _ as usize) / 512, // First page of the range.
+//!         LOG.len() / 512,                   // Number of pages.
+//!         8,                                 // Table pages.
+//!         9,                                 // Spare pages.
+//!         &mut WEAR_LEVELING_PAGE));
+//! hil::flash::HasClient::set_client(logger_flash, wear_leveling);
+//!
+//! // The user sees `wear_leveling.logical_pages()` pages, starting at 0.
+//! hil::flash::HasClient::set_client(wear_leveling, logger);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::ReturnCode;
+use kernel::common::bytes::{crc16, get_u16, get_u32, put_u16, put_u32};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+
+/// Most physical pages a range can have.
+pub const MAX_PAGES: usize = 64;
+
+/// "TWLV" in little endian.
+const TABLE_MAGIC: u32 = 0x564C5754;
+const TABLE_HEADER_LEN: usize = 12;
+const TABLE_ENTRY_LEN: usize = 6;
+const TABLE_CRC_LEN: usize = 2;
+
+/// Logical page numbers of pages that do not hold a logical page.
+const SPARE: u16 = 0xFFFF;
+const RETIRED: u16 = 0xFFFE;
+const TABLE: u16 = 0xFFFD;
+
+/// What is on a physical page.
+#[derive(Clone, Copy)]
+struct Entry {
+    logical: u16,
+    erases: u32,
+}
+
+const SPARE_ENTRY: Entry = Entry {
+    logical: SPARE,
+    erases: 0,
+};
+
+/// What the user asked for.
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Idle,
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+/// What the flash operation in progress is for.
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Reading a copy of the table.
+    Mount(usize),
+    /// Reading a logical page for the user.
+    Read,
+    /// Writing a spare page for the user.
+    Write(usize),
+    /// Reading a written page back.
+    VerifyWrite(usize),
+    /// Erasing a spare page for the user.
+    Erase(usize),
+    /// Writing a copy of the table.
+    WriteTable(usize),
+    /// Reading a copy of the table back.
+    VerifyTable(usize),
+}
+
+pub struct WearLeveling<'a, F: hil::flash::Flash + 'static> {
+    driver: &'a F,
+    client: Cell<Option<&'a hil::flash::Client<WearLeveling<'a, F>>>>,
+    // For the table, and for reading written pages back.
+    buffer: TakeCell<'static, F::Page>,
+    // The page of the user, while it is written.
+    client_buffer: TakeCell<'static, F::Page>,
+    first_page: usize,
+    num_pages: usize,
+    // Pages at the start of the range that hold copies of the table.
+    table_pages: usize,
+    logical_pages: usize,
+    table: Cell<[Entry; MAX_PAGES]>,
+    // Sequence number and page of the newest copy of the table.
+    seq: Cell<u32>,
+    table_page: Cell<usize>,
+    // Whether the table was read from the flash.
+    mounted: Cell<bool>,
+    op: Cell<Op>,
+    state: Cell<State>,
+}
+
+impl<'a, F: hil::flash::Flash + 'a> WearLeveling<'a, F> {
+    /// Manage the `num_pages` pages of `driver` from `first_page` on. The
+    /// first `table_pages` of them hold the table and `spare_pages` are kept
+    /// spare, the rest is what the user sees. `buffer` must be a page buffer
+    /// of its own.
+    ///
+    /// Panics if there are fewer than two table pages, since the table is
+    /// rotated through them, or if no page is left for the user.
+    pub fn new(driver: &'a F,
+               first_page: usize,
+               num_pages: usize,
+               table_pages: usize,
+               spare_pages: usize,
+               buffer: &'static mut F::Page)
+               -> WearLeveling<'a, F> {
+        // The table has to fit in a page.
+        let page_len = buffer.as_mut().len();
+        let max_pages = page_len.saturating_sub(TABLE_HEADER_LEN + TABLE_CRC_LEN) / TABLE_ENTRY_LEN;
+        let num_pages = cmp::min(num_pages, cmp::min(MAX_PAGES, max_pages));
+        if table_pages < 2 || num_pages <= table_pages + spare_pages {
+            panic!("WearLeveling needs two table pages and at least one logical page");
+        }
+        WearLeveling {
+            driver: driver,
+            client: Cell::new(None),
+            buffer: TakeCell::new(buffer),
+            client_buffer: TakeCell::empty(),
+            first_page: first_page,
+            num_pages: num_pages,
+            table_pages: table_pages,
+            logical_pages: num_pages - table_pages - spare_pages,
+            table: Cell::new([SPARE_ENTRY; MAX_PAGES]),
+            seq: Cell::new(0),
+            table_page: Cell::new(0),
+            mounted: Cell::new(false),
+            op: Cell::new(Op::Idle),
+            state: Cell::new(State::Idle),
+        }
+    }
+
+    /// The number of pages the user sees.
+    pub fn logical_pages(&self) -> usize {
+        self.logical_pages
+    }
+
+    /// The number of pages retired because they failed, as far as known.
+    /// Before the first operation the table has not been read yet.
+    pub fn retired_pages(&self) -> usize {
+        self.table.get()[0..self.num_pages].iter().filter(|entry| entry.logical == RETIRED).count()
+    }
+
+    /// The table of a range that was never written: logical pages on the
+    /// pages after the table, in order.
+    fn new_table(&self) -> [Entry; MAX_PAGES] {
+        let mut table = [SPARE_ENTRY; MAX_PAGES];
+        for (i, entry) in table[0..self.num_pages].iter_mut().enumerate() {
+            if i < self.table_pages {
+                entry.logical = TABLE;
+            } else if i - self.table_pages < self.logical_pages {
+                entry.logical = (i - self.table_pages) as u16;
+            }
+        }
+        table
+    }
+
+    fn physical_page(&self, logical: usize) -> Option<usize> {
+        self.table.get()[0..self.num_pages].iter().position(|entry| entry.logical as usize == logical)
+    }
+
+    /// The spare page with the fewest erases.
+    fn spare_page(&self) -> Option<usize> {
+        let table = self.table.get();
+        (self.table_pages..self.num_pages)
+            .filter(|page| table[*page].logical == SPARE)
+            .min_by_key(|page| table[*page].erases)
+    }
+
+    /// The page for the next copy of the table.
+    fn next_table_page(&self) -> Option<usize> {
+        let table = self.table.get();
+        let start = self.table_page.get() + 1;
+        (0..self.table_pages)
+            .map(|i| (start + i) % self.table_pages)
+            .find(|page| table[*page].logical == TABLE)
+    }
+
+    fn update(&self, page: usize, logical: u16) {
+        let mut table = self.table.get();
+        table[page].logical = logical;
+        table[page].erases = table[page].erases.saturating_add(1);
+        self.table.set(table);
+    }
+
+    fn retire(&self, page: usize) {
+        self.update(page, RETIRED);
+    }
+
+    /// Put the logical page of the operation on `page`, and make the page it
+    /// was on a spare.
+    fn remap(&self, page: usize) {
+        let logical = match self.op.get() {
+            Op::Write(logical) | Op::Erase(logical) => logical as u16,
+            _ => return,
+        };
+        let mut table = self.table.get();
+        for entry in table[0..self.num_pages].iter_mut() {
+            if entry.logical == logical {
+                entry.logical = SPARE;
+            }
+        }
+        self.table.set(table);
+        self.update(page, logical);
+    }
+
+    fn start(&self, op: Op, buf: Option<&'static mut F::Page>) -> ReturnCode {
+        if self.op.get() != Op::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let logical = match op {
+            Op::Read(logical) | Op::Write(logical) | Op::Erase(logical) => logical,
+            Op::Idle => return ReturnCode::FAIL,
+        };
+        if logical >= self.logical_pages {
+            return ReturnCode::EINVAL;
+        }
+
+        buf.map(|buf| self.client_buffer.replace(buf));
+        self.op.set(op);
+        if self.mounted.get() {
+            self.run();
+        } else {
+            self.mount();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn run(&self) {
+        match self.op.get() {
+            Op::Read(logical) => {
+                let physical = match self.physical_page(logical) {
+                    Some(physical) => physical,
+                    None => return self.done(hil::flash::Error::FlashError),
+                };
+                self.client_buffer.take().map(|buf| {
+                    self.state.set(State::Read);
+                    self.driver.read_page(self.first_page + physical, buf);
+                });
+            }
+            Op::Write(_) | Op::Erase(_) => self.program_spare(),
+            Op::Idle => {}
+        }
+    }
+
+    /// Tell the user the operation completed.
+    fn done(&self, error: hil::flash::Error) {
+        let op = self.op.get();
+        self.op.set(Op::Idle);
+        self.state.set(State::Idle);
+        self.client.get().map(|client| match op {
+            Op::Read(_) => {
+                self.client_buffer.take().map(|buf| client.read_complete(buf, error));
+            }
+            Op::Write(_) => {
+                self.client_buffer.take().map(|buf| client.write_complete(buf, error));
+            }
+            Op::Erase(_) => client.erase_complete(error),
+            Op::Idle => {}
+        });
+    }
+
+    fn mount(&self) {
+        // Until a copy is found, the range is new.
+        self.table.set(self.new_table());
+        self.seq.set(0);
+        self.table_page.set(self.table_pages.saturating_sub(1));
+        self.mount_next(0);
+    }
+
+    fn mount_next(&self, page: usize) {
+        if page >= self.table_pages {
+            self.mounted.set(true);
+            return self.run();
+        }
+        self.buffer.take().map(|buffer| {
+            self.state.set(State::Mount(page));
+            self.driver.read_page(self.first_page + page, buffer);
+        });
+    }
+
+    /// Write the data of the user to, or erase, the spare page with the
+    /// fewest erases.
+    fn program_spare(&self) {
+        let page = match self.spare_page() {
+            Some(page) => page,
+            // All spares are retired.
+            None => return self.done(hil::flash::Error::FlashError),
+        };
+        match self.op.get() {
+            Op::Write(_) => {
+                self.client_buffer.take().map(|buf| {
+                    self.state.set(State::Write(page));
+                    self.driver.write_page(self.first_page + page, buf);
+                });
+            }
+            Op::Erase(_) => {
+                self.state.set(State::Erase(page));
+                self.driver.erase_page(self.first_page + page);
+            }
+            _ => {}
+        }
+    }
+
+    /// Write the table to the next table page.
+    fn commit(&self) {
+        let page = match self.next_table_page() {
+            Some(page) => page,
+            None => {
+                // Nothing was recorded, read the table again next time.
+                self.mounted.set(false);
+                return self.done(hil::flash::Error::FlashError);
+            }
+        };
+        self.update(page, TABLE);
+        self.seq.set(self.seq.get().wrapping_add(1));
+        self.buffer.take().map(|buffer| {
+            write_table(buffer.as_mut(),
+                        self.seq.get(),
+                        &self.table.get()[0..self.num_pages],
+                        self.logical_pages);
+            self.state.set(State::WriteTable(page));
+            self.driver.write_page(self.first_page + page, buffer);
+        });
+    }
+}
+
+/// Fill `page` with a copy of the table.
+fn write_table(page: &mut [u8], seq: u32, table: &[Entry], logical_pages: usize) {
+    for byte in page.iter_mut() {
+        *byte = 0xFF;
+    }
+    put_u32(&mut page[0..4], TABLE_MAGIC);
+    put_u32(&mut page[4..8], seq);
+    put_u16(&mut page[8..10], table.len() as u16);
+    put_u16(&mut page[10..12], logical_pages as u16);
+    let end = TABLE_HEADER_LEN + table.len() * TABLE_ENTRY_LEN;
+    for (field, entry) in page[TABLE_HEADER_LEN..end].chunks_mut(TABLE_ENTRY_LEN).zip(table.iter()) {
+        put_u16(&mut field[0..2], entry.logical);
+        put_u32(&mut field[2..6], entry.erases);
+    }
+    let crc = crc16(&page[0..end]);
+    put_u16(&mut page[end..end + TABLE_CRC_LEN], crc);
+}
+
+/// The sequence number and the table in `page`, if it holds a valid copy
+/// of a table for `num_pages` pages with `logical_pages` logical pages.
+fn read_table(page: &[u8],
+              num_pages: usize,
+              logical_pages: usize)
+              -> Option<(u32, [Entry; MAX_PAGES])> {
+    let end = TABLE_HEADER_LEN + num_pages * TABLE_ENTRY_LEN;
+    if page.len() < end + TABLE_CRC_LEN || get_u32(&page[0..4]) != TABLE_MAGIC ||
+       get_u16(&page[8..10]) as usize != num_pages ||
+       get_u16(&page[10..12]) as usize != logical_pages ||
+       get_u16(&page[end..end + TABLE_CRC_LEN]) != crc16(&page[0..end]) {
+        return None;
+    }
+
+    let mut table = [SPARE_ENTRY; MAX_PAGES];
+    for (entry, field) in table.iter_mut().zip(page[TABLE_HEADER_LEN..end].chunks(TABLE_ENTRY_LEN)) {
+        entry.logical = get_u16(&field[0..2]);
+        entry.erases = get_u32(&field[2..6]);
+    }
+    // Every logical page must be somewhere, once.
+    for logical in 0..logical_pages {
+        if table[0..num_pages].iter().filter(|entry| entry.logical as usize == logical).count() != 1 {
+            return None;
+        }
+    }
+    Some((get_u32(&page[4..8]), table))
+}
+
+impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for WearLeveling<'a, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        if state == State::Read {
+            self.client_buffer.replace(buffer);
+            return self.done(error);
+        }
+        self.buffer.replace(buffer);
+        let ok = error == hil::flash::Error::CommandComplete;
+
+        match state {
+            State::Mount(page) => {
+                // The newest valid copy wins.
+                let found = self.buffer
+                    .map(|buffer| read_table(buffer.as_mut(), self.num_pages, self.logical_pages))
+                    .unwrap_or(None);
+                match found {
+                    Some((seq, table)) if ok && seq > self.seq.get() => {
+                        self.seq.set(seq);
+                        self.table.set(table);
+                        self.table_page.set(page);
+                    }
+                    _ => {}
+                }
+                self.mount_next(page + 1);
+            }
+            State::VerifyWrite(page) => {
+                let matches = self.buffer.map_or(false, |read| {
+                    self.client_buffer.map_or(false, |written| read.as_mut() == written.as_mut())
+                });
+                if ok && matches {
+                    self.remap(page);
+                    self.commit();
+                } else {
+                    self.retire(page);
+                    self.program_spare();
+                }
+            }
+            State::VerifyTable(page) => {
+                let seq = self.buffer
+                    .map(|buffer| read_table(buffer.as_mut(), self.num_pages, self.logical_pages))
+                    .unwrap_or(None)
+                    .map(|(seq, _)| seq);
+                if ok && seq == Some(self.seq.get()) {
+                    self.table_page.set(page);
+                    self.done(hil::flash::Error::CommandComplete);
+                } else {
+                    self.retire(page);
+                    self.commit();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        let ok = error == hil::flash::Error::CommandComplete;
+
+        match state {
+            State::Write(page) => {
+                self.client_buffer.replace(buffer);
+                if !ok {
+                    self.retire(page);
+                    return self.program_spare();
+                }
+                self.buffer.take().map(|buffer| {
+                    self.state.set(State::VerifyWrite(page));
+                    self.driver.read_page(self.first_page + page, buffer);
+                });
+            }
+            State::WriteTable(page) => {
+                if !ok {
+                    self.buffer.replace(buffer);
+                    self.retire(page);
+                    return self.commit();
+                }
+                self.state.set(State::VerifyTable(page));
+                self.driver.read_page(self.first_page + page, buffer);
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        if let State::Erase(page) = state {
+            if error == hil::flash::Error::CommandComplete {
+                self.remap(page);
+                self.commit();
+            } else {
+                self.retire(page);
+                self.program_spare();
+            }
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'a, C: hil::flash::Client<Self>> hil::flash::HasClient<'a, C>
+    for WearLeveling<'a, F> {
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(Some(client));
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'a> hil::flash::Flash for WearLeveling<'a, F> {
+    type Page = F::Page;
+
+    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.start(Op::Read(page_number), Some(buf))
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.start(Op::Write(page_number), Some(buf))
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.start(Op::Erase(page_number), None)
+    }
+}
@@ -34,6 +34,7 @@ impl kernel::Chip for NRF51 {
     fn service_pending_interrupts(&mut self) {
         unsafe {
             INTERRUPT_QUEUE.as_mut().unwrap().dequeue().map(|interrupt| {
+                kernel::trace::interrupt(interrupt as usize);
                 match interrupt {
                     NvicIdx::ECB => nrf5x::aes::AESECB.handle_interrupt(),
                     NvicIdx::GPIOTE => nrf5x::gpio::PORT.handle_interrupt(),
@@ -53,6 +54,8 @@ impl kernel::Chip for NRF51 {
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { INTERRUPT_QUEUE.as_mut().unwrap().has_elements() }
+        unsafe {
+            INTERRUPT_QUEUE.as_mut().unwrap().has_elements() || kernel::deferred_call::has_tasks()
+        }
     }
 }
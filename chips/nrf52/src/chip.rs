@@ -38,6 +38,7 @@ impl kernel::Chip for NRF52 {
                 .unwrap()
                 .dequeue()
                 .map(|interrupt| {
+                    kernel::trace::interrupt(interrupt as usize);
                     match interrupt {
                         NvicIdx::ECB => nrf5x::aes::AESECB.handle_interrupt(),
                         NvicIdx::GPIOTE => nrf5x::gpio::PORT.handle_interrupt(),
@@ -60,6 +61,8 @@ impl kernel::Chip for NRF52 {
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { INTERRUPT_QUEUE.as_mut().unwrap().has_elements() }
+        unsafe {
+            INTERRUPT_QUEUE.as_mut().unwrap().has_elements() || kernel::deferred_call::has_tasks()
+        }
     }
 }
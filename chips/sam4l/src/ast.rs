@@ -210,6 +210,19 @@ impl<'a> Ast<'a> {
         }
     }
 
+    pub fn alarm_irq_enabled(&self) -> bool {
+        unsafe { (*self.regs).imr.get() & (1 << 8) != 0 }
+    }
+
+    /// Tics until the alarm fires, if it is set.
+    pub fn tics_until_alarm(&self) -> Option<u32> {
+        if self.alarm_irq_enabled() {
+            Some(self.get_alarm().wrapping_sub(self.now()))
+        } else {
+            None
+        }
+    }
+
     pub fn enable_alarm_wake(&self) {
         while self.busy() {}
         unsafe {
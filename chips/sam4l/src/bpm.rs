@@ -50,6 +50,23 @@ pub enum PowerScaling {
     PS2,
 }
 
+/// What deep sleep, the Cortex-M SLEEPDEEP bit, means for the chip.
+///
+/// Backup mode is left out: it powers down RAM, so waking from it is a reset.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DeepSleepMode {
+    /// Wait mode: all clocks except the 32KHz ones are stopped.
+    Wait,
+    /// Retention mode: as Wait, with the core logic kept in retention at a
+    /// lower voltage.
+    Retention,
+}
+
+/// PMCON.BKUP
+const PMCON_BKUP: u32 = 1 << 8;
+/// PMCON.RET
+const PMCON_RET: u32 = 1 << 9;
+
 pub enum CK32Source {
     OSC32K = 0,
     RC32K = 1,
@@ -62,6 +79,19 @@ pub unsafe fn set_ck32source(source: CK32Source) {
     (*BPM).control.set(control | (source as u32) << 16);
 }
 
+/// Select the mode the chip enters on deep sleep.
+pub unsafe fn set_deep_sleep_mode(mode: DeepSleepMode) {
+    let control = (*BPM).control.get() & !(PMCON_BKUP | PMCON_RET);
+    let control = match mode {
+        DeepSleepMode::Wait => control,
+        DeepSleepMode::Retention => control | PMCON_RET,
+    };
+    if control != (*BPM).control.get() {
+        unlock_register(0x1c); // Control
+        (*BPM).control.set(control);
+    }
+}
+
 unsafe fn unlock_register(register_offset: u32) {
     (*BPM).unlock.set(BPM_UNLOCK_KEY | register_offset);
 }
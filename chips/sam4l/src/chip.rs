@@ -3,6 +3,9 @@
 use adc;
 use aes;
 use ast;
+use bpm;
+use core::cell::Cell;
+use core::cmp;
 use cortexm4;
 use crccu;
 use dac;
@@ -12,6 +15,11 @@ use gpio;
 use i2c;
 use kernel::Chip;
 use kernel::common::{RingBuffer, Queue};
+use kernel::deferred_call;
+use kernel::hil::time::{Frequency, Time};
+use kernel::power::{self, NUM_POWER_STATES, PowerState};
+use kernel::support;
+use kernel::trace;
 use nvic;
 use pm;
 use spi;
@@ -22,6 +30,21 @@ use usbc;
 pub struct Sam4l {
     pub mpu: cortexm4::mpu::MPU,
     pub systick: &'static cortexm4::systick::SysTick,
+    /// AST counter when the chip last woke up.
+    last_wake: Cell<u32>,
+}
+
+/// How long, in microseconds, the chip has to stay in each power state for
+/// it to be worth entering. Both deep sleep modes restart the main clocks on
+/// wake up, which takes a while with the PLL, and retention also has to bring
+/// the core voltage back up. These are conservative, so that a short sleep
+/// never costs more than it saves.
+const BREAK_EVEN_US: [u32; NUM_POWER_STATES] = [0, 0, 500, 2000];
+
+/// Convert AST tics to microseconds.
+fn ast_tics_to_us(tics: u32) -> u64 {
+    let hz = <<ast::Ast<'static> as Time>::Frequency as Frequency>::frequency();
+    tics as u64 * 1000000 / hz as u64
 }
 
 const IQ_SIZE: usize = 100;
@@ -68,6 +91,7 @@ impl Sam4l {
         Sam4l {
             mpu: cortexm4::mpu::MPU::new(),
             systick: cortexm4::systick::SysTick::new(),
+            last_wake: Cell::new(0),
         }
     }
 }
@@ -82,6 +106,7 @@ impl Chip for Sam4l {
         unsafe {
             let iq = INTERRUPT_QUEUE.as_mut().unwrap();
             while let Some(interrupt) = iq.dequeue() {
+                trace::interrupt(interrupt as usize);
                 match interrupt {
                     ASTALARM => ast::AST.handle_interrupt(),
 
@@ -147,7 +172,9 @@ impl Chip for Sam4l {
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { INTERRUPT_QUEUE.as_mut().unwrap().has_elements() }
+        unsafe {
+            INTERRUPT_QUEUE.as_mut().unwrap().has_elements() || deferred_call::has_tasks()
+        }
     }
 
     fn mpu(&self) -> &cortexm4::mpu::MPU {
@@ -158,15 +185,50 @@ impl Chip for Sam4l {
         self.systick
     }
 
-    fn prepare_for_sleep(&self) {
-        if pm::deep_sleep_ready() {
-            unsafe {
-                cortexm4::scb::set_sleepdeep();
-            }
+    /// Sleep as deep as the active peripherals, the power constraints and the
+    /// next AST alarm allow. Time is measured with the AST, which keeps
+    /// running in every sleep mode.
+    fn sleep(&self) {
+        let limit = if pm::deep_sleep_ready() {
+            power::deepest_allowed()
         } else {
-            unsafe {
-                cortexm4::scb::unset_sleepdeep();
+            cmp::min(power::deepest_allowed(), PowerState::Sleep)
+        };
+        let until_alarm = unsafe { ast::AST.tics_until_alarm() }
+            .map(|tics| cmp::min(ast_tics_to_us(tics), u32::max_value() as u64) as u32);
+        let state = power::choose(limit, until_alarm, &BREAK_EVEN_US);
+        if state == PowerState::Active {
+            // Still in the same active period, it is recorded once the chip
+            // does go to sleep.
+            return;
+        }
+
+        let sleep_start = unsafe { ast::AST.get_counter() };
+        power::record(PowerState::Active,
+                      ast_tics_to_us(sleep_start.wrapping_sub(self.last_wake.get())));
+
+        unsafe {
+            match state {
+                PowerState::Active => {}
+                PowerState::Sleep => {
+                    cortexm4::scb::unset_sleepdeep();
+                    support::wfi();
+                }
+                PowerState::DeepSleep => {
+                    bpm::set_deep_sleep_mode(bpm::DeepSleepMode::Wait);
+                    cortexm4::scb::set_sleepdeep();
+                    support::wfi();
+                }
+                PowerState::Retention => {
+                    bpm::set_deep_sleep_mode(bpm::DeepSleepMode::Retention);
+                    cortexm4::scb::set_sleepdeep();
+                    support::wfi();
+                }
             }
         }
+
+        let wake = unsafe { ast::AST.get_counter() };
+        power::record(state, ast_tics_to_us(wake.wrapping_sub(sleep_start)));
+        self.last_wake.set(wake);
     }
 }
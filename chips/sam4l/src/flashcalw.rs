@@ -27,6 +27,7 @@ use core::ops::{Index, IndexMut};
 use kernel::ReturnCode;
 use kernel::common::VolatileCell;
 use kernel::common::take_cell::TakeCell;
+use kernel::deferred_call::{self, DeferredCall};
 use kernel::hil;
 use nvic;
 use pm;
@@ -185,6 +186,7 @@ pub struct FLASHCALW {
     current_state: Cell<FlashState>,
     current_command: Cell<Command>,
     buffer: TakeCell<'static, Sam4lPage>,
+    deferred_call: Cell<Option<DeferredCall>>,
 }
 
 // static instance for the board. Only one FLASHCALW on chip.
@@ -234,6 +236,7 @@ impl FLASHCALW {
             current_state: Cell::new(FlashState::Unconfigured),
             current_command: Cell::new(Command::None),
             buffer: TakeCell::empty(),
+            deferred_call: Cell::new(None),
         }
     }
 
@@ -832,9 +835,21 @@ impl FLASHCALW {
         // here. So if the bootloader changes, nothing breaks.
         self.enable_picocache(true);
 
+        self.deferred_call();
+
         self.current_state.set(FlashState::Ready);
     }
 
+    /// Reads complete synchronously, the client is called from a deferred
+    /// call so it is not called from within read_range(). Registered on first
+    /// use, in case the flash is used before configure() ran.
+    fn deferred_call(&self) -> Option<DeferredCall> {
+        if self.deferred_call.get().is_none() {
+            self.deferred_call.set(unsafe { deferred_call::register(&FLASH_CONTROLLER) });
+        }
+        self.deferred_call.get()
+    }
+
     pub fn get_page_size(&self) -> u32 {
         PAGE_SIZE
     }
@@ -866,6 +881,12 @@ impl FLASHCALW {
             return ReturnCode::EINVAL;
         }
 
+        // Without a deferred call the client would never hear back.
+        let deferred_call = match self.deferred_call() {
+            Some(call) => call,
+            None => return ReturnCode::ERESERVE,
+        };
+
         let mut byte: *const u8 = address as *const u8;
         unsafe {
             for i in 0..size {
@@ -878,12 +899,9 @@ impl FLASHCALW {
         // Hold on to the buffer for the callback.
         self.buffer.replace(buffer);
 
-        // This is kind of strange, but because read() in this case is
-        // synchronous, we still need to schedule as if we had an interrupt so
-        // we can call the callback.
-        unsafe {
-            flash_handler();
-        }
+        // The read is already done, but the client expects the callback
+        // after read_range() returns.
+        deferred_call.set();
 
         ReturnCode::SUCCESS
     }
@@ -946,6 +964,20 @@ impl hil::flash::Flash for FLASHCALW {
     }
 }
 
+impl deferred_call::Client for FLASHCALW {
+    fn handle_deferred_call(&self) {
+        if self.current_command.get() == Command::Read {
+            self.current_state.set(FlashState::Ready);
+
+            self.client.get().map(|client| {
+                self.buffer.take().map(|buffer| {
+                    client.read_complete(buffer, hil::flash::Error::CommandComplete);
+                });
+            });
+        }
+    }
+}
+
 /// Assumes the only Peripheral Interrupt enabled for the FLASHCALW is the
 /// FRDY (Flash Ready) interrupt.
 pub unsafe extern "C" fn flash_handler() {
@@ -37,6 +37,18 @@ impl AppId {
     pub fn get_editable_flash_range(&self) -> (usize, usize) {
         process::get_editable_flash_range(self.idx)
     }
+
+    pub fn get_package_name(&self) -> &'static str {
+        process::get_package_name(self.idx)
+    }
+
+    pub fn get_storage_key(&self) -> Option<[u8; 32]> {
+        process::get_storage_key(self.idx)
+    }
+
+    pub fn get_nonvolatile_storage_size(&self) -> Option<usize> {
+        process::get_nonvolatile_storage_size(self.idx)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -0,0 +1,37 @@
+//! Helpers for the little endian fields and checksums of data kept in memory
+//! or storage in a fixed layout, e.g. flash logs, filesystems and buffers
+//! shared with userspace.
+
+/// The little endian `u16` at the start of `buf`.
+pub fn get_u16(buf: &[u8]) -> u16 {
+    buf[0] as u16 | (buf[1] as u16) << 8
+}
+
+/// Write `value` little endian to the start of `buf`.
+pub fn put_u16(buf: &mut [u8], value: u16) {
+    buf[0] = value as u8;
+    buf[1] = (value >> 8) as u8;
+}
+
+/// The little endian `u32` at the start of `buf`.
+pub fn get_u32(buf: &[u8]) -> u32 {
+    buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+/// Write `value` little endian to the start of `buf`.
+pub fn put_u32(buf: &mut [u8], value: u32) {
+    for (i, byte) in buf[0..4].iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+}
+
+/// CRC-16/CCITT, polynomial 0x1021 starting from 0xFFFF.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 {
+            crc << 1 ^ 0x1021
+        } else {
+            crc << 1
+        })
+    })
+}
@@ -1,5 +1,6 @@
 //! Common operations in the Tock OS.
 
+pub mod bytes;
 pub mod ring_buffer;
 pub mod queue;
 pub mod utils;
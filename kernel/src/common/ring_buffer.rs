@@ -17,6 +17,20 @@ impl<'a, T: Copy> RingBuffer<'a, T> {
             ring: ring,
         }
     }
+
+    /// Find the oldest element for which `f` returns true and return it so
+    /// that it can be changed in place.
+    pub fn find_mut<F: Fn(&T) -> bool>(&mut self, f: F) -> Option<&mut T> {
+        let len = self.ring.len();
+        let mut i = self.head;
+        while i != self.tail {
+            if f(&self.ring[i]) {
+                return Some(&mut self.ring[i]);
+            }
+            i = (i + 1) % len;
+        }
+        None
+    }
 }
 
 impl<'a, T: Copy> queue::Queue<T> for RingBuffer<'a, T> {
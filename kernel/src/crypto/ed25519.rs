@@ -0,0 +1,352 @@
+//! Ed25519 signatures (RFC 8032), following the TweetNaCl implementation.
+//!
+//! Field elements are sixteen 16 bit limbs stored in `i64`s and points are
+//! kept in extended coordinates. The code is short rather than fast and is
+//! not constant time, which is fine for checking public signatures. Signing
+//! is only meant for `elf2tbf`, which builds this file too, on trusted
+//! machines.
+
+use super::sha512::Sha512;
+
+type Gf = [i64; 16];
+
+const GF0: Gf = [0; 16];
+const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// The curve constant d.
+const D: Gf = [0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779,
+               0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203];
+
+/// 2 * d.
+const D2: Gf = [0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3,
+                0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406];
+
+/// Coordinates of the base point.
+const X: Gf = [0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6,
+               0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169];
+const Y: Gf = [0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+               0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666];
+
+/// A square root of -1.
+const I: Gf = [0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb,
+               0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83];
+
+/// The order of the base point, little endian.
+const L: [i64; 32] = [0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2,
+                      0xde, 0xf9, 0xde, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10];
+
+fn car25519(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// Swap `p` and `q` if `b` is 1.
+fn sel25519(p: &mut Gf, q: &mut Gf, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack25519(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    car25519(&mut t);
+    car25519(&mut t);
+    car25519(&mut t);
+    for _ in 0..2 {
+        let mut m = GF0;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        sel25519(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0; 32];
+    for i in 0..16 {
+        o[2 * i] = t[i] as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn neq25519(a: &Gf, b: &Gf) -> bool {
+    pack25519(a) != pack25519(b)
+}
+
+fn par25519(a: &Gf) -> u8 {
+    pack25519(a)[0] & 1
+}
+
+fn unpack25519(n: &[u8]) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn add25519(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn sub25519(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul25519(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o = GF0;
+    o.copy_from_slice(&t[..16]);
+    car25519(&mut o);
+    car25519(&mut o);
+    o
+}
+
+fn square25519(a: &Gf) -> Gf {
+    mul25519(a, a)
+}
+
+fn inv25519(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..254).rev() {
+        c = square25519(&c);
+        if a != 2 && a != 4 {
+            c = mul25519(&c, i);
+        }
+    }
+    c
+}
+
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..251).rev() {
+        c = square25519(&c);
+        if a != 1 {
+            c = mul25519(&c, i);
+        }
+    }
+    c
+}
+
+/// A point in extended coordinates (X, Y, Z, T).
+type Point = [Gf; 4];
+
+fn add(p: &mut Point, q: &Point) {
+    let a = mul25519(&sub25519(&p[1], &p[0]), &sub25519(&q[1], &q[0]));
+    let b = mul25519(&add25519(&p[0], &p[1]), &add25519(&q[0], &q[1]));
+    let c = mul25519(&mul25519(&p[3], &q[3]), &D2);
+    let d = mul25519(&p[2], &q[2]);
+    let d = add25519(&d, &d);
+    let e = sub25519(&b, &a);
+    let f = sub25519(&d, &c);
+    let g = add25519(&d, &c);
+    let h = add25519(&b, &a);
+    p[0] = mul25519(&e, &f);
+    p[1] = mul25519(&h, &g);
+    p[2] = mul25519(&g, &f);
+    p[3] = mul25519(&e, &h);
+}
+
+fn cswap(p: &mut Point, q: &mut Point, b: i64) {
+    for i in 0..4 {
+        sel25519(&mut p[i], &mut q[i], b);
+    }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = inv25519(&p[2]);
+    let tx = mul25519(&p[0], &zi);
+    let ty = mul25519(&p[1], &zi);
+    let mut r = pack25519(&ty);
+    r[31] ^= par25519(&tx) << 7;
+    r
+}
+
+/// Multiply `q` by the little endian scalar `s`.
+fn scalarmult(q: &Point, s: &[u8]) -> Point {
+    let mut p = [GF0, GF1, GF1, GF0];
+    let mut q = *q;
+    for i in (0..256).rev() {
+        let b = ((s[i / 8] >> (i & 7)) & 1) as i64;
+        cswap(&mut p, &mut q, b);
+        add(&mut q, &p);
+        let p_copy = p;
+        add(&mut p, &p_copy);
+        cswap(&mut p, &mut q, b);
+    }
+    p
+}
+
+/// Multiply the base point by the little endian scalar `s`.
+fn scalarbase(s: &[u8]) -> Point {
+    scalarmult(&[X, Y, GF1, mul25519(&X, &Y)], s)
+}
+
+/// Reduce the 512 bit number in `x` modulo `L`.
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        for j in (i - 32)..(i - 12) {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+        }
+        x[i - 12] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+    let mut r = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+/// Reduce a SHA-512 digest modulo `L`.
+fn reduce(digest: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for i in 0..64 {
+        x[i] = digest[i] as i64;
+    }
+    mod_l(&mut x)
+}
+
+/// Decode a public key into the negated point, or `None` if it is not a
+/// valid point.
+fn unpackneg(p: &[u8; 32]) -> Option<Point> {
+    let mut r = [GF0, GF0, GF1, GF0];
+    r[1] = unpack25519(p);
+    let num = square25519(&r[1]);
+    let den = mul25519(&num, &D);
+    let num = sub25519(&num, &r[2]);
+    let den = add25519(&r[2], &den);
+
+    let den2 = square25519(&den);
+    let den4 = square25519(&den2);
+    let den6 = mul25519(&den4, &den2);
+    let t = mul25519(&mul25519(&den6, &num), &den);
+    let t = mul25519(&pow2523(&t), &num);
+    let t = mul25519(&mul25519(&t, &den), &den);
+    r[0] = mul25519(&t, &den);
+
+    if neq25519(&mul25519(&square25519(&r[0]), &den), &num) {
+        r[0] = mul25519(&r[0], &I);
+    }
+    if neq25519(&mul25519(&square25519(&r[0]), &den), &num) {
+        return None;
+    }
+    if par25519(&r[0]) == p[31] >> 7 {
+        r[0] = sub25519(&GF0, &r[0]);
+    }
+    r[3] = mul25519(&r[0], &r[1]);
+    Some(r)
+}
+
+/// Check that `signature` is a valid signature of `message` by the owner of
+/// `public_key`.
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let q = match unpackneg(public_key) {
+        Some(q) => q,
+        None => return false,
+    };
+
+    let mut hasher = Sha512::new();
+    hasher.update(&signature[..32]);
+    hasher.update(public_key);
+    hasher.update(message);
+    let h = reduce(&hasher.finish());
+
+    let mut p = scalarmult(&q, &h);
+    add(&mut p, &scalarbase(&signature[32..]));
+    pack(&p)[..] == signature[..32]
+}
+
+/// Expand a 32 byte secret key into the signing scalar and the nonce prefix.
+fn expand_secret_key(secret_key: &[u8; 32]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(secret_key);
+    let mut d = hasher.finish();
+    d[0] &= 248;
+    d[31] &= 127;
+    d[31] |= 64;
+    d
+}
+
+/// The public key that belongs to `secret_key`.
+pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+    pack(&scalarbase(&expand_secret_key(secret_key)[..32]))
+}
+
+/// Sign `message` with `secret_key`.
+pub fn sign(secret_key: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let d = expand_secret_key(secret_key);
+    let public_key = pack(&scalarbase(&d[..32]));
+
+    let mut hasher = Sha512::new();
+    hasher.update(&d[32..]);
+    hasher.update(message);
+    let r = reduce(&hasher.finish());
+    let big_r = pack(&scalarbase(&r));
+
+    let mut hasher = Sha512::new();
+    hasher.update(&big_r);
+    hasher.update(&public_key);
+    hasher.update(message);
+    let h = reduce(&hasher.finish());
+
+    let mut x = [0i64; 64];
+    for i in 0..32 {
+        x[i] = r[i] as i64;
+    }
+    for i in 0..32 {
+        for j in 0..32 {
+            x[i + j] += h[i] as i64 * d[j] as i64;
+        }
+    }
+    let s = mod_l(&mut x);
+
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&s);
+    signature
+}
@@ -0,0 +1,9 @@
+//! Software cryptography the kernel itself needs.
+//!
+//! These are used to check app images before they are loaded, when no
+//! hardware crypto engine has been set up yet. Capsules that need fast
+//! crypto should use the hardware through the HIL instead.
+
+pub mod ed25519;
+pub mod sha256;
+pub mod sha512;
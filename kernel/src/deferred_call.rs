@@ -0,0 +1,102 @@
+//! Deferred calls, for work that should run after the current call returns.
+//!
+//! Drivers and capsules normally only run in the bottom half of an interrupt
+//! or while the kernel handles a syscall. When a driver wants to finish an
+//! operation later, for example to call its client from outside the stack of
+//! the caller that started the operation, it registers a `DeferredCall` once
+//! at boot and `set`s it when it needs to run. The kernel main loop calls the
+//! client after servicing interrupts, and the chip does not sleep while a
+//! call is pending.
+//!
+//! Completing an operation through a deferred call rather than calling the
+//! client directly keeps a virtualizer from recursing into itself when the
+//! operation it starts from a callback completes synchronously.
+//!
+//! ```rust
+//! impl Driver {
+//!     pub fn configure(&'static self) {
+//!         self.deferred_call.set(unsafe { deferred_call::register(self) });
+//!     }
+//!
+//!     fn start(&self) {
+//!         // ... finished already, call the client later.
+//!         self.deferred_call.get().map(|call| call.set());
+//!     }
+//! }
+//!
+//! impl deferred_call::Client for Driver {
+//!     fn handle_deferred_call(&self) {
+//!         self.client.get().map(|client| client.done());
+//!     }
+//! }
+//! ```
+//!
+//! Deferred calls are set and serviced from the main loop only, never from
+//! interrupt handlers, so they need no locking.
+
+/// Deferred calls that can be registered.
+pub const MAX_DEFERRED_CALLS: usize = 32;
+
+pub trait Client {
+    fn handle_deferred_call(&self);
+}
+
+static mut CLIENTS: [Option<&'static Client>; MAX_DEFERRED_CALLS] = [None; MAX_DEFERRED_CALLS];
+static mut REGISTERED: usize = 0;
+/// One bit per registered call.
+static mut PENDING: usize = 0;
+
+/// A registered deferred call.
+#[derive(Clone, Copy)]
+pub struct DeferredCall {
+    slot: usize,
+}
+
+impl DeferredCall {
+    /// Ask the kernel to call the client the next time it services
+    /// interrupts. Setting a call that is already pending does nothing, the
+    /// client is called once.
+    pub fn set(&self) {
+        unsafe {
+            PENDING |= 1 << self.slot;
+        }
+    }
+
+    /// Whether the call is set and has not run yet.
+    pub fn is_pending(&self) -> bool {
+        unsafe { PENDING & (1 << self.slot) != 0 }
+    }
+}
+
+/// Register `client` for a deferred call. Returns `None` once all
+/// `MAX_DEFERRED_CALLS` are taken. Registration is meant to happen once per
+/// driver, at boot.
+pub unsafe fn register(client: &'static Client) -> Option<DeferredCall> {
+    if REGISTERED == MAX_DEFERRED_CALLS {
+        return None;
+    }
+    let slot = REGISTERED;
+    CLIENTS[slot] = Some(client);
+    REGISTERED += 1;
+    Some(DeferredCall { slot: slot })
+}
+
+/// Whether any deferred call is pending.
+pub fn has_tasks() -> bool {
+    unsafe { PENDING != 0 }
+}
+
+/// Run the pending deferred calls, in the order they were registered. Calls
+/// set while running are left for the next time, so a client that keeps
+/// setting its own call cannot starve interrupts.
+pub fn service() {
+    unsafe {
+        let pending = PENDING;
+        PENDING = 0;
+        for slot in 0..REGISTERED {
+            if pending & (1 << slot) != 0 {
+                CLIENTS[slot].map(|client| client.handle_deferred_call());
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Records of process faults and kernel panics that survive a soft reset.
+//!
+//! When a process faults or the kernel panics, the kernel writes a compact
+//! record of what happened into a part of RAM that is neither initialized nor
+//! zeroed at boot (the `.noinit` section of the kernel linker script). After
+//! the board resets, the record of the last fault is still there and can be
+//! read with the process manager driver or the process console. Records do
+//! not survive losing power.
+//!
+//! Only the most recent fault is kept, along with a count of how many faults
+//! happened since the record was last cleared. A kernel panic in response to
+//! a process fault, because the process asked for `FaultResponse::Panic`, adds
+//! the line of the panic to the record of the process fault. Any other panic
+//! gets a record of its own.
+//!
+//! A record is only trusted if its magic number and checksum are correct, so
+//! whatever RAM holds after power-on is not mistaken for one.
+
+use core::{cmp, mem, slice, str};
+use core::fmt::Write;
+use core::ptr::{read_volatile, write_volatile};
+use process::{FaultCause, Process};
+
+/// Marks a valid record, "FLT0".
+const MAGIC: u32 = 0x30544C46;
+
+/// Bytes of the package name, or source file, kept in a record.
+pub const NAME_LEN: usize = 20;
+
+/// What a fault record describes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultKind {
+    /// The CPU raised a fault while a process was running.
+    ProcessFault = 1,
+    /// The stack of a process grew past its bottom.
+    StackOverflow = 2,
+    /// The kernel panicked without a process faulting first.
+    KernelPanic = 3,
+}
+
+/// A fault record as it is kept in RAM and copied to userspace.
+///
+/// All fields are little endian 32 bit words followed by the name, which is
+/// padded with NUL bytes. For kernel panics the registers are 0 and the name
+/// is the end of the path of the source file that panicked.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FaultRecord {
+    magic: u32,
+    checksum: u32,
+    kind: u32,
+    /// Faults recorded since the record was cleared, this one included.
+    pub count: u32,
+    /// Line of the kernel panic, 0 if the kernel did not panic.
+    pub panic_line: u32,
+    pub pc: u32,
+    pub lr: u32,
+    pub sp: u32,
+    pub xpsr: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    pub syscall_count: u32,
+    /// Number of the last syscall of the process, `!0` if there was none.
+    pub last_syscall: u32,
+    name: [u8; NAME_LEN],
+}
+
+#[link_section = ".noinit"]
+static mut RECORD: FaultRecord = FaultRecord {
+    magic: 0,
+    checksum: 0,
+    kind: 0,
+    count: 0,
+    panic_line: 0,
+    pc: 0,
+    lr: 0,
+    sp: 0,
+    xpsr: 0,
+    cfsr: 0,
+    hfsr: 0,
+    mmfar: 0,
+    bfar: 0,
+    syscall_count: 0,
+    last_syscall: 0,
+    name: [0; NAME_LEN],
+};
+
+/// Whether the kernel is about to panic because of the process fault that
+/// was just recorded. Unlike the record itself this is zeroed at boot.
+static mut PANICKING_ON_FAULT: bool = false;
+
+impl FaultRecord {
+    /// XOR of all words of the record except the checksum itself.
+    fn compute_checksum(&self) -> u32 {
+        self.as_bytes()
+            .chunks(4)
+            .enumerate()
+            .filter(|&(i, _)| i != 1)
+            .fold(0, |checksum, (_, word)| {
+                checksum ^
+                word.iter().enumerate().fold(0, |w, (i, byte)| w | (*byte as u32) << (8 * i))
+            })
+    }
+
+    /// The raw bytes of the record.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const FaultRecord as *const u8,
+                                  mem::size_of::<FaultRecord>())
+        }
+    }
+
+    pub fn kind(&self) -> Option<FaultKind> {
+        match self.kind {
+            1 => Some(FaultKind::ProcessFault),
+            2 => Some(FaultKind::StackOverflow),
+            3 => Some(FaultKind::KernelPanic),
+            _ => None,
+        }
+    }
+
+    /// Package name of the process that faulted, or the end of the source file
+    /// of a kernel panic.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(NAME_LEN);
+        str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// Keep `name`, cut to `NAME_LEN` bytes.
+    fn set_name(&mut self, name: &[u8]) {
+        self.name = [0; NAME_LEN];
+        for (dst, src) in self.name.iter_mut().zip(name.iter()) {
+            *dst = *src;
+        }
+    }
+
+    /// Print the record in the same style as `Process::fault_str`.
+    pub fn fault_str<W: Write>(&self, writer: &mut W) {
+        let what = match self.kind() {
+            Some(FaultKind::ProcessFault) => "Process fault",
+            Some(FaultKind::StackOverflow) => "Process stack overflow",
+            Some(FaultKind::KernelPanic) => "Kernel panic",
+            None => "Unknown",
+        };
+        let _ = writer.write_fmt(format_args!("\r\n---| Fault Record |---\r\n\
+                                               {} in {} ({} since cleared)\r\n",
+                                              what,
+                                              self.name(),
+                                              self.count));
+        if self.panic_line != 0 {
+            let _ = writer.write_fmt(format_args!("Kernel panic at line {}\r\n", self.panic_line));
+        }
+        if self.kind() != Some(FaultKind::KernelPanic) {
+            let _ = writer.write_fmt(format_args!("  PC : {:#010X}    LR  : {:#010X}\
+\r\n  SP : {:#010X}    xPSR: {:#010X}\
+\r\n CFSR: {:#010X}    HFSR: {:#010X}\
+\r\n MMFAR: {:#010X}   BFAR: {:#010X}\
+\r\n Syscall Count: {}   Last Syscall: ",
+                                                  self.pc,
+                                                  self.lr,
+                                                  self.sp,
+                                                  self.xpsr,
+                                                  self.cfsr,
+                                                  self.hfsr,
+                                                  self.mmfar,
+                                                  self.bfar,
+                                                  self.syscall_count));
+            let _ = match self.last_syscall {
+                0 => writer.write_str("YIELD\r\n"),
+                1 => writer.write_str("SUBSCRIBE\r\n"),
+                2 => writer.write_str("COMMAND\r\n"),
+                3 => writer.write_str("ALLOW\r\n"),
+                4 => writer.write_str("MEMOP\r\n"),
+                _ => writer.write_str("None\r\n"),
+            };
+        }
+    }
+}
+
+/// The record of the most recent fault, if there is a valid one.
+pub fn last() -> Option<FaultRecord> {
+    let record = unsafe { read_volatile(&RECORD) };
+    if record.magic == MAGIC && record.checksum == record.compute_checksum() {
+        Some(record)
+    } else {
+        None
+    }
+}
+
+/// Forget the recorded fault and reset the fault count.
+pub fn clear() {
+    unsafe {
+        write_volatile(&mut RECORD.magic, 0);
+    }
+}
+
+/// Seal `record` with the magic number and checksum and store it.
+unsafe fn store(mut record: FaultRecord) {
+    record.magic = MAGIC;
+    record.checksum = record.compute_checksum();
+    write_volatile(&mut RECORD, record);
+}
+
+/// Record that `process` faulted. `scb_registers` are the fault status
+/// registers saved for the process.
+pub unsafe fn record_process_fault(process: &Process,
+                                   cause: FaultCause,
+                                   scb_registers: [u32; 5]) {
+    let count = last().map_or(0, |record| record.count);
+    let mut record = FaultRecord {
+        magic: 0,
+        checksum: 0,
+        kind: match cause {
+            FaultCause::Hardware => FaultKind::ProcessFault as u32,
+            FaultCause::StackOverflow { .. } => FaultKind::StackOverflow as u32,
+        },
+        count: count.wrapping_add(1),
+        panic_line: 0,
+        pc: 0,
+        lr: 0,
+        sp: process.sp() as u32,
+        xpsr: 0,
+        cfsr: scb_registers[1],
+        hfsr: scb_registers[2],
+        mmfar: scb_registers[3],
+        bfar: scb_registers[4],
+        syscall_count: process.syscall_count() as u32,
+        last_syscall: process.last_syscall().map_or(!0, |syscall| syscall as u32),
+        name: [0; NAME_LEN],
+    };
+    // The stacked registers cannot be trusted, and may not even be readable,
+    // if the stack pointer left the memory of the process.
+    if process.sp() >= process.mem_start() as usize {
+        record.pc = process.pc() as u32;
+        record.lr = process.lr() as u32;
+        record.xpsr = process.xpsr() as u32;
+    }
+    record.set_name(process.package_name.as_bytes());
+    store(record);
+}
+
+/// Mark the next kernel panic as the response to the process fault that was
+/// just recorded, so that `record_panic` adds to that record instead of
+/// replacing it.
+pub unsafe fn panicking_on_fault() {
+    PANICKING_ON_FAULT = true;
+}
+
+/// Record a kernel panic at `line` of `file`. Boards call this first thing in
+/// their panic handler.
+pub unsafe fn record_panic(file: &str, line: u32) {
+    match last() {
+        Some(mut record) if PANICKING_ON_FAULT => {
+            // The panic is the kernel's response to the fault just recorded.
+            record.panic_line = line;
+            store(record);
+        }
+        previous => {
+            let mut record = FaultRecord {
+                magic: 0,
+                checksum: 0,
+                kind: FaultKind::KernelPanic as u32,
+                count: previous.map_or(0, |record| record.count).wrapping_add(1),
+                panic_line: line,
+                pc: 0,
+                lr: 0,
+                sp: 0,
+                xpsr: 0,
+                cfsr: 0,
+                hfsr: 0,
+                mmfar: 0,
+                bfar: 0,
+                syscall_count: 0,
+                last_syscall: !0,
+                name: [0; NAME_LEN],
+            };
+            // Keep the end of long paths, that is where they differ.
+            let file = file.as_bytes();
+            record.set_name(&file[file.len() - cmp::min(file.len(), NAME_LEN)..]);
+            store(record);
+        }
+    }
+}
@@ -1,15 +1,29 @@
 //! Data structure to store a list of userspace applications.
 
 use callback::AppId;
+use core::intrinsics;
 use core::marker::PhantomData;
 use core::mem::size_of;
 use core::ops::{Deref, DerefMut};
-use core::ptr::{read_volatile, write_volatile, Unique};
+use core::ptr::{read_volatile, write, write_volatile, Unique};
+use core::slice;
 use debug;
 use process::{self, Error};
 
 pub static mut CONTAINER_COUNTER: usize = 0;
 
+/// Number of grants whose type names are kept for debugging output.
+const MAX_NAMED_GRANTS: usize = 32;
+
+/// Type name of the data stored in each grant, by grant number.
+static mut GRANT_NAMES: [&'static str; MAX_NAMED_GRANTS] = [""; MAX_NAMED_GRANTS];
+
+/// Name of the type stored in grant `grant_num`, which tells which capsule
+/// the grant belongs to. Empty if the name was not recorded.
+pub fn grant_name(grant_num: usize) -> &'static str {
+    unsafe { GRANT_NAMES.get(grant_num).map_or("", |name| *name) }
+}
+
 pub struct Grant<T: Default> {
     grant_num: usize,
     ptr: PhantomData<T>,
@@ -17,6 +31,7 @@ pub struct Grant<T: Default> {
 
 pub struct AppliedGrant<T> {
     appid: usize,
+    grant_num: usize,
     grant: *mut T,
     _phantom: PhantomData<T>,
 }
@@ -36,15 +51,19 @@ impl<T> AppliedGrant<T> {
         let mut allocator = Allocator {
             app: unsafe { Some(process::PROCS[self.appid].as_mut().unwrap()) },
             app_id: self.appid,
+            grant_num: self.grant_num,
         };
         let mut root = unsafe { Owned::new(self.grant, self.appid) };
         fun(&mut root, &mut allocator)
     }
 }
 
+/// Allocates memory in the grant region of a process. Allocations are
+/// charged to the grant the allocator was handed out for.
 pub struct Allocator<'a> {
     app: Option<&'a mut process::Process<'a>>,
     app_id: usize,
+    grant_num: usize,
 }
 
 pub struct Owned<T: ?Sized> {
@@ -102,9 +121,10 @@ impl<'a> Allocator<'a> {
     pub fn alloc<T>(&mut self, data: T) -> Result<Owned<T>, Error> {
         unsafe {
             let app_id = self.app_id;
+            let grant_num = self.grant_num;
             match self.app.as_mut() {
                 Some(app) => {
-                    app.alloc(size_of::<T>()).map_or(Err(Error::OutOfMemory), |arr| {
+                    app.alloc(size_of::<T>(), grant_num).map_or(Err(Error::OutOfMemory), |arr| {
                         let mut owned = Owned::new(arr.as_mut_ptr() as *mut T, app_id);
                         *owned = data;
                         Ok(owned)
@@ -119,6 +139,27 @@ impl<'a> Allocator<'a> {
             }
         }
     }
+
+    /// Allocate a slice of `len` default values. Used for grant data whose
+    /// size depends on the board, such as per-process tables.
+    pub fn alloc_slice<T: Default>(&mut self, len: usize) -> Result<Owned<[T]>, Error> {
+        unsafe {
+            let app_id = self.app_id;
+            let grant_num = self.grant_num;
+            match self.app.as_mut() {
+                Some(app) => {
+                    app.alloc(len * size_of::<T>(), grant_num).map_or(Err(Error::OutOfMemory), |arr| {
+                        let ptr = arr.as_mut_ptr() as *mut T;
+                        for i in 0..len {
+                            write(ptr.offset(i as isize), T::default());
+                        }
+                        Ok(Owned::new(slice::from_raw_parts_mut(ptr, len) as *mut [T], app_id))
+                    })
+                }
+                None => panic!("Request to allocate in kernel grant"),
+            }
+        }
+    }
 }
 
 pub struct Borrowed<'a, T: 'a + ?Sized> {
@@ -157,6 +198,9 @@ impl<T: Default> Grant<T> {
     pub unsafe fn create() -> Grant<T> {
         let ctr = read_volatile(&CONTAINER_COUNTER);
         write_volatile(&mut CONTAINER_COUNTER, ctr + 1);
+        if ctr < MAX_NAMED_GRANTS {
+            GRANT_NAMES[ctr] = intrinsics::type_name::<T>();
+        }
         Grant {
             grant_num: ctr,
             ptr: PhantomData,
@@ -170,6 +214,7 @@ impl<T: Default> Grant<T> {
                 let cntr = kernel_grant_for::<T>(app_id);
                 Some(AppliedGrant {
                     appid: app_id,
+                    grant_num: self.grant_num,
                     grant: cntr,
                     _phantom: PhantomData,
                 })
@@ -182,6 +227,7 @@ impl<T: Default> Grant<T> {
                         } else {
                             Some(AppliedGrant {
                                 appid: app_id,
+                                grant_num: self.grant_num,
                                 grant: cntr,
                                 _phantom: PhantomData,
                             })
@@ -205,6 +251,7 @@ impl<T: Default> Grant<T> {
                 let mut allocator = Allocator {
                     app: None,
                     app_id: app_id,
+                    grant_num: self.grant_num,
                 };
                 let res = fun(&mut root, &mut allocator);
                 Ok(res)
@@ -217,6 +264,7 @@ impl<T: Default> Grant<T> {
                                 let mut allocator = Allocator {
                                     app: Some(app),
                                     app_id: app_id,
+                                    grant_num: self.grant_num,
                                 };
                                 let res = fun(&mut root, &mut allocator);
                                 Ok(res)
@@ -1,31 +1,81 @@
 //! Inter-process communication mechanism for Tock.
 //!
 //! This is a special syscall driver that allows userspace applications to
-//! share memory.
+//! share memory. To pass messages without sharing memory, see `ipc_message`.
+//!
+//! Each process keeps one entry per other process on the board for the
+//! buffer it shares with that process and, if it is a client of that process,
+//! its client callback. The table is allocated in the process's grant region
+//! the first time it is needed and has as many entries as the board has
+//! process slots.
+//!
+//! A process can restrict which other processes may use IPC with it by
+//! listing their package names in its TBF header. Discovery, sharing and
+//! notifications from anyone else fail with `EPERM`.
 
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x00010000;
 
+/// Passed as the length argument of client callbacks when the service
+/// restarted. The buffer argument is 0.
+pub const SERVICE_RESTARTED: usize = !0;
+
 use {AppId, AppSlice, Grant, Callback, Driver, Shared};
+use grant::{Allocator, Owned};
 use process;
 use returncode::ReturnCode;
 
+#[derive(Default)]
+struct Peer {
+    shared_memory: Option<AppSlice<Shared, u8>>,
+    client_callback: Option<Callback>,
+}
+
 struct IPCData {
-    shared_memory: [Option<AppSlice<Shared, u8>>; 8],
-    client_callbacks: [Option<Callback>; 8],
+    peers: Option<Owned<[Peer]>>,
     callback: Option<Callback>,
 }
 
 impl Default for IPCData {
     fn default() -> IPCData {
         IPCData {
-            shared_memory: [None, None, None, None, None, None, None, None],
-            client_callbacks: [None, None, None, None, None, None, None, None],
+            peers: None,
             callback: None,
         }
     }
 }
 
+impl IPCData {
+    /// The entry for the process at `idx`, if the table has been allocated.
+    fn peer(&self, idx: usize) -> Option<&Peer> {
+        self.peers.as_ref().and_then(|peers| peers.get(idx))
+    }
+
+    /// The entry for the process at `idx`, allocating the table if needed.
+    fn peer_mut(&mut self, allocator: &mut Allocator, idx: usize) -> Result<&mut Peer, ReturnCode> {
+        if self.peers.is_none() {
+            let num_procs = unsafe { process::PROCS.len() };
+            self.peers = Some(allocator.alloc_slice(num_procs).map_err(|_| ReturnCode::ENOMEM)?);
+        }
+        self.peers
+            .as_mut()
+            .and_then(|peers| peers.get_mut(idx))
+            .ok_or(ReturnCode::EINVAL)
+    }
+}
+
+/// Whether the process at index `target` accepts IPC from `appid`, according
+/// to the list of permitted clients in its TBF header.
+pub fn client_permitted(appid: AppId, target: usize) -> bool {
+    let procs = unsafe { &process::PROCS };
+    let client = procs.get(appid.idx())
+        .and_then(|p| p.as_ref())
+        .map_or("", |p| p.package_name);
+    procs.get(target)
+        .and_then(|p| p.as_ref())
+        .map_or(true, |p| p.ipc_client_allowed(client))
+}
+
 pub struct IPC {
     data: Grant<IPCData>,
 }
@@ -44,17 +94,15 @@ impl IPC {
                 let callback = match cb_type {
                     process::IPCType::Service => mydata.callback,
                     process::IPCType::Client => {
-                        *mydata.client_callbacks.get(otherapp.idx()).unwrap_or(&None)
+                        mydata.peer(otherapp.idx()).and_then(|peer| peer.client_callback)
                     }
                 };
                 callback.map(|mut callback| {
                         self.data
                             .enter(otherapp, |otherdata, _| {
-                                if appid.idx() >= otherdata.shared_memory.len() {
-                                    return;
-                                }
-                                match otherdata.shared_memory[appid.idx()] {
-                                    Some(ref slice) => {
+                                match otherdata.peer(appid.idx())
+                                    .and_then(|peer| peer.shared_memory.as_ref()) {
+                                    Some(slice) => {
                                         slice.expose_to(appid);
                                         callback.schedule(otherapp.idx() + 1,
                                                           slice.len(),
@@ -71,6 +119,65 @@ impl IPC {
             })
             .unwrap_or(());
     }
+
+    /// Tell the clients of `service` that it restarted and lost all of its
+    /// state. Only processes that registered a client callback for `service`
+    /// are called.
+    pub fn notify_restarted(&self, service: AppId) {
+        for cntr in self.data.iter() {
+            cntr.enter(|data, _| {
+                data.peer(service.idx())
+                    .and_then(|peer| peer.client_callback)
+                    .map(|mut callback| {
+                        callback.schedule(service.idx() + 1, SERVICE_RESTARTED, 0);
+                    });
+            });
+        }
+    }
+
+    /// Find the service with the package name in `name`.
+    ///
+    /// Returns the id of the service, `ENODEVICE` if no process has that name,
+    /// `EPERM` if the service does not accept `appid` as a client, `EBUSY` if
+    /// the process has not registered as a service yet and `FAIL` if more than
+    /// one process has that name.
+    fn discover(&self, appid: AppId, name: &AppSlice<Shared, u8>) -> ReturnCode {
+        let procs = unsafe { &process::PROCS };
+        let mut found = None;
+        for (i, process) in procs.iter().enumerate() {
+            match process {
+                &Some(ref p) => {
+                    let s = p.package_name.as_bytes();
+                    // are slices equal?
+                    if s.len() == name.len() &&
+                       s.iter()
+                        .zip(name.iter())
+                        .all(|(c1, c2)| c1 == c2) {
+                        if found.is_some() {
+                            return ReturnCode::FAIL;
+                        }
+                        found = Some(i);
+                    }
+                }
+                &None => {}
+            }
+        }
+
+        match found {
+            None => ReturnCode::ENODEVICE,
+            Some(i) if !client_permitted(appid, i) => ReturnCode::EPERM,
+            Some(i) => {
+                let registered = self.data
+                    .grant(AppId::new(i))
+                    .map_or(false, |data| data.enter(|data, _| data.callback.is_some()));
+                if registered {
+                    ReturnCode::SuccessWithValue { value: i + 1 }
+                } else {
+                    ReturnCode::EBUSY
+                }
+            }
+        }
+    }
 }
 
 impl Driver for IPC {
@@ -101,18 +208,19 @@ impl Driver for IPC {
             // a callback for a given service. The service number (passed
             // here as subscribe_num) is returned from the allow() call.
             // Once subscribed, the client will receive callbacks when the
-            // service process calls notify_client().
+            // service process calls notify_client() and when the service
+            // restarts.
             svc_id => {
-                if svc_id - 1 >= 8 {
-                    ReturnCode::EINVAL /* Maximum of 8 IPC's exceeded */
-                } else {
-                    self.data
-                        .enter(callback.app_id(), |data, _| {
-                            data.client_callbacks[svc_id - 1] = Some(callback);
-                            ReturnCode::SUCCESS
-                        })
-                        .unwrap_or(ReturnCode::EBUSY)
-                }
+                self.data
+                    .enter(callback.app_id(), |data, allocator| {
+                        data.peer_mut(allocator, svc_id - 1)
+                            .map(|peer| {
+                                peer.client_callback = Some(callback);
+                                ReturnCode::SUCCESS
+                            })
+                            .unwrap_or_else(|err| err)
+                    })
+                    .unwrap_or(ReturnCode::EBUSY)
             }
         }
     }
@@ -133,11 +241,16 @@ impl Driver for IPC {
             return ReturnCode::EINVAL; /* Request to IPC to impossible process */
         }
 
-        let cb_type = if client_or_svc == 0 {
-            process::IPCType::Service
+        // The list of permitted clients belongs to the service, whichever
+        // side sends the notification.
+        let (cb_type, permitted) = if client_or_svc == 0 {
+            (process::IPCType::Service, client_permitted(appid, target_id - 1))
         } else {
-            process::IPCType::Client
+            (process::IPCType::Client, client_permitted(AppId::new(target_id - 1), appid.idx()))
         };
+        if !permitted {
+            return ReturnCode::EPERM;
+        }
 
         procs[target_id - 1]
             .as_mut()
@@ -164,34 +277,21 @@ impl Driver for IPC {
     fn allow(&self, appid: AppId, target_id: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
         if target_id == 0 {
             if slice.len() > 0 {
-                let procs = unsafe { &mut process::PROCS };
-                for (i, process) in procs.iter().enumerate() {
-                    match process {
-                        &Some(ref p) => {
-                            let s = p.package_name.as_bytes();
-                            // are slices equal?
-                            if s.len() == slice.len() &&
-                               s.iter()
-                                .zip(slice.iter())
-                                .all(|(c1, c2)| c1 == c2) {
-                                return ReturnCode::SuccessWithValue { value: (i as usize) + 1 };
-                            }
-                        }
-                        &None => {}
-                    }
-                }
+                return self.discover(appid, &slice);
             }
             return ReturnCode::EINVAL; /* AppSlice must have non-zero length */
         }
+        if !client_permitted(appid, target_id - 1) {
+            return ReturnCode::EPERM;
+        }
         return self.data
-            .enter(appid, |data, _| {
-                data.shared_memory
-                    .get_mut(target_id - 1)
-                    .map(|smem| {
-                        *smem = Some(slice);
+            .enter(appid, |data, allocator| {
+                data.peer_mut(allocator, target_id - 1)
+                    .map(|peer| {
+                        peer.shared_memory = Some(slice);
                         ReturnCode::SUCCESS
                     })
-                    .unwrap_or(ReturnCode::EINVAL) /* Target process does not exist */
+                    .unwrap_or_else(|err| err) /* EINVAL if the target process does not exist */
             })
             .unwrap_or(ReturnCode::EBUSY);
     }
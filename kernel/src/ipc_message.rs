@@ -0,0 +1,242 @@
+//! Message passing between processes for Tock.
+//!
+//! This is a special syscall driver that copies bounded messages from one
+//! process into a receive queue of another, so that client and service never
+//! share memory. Processes find each other with the service discovery of the
+//! `ipc` driver and use the same process ids.
+//!
+//! A process that wants to receive messages allows a receive buffer and
+//! subscribes a callback. The buffer is split into `queue_depth` equally sized
+//! slots, each holding one message. The kernel fills the slots in order and
+//! calls the callback once for every message with the id of the sender, the
+//! length of the message and the correlation id the sender chose. The
+//! receiver reads the messages in the same order and hands each slot back
+//! with the release command. When all slots are full, senders get `EBUSY`
+//! until the receiver catches up.
+//!
+//! Like the `ipc` driver, a receiver can limit which processes may send to it
+//! with the list of permitted IPC clients in its TBF header.
+//!
+//! Correlation ids are 16 bits and not interpreted by the kernel. A service
+//! copies the id of a request into its response so that a client with several
+//! outstanding requests can match them up.
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x00010002;
+
+/// Queue depth of a process that never set one.
+pub const DEFAULT_QUEUE_DEPTH: usize = 1;
+
+use {AppId, AppSlice, Callback, Driver, Grant, Shared};
+use ipc;
+use process;
+use returncode::ReturnCode;
+
+struct App {
+    callback: Option<Callback>,
+    receive_buffer: Option<AppSlice<Shared, u8>>,
+    send_buffer: Option<AppSlice<Shared, u8>>,
+    queue_depth: usize,
+    /// Slot of the oldest message that has not been released.
+    head: usize,
+    /// Number of messages that have not been released.
+    count: usize,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            receive_buffer: None,
+            send_buffer: None,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            head: 0,
+            count: 0,
+        }
+    }
+}
+
+impl App {
+    /// Copy `message` into the next free slot of the receive queue and notify
+    /// the process.
+    fn deliver(&mut self, sender: usize, message: &[u8], correlation_id: usize) -> ReturnCode {
+        if self.count >= self.queue_depth {
+            return ReturnCode::EBUSY;
+        }
+        let slot = (self.head + self.count) % self.queue_depth;
+        let queue_depth = self.queue_depth;
+        let mut callback = match self.callback {
+            Some(callback) => callback,
+            None => return ReturnCode::ENOMEM,
+        };
+
+        let result = self.receive_buffer.as_mut().map_or(ReturnCode::ENOMEM, |buffer| {
+            let slot_size = buffer.len() / queue_depth;
+            if message.len() > slot_size {
+                return ReturnCode::ESIZE;
+            }
+            let start = slot * slot_size;
+            buffer.as_mut()[start..start + message.len()].copy_from_slice(message);
+            if callback.schedule(sender, message.len(), correlation_id) {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::EBUSY
+            }
+        });
+        if result == ReturnCode::SUCCESS {
+            self.count += 1;
+        }
+        result
+    }
+}
+
+pub struct IPCMessage {
+    apps: Grant<App>,
+}
+
+impl IPCMessage {
+    pub unsafe fn new() -> IPCMessage {
+        IPCMessage { apps: Grant::create() }
+    }
+
+    /// Copy the first `len` bytes of the send buffer of `appid` into the
+    /// receive queue of the process with id `target_id`.
+    fn send(&self,
+            appid: AppId,
+            target_id: usize,
+            correlation_id: usize,
+            len: usize)
+            -> ReturnCode {
+        let procs = unsafe { &process::PROCS };
+        if target_id == 0 || target_id > procs.len() || target_id - 1 == appid.idx() {
+            return ReturnCode::EINVAL;
+        }
+        if procs[target_id - 1].is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if !ipc::client_permitted(appid, target_id - 1) {
+            return ReturnCode::EPERM;
+        }
+        let target = AppId::new(target_id - 1);
+
+        self.apps
+            .enter(appid, |app, _| {
+                app.send_buffer.as_ref().map_or(ReturnCode::ERESERVE, |message| {
+                    if len > message.len() {
+                        return ReturnCode::EINVAL;
+                    }
+                    self.apps
+                        .enter(target, |target_app, _| {
+                            target_app.deliver(appid.idx() + 1,
+                                               &message.as_ref()[..len],
+                                               correlation_id)
+                        })
+                        .unwrap_or_else(|err| err.into())
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl Driver for IPCMessage {
+    /// subscribe registers the callback for received messages.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called for every message with the id of the sender, the length
+    ///        of the message and its correlation id.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => {
+                self.apps
+                    .enter(callback.app_id(), |app, _| {
+                        app.callback = Some(callback);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// allow passes the buffers messages are received into and sent from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Receive buffer. Messages that have not been released yet are
+    ///        discarded. Fails with `EINVAL` if the buffer does not have a
+    ///        byte for every slot of the queue.
+    /// - `1`: Buffer messages are sent from.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => {
+                        if slice.len() / app.queue_depth == 0 {
+                            return ReturnCode::EINVAL;
+                        }
+                        app.receive_buffer = Some(slice);
+                        app.head = 0;
+                        app.count = 0;
+                        ReturnCode::SUCCESS
+                    }
+                    1 => {
+                        app.send_buffer = Some(slice);
+                        ReturnCode::SUCCESS
+                    }
+                    _ => ReturnCode::ENOSUPPORT,
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// command sends messages and manages the receive queue.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Set the number of slots in the receive queue to `data`. Fails
+    ///        with `EBUSY` while messages are waiting to be released, and
+    ///        with `EINVAL` if that leaves slots of zero bytes.
+    /// - `2`: Release the oldest received message.
+    /// - `3`: Send the first `data2` bytes of the send buffer to the process
+    ///        whose id is in the low 16 bits of `data`, tagged with the
+    ///        correlation id in the high 16 bits.
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                self.apps
+                    .enter(appid, |app, _| {
+                        if data == 0 {
+                            ReturnCode::EINVAL
+                        } else if app.receive_buffer
+                            .as_ref()
+                            .map_or(false, |buffer| buffer.len() / data == 0) {
+                            ReturnCode::EINVAL
+                        } else if app.count > 0 {
+                            ReturnCode::EBUSY
+                        } else {
+                            app.queue_depth = data;
+                            app.head = 0;
+                            ReturnCode::SUCCESS
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            2 => {
+                self.apps
+                    .enter(appid, |app, _| if app.count == 0 {
+                        ReturnCode::EALREADY
+                    } else {
+                        app.head = (app.head + 1) % app.queue_depth;
+                        app.count -= 1;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            3 => self.send(appid, data & 0xffff, data >> 16, data2),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -5,14 +5,22 @@
 pub mod common;
 
 pub mod callback;
+pub mod crypto;
+pub mod deferred_call;
 pub mod grant;
 #[macro_use]
 pub mod debug;
 pub mod driver;
+pub mod fault_record;
 pub mod ipc;
+pub mod ipc_message;
 pub mod mem;
 pub mod memop;
+pub mod power;
+pub mod process_manager;
 pub mod returncode;
+pub mod scheduler;
+pub mod trace;
 pub mod hil;
 
 // Work around https://github.com/rust-lang-nursery/rustfmt/issues/6
@@ -36,12 +44,17 @@ pub use platform::{Chip, mpu, Platform, systick};
 pub use platform::systick::SysTick;
 pub use process::{Process, State};
 pub use returncode::ReturnCode;
+pub use scheduler::Scheduler;
 
 /// Main loop.
-pub fn main<P: Platform, C: Chip>(platform: &P,
-                                  chip: &mut C,
-                                  processes: &'static mut [Option<process::Process<'static>>],
-                                  ipc: &ipc::IPC) {
+///
+/// `scheduler` decides which of the ready processes runs next and for how
+/// long.
+pub fn main<P: Platform, C: Chip, S: Scheduler>(platform: &P,
+                                                chip: &mut C,
+                                                processes: &'static mut [Option<process::Process<'static>>],
+                                                ipc: &ipc::IPC,
+                                                scheduler: &S) {
     let processes = unsafe {
         process::PROCS = processes;
         &mut process::PROCS
@@ -50,19 +63,30 @@ pub fn main<P: Platform, C: Chip>(platform: &P,
     loop {
         unsafe {
             chip.service_pending_interrupts();
+            deferred_call::service();
 
-            for (i, p) in processes.iter_mut().enumerate() {
-                p.as_mut().map(|process| {
-                    sched::do_process(platform, chip, process, AppId::new(i), ipc);
-                });
-                if chip.has_pending_interrupts() {
-                    break;
-                }
+            while !chip.has_pending_interrupts() {
+                let i = match scheduler.next(&processes[..]) {
+                    Some(i) => i,
+                    None => break,
+                };
+                let reason = match processes[i] {
+                    Some(ref mut process) => {
+                        let timeslice_us = scheduler.timeslice_us(i, process);
+                        sched::do_process(platform,
+                                          chip,
+                                          process,
+                                          AppId::new(i),
+                                          ipc,
+                                          timeslice_us)
+                    }
+                    None => break,
+                };
+                scheduler.stopped(i, reason);
             }
 
             support::atomic(|| if !chip.has_pending_interrupts() && process::processes_blocked() {
-                chip.prepare_for_sleep();
-                support::wfi();
+                chip.sleep();
             })
         };
     }
@@ -1,8 +1,17 @@
 //! Implementation of the MEMOP family of syscalls.
 
+use common::bytes::{get_u32, put_u32};
+use core::slice;
 use process::Process;
 use returncode::ReturnCode;
 
+/// Bytes of the fixed part of the layout written by op 15, before the
+/// writeable flash regions.
+const LAYOUT_HEADER_LEN: usize = 32;
+
+/// Bytes of each writeable flash region in the layout written by op 15.
+const LAYOUT_REGION_LEN: usize = 8;
+
 /// Handle the `memop` syscall.
 ///
 /// ### `memop_num`
@@ -36,6 +45,16 @@ use returncode::ReturnCode;
 ///   where the app has put the start of its heap. This is not strictly
 ///   necessary for correct operation, but allows for better debugging if the
 ///   app crashes.
+/// - `12`: Get the number of bytes of the grant region in use, including the
+///   kernel's own bookkeeping.
+/// - `13`: Get the number of bytes allocated in the grant region for the grant
+///   indexed from 0 by r1. Fails if the grant does not exist.
+/// - `14`: Get the number of upcalls to the app that were dropped because its
+///   callback queue was full.
+/// - `15`: Write the memory layout of the app to the buffer at r1, see
+///   `write_layout`. Returns the size of the whole layout.
+/// - `16`: Do not let BRK and SBRK move the program break above the address
+///   in r1. The limit can only be lowered, and not below the current break.
 pub fn memop(process: &mut Process) -> ReturnCode {
     let op_type = process.r0();
     let r1 = process.r1();
@@ -111,6 +130,89 @@ pub fn memop(process: &mut Process) -> ReturnCode {
             ReturnCode::SUCCESS
         }
 
+        // Op Type 12: Size of the grant region in use.
+        12 => ReturnCode::SuccessWithValue { value: process.grant_region_size() },
+
+        // Op Type 13: Bytes of the grant region used by the grant indexed by r1.
+        13 => {
+            process.grant_usage(r1)
+                .map_or(ReturnCode::FAIL, |bytes| ReturnCode::SuccessWithValue { value: bytes })
+        }
+
+        // Op Type 14: Number of upcalls dropped because the queue was full.
+        14 => ReturnCode::SuccessWithValue { value: process.dropped_callback_count() },
+
+        // Op Type 15: Memory layout of the app.
+        15 => write_layout(process, r1 as *mut u8),
+
+        // Op Type 16: Limit the growth of the app break.
+        16 => {
+            process.set_app_break_limit(r1 as *const u8)
+                .map(|_| ReturnCode::SUCCESS)
+                .unwrap_or_else(|err| err.into())
+        }
+
         _ => ReturnCode::ENOSUPPORT,
     }
 }
+
+/// Write the memory layout of `process` to the buffer at `buf`, which must be
+/// below the app break. The first word of the buffer is its length in bytes,
+/// set by the app. The kernel fills in, as little endian words:
+///
+/// | Offset | Field                                                      |
+/// |--------|------------------------------------------------------------|
+/// | 0      | Length of the buffer, not changed.                         |
+/// | 4      | Top of the stack, 0 if the kernel does not know it.        |
+/// | 8      | Size of the stack, 0 if the kernel does not know it.       |
+/// | 12     | Start of the heap, 0 if the kernel does not know it.       |
+/// | 16     | App break.                                                 |
+/// | 20     | Kernel break, the lowest address of the grant region.      |
+/// | 24     | Limit set with op 16, 0 if there is none.                  |
+/// | 28     | Number of writeable flash regions.                         |
+/// | 32     | Start and end address of each writeable flash region.      |
+///
+/// Regions that do not fit in the buffer are left out. The stack grows down
+/// from its top to the start of the app's memory.
+fn write_layout(process: &Process, buf: *mut u8) -> ReturnCode {
+    if !process.in_app_owned_memory(buf, 4) {
+        return ReturnCode::EINVAL;
+    }
+    let len = unsafe { get_u32(slice::from_raw_parts(buf, 4)) as usize };
+    if len < LAYOUT_HEADER_LEN || !process.in_app_owned_memory(buf, len) {
+        return ReturnCode::EINVAL;
+    }
+    let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+
+    let stack_start = process.app_stack_start().map_or(0, |ptr| ptr as usize);
+    let stack_size = if stack_start == 0 {
+        0
+    } else {
+        stack_start - process.mem_start() as usize
+    };
+    let regions = process.number_writeable_flash_regions();
+    let fields = [stack_start,
+                  stack_size,
+                  process.app_heap_start().map_or(0, |ptr| ptr as usize),
+                  process.app_memory_break() as usize,
+                  process.kernel_memory_break() as usize,
+                  process.app_break_limit().map_or(0, |ptr| ptr as usize),
+                  regions];
+    for (i, field) in fields.iter().enumerate() {
+        put_u32(&mut buffer[4 + i * 4..], *field as u32);
+    }
+
+    let flash_start = process.flash_start() as usize;
+    let region_bufs = buffer[LAYOUT_HEADER_LEN..].chunks_mut(LAYOUT_REGION_LEN);
+    for (i, region_buf) in region_bufs.take(regions).enumerate() {
+        if region_buf.len() < LAYOUT_REGION_LEN {
+            break;
+        }
+        let (offset, size) = process.get_writeable_flash_region(i);
+        let start = flash_start + offset as usize;
+        put_u32(&mut region_buf[0..4], start as u32);
+        put_u32(&mut region_buf[4..8], (start + size as usize) as u32);
+    }
+
+    ReturnCode::SuccessWithValue { value: LAYOUT_HEADER_LEN + regions * LAYOUT_REGION_LEN }
+}
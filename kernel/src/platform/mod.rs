@@ -1,4 +1,5 @@
 use driver::Driver;
+use support;
 
 pub mod mpu;
 pub mod systick;
@@ -19,5 +20,16 @@ pub trait Chip {
     fn has_pending_interrupts(&self) -> bool;
     fn mpu(&self) -> &Self::MPU;
     fn systick(&self) -> &Self::SysTick;
-    fn prepare_for_sleep(&self) {}
+
+    /// Sleep until an interrupt is pending. The main loop calls this with
+    /// interrupts disabled when no process is ready.
+    ///
+    /// The default only waits for an interrupt. Chips with deeper sleep modes
+    /// choose one with `power::choose` and report the time they spent in it
+    /// with `power::record`.
+    fn sleep(&self) {
+        unsafe {
+            support::wfi();
+        }
+    }
 }
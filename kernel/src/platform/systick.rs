@@ -11,6 +11,10 @@ pub trait SysTick {
     /// Returns the time left in approximate microseconds
     fn value(&self) -> u32;
 
+    /// Returns the longest interval in microseconds that `set_timer` can
+    /// count down.
+    fn max_timer_us(&self) -> u32;
+
 
     fn overflowed(&self) -> bool;
 
@@ -36,6 +40,10 @@ impl SysTick for () {
         !0
     }
 
+    fn max_timer_us(&self) -> u32 {
+        !0
+    }
+
     fn overflow_fired() -> bool {
         false
     }
@@ -0,0 +1,154 @@
+//! Power management for the idle loop.
+//!
+//! When no process is ready, the main loop asks the chip to sleep with
+//! `Chip::sleep`. How deep the chip can go depends on:
+//!
+//!   * Its own peripherals. A peripheral that is in the middle of an
+//!   operation keeps the clocks it needs running, and the chip knows which
+//!   states stop those clocks.
+//!
+//!   * Capsules, which can hold a `PowerConstraint` while they need the chip
+//!   to stay responsive, for example while waiting for an external device
+//!   that only signals through a pin the chip does not watch in deep sleep.
+//!
+//!   * The next alarm. Deeper states take longer to enter and leave, so they
+//!   are only worth it if the chip stays there long enough. Chips pass the
+//!   time until their alarm and the break-even time of each state to
+//!   `choose`.
+//!
+//! Chips report how long they spent in each state with `record`, and the
+//! totals since boot are available from `residency`.
+
+use core::cell::Cell;
+
+/// Power states, from the most to the least power hungry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum PowerState {
+    /// The core is running.
+    Active = 0,
+    /// The core is stopped and all clocks run. Any interrupt wakes the chip.
+    Sleep = 1,
+    /// Only low frequency clocks run, so peripherals that need the fast
+    /// clocks stop. Wakes quickly.
+    DeepSleep = 2,
+    /// As `DeepSleep`, but most of the chip is kept in retention at a lower
+    /// voltage. RAM and registers are kept. Slowest to wake.
+    Retention = 3,
+}
+
+/// Number of `PowerState`s.
+pub const NUM_POWER_STATES: usize = 4;
+
+/// All power states, from the most to the least power hungry.
+pub const POWER_STATES: [PowerState; NUM_POWER_STATES] =
+    [PowerState::Active, PowerState::Sleep, PowerState::DeepSleep, PowerState::Retention];
+
+impl PowerState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PowerState::Active => "Active",
+            PowerState::Sleep => "Sleep",
+            PowerState::DeepSleep => "Deep Sleep",
+            PowerState::Retention => "Retention",
+        }
+    }
+}
+
+/// Time spent in a power state since boot.
+#[derive(Clone, Copy, Default)]
+pub struct Residency {
+    /// How many times the chip entered the state.
+    pub entries: usize,
+    /// Microseconds spent in the state.
+    pub time_us: u64,
+}
+
+const NO_RESIDENCY: Residency = Residency {
+    entries: 0,
+    time_us: 0,
+};
+
+static mut RESIDENCY: [Residency; NUM_POWER_STATES] = [NO_RESIDENCY; NUM_POWER_STATES];
+
+/// How many constraints limit the chip to each state.
+static mut LIMITS: [usize; NUM_POWER_STATES] = [0; NUM_POWER_STATES];
+
+/// A limit on how deep the chip may sleep, held by a capsule.
+///
+/// ```rust
+/// // Polling a sensor that does not raise an interrupt, with an alarm
+/// // that must not be delayed by a slow wake up.
+/// self.power.limit(PowerState::Sleep);
+/// ...
+/// // Done.
+/// self.power.release();
+/// ```
+pub struct PowerConstraint {
+    limit: Cell<Option<PowerState>>,
+}
+
+impl PowerConstraint {
+    pub const fn new() -> PowerConstraint {
+        PowerConstraint { limit: Cell::new(None) }
+    }
+
+    /// Do not let the chip go deeper than `state` until `release` is called.
+    /// `PowerState::Active` keeps the chip from sleeping at all.
+    pub fn limit(&self, state: PowerState) {
+        self.release();
+        self.limit.set(Some(state));
+        unsafe {
+            LIMITS[state as usize] += 1;
+        }
+    }
+
+    /// Remove the limit, if there is one.
+    pub fn release(&self) {
+        self.limit.take().map(|state| unsafe {
+            LIMITS[state as usize] -= 1;
+        });
+    }
+}
+
+/// The deepest state all constraints allow.
+pub fn deepest_allowed() -> PowerState {
+    unsafe {
+        POWER_STATES.iter()
+            .cloned()
+            .find(|state| LIMITS[*state as usize] > 0)
+            .unwrap_or(PowerState::Retention)
+    }
+}
+
+/// The deepest state that is no deeper than `limit` and worth entering when
+/// the chip has to wake up again in `until_deadline_us`, which is `None` if
+/// nothing will wake it but an interrupt. Returns `PowerState::Active` if no
+/// sleep state is worth it. `break_even_us` is, for each state, how long the
+/// chip needs to stay in it to save more than entering and leaving it costs.
+pub fn choose(limit: PowerState,
+              until_deadline_us: Option<u32>,
+              break_even_us: &[u32; NUM_POWER_STATES])
+              -> PowerState {
+    POWER_STATES.iter()
+        .cloned()
+        .rev()
+        .filter(|state| *state <= limit)
+        .find(|state| until_deadline_us.map_or(true, |us| us >= break_even_us[*state as usize]))
+        .unwrap_or(PowerState::Active)
+}
+
+/// Add `time_us` spent in `state`. Chips call this when they wake up, and
+/// for the time they were active before going to sleep.
+pub fn record(state: PowerState, time_us: u64) {
+    unsafe {
+        let residency = &mut RESIDENCY[state as usize];
+        residency.entries += 1;
+        residency.time_us += time_us;
+    }
+}
+
+/// Time spent in `state` since boot. All zero on chips that do not report
+/// their power states.
+pub fn residency(state: PowerState) -> Residency {
+    unsafe { RESIDENCY[state as usize] }
+}
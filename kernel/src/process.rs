@@ -4,7 +4,7 @@ use callback::AppId;
 use common::{RingBuffer, Queue, VolatileCell};
 
 use grant;
-use core::{mem, ptr, slice, str};
+use core::{cmp, mem, ptr, slice, str};
 use core::cell::Cell;
 use core::fmt::Write;
 use core::intrinsics;
@@ -14,6 +14,10 @@ use platform::mpu;
 use returncode::ReturnCode;
 use syscall::Syscall;
 use common::math;
+use fault_record;
+use trace;
+use crypto::ed25519;
+use crypto::sha256::Sha256;
 
 /// Takes a value and rounds it up to be aligned % 8
 macro_rules! align8 {
@@ -43,6 +47,18 @@ extern "C" {
 
 pub static mut PROCS: &'static mut [Option<Process<'static>>] = &mut [];
 
+/// How many tasks a process can have pending if its TBF header does not ask
+/// for a different number.
+pub const DEFAULT_CALLBACK_QUEUE_DEPTH: usize = 9;
+
+/// The most tasks a process can ask to have pending. Every slot takes memory
+/// from the grant region of the process.
+pub const MAX_CALLBACK_QUEUE_DEPTH: usize = 64;
+
+/// Kept in the lowest word of the stack of processes whose memory layout the
+/// kernel set up. A process that overwrote it has used all of its stack.
+const STACK_CANARY: usize = 0xC0DEFACE;
+
 /// Helper function to load processes from flash into an array of active
 /// processes. This is the default template for loading processes, but a board
 /// is able to create its own `load_processes()` function and use that instead.
@@ -52,11 +68,16 @@ pub static mut PROCS: &'static mut [Option<Process<'static>>] = &mut [];
 /// `app_memory` buffer until either the memory is exhausted or the allocated
 /// number of processes are created, with process structures placed in the
 /// provided array. How process faults are handled by the kernel is also
-/// selected.
+/// selected, although an app can override this with a fault response element
+/// in its TBF header. `grant_limit`, if set, caps the size of the grant region
+/// of every process. Apps that do not pass the `verification` policy are
+/// skipped.
 pub unsafe fn load_processes(start_of_flash: *const u8,
                              app_memory: &mut [u8],
                              procs: &mut [Option<Process<'static>>],
-                             fault_response: FaultResponse) {
+                             fault_response: FaultResponse,
+                             grant_limit: Option<usize>,
+                             verification: AppVerification) {
     let mut apps_in_flash_ptr = start_of_flash;
     let mut app_memory_ptr = app_memory.as_mut_ptr();
     let mut app_memory_size = app_memory.len();
@@ -64,7 +85,10 @@ pub unsafe fn load_processes(start_of_flash: *const u8,
         let (process, flash_offset, memory_offset) = Process::create(apps_in_flash_ptr,
                                                                      app_memory_ptr,
                                                                      app_memory_size,
-                                                                     fault_response);
+                                                                     fault_response,
+                                                                     grant_limit,
+                                                                     verification,
+                                                                     AppId::new(i));
 
         if process.is_none() {
             // We did not get a valid process, but we may have gotten a disabled
@@ -82,6 +106,176 @@ pub unsafe fn load_processes(start_of_flash: *const u8,
         app_memory_ptr = app_memory_ptr.offset(memory_offset as isize);
         app_memory_size -= memory_offset;
     }
+
+    // Keep the settings and the rest of the memory for processes that are
+    // loaded later with `load_process`.
+    DYNAMIC_LOADING = DynamicLoading {
+        fault_response: fault_response,
+        grant_limit: grant_limit,
+        verification: verification,
+        unused_memory: app_memory_ptr,
+        unused_memory_size: app_memory_size,
+        retired_slots: 0,
+        checked_app: None,
+    };
+}
+
+/// What `load_processes` leaves behind for processes that are loaded and
+/// unloaded while the board is running.
+struct DynamicLoading {
+    fault_response: FaultResponse,
+    grant_limit: Option<usize>,
+    verification: AppVerification,
+    /// Application memory that no process has used yet. Memory of unloaded
+    /// processes is not added back, see `unload_process`.
+    unused_memory: *mut u8,
+    unused_memory_size: usize,
+    /// One bit for each slot of the process array that `unload_process`
+    /// emptied. These slots are not used again until the next reboot.
+    retired_slots: usize,
+    /// The app that `check_app` last accepted and the key that signed it,
+    /// so that `load_process` does not verify its signature a second time.
+    checked_app: Option<(*const u8, Option<[u8; 32]>)>,
+}
+
+static mut DYNAMIC_LOADING: DynamicLoading = DynamicLoading {
+    fault_response: FaultResponse::Panic,
+    grant_limit: None,
+    verification: AppVerification::Off,
+    unused_memory: 0 as *mut u8,
+    unused_memory_size: 0,
+    retired_slots: 0,
+    checked_app: None,
+};
+
+/// A valid TBF found in flash, with its total size in bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TbfEntry {
+    App(usize),
+    Padding(usize),
+}
+
+/// Look for a TBF at `address`. Returns `None` if the header is missing or
+/// invalid, which is where the list of apps in flash ends.
+pub unsafe fn tbf_entry(address: *const u8) -> Option<TbfEntry> {
+    parse_and_validate_tbf_header(address).map(|tbf_header| {
+        let total_size = tbf_header.get_total_size() as usize;
+        if tbf_header.is_app() {
+            TbfEntry::App(total_size)
+        } else {
+            TbfEntry::Padding(total_size)
+        }
+    })
+}
+
+/// Check that the TBF at `app_flash_address` is an enabled app of exactly
+/// `size` bytes that passes the verification policy of the board, i.e. that
+/// `load_process` would accept it.
+pub unsafe fn check_app(app_flash_address: *const u8, size: usize) -> ReturnCode {
+    let tbf_header = match parse_and_validate_tbf_header(app_flash_address) {
+        Some(tbf_header) => tbf_header,
+        None => return ReturnCode::EINVAL,
+    };
+    if !tbf_header.is_app() || !tbf_header.enabled() ||
+       tbf_header.get_total_size() as usize != size {
+        return ReturnCode::EINVAL;
+    }
+    DYNAMIC_LOADING.checked_app = None;
+    match verify_app(&tbf_header, app_flash_address, DYNAMIC_LOADING.verification) {
+        Ok(signer) => {
+            DYNAMIC_LOADING.checked_app = Some((app_flash_address, signer));
+            ReturnCode::SUCCESS
+        }
+        Err(reason) => {
+            record_refusal(reason);
+            ReturnCode::EPERM
+        }
+    }
+}
+
+/// Load the app whose TBF is at `app_flash_address` into an empty slot of
+/// the process array and start it, without rebooting. The process gets the
+/// settings that were passed to `load_processes` and memory that no other
+/// process has used. An app that `check_app` has just accepted is not
+/// verified again.
+///
+/// Returns `ENOMEM` if there is no empty slot or not enough memory. Slots of
+/// unloaded processes do not count as empty, and neither do slots past the
+/// number of bits in a `usize`, see `unload_process`.
+pub unsafe fn load_process(app_flash_address: *const u8) -> Result<AppId, ReturnCode> {
+    let procs = &mut PROCS;
+    let retired_slots = DYNAMIC_LOADING.retired_slots;
+    let idx = procs.iter()
+        .enumerate()
+        .position(|(i, p)| {
+            p.is_none() && i < mem::size_of::<usize>() * 8 && retired_slots & (1 << i) == 0
+        })
+        .ok_or(ReturnCode::ENOMEM)?;
+    let tbf_header = parse_and_validate_tbf_header(app_flash_address).ok_or(ReturnCode::EINVAL)?;
+    if !tbf_header.is_app() {
+        return Err(ReturnCode::EINVAL);
+    }
+
+    // `Process::create` panics if memory runs out, which is what should
+    // happen at boot but not for an app loaded later.
+    let app_ram_size = math::closest_power_of_two(tbf_header.get_minimum_app_ram_size()) as usize;
+    let loading = &mut DYNAMIC_LOADING;
+    if app_ram_size > loading.unused_memory_size {
+        return Err(ReturnCode::ENOMEM);
+    }
+    let (verification, checked_signer) = match loading.checked_app.take() {
+        Some((address, signer)) if address == app_flash_address => {
+            (AppVerification::Off, Some(signer))
+        }
+        _ => (loading.verification, None),
+    };
+
+    let (process, _, memory_used) = Process::create(app_flash_address,
+                                                    loading.unused_memory,
+                                                    loading.unused_memory_size,
+                                                    loading.fault_response,
+                                                    loading.grant_limit,
+                                                    verification,
+                                                    AppId::new(idx));
+    loading.unused_memory = loading.unused_memory.offset(memory_used as isize);
+    loading.unused_memory_size -= memory_used;
+    match process {
+        Some(mut process) => {
+            if let Some(signer) = checked_signer {
+                process.signer = signer;
+            }
+            procs[idx] = Some(process);
+            Ok(AppId::new(idx))
+        }
+        None => Err(ReturnCode::FAIL),
+    }
+}
+
+/// Stop the process at `idx` and remove it from the process array. The TBF of
+/// the process stays in flash, its location is returned so that the caller
+/// can erase it.
+///
+/// Capsules are not told that the process is gone. They may still hold its
+/// callbacks, `AppSlice`s and grants, and IPC peers may still have its memory
+/// mapped in their MPU regions. So neither its slot nor its memory is given to
+/// another process until the next reboot, and callbacks for it are dropped.
+pub unsafe fn unload_process(idx: usize) -> Result<&'static [u8], ReturnCode> {
+    let procs = &mut PROCS;
+    if idx >= procs.len() {
+        return Err(ReturnCode::EINVAL);
+    }
+    match procs[idx].take() {
+        None => Err(ReturnCode::ENODEVICE),
+        Some(mut process) => {
+            // Stopping takes anything the process still has queued out of
+            // the kernel's count of pending work.
+            process.stop();
+            if idx < mem::size_of::<usize>() * 8 {
+                DYNAMIC_LOADING.retired_slots |= 1 << idx;
+            }
+            Ok(process.text)
+        }
+    }
 }
 
 pub fn schedule(callback: FunctionCall, appid: AppId) -> bool {
@@ -95,11 +289,47 @@ pub fn schedule(callback: FunctionCall, appid: AppId) -> bool {
         None => false,
         Some(ref mut p) => {
             // TODO(alevy): validate appid liveness
-            unsafe {
-                HAVE_WORK.set(HAVE_WORK.get() + 1);
+
+            // A faulted process will never run again, so anything queued for
+            // it would only keep the kernel from sleeping.
+            if p.state == State::Fault {
+                return false;
             }
 
-            p.tasks.enqueue(Task::FunctionCall(callback))
+            // Apps that ask for it only get the latest arguments of a
+            // callback that is still pending, so a flood of events from one
+            // source cannot fill the queue.
+            if p.header.coalesce_callbacks() {
+                let pending = p.tasks.find_mut(|task| match *task {
+                    Task::FunctionCall(ref pending) => {
+                        pending.pc == callback.pc && pending.r3 == callback.r3
+                    }
+                    Task::IPC(_) => false,
+                });
+                if let Some(task) = pending {
+                    trace::upcall_enqueue(idx, trace::Enqueued::Coalesced, callback.pc, callback.r0);
+                    *task = Task::FunctionCall(callback);
+                    p.debug.coalesced_callback_count += 1;
+                    return true;
+                }
+            }
+
+            let (pc, r0) = (callback.pc, callback.r0);
+            let ret = p.tasks.enqueue(Task::FunctionCall(callback));
+            // Tasks for stopped processes are kept for when they resume, but
+            // until then they are not work the kernel can do.
+            if ret && p.state != State::Stopped {
+                unsafe {
+                    HAVE_WORK.set(HAVE_WORK.get() + 1);
+                }
+            }
+            if ret {
+                trace::upcall_enqueue(idx, trace::Enqueued::Queued, pc, r0);
+            } else {
+                trace::upcall_enqueue(idx, trace::Enqueued::Dropped, pc, r0);
+                p.debug.dropped_callback_count += 1;
+            }
+            ret
         }
     }
 }
@@ -142,17 +372,108 @@ impl From<Error> for ReturnCode {
     }
 }
 
+/// Returns the package name of the app, or an empty string if there is no
+/// app at `app_idx` or it has no name.
+pub fn get_package_name(app_idx: usize) -> &'static str {
+    let procs = unsafe { &PROCS };
+    procs.get(app_idx)
+        .and_then(|p| p.as_ref())
+        .map_or("", |p| p.package_name)
+}
+
+/// Returns the key that identifies the app across reboots, see
+/// `Process::storage_key`, or `None` if there is no app at `app_idx` or it
+/// cannot be told apart from other apps.
+pub fn get_storage_key(app_idx: usize) -> Option<[u8; 32]> {
+    let procs = unsafe { &PROCS };
+    procs.get(app_idx)
+        .and_then(|p| p.as_ref())
+        .and_then(|p| p.storage_key())
+}
+
+/// Returns how many bytes of nonvolatile storage the app asked for in its TBF
+/// header, if it asked for any.
+pub fn get_nonvolatile_storage_size(app_idx: usize) -> Option<usize> {
+    let procs = unsafe { &PROCS };
+    procs.get(app_idx)
+        .and_then(|p| p.as_ref())
+        .and_then(|p| p.header.get_nonvolatile_storage_size())
+        .map(|size| size as usize)
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum State {
+    /// Loaded with its init function queued, but has not run yet.
+    Unstarted,
     Running,
     Yielded,
     Fault,
+    /// Paused by the kernel. Keeps its state and pending tasks, but is not
+    /// scheduled until it is resumed.
+    Stopped,
 }
 
+/// How the kernel responds when a process faults.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FaultResponse {
+    /// Panic the kernel and print the state of the faulting process.
     Panic,
+    /// Reload the process from flash and start it again from its init
+    /// function, no matter how many times it has faulted before.
     Restart,
+    /// Like `Restart`, but only up to the given number of times. A process
+    /// that faults after exhausting its restarts is left in the `Fault` state
+    /// and is never scheduled again.
+    RestartLimited(usize),
+    /// Leave the process in the `Fault` state without restarting it. Its
+    /// registers and fault status are kept so they can be inspected later.
+    Stop,
+}
+
+/// Why a process was put in the `Fault` state.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultCause {
+    /// The CPU raised a fault while the process was running. The fault status
+    /// registers saved with the process tell which one.
+    Hardware,
+    /// The stack of the process grew past its bottom, either into memory
+    /// outside the process or over the canary at the lowest word of its
+    /// stack. `stack_pointer` is the stack pointer of the process when this
+    /// was noticed.
+    StackOverflow { stack_pointer: usize },
+}
+
+/// Which app images the kernel agrees to load, based on the SHA-256 hash and
+/// Ed25519 signatures in their TBF footers. Keys are 32 byte Ed25519 public
+/// keys.
+///
+/// Hashing and checking signatures is done in software while the board
+/// boots, which takes time and about 7 KB of kernel stack. Boards that check
+/// signatures need a larger `__stack_size__` than the default 0x1000.
+#[derive(Copy, Clone, Debug)]
+pub enum AppVerification {
+    /// Load every app without looking at its footers.
+    Off,
+    /// Check the hash and signatures an app has and refuse it if any of them
+    /// is wrong. Apps without footers are loaded. Signatures by keys that
+    /// are not in the list are ignored.
+    IfPresent(&'static [[u8; 32]]),
+    /// Only load apps with a valid signature by one of the keys.
+    Required(&'static [[u8; 32]]),
+}
+
+/// Which process a board trusts with privileged drivers, like the process
+/// manager and the app loader. Package names are not authenticated, any app
+/// can claim one, so the process is picked by something only whoever builds
+/// or flashes the board controls.
+#[derive(Copy, Clone, Debug)]
+pub enum Privileged {
+    /// The app whose TBF starts at this address in flash, for example the
+    /// first app at `_sapps`.
+    FlashAddress(usize),
+    /// Apps with a valid signature by this key. The key also has to be
+    /// trusted by the board's `AppVerification`.
+    SignedBy(&'static [u8; 32]),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -224,7 +545,14 @@ enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderPicOption1 = 4,
-    Unused = 5,
+    TbfHeaderFaultResponse = 5,
+    TbfHeaderScheduling = 6,
+    TbfHeaderIpcClients = 7,
+    TbfHeaderSyscallFilter = 8,
+    TbfHeaderFooterOffset = 9,
+    TbfHeaderCallbackQueue = 10,
+    TbfHeaderNonvolatileStorage = 11,
+    Unused = 12,
 }
 
 /// The TLV header (T and L).
@@ -277,6 +605,92 @@ struct PicOption1Fields {
     minimum_stack_length: u32,
 }
 
+/// How the app wants the kernel to respond when it faults.
+///
+/// `response` is 0 for panic, 1 for restart, 2 for restart at most
+/// `max_restarts` times and 3 for stop. `max_restarts` is ignored for all
+/// other responses.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderV2FaultResponse {
+    response: u32,
+    max_restarts: u32,
+}
+
+/// Scheduling parameters for the app.
+///
+/// `priority` is used by priority based schedulers, higher values run first.
+/// `timeslice_us` is how long the app may run before being preempted, 0
+/// selects the kernel default.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderV2Scheduling {
+    priority: u32,
+    timeslice_us: u32,
+}
+
+/// One entry of the syscall filter of an app.
+///
+/// The app may subscribe and allow on driver `driver_num` and call commands
+/// `min_command` through `max_command`, inclusive, on it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderV2DriverPermission {
+    driver_num: u32,
+    min_command: u32,
+    max_command: u32,
+}
+
+/// Where the footers of the app start.
+///
+/// Footers follow the app binary and run to the end of the app. Everything
+/// before `binary_end_offset`, header included, is covered by the hash and
+/// signatures in the footers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderV2FooterOffset {
+    binary_end_offset: u32,
+}
+
+/// How the kernel queues upcalls for the app.
+///
+/// `queue_depth` is how many upcalls can be pending at once, 0 selects the
+/// kernel default. If bit 0 of `flags` is set, an upcall to a callback that
+/// already has one pending replaces the arguments of the pending one instead
+/// of taking another slot.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderV2CallbackQueue {
+    queue_depth: u32,
+    flags: u32,
+}
+
+/// How many bytes of nonvolatile storage the app wants the nonvolatile
+/// storage driver to reserve for it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderV2NonvolatileStorage {
+    size: u32,
+}
+
+/// The TLV header of a footer element.
+///
+/// Footer types start at 128 so they cannot be confused with header types,
+/// and a type of 0 marks the end of the footers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfFooterTlv {
+    tipe: u16,
+    length: u16,
+}
+
+/// Footer with the SHA-256 hash of the app.
+const TBF_FOOTER_SHA256: u16 = 128;
+
+/// Footer with an Ed25519 public key followed by a signature, by that key,
+/// of the SHA-256 hash of the app.
+const TBF_FOOTER_ED25519: u16 = 129;
+
 /// Single header that can contain all parts of a v2 header.
 #[derive(Clone, Copy, Debug)]
 struct TbfHeaderV2 {
@@ -285,6 +699,13 @@ struct TbfHeaderV2 {
     pic_values: Option<&'static PicOption1Fields>,
     package_name: Option<&'static str>,
     writeable_regions: Option<&'static [TbfHeaderV2WriteableFlashRegion]>,
+    fault_response: Option<&'static TbfHeaderV2FaultResponse>,
+    scheduling: Option<&'static TbfHeaderV2Scheduling>,
+    ipc_clients: Option<&'static [u8]>,
+    syscall_filter: Option<&'static [TbfHeaderV2DriverPermission]>,
+    footer_offset: Option<&'static TbfHeaderV2FooterOffset>,
+    callback_queue: Option<&'static TbfHeaderV2CallbackQueue>,
+    nonvolatile_storage: Option<&'static TbfHeaderV2NonvolatileStorage>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -293,7 +714,7 @@ struct TbfHeaderV2 {
 /// in the tock binary, as well as other information about the application.
 /// The kernel can also use this header to keep persistent state about
 /// the application.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum TbfHeader {
     TbfHeaderV1(&'static TbfHeaderV1),
     TbfHeaderV2(TbfHeaderV2),
@@ -454,6 +875,143 @@ impl TbfHeader {
         }
     }
 
+    /// Get the fault response the app asked for in its header, if it asked
+    /// for a valid one.
+    fn get_fault_response(&self) -> Option<FaultResponse> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.fault_response.and_then(|fr| match fr.response {
+                    0 => Some(FaultResponse::Panic),
+                    1 => Some(FaultResponse::Restart),
+                    2 => Some(FaultResponse::RestartLimited(fr.max_restarts as usize)),
+                    3 => Some(FaultResponse::Stop),
+                    _ => None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the scheduling priority of the app. Apps without a scheduling
+    /// element get the lowest priority, 0.
+    fn get_priority(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.scheduling.map_or(0, |s| s.priority),
+            _ => 0,
+        }
+    }
+
+    /// Get the timeslice the app asked for, if any.
+    fn get_timeslice_us(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.scheduling.and_then(|s| if s.timeslice_us == 0 {
+                    None
+                } else {
+                    Some(s.timeslice_us)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Return whether the app lets the process named `client` use IPC with
+    /// it. Names in the list are separated by NUL bytes, and a name ending in
+    /// `*` matches every package name that starts with the rest of it. Apps
+    /// without a list allow everyone.
+    fn ipc_client_allowed(&self, client: &str) -> bool {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.ipc_clients.map_or(true, |clients| {
+                    clients.split(|c| *c == 0)
+                        .filter(|name| name.len() > 0)
+                        .any(|name| if name[name.len() - 1] == b'*' {
+                            client.as_bytes().starts_with(&name[..name.len() - 1])
+                        } else {
+                            name == client.as_bytes()
+                        })
+                })
+            }
+            _ => true,
+        }
+    }
+
+    /// Check a syscall to driver `driver_num` against the syscall filter of
+    /// the app. `command_num` is `None` for subscribe and allow, which are
+    /// permitted on every listed driver, as is the driver check command `0`.
+    /// Drivers that are not listed do not exist for the app. Apps without a
+    /// filter may use every driver.
+    fn check_syscall_filter(&self,
+                            driver_num: usize,
+                            command_num: Option<usize>)
+                            -> Result<(), ReturnCode> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.syscall_filter.map_or(Ok(()), |filter| {
+                    let mut entries =
+                        filter.iter().filter(|entry| entry.driver_num as usize == driver_num);
+                    match (entries.next(), command_num) {
+                        (None, _) => Err(ReturnCode::ENODEVICE),
+                        (Some(_), None) | (Some(_), Some(0)) => Ok(()),
+                        (Some(first), Some(command_num)) => {
+                            let permitted = |entry: &TbfHeaderV2DriverPermission| {
+                                entry.min_command as usize <= command_num &&
+                                command_num <= entry.max_command as usize
+                            };
+                            if permitted(first) || entries.any(permitted) {
+                                Ok(())
+                            } else {
+                                Err(ReturnCode::EPERM)
+                            }
+                        }
+                    }
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the offset of the first footer, which is also the length of the
+    /// part of the app covered by the footers.
+    fn get_footer_offset(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.footer_offset.map(|fo| fo.binary_end_offset),
+            _ => None,
+        }
+    }
+
+    /// Get how many upcalls the app wants to be able to have pending, if it
+    /// asked for a particular number.
+    fn get_callback_queue_depth(&self) -> Option<usize> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.callback_queue.and_then(|cq| if cq.queue_depth == 0 {
+                    None
+                } else {
+                    Some(cq.queue_depth as usize)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the app wants duplicate pending upcalls to be coalesced.
+    fn coalesce_callbacks(&self) -> bool {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.callback_queue.map_or(false, |cq| cq.flags & 0x1 != 0),
+            _ => false,
+        }
+    }
+
+    /// Get how many bytes of nonvolatile storage the app asked for, if it
+    /// asked for any.
+    fn get_nonvolatile_storage_size(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.nonvolatile_storage.map(|ns| ns.size),
+            _ => None,
+        }
+    }
+
     /// Get the offset and size of a given flash region.
     fn get_writeable_flash_region(&self, index: usize) -> (u32, u32) {
         match *self {
@@ -558,6 +1116,13 @@ unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfHeader>
                 let mut main_pointer: Option<&TbfHeaderV2Main> = None;
                 let mut pic1_pointer: Option<&PicOption1Fields> = None;
                 let mut wfr_pointer: Option<&'static [TbfHeaderV2WriteableFlashRegion]> = None;
+                let mut fault_response_pointer: Option<&TbfHeaderV2FaultResponse> = None;
+                let mut scheduling_pointer: Option<&TbfHeaderV2Scheduling> = None;
+                let mut ipc_clients_pointer: Option<&'static [u8]> = None;
+                let mut syscall_filter_pointer: Option<&'static [TbfHeaderV2DriverPermission]> = None;
+                let mut footer_offset_pointer: Option<&TbfHeaderV2FooterOffset> = None;
+                let mut callback_queue_pointer: Option<&TbfHeaderV2CallbackQueue> = None;
+                let mut nonvolatile_storage_pointer: Option<&TbfHeaderV2NonvolatileStorage> = None;
                 let mut app_name_str = "";
 
                 // Loop through the header looking for known options.
@@ -602,6 +1167,66 @@ unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfHeader>
                                     pic1_pointer = Some(tbf_pic1);
                                 }
                             }
+                            TbfHeaderTypes::TbfHeaderFaultResponse => /* Fault Response */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2FaultResponse>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2FaultResponse>() {
+                                    let tbf_fault_response = &*(address.offset(offset) as *const TbfHeaderV2FaultResponse);
+                                    fault_response_pointer = Some(tbf_fault_response);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderScheduling => /* Scheduling */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2Scheduling>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2Scheduling>() {
+                                    let tbf_scheduling = &*(address.offset(offset) as *const TbfHeaderV2Scheduling);
+                                    scheduling_pointer = Some(tbf_scheduling);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderIpcClients => /* IPC Clients */ {
+                                if remaining_length >= tbf_tlv_header.length as usize {
+                                    let ipc_clients =
+                                        slice::from_raw_parts(address.offset(offset), tbf_tlv_header.length as usize);
+                                    ipc_clients_pointer = Some(ipc_clients);
+                                } else {
+                                    // Skipping a truncated list would open the
+                                    // service to every client, refuse the app
+                                    // instead.
+                                    return None;
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderSyscallFilter => /* Syscall Filter */ {
+                                // Length must be a multiple of the size of an entry.
+                                if remaining_length >= tbf_tlv_header.length as usize &&
+                                   tbf_tlv_header.length as usize % mem::size_of::<TbfHeaderV2DriverPermission>() == 0 {
+                                    let number_entries = tbf_tlv_header.length as usize / mem::size_of::<TbfHeaderV2DriverPermission>();
+                                    let entries_start = address.offset(offset) as *const TbfHeaderV2DriverPermission;
+                                    syscall_filter_pointer = Some(slice::from_raw_parts(entries_start, number_entries));
+                                } else {
+                                    // Skipping a broken filter would give the
+                                    // app every syscall, refuse the app instead.
+                                    return None;
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderFooterOffset => /* Footer Offset */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2FooterOffset>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2FooterOffset>() {
+                                    let tbf_footer_offset = &*(address.offset(offset) as *const TbfHeaderV2FooterOffset);
+                                    footer_offset_pointer = Some(tbf_footer_offset);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderCallbackQueue => /* Callback Queue */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2CallbackQueue>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2CallbackQueue>() {
+                                    let tbf_callback_queue = &*(address.offset(offset) as *const TbfHeaderV2CallbackQueue);
+                                    callback_queue_pointer = Some(tbf_callback_queue);
+                                }
+                            }
+                            TbfHeaderTypes::TbfHeaderNonvolatileStorage => /* Nonvolatile Storage */ {
+                                if remaining_length >= mem::size_of::<TbfHeaderV2NonvolatileStorage>() &&
+                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2NonvolatileStorage>() {
+                                    let tbf_storage = &*(address.offset(offset) as *const TbfHeaderV2NonvolatileStorage);
+                                    nonvolatile_storage_pointer = Some(tbf_storage);
+                                }
+                            }
                             TbfHeaderTypes::Unused => {}
                         }
                     }
@@ -618,6 +1243,13 @@ unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfHeader>
                     pic_values: pic1_pointer,
                     package_name: Some(app_name_str),
                     writeable_regions: wfr_pointer,
+                    fault_response: fault_response_pointer,
+                    scheduling: scheduling_pointer,
+                    ipc_clients: ipc_clients_pointer,
+                    syscall_filter: syscall_filter_pointer,
+                    footer_offset: footer_offset_pointer,
+                    callback_queue: callback_queue_pointer,
+                    nonvolatile_storage: nonvolatile_storage_pointer,
                 };
 
                 Some(TbfHeader::TbfHeaderV2(tbf_header))
@@ -661,9 +1293,51 @@ struct ProcessDebug {
 
     /// What was the most recent syscall.
     last_syscall: Cell<Option<Syscall>>,
+
+    /// Copy of `SCB_REGISTERS` taken when the process last faulted. Later
+    /// faults in other processes overwrite the global copy.
+    fault_scb_registers: Option<[u32; 5]>,
+
+    /// Why the process last faulted.
+    fault_cause: Option<FaultCause>,
+
+    /// How many grant allocations have failed.
+    grant_alloc_failures: usize,
+
+    /// Grant number and size of the most recent failed grant allocation.
+    last_failed_grant_alloc: Option<(usize, usize)>,
+
+    /// How many tasks were dropped because the task queue was full.
+    dropped_callback_count: usize,
+
+    /// How many upcalls were merged into one that was already pending.
+    coalesced_callback_count: usize,
+}
+
+/// How much of the CPU a process has used. Unlike `ProcessDebug`, this is
+/// kept when the process is restarted, so it covers everything the app did
+/// since the board booted.
+#[derive(Default)]
+struct ProcessUsage {
+    /// Microseconds of timeslices the process used up, as measured by the
+    /// SysTick. Always 0 on chips without one.
+    cpu_time_us: u64,
+
+    /// How many times the process was preempted at the end of its timeslice.
+    timeslice_expirations: usize,
+
+    /// How many times the scheduler picked the process to run.
+    scheduled_count: usize,
+
+    /// How many upcalls, including the call to the init function, were
+    /// delivered to the process.
+    upcalls_delivered: usize,
 }
 
 pub struct Process<'a> {
+    /// Index of this process in `PROCS`.
+    app_id: AppId,
+
     /// Application memory layout:
     ///
     /// ```text
@@ -696,6 +1370,9 @@ pub struct Process<'a> {
     /// Pointer to the end of process RAM that has been sbrk'd to the process.
     app_break: *const u8,
 
+    /// Highest address the process allowed its own break to move to.
+    app_break_limit: Option<*const u8>,
+
     /// Saved when the app switches to the kernel.
     current_stack_pointer: *const u8,
 
@@ -717,9 +1394,26 @@ pub struct Process<'a> {
     /// Whether the scheduler can schedule this app.
     state: State,
 
+    /// What state to return to when a stopped process is resumed.
+    state_before_stop: State,
+
     /// How to deal with Faults occurring in the process
     fault_response: FaultResponse,
 
+    /// How many times the kernel has restarted this process after a fault.
+    restart_count: usize,
+
+    /// Whether the process restarted and its IPC clients have not been told
+    /// yet, see `take_restart_notification`.
+    restart_notification: bool,
+
+    /// CPU time and wakeups of the process since boot.
+    usage: ProcessUsage,
+
+    /// Largest size in bytes the grant region may grow to, if the board set
+    /// a limit.
+    grant_limit: Option<usize>,
+
     /// MPU regions are saved as a pointer-size pair.
     ///
     /// size is encoded as X where
@@ -740,10 +1434,18 @@ pub struct Process<'a> {
     /// Name of the app. Public so that IPC can use it.
     pub package_name: &'static str,
 
+    /// The trusted key that signed the app, if the board checks signatures
+    /// and the app is signed.
+    signer: Option<[u8; 32]>,
+
     /// Values kept so that we can print useful debug messages when apps fault.
     debug: ProcessDebug,
 }
 
+/// Timeslice given to processes that do not ask for a specific one in their
+/// TBF header.
+pub const DEFAULT_TIMESLICE_US: u32 = 10000;
+
 // Stores the current number of callbacks enqueued + processes in Running state
 static mut HAVE_WORK: VolatileCell<usize> = VolatileCell::new(0);
 
@@ -773,18 +1475,195 @@ pub fn ipsr_isr_number_to_str(isr_number: usize) -> &'static str {
     }
 }
 
+/// How many app images the verification policy refused since boot, and why
+/// the last one was refused.
+static mut REFUSED_APPS: (usize, Option<&'static str>) = (0, None);
+
+fn record_refusal(reason: &'static str) {
+    unsafe {
+        REFUSED_APPS = (REFUSED_APPS.0 + 1, Some(reason));
+    }
+}
+
+/// How many app images the board's `AppVerification` refused since boot, at
+/// boot or when installed, and why the last one was refused.
+pub fn refused_apps() -> (usize, Option<&'static str>) {
+    unsafe { REFUSED_APPS }
+}
+
+/// Check the footers of the app at `address` against the `verification`
+/// policy. Returns the trusted key that signed the app, if any, or why the app
+/// should not be loaded.
+unsafe fn verify_app(header: &TbfHeader,
+                     address: *const u8,
+                     verification: AppVerification)
+                     -> Result<Option<[u8; 32]>, &'static str> {
+    let keys = match verification {
+        AppVerification::Off => return Ok(None),
+        AppVerification::IfPresent(keys) |
+        AppVerification::Required(keys) => keys,
+    };
+    let signature_required = match verification {
+        AppVerification::Required(_) => true,
+        _ => false,
+    };
+
+    let total_size = header.get_total_size() as usize;
+    let binary_end = match header.get_footer_offset() {
+        Some(offset) if offset as usize <= total_size => offset as usize,
+        Some(_) => return Err("footers beyond the end of the app"),
+        None if signature_required => return Err("not signed"),
+        None => return Ok(None),
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(slice::from_raw_parts(address, binary_end));
+    let digest = hasher.finish();
+
+    let mut signer = None;
+    let mut offset = binary_end;
+    while offset + mem::size_of::<TbfFooterTlv>() <= total_size {
+        let tlv = &*(address.offset(offset as isize) as *const TbfFooterTlv);
+        offset += mem::size_of::<TbfFooterTlv>();
+        let length = tlv.length as usize;
+        if tlv.tipe == 0 {
+            break;
+        }
+        if offset + length > total_size {
+            return Err("footer beyond the end of the app");
+        }
+        let data = slice::from_raw_parts(address.offset(offset as isize), length);
+
+        match tlv.tipe {
+            TBF_FOOTER_SHA256 if length == 32 => {
+                if data != &digest[..] {
+                    return Err("hash does not match");
+                }
+            }
+            TBF_FOOTER_ED25519 if length == 96 => {
+                let mut public_key = [0; 32];
+                let mut signature = [0; 64];
+                public_key.copy_from_slice(&data[..32]);
+                signature.copy_from_slice(&data[32..]);
+                if keys.iter().any(|key| *key == public_key) {
+                    if !ed25519::verify(&public_key, &digest, &signature) {
+                        return Err("bad signature");
+                    }
+                    signer = Some(public_key);
+                }
+            }
+            _ => {}
+        }
+
+        // Footer elements are padded to four bytes like header elements.
+        offset += (4 - length % 4) % 4 + length;
+    }
+
+    if signature_required && signer.is_none() {
+        Err("not signed by a trusted key")
+    } else {
+        Ok(signer)
+    }
+}
+
+/// Set up the initial grant region at the top of a process's memory.
+///
+/// This reserves one (null) pointer for every grant in the system, below those
+/// one counter per grant of the bytes allocated for it, and below those the
+/// ring buffer that holds up to `queue_depth` pending tasks of the process.
+/// Returns the resulting kernel memory break and the empty task queue.
+unsafe fn init_grant_region<'a>(memory_end: *mut u8,
+                                queue_depth: usize)
+                                -> (*const u8, RingBuffer<'a, Task>) {
+    let mut kernel_memory_break = memory_end;
+
+    // Make room for grant pointers.
+    let pointer_size = mem::size_of::<*const usize>();
+    let num_ctrs = read_volatile(&grant::CONTAINER_COUNTER);
+    let grant_ptrs_size = num_ctrs * pointer_size;
+    kernel_memory_break = kernel_memory_break.offset(-(grant_ptrs_size as isize));
+
+    // Set all pointers to null.
+    let opts = slice::from_raw_parts_mut(kernel_memory_break as *mut *const usize,
+                                         num_ctrs);
+    for opt in opts.iter_mut() {
+        *opt = ptr::null()
+    }
+
+    // Make room for the grant usage counters and start them at zero.
+    kernel_memory_break = kernel_memory_break.offset(-((num_ctrs * mem::size_of::<usize>()) as isize));
+    let usage = slice::from_raw_parts_mut(kernel_memory_break as *mut usize, num_ctrs);
+    for counter in usage.iter_mut() {
+        *counter = 0;
+    }
+
+    // Allocate memory for callback ring buffer. The ring buffer keeps one
+    // slot empty to tell a full queue from an empty one.
+    let callback_size = mem::size_of::<Task>();
+    let callback_len = queue_depth + 1;
+    let callback_offset = callback_len * callback_size;
+    kernel_memory_break = kernel_memory_break.offset(-(callback_offset as isize));
+
+    // Set up ring buffer.
+    let callback_buf = slice::from_raw_parts_mut(kernel_memory_break as *mut Task,
+                                                 callback_len);
+    let tasks = RingBuffer::new(callback_buf);
+
+    (kernel_memory_break, tasks)
+}
+
+/// How many tasks the app can have pending, as asked for in its header and
+/// capped at `MAX_CALLBACK_QUEUE_DEPTH`.
+fn callback_queue_depth(header: &TbfHeader) -> usize {
+    header.get_callback_queue_depth()
+        .map_or(DEFAULT_CALLBACK_QUEUE_DEPTH,
+                |depth| cmp::min(depth, MAX_CALLBACK_QUEUE_DEPTH))
+}
+
 impl<'a> Process<'a> {
     pub fn schedule_ipc(&mut self, from: AppId, cb_type: IPCType) {
-        unsafe {
-            HAVE_WORK.set(HAVE_WORK.get() + 1);
+        if self.state == State::Fault {
+            return;
+        }
+        let ret = self.tasks.enqueue(Task::IPC((from, cb_type)));
+        // IPC tasks are traced with no pc, and the notifying process as r0.
+        let result = if ret {
+            trace::Enqueued::Queued
+        } else {
+            trace::Enqueued::Dropped
+        };
+        trace::upcall_enqueue(self.app_id.idx(), result, 0, from.idx());
+        if !ret {
+            self.debug.dropped_callback_count += 1;
+        } else if self.state != State::Stopped {
+            unsafe {
+                HAVE_WORK.set(HAVE_WORK.get() + 1);
+            }
         }
-        self.tasks.enqueue(Task::IPC((from, cb_type)));
     }
 
     pub fn current_state(&self) -> State {
         self.state
     }
 
+    /// Whether the process has something to do: it is running, or it has
+    /// yielded (or not started yet) and has tasks queued.
+    pub fn ready(&self) -> bool {
+        match self.state {
+            State::Running => true,
+            State::Yielded | State::Unstarted => self.tasks.has_elements(),
+            State::Fault | State::Stopped => false,
+        }
+    }
+
+    pub fn priority(&self) -> u32 {
+        self.header.get_priority()
+    }
+
+    pub fn timeslice_us(&self) -> u32 {
+        self.header.get_timeslice_us().unwrap_or(DEFAULT_TIMESLICE_US)
+    }
+
     pub fn yield_state(&mut self) {
         if self.state == State::Running {
             self.state = State::Yielded;
@@ -794,32 +1673,313 @@ impl<'a> Process<'a> {
         }
     }
 
+    pub fn app_id(&self) -> AppId {
+        self.app_id
+    }
+
+    /// Check a syscall to driver `driver_num` against the syscall filter in
+    /// the TBF header. `command_num` is `None` for subscribe and allow.
+    ///
+    /// Fails with `ENODEVICE` if the process may not use the driver at all
+    /// and with `EPERM` if it may not call that command.
+    pub fn check_syscall_filter(&self,
+                                driver_num: usize,
+                                command_num: Option<usize>)
+                                -> Result<(), ReturnCode> {
+        self.header.check_syscall_filter(driver_num, command_num)
+    }
+
+    /// Whether this process accepts IPC from the process named `client`.
+    pub fn ipc_client_allowed(&self, client: &str) -> bool {
+        self.header.ipc_client_allowed(client)
+    }
+
+    /// The trusted key that signed the app, if any.
+    pub fn signer(&self) -> Option<[u8; 32]> {
+        self.signer
+    }
+
+    /// Whether the board trusts this process with privileged drivers.
+    pub fn is_privileged(&self, privileged: Privileged) -> bool {
+        match privileged {
+            Privileged::FlashAddress(address) => self.text.as_ptr() as usize == address,
+            Privileged::SignedBy(key) => self.signer.as_ref() == Some(key),
+        }
+    }
+
+    /// A key that identifies the app across reboots and reinstalls, for
+    /// capsules that keep data for apps in nonvolatile memory. It is the
+    /// SHA-256 hash of the package name and, if the app is signed, the key
+    /// that signed it, so an app can only take the data of a signed app by
+    /// being signed by the same key. Apps without a name cannot be told
+    /// apart and have no key.
+    pub fn storage_key(&self) -> Option<[u8; 32]> {
+        if self.package_name.is_empty() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        // Names come from a TLV with a 16 bit length.
+        let name_len = self.package_name.len();
+        hasher.update(&[name_len as u8, (name_len >> 8) as u8]);
+        hasher.update(self.package_name.as_bytes());
+        if let Some(signer) = self.signer {
+            hasher.update(&signer);
+        }
+        Some(hasher.finish())
+    }
+
+    pub fn restart_count(&self) -> usize {
+        self.restart_count
+    }
+
+    pub fn syscall_count(&self) -> usize {
+        self.debug.syscall_count.get()
+    }
+
+    /// Account for one run of the process, from when the scheduler picked it
+    /// until control returned to the main loop. `cpu_time_us` is how much of
+    /// the timeslice it used.
+    pub fn account_run(&mut self, cpu_time_us: u32, timeslice_expired: bool) {
+        self.usage.cpu_time_us += cpu_time_us as u64;
+        self.usage.scheduled_count += 1;
+        if timeslice_expired {
+            self.usage.timeslice_expirations += 1;
+        }
+    }
+
+    /// Microseconds of CPU time the process has used since boot.
+    pub fn cpu_time_us(&self) -> u64 {
+        self.usage.cpu_time_us
+    }
+
+    /// How many times the process used up its timeslice since boot.
+    pub fn timeslice_expirations(&self) -> usize {
+        self.usage.timeslice_expirations
+    }
+
+    /// How many times the process was scheduled since boot.
+    pub fn scheduled_count(&self) -> usize {
+        self.usage.scheduled_count
+    }
+
+    /// How many upcalls were delivered to the process since boot.
+    pub fn upcalls_delivered(&self) -> usize {
+        self.usage.upcalls_delivered
+    }
+
+    /// How many upcalls were dropped because the task queue of the process
+    /// was full.
+    pub fn dropped_callback_count(&self) -> usize {
+        self.debug.dropped_callback_count
+    }
+
+    pub fn last_syscall(&self) -> Option<Syscall> {
+        self.debug.last_syscall.get()
+    }
+
+    /// Bytes of RAM assigned to the process.
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Bytes of RAM the process is actually using: everything below its
+    /// break plus the grant region.
+    pub fn memory_used(&self) -> usize {
+        (self.app_break as usize - self.mem_start() as usize) +
+            (self.mem_end() as usize - self.kernel_memory_break as usize)
+    }
+
+    /// The amount of pending work this process contributes to HAVE_WORK.
+    fn pending_work(&self) -> usize {
+        let running = if self.state == State::Running { 1 } else { 0 };
+        self.tasks.len() + running
+    }
+
+    /// Stop the process. It will not be scheduled again until `resume` is
+    /// called. Only running, yielded and unstarted processes can be stopped.
+    pub fn stop(&mut self) {
+        match self.state {
+            State::Running | State::Yielded | State::Unstarted => {
+                unsafe {
+                    HAVE_WORK.set(HAVE_WORK.get() - self.pending_work());
+                }
+                self.state_before_stop = self.state;
+                self.state = State::Stopped;
+            }
+            State::Fault | State::Stopped => {}
+        }
+    }
+
+    /// Let a stopped process run again from where it was stopped.
+    pub fn resume(&mut self) {
+        if self.state == State::Stopped {
+            self.state = self.state_before_stop;
+            unsafe {
+                HAVE_WORK.set(HAVE_WORK.get() + self.pending_work());
+            }
+        }
+    }
+
     pub unsafe fn fault_state(&mut self) {
+        // Stack overflows can be noticed without the CPU faulting, then the
+        // fault status registers say nothing about this process.
+        let hardware_fault = read_volatile(&APP_FAULT) != 0;
         write_volatile(&mut APP_FAULT, 0);
+
+        // A running process counts towards HAVE_WORK. Faulted ones do not.
+        if self.state == State::Running {
+            HAVE_WORK.set(HAVE_WORK.get() - 1);
+        }
         self.state = State::Fault;
+        let scb_registers = if hardware_fault {
+            read_volatile(&SCB_REGISTERS)
+        } else {
+            [0; 5]
+        };
+        let cause = if self.stack_overflowed() {
+            FaultCause::StackOverflow { stack_pointer: self.current_stack_pointer as usize }
+        } else {
+            FaultCause::Hardware
+        };
+        self.debug.fault_scb_registers = Some(scb_registers);
+        self.debug.fault_cause = Some(cause);
+
+        // Keep a record that outlives a restart of the process, or a reset
+        // of the board.
+        fault_record::record_process_fault(self, cause, scb_registers);
 
         match self.fault_response {
             FaultResponse::Panic => {
                 // process faulted. Panic and print status
+                fault_record::panicking_on_fault();
                 panic!("Process {} had a fault", self.package_name);
             }
             FaultResponse::Restart => {
-                //XXX: unimplemented
-                panic!("Process {} had a fault and could not be restarted",
-                       self.package_name);
-                /*
-                // HAVE_WORK is really screwed up in this case
-                // the tasks ring buffer needs to be cleared
-                // need to re-load() the app
-                 */
+                self.restart_count += 1;
+                self.restart();
+            }
+            FaultResponse::RestartLimited(max_restarts) => {
+                if self.restart_count < max_restarts {
+                    self.restart_count += 1;
+                    self.restart();
+                } else {
+                    // Out of restarts, the process stays faulted. Drop
+                    // anything still queued for it so the kernel can sleep.
+                    while self.dequeue_task().is_some() {}
+                }
+            }
+            FaultResponse::Stop => {
+                // Keep the registers and fault record around for debugging,
+                // but nothing queued will ever run.
+                while self.dequeue_task().is_some() {}
             }
         }
     }
 
+    /// Reload the process from its TBF header and schedule its init
+    /// function again. The process does not have to have faulted.
+    ///
+    /// All state from the previous run is discarded: pending tasks, grants,
+    /// IPC MPU regions and memory breaks. Capsules that held on to grant
+    /// memory for this process will see it as newly allocated on next use.
+    /// IPC clients of this process are told the next time the kernel runs
+    /// it, see `take_restart_notification`.
+    ///
+    /// This must not be called on the process that is currently executing.
+    pub unsafe fn restart(&mut self) {
+        // Tasks queued for the old instance must not be delivered to the new
+        // one. Dequeueing them keeps HAVE_WORK consistent.
+        if self.state == State::Running {
+            HAVE_WORK.set(HAVE_WORK.get() - 1);
+        }
+        while self.dequeue_task().is_some() {}
+
+        let app_flash_address = self.text.as_ptr();
+        let load_result = match load(self.header,
+                                     app_flash_address,
+                                     self.memory.as_mut_ptr(),
+                                     self.memory.len()) {
+            Some(load_result) => load_result,
+            // This process loaded once before, so this should not happen. If
+            // it does, leave the process faulted.
+            None => return,
+        };
+
+        let mem_end = self.memory.as_mut_ptr().offset(self.memory.len() as isize);
+        let (kernel_memory_break, tasks) = init_grant_region(mem_end, callback_queue_depth(&self.header));
+        self.kernel_memory_break = kernel_memory_break;
+        self.tasks = tasks;
+
+        self.app_break = load_result.initial_sbrk_pointer;
+        self.app_break_limit = None;
+        self.current_stack_pointer = load_result.initial_stack_pointer;
+
+        for region in self.mpu_regions.iter() {
+            region.set((ptr::null(), math::PowerOfTwo::zero()));
+        }
+
+        let init_fn = app_flash_address
+            .offset(self.header.get_init_function_offset() as isize) as usize;
+        self.stored_regs = Default::default();
+        self.yield_pc = init_fn;
+        // Set the Thumb bit and clear everything else
+        self.psr = 0x01000000;
+
+        let needs_pic_fixup = self.header.needs_pic_fixup();
+        self.debug = ProcessDebug {
+            app_heap_start_pointer: if needs_pic_fixup {
+                Some(load_result.initial_sbrk_pointer)
+            } else {
+                None
+            },
+            app_stack_start_pointer: if needs_pic_fixup {
+                Some(load_result.initial_stack_pointer)
+            } else {
+                None
+            },
+            min_stack_pointer: load_result.initial_stack_pointer,
+            syscall_count: Cell::new(0),
+            last_syscall: Cell::new(None),
+            fault_scb_registers: None,
+            fault_cause: None,
+            grant_alloc_failures: 0,
+            last_failed_grant_alloc: None,
+            dropped_callback_count: 0,
+            coalesced_callback_count: 0,
+        };
+        self.write_stack_canary();
+
+        self.state = State::Unstarted;
+
+        let flash_app_start = self.flash_non_protected_start() as usize;
+        self.tasks.enqueue(Task::FunctionCall(FunctionCall {
+            pc: init_fn,
+            r0: flash_app_start,
+            r1: self.memory.as_ptr() as usize,
+            r2: self.memory.len() as usize,
+            r3: self.app_break as usize,
+        }));
+
+        HAVE_WORK.set(HAVE_WORK.get() + 1);
+        self.restart_notification = true;
+    }
+
+    /// Whether the process restarted since the last call. IPC clients of the
+    /// process need to know that the service they were talking to lost all
+    /// of its state, but only the IPC driver knows who its clients are.
+    pub fn take_restart_notification(&mut self) -> bool {
+        let restarted = self.restart_notification;
+        self.restart_notification = false;
+        restarted
+    }
+
     pub fn dequeue_task(&mut self) -> Option<Task> {
+        let counted = self.state != State::Stopped;
         self.tasks.dequeue().map(|cb| {
-            unsafe {
-                HAVE_WORK.set(HAVE_WORK.get() - 1);
+            if counted {
+                unsafe {
+                    HAVE_WORK.set(HAVE_WORK.get() - 1);
+                }
             }
             cb
         })
@@ -849,6 +2009,26 @@ impl<'a> Process<'a> {
         self.kernel_memory_break
     }
 
+    pub fn app_memory_break(&self) -> *const u8 {
+        self.app_break
+    }
+
+    pub fn app_break_limit(&self) -> Option<*const u8> {
+        self.app_break_limit
+    }
+
+    /// Top of the stack, if the kernel set up the stack or the process told
+    /// it where the stack is.
+    pub fn app_stack_start(&self) -> Option<*const u8> {
+        self.debug.app_stack_start_pointer
+    }
+
+    /// Start of the heap, if the kernel set up the heap or the process told
+    /// it where the heap is.
+    pub fn app_heap_start(&self) -> Option<*const u8> {
+        self.debug.app_heap_start_pointer
+    }
+
     pub fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -964,7 +2144,10 @@ impl<'a> Process<'a> {
     pub unsafe fn create(app_flash_address: *const u8,
                          remaining_app_memory: *mut u8,
                          remaining_app_memory_size: usize,
-                         fault_response: FaultResponse)
+                         fault_response: FaultResponse,
+                         grant_limit: Option<usize>,
+                         verification: AppVerification,
+                         app_id: AppId)
                          -> (Option<Process<'a>>, usize, usize) {
         if let Some(tbf_header) = parse_and_validate_tbf_header(app_flash_address) {
             let app_flash_size = tbf_header.get_total_size() as usize;
@@ -975,7 +2158,19 @@ impl<'a> Process<'a> {
                 return (None, app_flash_size, 0);
             }
 
-            // Otherwise, actually load the app.
+            // Skip apps the board does not trust, before any of their code
+            // or settings are used.
+            let signer = match verify_app(&tbf_header, app_flash_address, verification) {
+                Ok(signer) => signer,
+                Err(reason) => {
+                    record_refusal(reason);
+                    return (None, app_flash_size, 0);
+                }
+            };
+
+            // Otherwise, actually load the app. Apps may override how the
+            // board responds to their faults.
+            let fault_response = tbf_header.get_fault_response().unwrap_or(fault_response);
             let min_app_ram_size = tbf_header.get_minimum_app_ram_size();
             let package_name = tbf_header.get_package_name(app_flash_address);
             let init_fn = app_flash_address.offset(tbf_header.get_init_function_offset() as isize) as usize;
@@ -1003,32 +2198,9 @@ impl<'a> Process<'a> {
                 let app_memory = slice::from_raw_parts_mut(remaining_app_memory, app_ram_size);
 
                 // Set up initial grant region.
-                let mut kernel_memory_break = app_memory.as_mut_ptr()
-                    .offset(app_memory.len() as isize);
-
-                // Make room for grant pointers.
-                let pointer_size = mem::size_of::<*const usize>();
-                let num_ctrs = read_volatile(&grant::CONTAINER_COUNTER);
-                let grant_ptrs_size = num_ctrs * pointer_size;
-                kernel_memory_break = kernel_memory_break.offset(-(grant_ptrs_size as isize));
-
-                // Set all pointers to null.
-                let opts = slice::from_raw_parts_mut(kernel_memory_break as *mut *const usize,
-                                                     num_ctrs);
-                for opt in opts.iter_mut() {
-                    *opt = ptr::null()
-                }
-
-                // Allocate memory for callback ring buffer.
-                let callback_size = mem::size_of::<Task>();
-                let callback_len = 10;
-                let callback_offset = callback_len * callback_size;
-                kernel_memory_break = kernel_memory_break.offset(-(callback_offset as isize));
-
-                // Set up ring buffer.
-                let callback_buf = slice::from_raw_parts_mut(kernel_memory_break as *mut Task,
-                                                             callback_len);
-                let tasks = RingBuffer::new(callback_buf);
+                let (kernel_memory_break, tasks) =
+                    init_grant_region(app_memory.as_mut_ptr().offset(app_memory.len() as isize),
+                                      callback_queue_depth(&tbf_header));
 
                 // Determine the debug information to the best of our
                 // understanding. If the app is doing all of the PIC fixup and
@@ -1041,12 +2213,15 @@ impl<'a> Process<'a> {
                 }
 
                 let mut process = Process {
+                    app_id: app_id,
+
                     memory: app_memory,
 
                     header: load_result.header,
 
                     kernel_memory_break: kernel_memory_break,
                     app_break: load_result.initial_sbrk_pointer,
+                    app_break_limit: None,
                     current_stack_pointer: load_result.initial_stack_pointer,
 
                     text: slice::from_raw_parts(app_flash_address, app_flash_size),
@@ -1056,8 +2231,13 @@ impl<'a> Process<'a> {
                     // Set the Thumb bit and clear everything else
                     psr: 0x01000000,
 
-                    state: State::Yielded,
+                    state: State::Unstarted,
+                    state_before_stop: State::Unstarted,
                     fault_response: fault_response,
+                    restart_count: 0,
+                    restart_notification: false,
+                    usage: ProcessUsage::default(),
+                    grant_limit: grant_limit,
 
                     mpu_regions: [Cell::new((ptr::null(), math::PowerOfTwo::zero())),
                                   Cell::new((ptr::null(), math::PowerOfTwo::zero())),
@@ -1066,6 +2246,7 @@ impl<'a> Process<'a> {
                                   Cell::new((ptr::null(), math::PowerOfTwo::zero()))],
                     tasks: tasks,
                     package_name: package_name,
+                    signer: signer,
 
                     debug: ProcessDebug {
                         app_heap_start_pointer: app_heap_start_pointer,
@@ -1073,6 +2254,12 @@ impl<'a> Process<'a> {
                         min_stack_pointer: load_result.initial_stack_pointer,
                         syscall_count: Cell::new(0),
                         last_syscall: Cell::new(None),
+                        fault_scb_registers: None,
+                        fault_cause: None,
+                        grant_alloc_failures: 0,
+                        last_failed_grant_alloc: None,
+                        dropped_callback_count: 0,
+                        coalesced_callback_count: 0,
                     }
                 };
 
@@ -1083,6 +2270,8 @@ impl<'a> Process<'a> {
                            init_fn);
                 }
 
+                process.write_stack_canary();
+
                 let flash_protected_size = process.header.get_protected_size() as usize;
                 let flash_app_start = app_flash_address as usize + flash_protected_size;
 
@@ -1112,6 +2301,8 @@ impl<'a> Process<'a> {
             Err(Error::AddressOutOfBounds)
         } else if new_break > self.kernel_memory_break {
             Err(Error::OutOfMemory)
+        } else if self.app_break_limit.map_or(false, |limit| new_break > limit) {
+            Err(Error::OutOfMemory)
         } else {
             let old_break = self.app_break;
             self.app_break = new_break;
@@ -1119,6 +2310,26 @@ impl<'a> Process<'a> {
         }
     }
 
+    /// Keep `brk` and `sbrk` from moving the app break above `limit`. The
+    /// limit can only be lowered, and not below the current break.
+    pub fn set_app_break_limit(&mut self, limit: *const u8) -> Result<(), Error> {
+        if limit < self.app_break || self.app_break_limit.map_or(false, |old| limit > old) {
+            Err(Error::AddressOutOfBounds)
+        } else {
+            self.app_break_limit = Some(limit);
+            Ok(())
+        }
+    }
+
+    /// Whether `size` bytes at `buf_start_addr` are below the app break, and
+    /// so memory the process may read and write.
+    pub fn in_app_owned_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
+        let buf_end_addr = (buf_start_addr as usize).checked_add(size);
+
+        buf_start_addr >= self.mem_start() &&
+        buf_end_addr.map_or(false, |end| end <= self.app_break as usize)
+    }
+
     pub fn in_exposed_bounds(&self, buf_start_addr: *const u8, size: usize) -> bool {
 
         let buf_end_addr = unsafe { buf_start_addr.offset(size as isize) };
@@ -1126,12 +2337,20 @@ impl<'a> Process<'a> {
         buf_start_addr >= self.mem_start() && buf_end_addr <= self.mem_end()
     }
 
-    pub unsafe fn alloc(&mut self, size: usize) -> Option<&mut [u8]> {
+    /// Allocate `size` bytes in the grant region and charge them to grant
+    /// `grant_num`. Fails if the grant region would run into the app break or
+    /// grow beyond the limit the board set.
+    pub unsafe fn alloc(&mut self, size: usize, grant_num: usize) -> Option<&mut [u8]> {
         let new_break = self.kernel_memory_break.offset(-(size as isize));
-        if new_break < self.app_break {
+        let over_limit = self.grant_limit
+            .map_or(false, |limit| self.mem_end() as usize - new_break as usize > limit);
+        if new_break < self.app_break || over_limit {
+            self.debug.grant_alloc_failures += 1;
+            self.debug.last_failed_grant_alloc = Some((grant_num, size));
             None
         } else {
             self.kernel_memory_break = new_break;
+            *self.grant_usage_ptr(grant_num) += size;
             Some(slice::from_raw_parts_mut(new_break as *mut u8, size))
         }
     }
@@ -1143,6 +2362,34 @@ impl<'a> Process<'a> {
         (self.mem_end() as *mut *mut T).offset(-(grant_num + 1))
     }
 
+    unsafe fn grant_usage_ptr(&self, grant_num: usize) -> *mut usize {
+        let num_ctrs = read_volatile(&grant::CONTAINER_COUNTER) as isize;
+        (self.mem_end() as *mut usize).offset(-(num_ctrs + grant_num as isize + 1))
+    }
+
+    /// Bytes of the grant region allocated for grant `grant_num`, or `None`
+    /// if there is no such grant.
+    pub fn grant_usage(&self, grant_num: usize) -> Option<usize> {
+        unsafe {
+            if grant_num < read_volatile(&grant::CONTAINER_COUNTER) {
+                Some(*self.grant_usage_ptr(grant_num))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Bytes of the grant region in use, including the grant pointers, usage
+    /// counters and task queue the kernel keeps there.
+    pub fn grant_region_size(&self) -> usize {
+        self.mem_end() as usize - self.kernel_memory_break as usize
+    }
+
+    /// The limit the board set on the size of the grant region.
+    pub fn grant_limit(&self) -> Option<usize> {
+        self.grant_limit
+    }
+
     pub unsafe fn grant_for<T>(&mut self, grant_num: usize) -> *mut T {
         *self.grant_ptr(grant_num)
     }
@@ -1152,7 +2399,7 @@ impl<'a> Process<'a> {
                                                      -> Option<*mut T> {
         let ctr_ptr = self.grant_ptr::<T>(grant_num);
         if (*ctr_ptr).is_null() {
-            self.alloc(mem::size_of::<T>()).map(|root_arr| {
+            self.alloc(mem::size_of::<T>(), grant_num).map(|root_arr| {
                 let root_ptr = root_arr.as_mut_ptr() as *mut T;
                 // Initialize the grant contents using ptr::write, to
                 // ensure that we don't try to drop the contents of
@@ -1183,6 +2430,7 @@ impl<'a> Process<'a> {
     /// Context switch to the process.
     pub unsafe fn push_function_call(&mut self, callback: FunctionCall) {
         HAVE_WORK.set(HAVE_WORK.get() + 1);
+        self.usage.upcalls_delivered += 1;
 
         self.state = State::Running;
         // Fill in initial stack expected by SVC handler
@@ -1225,6 +2473,32 @@ impl<'a> Process<'a> {
         }
     }
 
+    /// Put the stack canary in the lowest word of the stack. Only processes
+    /// whose memory the kernel laid out have their stack at the bottom of
+    /// their memory, the others get no canary.
+    unsafe fn write_stack_canary(&mut self) {
+        if self.header.needs_pic_fixup() {
+            write_volatile(self.memory.as_mut_ptr() as *mut usize, STACK_CANARY);
+        }
+    }
+
+    /// Whether the stack of the process has grown past its bottom: the stack
+    /// pointer is below the memory of the process, or the process overwrote
+    /// the stack canary.
+    pub fn stack_overflowed(&self) -> bool {
+        if self.current_stack_pointer < self.memory.as_ptr() {
+            return true;
+        }
+        self.header.needs_pic_fixup() &&
+        unsafe { read_volatile(self.memory.as_ptr() as *const usize) } != STACK_CANARY
+    }
+
+    /// Why the process last faulted, if it has faulted since it was last
+    /// (re)started.
+    pub fn fault_cause(&self) -> Option<FaultCause> {
+        self.debug.fault_cause
+    }
+
     pub fn svc_number(&self) -> Option<Syscall> {
         let psp = self.current_stack_pointer as *const *const u16;
         unsafe {
@@ -1303,11 +2577,14 @@ impl<'a> Process<'a> {
 
 
     pub unsafe fn fault_str<W: Write>(&mut self, writer: &mut W) {
-        let _ccr = SCB_REGISTERS[0];
-        let cfsr = SCB_REGISTERS[1];
-        let hfsr = SCB_REGISTERS[2];
-        let mmfar = SCB_REGISTERS[3];
-        let bfar = SCB_REGISTERS[4];
+        // Prefer the registers saved when this process faulted, they are
+        // still accurate if another process faulted since.
+        let scb_registers = self.debug.fault_scb_registers.unwrap_or(SCB_REGISTERS);
+        let _ccr = scb_registers[0];
+        let cfsr = scb_registers[1];
+        let hfsr = scb_registers[2];
+        let mmfar = scb_registers[3];
+        let bfar = scb_registers[4];
 
         let iaccviol = (cfsr & 0x01) == 0x01;
         let daccviol = (cfsr & 0x02) == 0x02;
@@ -1334,9 +2611,20 @@ impl<'a> Process<'a> {
         let vecttbl = (hfsr & 0x02) == 0x02;
         let forced = (hfsr & 0x40000000) == 0x40000000;
 
+        let overflow_stack_pointer = match self.debug.fault_cause {
+            Some(FaultCause::StackOverflow { stack_pointer }) => Some(stack_pointer),
+            _ => None,
+        };
+
 
         let _ = writer.write_fmt(format_args!("\r\n---| Fault Status |---\r\n"));
 
+        if let Some(stack_pointer) = overflow_stack_pointer {
+            let _ =
+                writer.write_fmt(format_args!("Stack Overflow, Stack Pointer:      {:#010X}\r\n",
+                                              stack_pointer));
+        }
+
         if iaccviol {
             let _ =
                 writer.write_fmt(format_args!("Instruction Access Violation:       {}\r\n",
@@ -1439,7 +2727,9 @@ impl<'a> Process<'a> {
         }
 
         if cfsr == 0 && hfsr == 0 {
-            let _ = writer.write_fmt(format_args!("No faults detected.\r\n"));
+            if overflow_stack_pointer.is_none() {
+                let _ = writer.write_fmt(format_args!("No faults detected.\r\n"));
+            }
         } else {
             let _ =
                 writer.write_fmt(format_args!("Fault Status Register (CFSR):       {:#010X}\r\n",
@@ -1450,14 +2740,41 @@ impl<'a> Process<'a> {
         }
     }
 
-    pub unsafe fn statistics_str<W: Write>(&mut self, writer: &mut W) {
+    /// Print how much grant memory each grant uses and any failed grant allocations.
+    pub fn grant_usage_str<W: Write>(&self, writer: &mut W) {
+        let _ = writer.write_fmt(format_args!(" Grant region: {} bytes", self.grant_region_size()));
+        let _ = match self.grant_limit {
+            Some(limit) => writer.write_fmt(format_args!(" of at most {}\r\n", limit)),
+            None => writer.write_fmt(format_args!("\r\n")),
+        };
+        let mut grant_num = 0;
+        while let Some(bytes) = self.grant_usage(grant_num) {
+            if bytes > 0 {
+                let _ = writer.write_fmt(format_args!("  {:>6} bytes  {:>2} {}\r\n",
+                                                      bytes,
+                                                      grant_num,
+                                                      grant::grant_name(grant_num)));
+            }
+            grant_num += 1;
+        }
+        if let Some((grant_num, size)) = self.debug.last_failed_grant_alloc {
+            let _ = writer.write_fmt(format_args!(" Failed grant allocations: {}   \
+                                                   Last: {} bytes for {} {}\r\n",
+                                                  self.debug.grant_alloc_failures,
+                                                  size,
+                                                  grant_num,
+                                                  grant::grant_name(grant_num)));
+        }
+    }
+
+    /// Write a diagram of the process's flash and RAM regions to `writer`.
+    pub unsafe fn memory_map_str<W: Write>(&self, writer: &mut W) {
         // Flash
         let flash_end = self.text.as_ptr().offset(self.text.len() as isize) as usize;
         let flash_start = self.text.as_ptr() as usize;
         let flash_protected_size = self.header.get_protected_size() as usize;
         let flash_app_start = flash_start + flash_protected_size;
         let flash_app_size = flash_end - flash_app_start;
-        let flash_init_fn = flash_start + self.header.get_init_function_offset() as usize;
 
         // SRAM addresses
         let sram_end = self.memory.as_ptr().offset(self.memory.len() as isize) as usize;
@@ -1492,10 +2809,60 @@ impl<'a> Process<'a> {
             sram_stack_error_str = " EXCEEDED!"
         }
 
+        let _ = writer.write_fmt(format_args!("\
+\r\n ╔═══════════╤══════════════════════════════════════════╗\
+\r\n ║  Address  │ Region Name    Used | Allocated (bytes)  ║\
+\r\n ╚{:#010X}═╪══════════════════════════════════════════╝\
+\r\n             │ ▼ Grant      {:6} | {:6}{}\
+  \r\n  {:#010X} ┼───────────────────────────────────────────\
+\r\n             │ Unused\
+  \r\n  {:#010X} ┼───────────────────────────────────────────\
+\r\n             │ ▲ Heap       {:6} | {:6}{}     S\
+  \r\n  {:#010X} ┼─────────────────────────────────────────── R\
+\r\n             │ Data         {:6} | {:6}               A\
+  \r\n  {:#010X} ┼─────────────────────────────────────────── M\
+\r\n             │ ▼ Stack      {:6} | {:6}{}\
+  \r\n  {:#010X} ┼───────────────────────────────────────────\
+\r\n             │ Unused\
+  \r\n  {:#010X} ┴───────────────────────────────────────────\
+\r\n             .....\
+  \r\n  {:#010X} ┬─────────────────────────────────────────── F\
+\r\n             │ App Flash    {:6}                        L\
+  \r\n  {:#010X} ┼─────────────────────────────────────────── A\
+\r\n             │ Protected    {:6}                        S\
+  \r\n  {:#010X} ┴─────────────────────────────────────────── H\
+\r\n\
+",
+  sram_end,
+  sram_grant_size, sram_grant_allocated, sram_grant_error_str,
+  sram_grant_start,
+  sram_heap_end,
+  sram_heap_size, sram_heap_allocated, sram_heap_error_str,
+  sram_heap_start,
+  sram_data_size, sram_data_allocated,
+  sram_stack_start,
+  sram_stack_size, sram_stack_allocated, sram_stack_error_str,
+  sram_stack_bottom,
+  sram_start,
+  flash_end,
+  flash_app_size,
+  flash_app_start,
+  flash_protected_size,
+  flash_start,
+  ));
+    }
+
+    pub unsafe fn statistics_str<W: Write>(&mut self, writer: &mut W) {
+        let flash_start = self.text.as_ptr() as usize;
+        let flash_init_fn = flash_start + self.header.get_init_function_offset() as usize;
+        let sram_start = self.memory.as_ptr() as usize;
+
         // application statistics
         let events_queued = self.tasks.len();
         let syscall_count = self.debug.syscall_count.get();
         let last_syscall = self.debug.last_syscall.get();
+        let events_dropped = self.debug.dropped_callback_count;
+        let events_coalesced = self.debug.coalesced_callback_count;
 
         // register values
         let (r0, r1, r2, r3, r12, sp, lr, pc, xpsr) = (self.r0(),
@@ -1509,11 +2876,14 @@ impl<'a> Process<'a> {
                                                        self.xpsr());
 
         let _ = writer.write_fmt(format_args!("\
-        App: {}   -   [{:?}]\
-        \r\n Events Queued: {}   Syscall Count: {}   ",
+        App: {}   -   [{:?}]   Restarts: {}\
+        \r\n Events Queued: {}   Dropped: {}   Coalesced: {}   Syscall Count: {}   ",
                                               self.package_name,
                                               self.state,
+                                              self.restart_count,
                                               events_queued,
+                                              events_dropped,
+                                              events_coalesced,
                                               syscall_count,
                                               ));
 
@@ -1522,30 +2892,20 @@ impl<'a> Process<'a> {
             None => writer.write_fmt(format_args!("Last Syscall: None")),
         };
 
+        let cpu_time_us = self.usage.cpu_time_us;
+        let _ = writer.write_fmt(format_args!("\
+        \r\n CPU Time: {}.{:03} ms   Scheduled: {}   Timeslice Expirations: {}   Upcalls: {}",
+                                              cpu_time_us / 1000,
+                                              cpu_time_us % 1000,
+                                              self.usage.scheduled_count,
+                                              self.usage.timeslice_expirations,
+                                              self.usage.upcalls_delivered));
+
+        let _ = writer.write_fmt(format_args!("\r\n"));
+        self.memory_map_str(writer);
+        self.grant_usage_str(writer);
+
         let _ = writer.write_fmt(format_args!("\
-\r\n\
-\r\n ╔═══════════╤══════════════════════════════════════════╗\
-\r\n ║  Address  │ Region Name    Used | Allocated (bytes)  ║\
-\r\n ╚{:#010X}═╪══════════════════════════════════════════╝\
-\r\n             │ ▼ Grant      {:6} | {:6}{}\
-  \r\n  {:#010X} ┼───────────────────────────────────────────\
-\r\n             │ Unused\
-  \r\n  {:#010X} ┼───────────────────────────────────────────\
-\r\n             │ ▲ Heap       {:6} | {:6}{}     S\
-  \r\n  {:#010X} ┼─────────────────────────────────────────── R\
-\r\n             │ Data         {:6} | {:6}               A\
-  \r\n  {:#010X} ┼─────────────────────────────────────────── M\
-\r\n             │ ▼ Stack      {:6} | {:6}{}\
-  \r\n  {:#010X} ┼───────────────────────────────────────────\
-\r\n             │ Unused\
-  \r\n  {:#010X} ┴───────────────────────────────────────────\
-\r\n             .....\
-  \r\n  {:#010X} ┬─────────────────────────────────────────── F\
-\r\n             │ App Flash    {:6}                        L\
-  \r\n  {:#010X} ┼─────────────────────────────────────────── A\
-\r\n             │ Protected    {:6}                        S\
-  \r\n  {:#010X} ┴─────────────────────────────────────────── H\
-\r\n\
   \r\n  R0 : {:#010X}    R6 : {:#010X}\
   \r\n  R1 : {:#010X}    R7 : {:#010X}\
   \r\n  R2 : {:#010X}    R8 : {:#010X}\
@@ -1558,22 +2918,6 @@ impl<'a> Process<'a> {
   \r\n  PC : {:#010X}\
   \r\n YPC : {:#010X}\
 \r\n",
-  sram_end,
-  sram_grant_size, sram_grant_allocated, sram_grant_error_str,
-  sram_grant_start,
-  sram_heap_end,
-  sram_heap_size, sram_heap_allocated, sram_heap_error_str,
-  sram_heap_start,
-  sram_data_size, sram_data_allocated,
-  sram_stack_start,
-  sram_stack_size, sram_stack_allocated, sram_stack_error_str,
-  sram_stack_bottom,
-  sram_start,
-  flash_end,
-  flash_app_size,
-  flash_app_start,
-  flash_protected_size,
-  flash_start,
   r0, self.stored_regs.r6,
   r1, self.stored_regs.r7,
   r2, self.stored_regs.r8,
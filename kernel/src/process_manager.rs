@@ -0,0 +1,238 @@
+//! Process lifecycle management for Tock.
+//!
+//! This is a special syscall driver that lets one designated process, for
+//! example an on-device supervisor, list the other processes on the board and
+//! stop, resume or restart them. The board picks the manager with a
+//! `process::Privileged`, by its place in flash or the key that signed it.
+//! Every other process gets `EPERM` for all operations.
+//!
+//! Processes are identified by their index in the board's process array,
+//! which is the same number as their `AppId`. The manager cannot act on
+//! itself.
+//!
+//! The manager can also read the record of the last process fault or kernel
+//! panic, which survives a reset of the board, see `fault_record`.
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x00010001;
+
+use {AppId, AppSlice, Driver, Grant, Shared};
+use fault_record;
+use process::{self, State};
+use returncode::ReturnCode;
+
+#[derive(Default)]
+struct App {
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct ProcessManager {
+    manager: process::Privileged,
+    apps: Grant<App>,
+}
+
+impl ProcessManager {
+    pub unsafe fn new(manager: process::Privileged) -> ProcessManager {
+        ProcessManager {
+            manager: manager,
+            apps: Grant::create(),
+        }
+    }
+
+    /// Copy the last fault record into the buffer of the manager.
+    fn copy_fault_record(&self, appid: AppId) -> ReturnCode {
+        let record = match fault_record::last() {
+            Some(record) => record,
+            None => return ReturnCode::SuccessWithValue { value: 0 },
+        };
+        let bytes = record.as_bytes();
+        self.apps
+            .enter(appid, |app, _| {
+                app.buffer.as_mut().map_or(ReturnCode::ENOMEM, |buffer| {
+                    if buffer.len() < bytes.len() {
+                        return ReturnCode::ESIZE;
+                    }
+                    buffer.as_mut()[..bytes.len()].copy_from_slice(bytes);
+                    ReturnCode::SuccessWithValue { value: bytes.len() }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Copy `name` into the buffer of the manager.
+    fn copy_name(&self, appid: AppId, name: &str) -> ReturnCode {
+        let name = name.as_bytes();
+        self.apps
+            .enter(appid, |app, _| {
+                app.buffer.as_mut().map_or(ReturnCode::ENOMEM, |buffer| {
+                    for (dst, src) in buffer.iter_mut().zip(name.iter()) {
+                        *dst = *src;
+                    }
+                    ReturnCode::SuccessWithValue { value: name.len() }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Whether `appid` is the designated manager process.
+    fn is_manager(&self, appid: AppId) -> bool {
+        let procs = unsafe { &process::PROCS };
+        procs.get(appid.idx())
+            .and_then(|p| p.as_ref())
+            .map_or(false, |p| p.is_privileged(self.manager))
+    }
+}
+
+/// Encode a process state as the number returned to userspace.
+fn state_to_usize(state: State) -> usize {
+    match state {
+        State::Unstarted => 0,
+        State::Running => 1,
+        State::Yielded => 2,
+        State::Fault => 3,
+        State::Stopped => 4,
+    }
+}
+
+impl Driver for ProcessManager {
+    /// allow lets the manager pass the buffer that `command(5)` copies
+    /// package names and `command(12)` copies fault records into.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for package names and fault records.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        if !self.is_manager(appid) {
+            return ReturnCode::EPERM;
+        }
+        match allow_num {
+            0 => {
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.buffer = Some(slice);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// command queries and controls processes. `target` is the index of the
+    /// process to act on.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return the number of process slots on the board.
+    /// - `1`: Return the state of the process: 0 unstarted, 1 running,
+    ///        2 yielded, 3 faulted, 4 stopped.
+    /// - `2`: Return how many syscalls the process has made.
+    /// - `3`: Return how many bytes of RAM are assigned to the process.
+    /// - `4`: Return how many bytes of RAM the process is using.
+    /// - `5`: Copy the package name of the process into the allowed buffer
+    ///        and return its full length.
+    /// - `6`: Stop the process.
+    /// - `7`: Resume a stopped process.
+    /// - `8`: Restart the process from its init function.
+    /// - `9`: Return how many times the process has been restarted after a
+    ///        fault.
+    /// - `10`: Return how many bytes of grant memory the process is using.
+    /// - `11`: Return how many upcalls to the process were dropped because
+    ///         its callback queue was full.
+    /// - `12`: Copy the record of the last fault into the allowed buffer and
+    ///         return its length, or 0 if there is no record. `target` is
+    ///         ignored.
+    /// - `13`: Clear the record of the last fault. `target` is ignored.
+    /// - `14`: Return how many milliseconds of CPU time the process has used
+    ///         since boot.
+    /// - `15`: Return how many times the process used up its timeslice since
+    ///         boot.
+    /// - `16`: Return how many times the process was scheduled since boot.
+    /// - `17`: Return how many upcalls were delivered to the process since
+    ///         boot.
+    ///
+    /// All commands except `0`, `12` and `13` return `EINVAL` if `target` is
+    /// beyond the end of the process array and `ENODEVICE` if there is no
+    /// process in that slot.
+    fn command(&self, command_num: usize, target: usize, _: usize, appid: AppId) -> ReturnCode {
+        if !self.is_manager(appid) {
+            return ReturnCode::EPERM;
+        }
+
+        let procs = unsafe { &mut process::PROCS };
+        match command_num {
+            0 => return ReturnCode::SuccessWithValue { value: procs.len() },
+            12 => return self.copy_fault_record(appid),
+            13 => {
+                fault_record::clear();
+                return ReturnCode::SUCCESS;
+            }
+            _ => {}
+        }
+        if target >= procs.len() {
+            return ReturnCode::EINVAL;
+        }
+
+        // Changing the state of the process that is making this call would
+        // pull the rug out from under the scheduler.
+        if target == appid.idx() && command_num >= 6 && command_num <= 8 {
+            return ReturnCode::EINVAL;
+        }
+
+        // Entering the grant of the manager borrows its process, which may be
+        // the target, so the name is copied out of the target first.
+        if command_num == 5 {
+            let name = match procs[target] {
+                None => return ReturnCode::ENODEVICE,
+                Some(ref p) => p.package_name,
+            };
+            return self.copy_name(appid, name);
+        }
+
+        match procs[target] {
+            None => ReturnCode::ENODEVICE,
+            Some(ref mut p) => {
+                match command_num {
+                    1 => ReturnCode::SuccessWithValue { value: state_to_usize(p.current_state()) },
+                    2 => ReturnCode::SuccessWithValue { value: p.syscall_count() },
+                    3 => ReturnCode::SuccessWithValue { value: p.memory_size() },
+                    4 => ReturnCode::SuccessWithValue { value: p.memory_used() },
+                    6 => {
+                        match p.current_state() {
+                            State::Stopped => ReturnCode::EALREADY,
+                            State::Fault => ReturnCode::EINVAL,
+                            _ => {
+                                p.stop();
+                                ReturnCode::SUCCESS
+                            }
+                        }
+                    }
+                    7 => {
+                        match p.current_state() {
+                            State::Stopped => {
+                                p.resume();
+                                ReturnCode::SUCCESS
+                            }
+                            State::Fault => ReturnCode::EINVAL,
+                            _ => ReturnCode::EALREADY,
+                        }
+                    }
+                    8 => {
+                        unsafe {
+                            p.restart();
+                        }
+                        ReturnCode::SUCCESS
+                    }
+                    9 => ReturnCode::SuccessWithValue { value: p.restart_count() },
+                    10 => ReturnCode::SuccessWithValue { value: p.grant_region_size() },
+                    11 => ReturnCode::SuccessWithValue { value: p.dropped_callback_count() },
+                    14 => ReturnCode::SuccessWithValue { value: (p.cpu_time_us() / 1000) as usize },
+                    15 => ReturnCode::SuccessWithValue { value: p.timeslice_expirations() },
+                    16 => ReturnCode::SuccessWithValue { value: p.scheduled_count() },
+                    17 => ReturnCode::SuccessWithValue { value: p.upcalls_delivered() },
+                    _ => ReturnCode::ENOSUPPORT,
+                }
+            }
+        }
+    }
+}
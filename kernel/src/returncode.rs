@@ -21,6 +21,7 @@ pub enum ReturnCode {
     ENODEVICE, //..... Device does not exist
     EUNINSTALLED, //.. Device is not physically installed
     ENOACK, //........ Packet transmission not acknowledged
+    EPERM, //......... Not allowed for this process
 }
 
 impl From<ReturnCode> for isize {
@@ -41,6 +42,7 @@ impl From<ReturnCode> for isize {
             ReturnCode::ENODEVICE => -11,
             ReturnCode::EUNINSTALLED => -12,
             ReturnCode::ENOACK => -13,
+            ReturnCode::EPERM => -14,
         }
     }
 }
@@ -1,5 +1,6 @@
 //! Tock core scheduler.
 
+use core::cmp;
 use core::nonzero::NonZero;
 use memop;
 use platform::{Chip, Platform};
@@ -8,21 +9,49 @@ use platform::systick::SysTick;
 use process;
 use process::{Process, Task};
 use returncode::ReturnCode;
+use scheduler::StoppedReason;
 use syscall::Syscall;
+use trace;
 
+/// Do not switch to a process if less than this many microseconds are left
+/// in its timeslice.
+const MIN_QUANTA_THRESHOLD_US: u32 = 500;
+
+/// Shortest timeslice a process gets, whatever its TBF header or the
+/// scheduler asks for. Anything at or below `MIN_QUANTA_THRESHOLD_US` would
+/// expire before the process ever ran.
+const MIN_TIMESLICE_US: u32 = MIN_QUANTA_THRESHOLD_US + 500;
+
+/// Run `process` until it yields with nothing left to do, faults, uses up
+/// `timeslice_us` or an interrupt needs servicing, and return which one
+/// happened. `timeslice_us` is clamped to what the SysTick can count and to
+/// at least `MIN_TIMESLICE_US`.
 pub unsafe fn do_process<P: Platform, C: Chip>(platform: &P,
                                                chip: &mut C,
                                                process: &mut Process,
                                                appid: ::AppId,
-                                               ipc: &::ipc::IPC) {
+                                               ipc: &::ipc::IPC,
+                                               timeslice_us: u32)
+                                               -> StoppedReason {
+    // A process restarted while it was not running, e.g. by the process
+    // manager, still owes its IPC clients a notification.
+    if process.take_restart_notification() {
+        ipc.notify_restarted(appid);
+    }
+
     let systick = chip.systick();
+    let timeslice_us = cmp::max(cmp::min(timeslice_us, systick.max_timer_us()),
+                                MIN_TIMESLICE_US);
     systick.reset();
-    systick.set_timer(10000);
+    systick.set_timer(timeslice_us);
     systick.enable(true);
 
-    loop {
-        if chip.has_pending_interrupts() || systick.overflowed() || systick.value() <= 500 {
-            break;
+    let reason = loop {
+        if chip.has_pending_interrupts() {
+            break StoppedReason::Interrupted;
+        }
+        if systick.overflowed() || systick.value() <= MIN_QUANTA_THRESHOLD_US {
+            break StoppedReason::TimesliceExpired;
         }
 
         match process.current_state() {
@@ -30,16 +59,27 @@ pub unsafe fn do_process<P: Platform, C: Chip>(platform: &P,
                 process.setup_mpu(chip.mpu());
                 chip.mpu().enable_mpu();
                 systick.enable(true);
+                trace::switch_in(process);
                 process.switch_to();
+                trace::switch_out(process);
                 systick.enable(false);
                 chip.mpu().disable_mpu();
+
+                // A stack that grew past its bottom may not have faulted,
+                // for example if it only overwrote the canary. Stop the
+                // process before it runs on with corrupted memory.
+                if process.stack_overflowed() {
+                    process.fault_state();
+                    continue;
+                }
             }
-            process::State::Yielded => {
+            process::State::Yielded | process::State::Unstarted => {
                 match process.dequeue_task() {
-                    None => break,
+                    None => break StoppedReason::Yielded,
                     Some(cb) => {
                         match cb {
                             Task::FunctionCall(ccb) => {
+                                trace::upcall_delivery(appid.idx(), ccb.pc, ccb.r0);
                                 process.push_function_call(ccb);
                             }
                             Task::IPC((otherapp, ipc_type)) => {
@@ -51,13 +91,22 @@ pub unsafe fn do_process<P: Platform, C: Chip>(platform: &P,
                 }
             }
             process::State::Fault => {
-                // we should never be scheduling a process in fault
-                panic!("Attempted to schedule a faulty process");
+                // A faulted process that was not restarted is never run again.
+                break StoppedReason::Faulted;
+            }
+            process::State::Stopped => {
+                break StoppedReason::Stopped;
             }
         }
 
         if !process.syscall_fired() {
-            break;
+            // The process was preempted, either by the end of its timeslice
+            // or by an interrupt.
+            if systick.overflowed() {
+                break StoppedReason::TimesliceExpired;
+            } else {
+                break StoppedReason::Interrupted;
+            }
         }
 
         // check if the app had a fault
@@ -70,6 +119,7 @@ pub unsafe fn do_process<P: Platform, C: Chip>(platform: &P,
 
         // process had a system call, count it
         process.incr_syscall_count();
+        trace::syscall_entry(process);
         match process.svc_number() {
             Some(Syscall::MEMOP) => {
                 let res = memop::memop(process);
@@ -88,7 +138,9 @@ pub unsafe fn do_process<P: Platform, C: Chip>(platform: &P,
                 let callback_ptr_raw = process.r2() as *mut ();
                 let appdata = process.r3();
 
-                let res = if callback_ptr_raw as usize == 0 {
+                let res = if let Err(err) = process.check_syscall_filter(driver_num, None) {
+                    err
+                } else if callback_ptr_raw as usize == 0 {
                     ReturnCode::EINVAL
                 } else {
                     let callback_ptr = NonZero::new_unchecked(callback_ptr_raw);
@@ -102,32 +154,60 @@ pub unsafe fn do_process<P: Platform, C: Chip>(platform: &P,
                 process.set_return_code(res);
             }
             Some(Syscall::COMMAND) => {
-                let res = platform.with_driver(process.r0(), |driver| match driver {
-                    Some(d) => d.command(process.r1(), process.r2(), process.r3(), appid),
-                    None => ReturnCode::ENODEVICE,
-                });
+                let res = match process.check_syscall_filter(process.r0(), Some(process.r1())) {
+                    Err(err) => err,
+                    Ok(()) => {
+                        platform.with_driver(process.r0(), |driver| match driver {
+                            Some(d) => d.command(process.r1(), process.r2(), process.r3(), appid),
+                            None => ReturnCode::ENODEVICE,
+                        })
+                    }
+                };
                 process.set_return_code(res);
             }
             Some(Syscall::ALLOW) => {
-                let res = platform.with_driver(process.r0(), |driver| {
-                    match driver {
-                        Some(d) => {
-                            let start_addr = process.r2() as *mut u8;
-                            let size = process.r3();
-                            if process.in_exposed_bounds(start_addr, size) {
-                                let slice = ::AppSlice::new(start_addr as *mut u8, size, appid);
-                                d.allow(appid, process.r1(), slice)
-                            } else {
-                                ReturnCode::EINVAL /* memory not allocated to process */
+                let res = match process.check_syscall_filter(process.r0(), None) {
+                    Err(err) => err,
+                    Ok(()) => {
+                        platform.with_driver(process.r0(), |driver| {
+                            match driver {
+                                Some(d) => {
+                                    let start_addr = process.r2() as *mut u8;
+                                    let size = process.r3();
+                                    if process.in_exposed_bounds(start_addr, size) {
+                                        let slice = ::AppSlice::new(start_addr as *mut u8,
+                                                                    size,
+                                                                    appid);
+                                        d.allow(appid, process.r1(), slice)
+                                    } else {
+                                        ReturnCode::EINVAL /* memory not allocated to process */
+                                    }
+                                }
+                                None => ReturnCode::ENODEVICE,
                             }
-                        }
-                        None => ReturnCode::ENODEVICE,
+                        })
                     }
-                });
+                };
                 process.set_return_code(res);
             }
             _ => {}
         }
-    }
+        trace::syscall_exit(process);
+    };
+
+    // Kernel work done on behalf of the process, like handling its syscalls,
+    // counts towards its CPU time. Reading the SysTick's overflow flag
+    // clears it, and it was already read to decide that the timeslice
+    // expired, so go by `reason` instead.
+    let used_us = if reason == StoppedReason::TimesliceExpired {
+        timeslice_us
+    } else {
+        timeslice_us.saturating_sub(systick.value())
+    };
+    process.account_run(used_us, reason == StoppedReason::TimesliceExpired);
     systick.reset();
+    if process.take_restart_notification() {
+        ipc.notify_restarted(appid);
+    }
+    reason
 }
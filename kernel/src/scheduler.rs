@@ -0,0 +1,201 @@
+//! Policies for choosing which process the kernel runs next.
+//!
+//! The main loop asks the board's `Scheduler` for a process to run, runs it
+//! until it yields, uses up its timeslice or an interrupt arrives, and then
+//! tells the scheduler why it stopped. Three policies are provided:
+//!
+//!   * `RoundRobin` runs every ready process in turn. This is the traditional
+//!   Tock behavior.
+//!
+//!   * `StaticPriority` always runs the ready process with the highest
+//!   priority from its TBF header, round robin among equal priorities.
+//!
+//!   * `MultilevelFeedbackQueue` favors processes that yield quickly over
+//!   processes that use up their timeslices, and periodically gives every
+//!   process a fresh start.
+
+use core::cell::Cell;
+use process::Process;
+
+/// Why a process stopped running and control returned to the main loop.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StoppedReason {
+    /// The process yielded and has no more queued tasks.
+    Yielded,
+    /// The process used up its timeslice.
+    TimesliceExpired,
+    /// An interrupt is pending and the kernel needs to service it.
+    Interrupted,
+    /// The process faulted and was not restarted.
+    Faulted,
+    /// The process is stopped.
+    Stopped,
+}
+
+/// Interface for scheduling policies.
+///
+/// All methods take `&self`, implementations keep their state in `Cell`s.
+pub trait Scheduler {
+    /// Return the index in `processes` of the next process to run, or `None`
+    /// if no process is ready.
+    fn next(&self, processes: &[Option<Process>]) -> Option<usize>;
+
+    /// How long, in microseconds, the process at `idx` may run before it is
+    /// preempted. Defaults to the timeslice from the process's TBF header.
+    fn timeslice_us(&self, _idx: usize, process: &Process) -> u32 {
+        process.timeslice_us()
+    }
+
+    /// Called after the process at `idx` stopped running.
+    fn stopped(&self, _idx: usize, _reason: StoppedReason) {}
+}
+
+/// Search `processes` for a ready process, starting at `start` and wrapping
+/// around. Among ready processes, the first one for which `better` returns
+/// true against all previously found candidates wins.
+fn find_ready<F>(processes: &[Option<Process>], start: usize, better: F) -> Option<usize>
+    where F: Fn(usize, &Process, usize, &Process) -> bool
+{
+    let len = processes.len();
+    let mut best: Option<usize> = None;
+    for offset in 0..len {
+        let idx = (start + offset) % len;
+        if let Some(ref p) = processes[idx] {
+            if !p.ready() {
+                continue;
+            }
+            best = match best {
+                None => Some(idx),
+                Some(best_idx) => {
+                    let better_than_best = processes[best_idx]
+                        .as_ref()
+                        .map_or(true, |best_p| better(idx, p, best_idx, best_p));
+                    if better_than_best { Some(idx) } else { Some(best_idx) }
+                }
+            };
+        }
+    }
+    best
+}
+
+/// Run every ready process in turn.
+pub struct RoundRobin {
+    next: Cell<usize>,
+}
+
+impl RoundRobin {
+    pub const fn new() -> RoundRobin {
+        RoundRobin { next: Cell::new(0) }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn next(&self, processes: &[Option<Process>]) -> Option<usize> {
+        find_ready(processes, self.next.get(), |_, _, _, _| false).map(|idx| {
+            self.next.set((idx + 1) % processes.len());
+            idx
+        })
+    }
+}
+
+/// Always run the ready process with the highest priority.
+///
+/// Priorities come from the scheduling element of the TBF header, higher
+/// values run first. Processes without one have priority 0. A high priority
+/// process that never yields starves everything below it.
+pub struct StaticPriority {
+    next: Cell<usize>,
+}
+
+impl StaticPriority {
+    pub const fn new() -> StaticPriority {
+        StaticPriority { next: Cell::new(0) }
+    }
+}
+
+impl Scheduler for StaticPriority {
+    fn next(&self, processes: &[Option<Process>]) -> Option<usize> {
+        // Starting the search after the last process we ran rotates among
+        // processes of equal priority.
+        find_ready(processes,
+                   self.next.get(),
+                   |_, p, _, best| p.priority() > best.priority())
+            .map(|idx| {
+                self.next.set((idx + 1) % processes.len());
+                idx
+            })
+    }
+}
+
+/// Number of queues in the multilevel feedback queue scheduler.
+const MLFQ_NUM_LEVELS: usize = 3;
+
+/// Number of scheduling decisions between moving all processes back to the
+/// highest priority queue.
+const MLFQ_BOOST_INTERVAL: usize = 100;
+
+/// Multilevel feedback queue scheduler.
+///
+/// Every process starts in the highest priority queue. A process that uses
+/// up its whole timeslice moves down one queue, and processes in lower queues
+/// get longer timeslices (the process's own timeslice doubled for each level).
+/// Every `MLFQ_BOOST_INTERVAL` decisions all processes move back to the top so
+/// that long-running processes are not starved forever. TBF priorities are
+/// ignored.
+///
+/// The board provides one `Cell` per process slot to hold the current queue
+/// of each process. Processes beyond the end of `levels` are always treated as
+/// being in the lowest queue.
+pub struct MultilevelFeedbackQueue<'a> {
+    levels: &'a [Cell<usize>],
+    next: Cell<usize>,
+    decisions: Cell<usize>,
+}
+
+impl<'a> MultilevelFeedbackQueue<'a> {
+    pub const fn new(levels: &'a [Cell<usize>]) -> MultilevelFeedbackQueue<'a> {
+        MultilevelFeedbackQueue {
+            levels: levels,
+            next: Cell::new(0),
+            decisions: Cell::new(0),
+        }
+    }
+
+    fn level(&self, idx: usize) -> usize {
+        self.levels.get(idx).map_or(MLFQ_NUM_LEVELS - 1, |level| level.get())
+    }
+}
+
+impl<'a> Scheduler for MultilevelFeedbackQueue<'a> {
+    fn next(&self, processes: &[Option<Process>]) -> Option<usize> {
+        self.decisions.set(self.decisions.get() + 1);
+        if self.decisions.get() >= MLFQ_BOOST_INTERVAL {
+            self.decisions.set(0);
+            for level in self.levels.iter() {
+                level.set(0);
+            }
+        }
+
+        find_ready(processes,
+                   self.next.get(),
+                   |idx, _, best_idx, _| self.level(idx) < self.level(best_idx))
+            .map(|idx| {
+                self.next.set((idx + 1) % processes.len());
+                idx
+            })
+    }
+
+    fn timeslice_us(&self, idx: usize, process: &Process) -> u32 {
+        process.timeslice_us().saturating_mul(1 << self.level(idx))
+    }
+
+    fn stopped(&self, idx: usize, reason: StoppedReason) {
+        if reason == StoppedReason::TimesliceExpired {
+            if let Some(level) = self.levels.get(idx) {
+                if level.get() < MLFQ_NUM_LEVELS - 1 {
+                    level.set(level.get() + 1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Kernel event tracing.
+//!
+//! With the `trace` feature of the kernel crate enabled, the kernel records
+//! syscalls, upcalls, context switches and interrupts into a ring of
+//! fixed-size binary events. A board drains the ring, usually over a UART
+//! with the `trace_uart` capsule, and `tools/trace_decode.py` turns the
+//! stream into a readable log or a Chrome trace.
+//!
+//! Without the feature every function in this module compiles to nothing,
+//! so the hooks can stay in the kernel at no cost.
+//!
+//! Each event is `EVENT_LEN` little endian bytes:
+//!
+//! ```text
+//! 0         4      5     6       8      12     16
+//! +---------+------+-----+-------+------+------+
+//! |timestamp| kind | app | extra | arg0 | arg1 |
+//! +---------+------+-----+-------+------+------+
+//! ```
+//!
+//! | kind              | extra       | arg0                | arg1                |
+//! |-------------------|-------------|---------------------|---------------------|
+//! | 1 syscall entry   | syscall     | r0 (driver)         | r1 (command)        |
+//! | 2 syscall exit    | syscall     | r1 (command)        | return value        |
+//! | 3 upcall enqueue  | `Enqueued`  | callback pc         | r0 of the callback  |
+//! | 4 upcall delivery | 0           | callback pc         | r0 of the callback  |
+//! | 5 switch in       | 0           | 0                   | 0                   |
+//! | 6 switch out      | 1 if syscall| pc of the process   | 0                   |
+//! | 7 interrupt       | 0           | interrupt number    | 0                   |
+//!
+//! The exit of a syscall does not repeat the driver, it is in the entry
+//! that precedes it for the same process. `app` is the index of the process,
+//! 0xff for interrupts. Timestamps come from the clock the board sets with
+//! `set_clock` and wrap at 2^32.
+//!
+//! Events are recorded from the main loop of the kernel only, never from
+//! interrupt handlers, so recording needs no locking. When the ring is full
+//! new events are dropped and counted, see `lost()`.
+
+use process::Process;
+
+/// Bytes of one event.
+pub const EVENT_LEN: usize = 16;
+
+/// Events the ring holds before it drops new ones.
+pub const BUFFER_EVENTS: usize = 128;
+
+/// The `app` of events that do not belong to a process.
+pub const NO_APP: usize = 0xff;
+
+/// Types of trace events.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    SyscallEntry = 1,
+    SyscallExit = 2,
+    UpcallEnqueue = 3,
+    UpcallDelivery = 4,
+    SwitchIn = 5,
+    SwitchOut = 6,
+    Interrupt = 7,
+}
+
+/// What happened to an upcall when it was enqueued.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Enqueued {
+    /// The upcall was added to the callback queue of the process.
+    Queued = 0,
+    /// The callback queue was full and the upcall was dropped.
+    Dropped = 1,
+    /// The upcall replaced an identical pending one.
+    Coalesced = 2,
+}
+
+/// Whether the kernel was built with tracing.
+pub fn enabled() -> bool {
+    cfg!(feature = "trace")
+}
+
+/// Set the timestamp source for events. `now` is called for every event and
+/// must be cheap, a free running cycle counter is ideal. `hz` is the rate of
+/// the clock, which is passed on to the decoder.
+pub unsafe fn set_clock(now: fn() -> u32, hz: u32) {
+    imp::set_clock(now, hz);
+}
+
+/// Rate of the timestamp clock, 0 if the board did not set one.
+pub fn clock_hz() -> u32 {
+    imp::clock_hz()
+}
+
+/// Copy as many whole events as fit into `buf`, oldest first, and remove
+/// them from the ring. Returns the number of bytes copied.
+pub fn drain(buf: &mut [u8]) -> usize {
+    imp::drain(buf)
+}
+
+/// Number of events dropped because the ring was full, since boot.
+pub fn lost() -> u32 {
+    imp::lost()
+}
+
+/// Number of events waiting in the ring.
+pub fn pending() -> usize {
+    imp::pending()
+}
+
+/// Record that `process` made a syscall, before it is handled.
+#[inline(always)]
+pub fn syscall_entry(process: &Process) {
+    if enabled() {
+        imp::record(EventKind::SyscallEntry,
+                    process.app_id().idx(),
+                    syscall_number(process),
+                    process.r0(),
+                    process.r1());
+    }
+}
+
+/// Record the return value of the syscall `process` made.
+#[inline(always)]
+pub fn syscall_exit(process: &Process) {
+    if enabled() {
+        imp::record(EventKind::SyscallExit,
+                    process.app_id().idx(),
+                    syscall_number(process),
+                    process.r1(),
+                    process.r0());
+    }
+}
+
+fn syscall_number(process: &Process) -> usize {
+    process.svc_number().map_or(0xff, |svc| svc as usize)
+}
+
+#[inline(always)]
+pub fn upcall_enqueue(app: usize, result: Enqueued, pc: usize, r0: usize) {
+    imp::record(EventKind::UpcallEnqueue, app, result as usize, pc, r0);
+}
+
+#[inline(always)]
+pub fn upcall_delivery(app: usize, pc: usize, r0: usize) {
+    imp::record(EventKind::UpcallDelivery, app, 0, pc, r0);
+}
+
+#[inline(always)]
+pub fn switch_in(process: &Process) {
+    imp::record(EventKind::SwitchIn, process.app_id().idx(), 0, 0, 0);
+}
+
+/// Record that `process` stopped running, either because it made a syscall
+/// or because it was preempted at its current pc.
+#[inline(always)]
+pub fn switch_out(process: &Process) {
+    if enabled() {
+        imp::record(EventKind::SwitchOut,
+                    process.app_id().idx(),
+                    unsafe { process.syscall_fired() } as usize,
+                    process.pc(),
+                    0);
+    }
+}
+
+#[inline(always)]
+pub fn interrupt(number: usize) {
+    imp::record(EventKind::Interrupt, NO_APP, 0, number, 0);
+}
+
+#[cfg(feature = "trace")]
+mod imp {
+    use super::{BUFFER_EVENTS, EVENT_LEN, EventKind};
+
+    #[derive(Clone, Copy)]
+    struct Event {
+        timestamp: u32,
+        kind: u8,
+        app: u8,
+        extra: u16,
+        arg0: u32,
+        arg1: u32,
+    }
+
+    impl Event {
+        fn encode(&self, buf: &mut [u8]) {
+            put_u32(&mut buf[0..4], self.timestamp);
+            buf[4] = self.kind;
+            buf[5] = self.app;
+            buf[6] = self.extra as u8;
+            buf[7] = (self.extra >> 8) as u8;
+            put_u32(&mut buf[8..12], self.arg0);
+            put_u32(&mut buf[12..16], self.arg1);
+        }
+    }
+
+    fn put_u32(buf: &mut [u8], value: u32) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = (value >> (8 * i)) as u8;
+        }
+    }
+
+    const EMPTY: Event = Event {
+        timestamp: 0,
+        kind: 0,
+        app: 0,
+        extra: 0,
+        arg0: 0,
+        arg1: 0,
+    };
+
+    static mut EVENTS: [Event; BUFFER_EVENTS] = [EMPTY; BUFFER_EVENTS];
+    /// Index of the oldest event.
+    static mut HEAD: usize = 0;
+    static mut LEN: usize = 0;
+    static mut LOST: u32 = 0;
+    static mut CLOCK: Option<fn() -> u32> = None;
+    static mut CLOCK_HZ: u32 = 0;
+
+    pub unsafe fn set_clock(now: fn() -> u32, hz: u32) {
+        CLOCK = Some(now);
+        CLOCK_HZ = hz;
+    }
+
+    pub fn clock_hz() -> u32 {
+        unsafe { CLOCK_HZ }
+    }
+
+    pub fn record(kind: EventKind, app: usize, extra: usize, arg0: usize, arg1: usize) {
+        unsafe {
+            if LEN == BUFFER_EVENTS {
+                LOST = LOST.wrapping_add(1);
+                return;
+            }
+            EVENTS[(HEAD + LEN) % BUFFER_EVENTS] = Event {
+                timestamp: CLOCK.map_or(0, |now| now()),
+                kind: kind as u8,
+                app: app as u8,
+                extra: extra as u16,
+                arg0: arg0 as u32,
+                arg1: arg1 as u32,
+            };
+            LEN += 1;
+        }
+    }
+
+    pub fn drain(buf: &mut [u8]) -> usize {
+        unsafe {
+            let mut copied = 0;
+            for chunk in buf.chunks_mut(EVENT_LEN) {
+                if LEN == 0 || chunk.len() < EVENT_LEN {
+                    break;
+                }
+                EVENTS[HEAD].encode(chunk);
+                HEAD = (HEAD + 1) % BUFFER_EVENTS;
+                LEN -= 1;
+                copied += EVENT_LEN;
+            }
+            copied
+        }
+    }
+
+    pub fn lost() -> u32 {
+        unsafe { LOST }
+    }
+
+    pub fn pending() -> usize {
+        unsafe { LEN }
+    }
+}
+
+#[cfg(not(feature = "trace"))]
+mod imp {
+    use super::EventKind;
+
+    pub unsafe fn set_clock(_now: fn() -> u32, _hz: u32) {}
+
+    pub fn clock_hz() -> u32 {
+        0
+    }
+
+    #[inline(always)]
+    pub fn record(_kind: EventKind, _app: usize, _extra: usize, _arg0: usize, _arg1: usize) {}
+
+    pub fn drain(_buf: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn lost() -> u32 {
+        0
+    }
+
+    pub fn pending() -> usize {
+        0
+    }
+}
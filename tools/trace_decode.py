@@ -0,0 +1,226 @@
+#!/usr/bin/env python
+# Decode the kernel trace stream sent by the trace_uart capsule.
+#
+# Reads frames from a capture file, a serial port or stdin and prints one line
+# per event, or writes a Chrome trace (chrome://tracing, Perfetto) with
+# --chrome. Bytes between frames, for example console output on a shared
+# serial port, are ignored.
+#
+#   tools/trace_decode.py capture.bin
+#   tools/trace_decode.py --serial /dev/ttyUSB0
+#   tools/trace_decode.py --chrome trace.json capture.bin
+#
+# See doc/Tracing.md for the format.
+from __future__ import print_function
+import argparse
+import json
+import struct
+import sys
+
+MAGIC = b'\x7eT'
+HEADER = struct.Struct('<2sBBII')
+EVENT = struct.Struct('<IBBHII')
+VERSION = 1
+NO_APP = 0xff
+
+SYSCALL_ENTRY = 1
+SYSCALL_EXIT = 2
+UPCALL_ENQUEUE = 3
+UPCALL_DELIVERY = 4
+SWITCH_IN = 5
+SWITCH_OUT = 6
+INTERRUPT = 7
+
+SYSCALLS = ['yield', 'subscribe', 'command', 'allow', 'memop']
+ENQUEUED = ['queued', 'dropped', 'coalesced']
+
+
+def syscall_name(number):
+    if number < len(SYSCALLS):
+        return SYSCALLS[number]
+    return 'syscall {}'.format(number)
+
+
+def frames(data):
+    """Yield (clock_hz, lost, events) for every valid frame in data."""
+    i = 0
+    while True:
+        i = data.find(MAGIC, i)
+        if i < 0 or i + HEADER.size > len(data):
+            return
+        _, count, version, hz, lost = HEADER.unpack_from(data, i)
+        end = i + HEADER.size + count * EVENT.size
+        if version != VERSION or end >= len(data):
+            i += 1
+            continue
+        checksum = 0
+        for byte in bytearray(data[i + 2:end]):
+            checksum ^= byte
+        if checksum != bytearray(data[end:end + 1])[0]:
+            i += 1
+            continue
+        events = [EVENT.unpack_from(data, i + HEADER.size + n * EVENT.size)
+                  for n in range(count)]
+        yield hz, lost, events
+        i = end + 1
+
+
+class Decoder(object):
+    """Turns frames into events with timestamps in microseconds."""
+
+    def __init__(self):
+        self.last_raw = None
+        self.wraps = 0
+        self.lost = 0
+        self.hz = 0
+
+    def timestamp(self, raw):
+        # Events are in order, so a timestamp that went backwards wrapped.
+        if self.last_raw is not None and raw < self.last_raw:
+            self.wraps += 1
+        self.last_raw = raw
+        ticks = (self.wraps << 32) + raw
+        if self.hz == 0:
+            return float(ticks)
+        return ticks * 1e6 / self.hz
+
+    def events(self, data):
+        for hz, lost, events in frames(data):
+            self.hz = hz
+            if lost != self.lost:
+                yield None, 'lost', lost - self.lost
+                self.lost = lost
+            for raw, kind, app, extra, arg0, arg1 in events:
+                yield self.timestamp(raw), kind, (app, extra, arg0, arg1)
+
+
+def describe(kind, app, extra, arg0, arg1):
+    if kind == SYSCALL_ENTRY:
+        return 'app {} {} r0={:#x} r1={:#x}'.format(app, syscall_name(extra), arg0, arg1)
+    if kind == SYSCALL_EXIT:
+        ret = struct.unpack('<i', struct.pack('<I', arg1))[0]
+        return 'app {} {} returned {}'.format(app, syscall_name(extra), ret)
+    if kind == UPCALL_ENQUEUE:
+        what = ENQUEUED[extra] if extra < len(ENQUEUED) else str(extra)
+        return 'app {} upcall {} pc={:#x} r0={:#x}'.format(app, what, arg0, arg1)
+    if kind == UPCALL_DELIVERY:
+        return 'app {} upcall delivered pc={:#x} r0={:#x}'.format(app, arg0, arg1)
+    if kind == SWITCH_IN:
+        return 'app {} switched in'.format(app)
+    if kind == SWITCH_OUT:
+        why = 'syscall' if extra else 'preempted'
+        return 'app {} switched out ({}) pc={:#x}'.format(app, why, arg0)
+    if kind == INTERRUPT:
+        return 'interrupt {}'.format(arg0)
+    return 'unknown event {} app {} {} {:#x} {:#x}'.format(kind, app, extra, arg0, arg1)
+
+
+def print_text(decoder, data, out):
+    for ts, kind, args in decoder.events(data):
+        if kind == 'lost':
+            print('--- {} events lost'.format(args), file=out)
+            continue
+        unit = 'us' if decoder.hz else 'ticks'
+        print('{:14.1f} {} {}'.format(ts, unit, describe(kind, *args)), file=out)
+
+
+def chrome_trace(decoder, data):
+    """Processes are threads of a single "tock" process. The time they run
+    and the syscalls they make, which the kernel handles after switching
+    out of the process, are complete events. Interrupts, upcalls and lost
+    events are instant events."""
+    trace = []
+    running = {}
+    syscalls = {}
+    names = set()
+
+    def thread(app):
+        return 'kernel' if app == NO_APP else 'app {}'.format(app)
+
+    def tid(app):
+        names.add(app)
+        return app
+
+    for ts, kind, args in decoder.events(data):
+        if kind == 'lost':
+            trace.append({'name': '{} events lost'.format(args), 'ph': 'i', 's': 'g',
+                          'ts': trace[-1]['ts'] if trace else 0, 'pid': 0, 'tid': tid(NO_APP)})
+            running.clear()
+            syscalls.clear()
+            continue
+        app, extra, arg0, arg1 = args
+        event = {'pid': 0, 'tid': tid(app), 'ts': ts}
+        if kind == SWITCH_IN:
+            running[app] = ts
+        elif kind == SWITCH_OUT and app in running:
+            event.update(name='running', ph='X', dur=ts - running.pop(app),
+                         args={'stopped': 'syscall' if extra else 'preempted',
+                               'pc': hex(arg0)})
+            trace.append(event)
+        elif kind == SYSCALL_ENTRY:
+            syscalls[app] = (ts, extra, arg0, arg1)
+        elif kind == SYSCALL_EXIT and app in syscalls:
+            start, number, r0, r1 = syscalls.pop(app)
+            ret = struct.unpack('<i', struct.pack('<I', arg1))[0]
+            event.update(name=syscall_name(number), ph='X', ts=start, dur=ts - start,
+                         args={'r0': hex(r0), 'r1': hex(r1), 'return': ret})
+            trace.append(event)
+        elif kind in (UPCALL_ENQUEUE, UPCALL_DELIVERY, INTERRUPT):
+            event.update(name=describe(kind, *args), ph='i', s='t')
+            trace.append(event)
+
+    for app in sorted(names):
+        trace.append({'name': 'thread_name', 'ph': 'M', 'pid': 0, 'tid': app,
+                      'args': {'name': thread(app)}})
+    trace.append({'name': 'process_name', 'ph': 'M', 'pid': 0,
+                  'args': {'name': 'tock'}})
+    return {'traceEvents': trace, 'displayTimeUnit': 'ns'}
+
+
+def read_serial(port, baud):
+    try:
+        import serial
+    except ImportError:
+        print("ERROR: Could not import serial. You can install it using:\n\n" +
+              "\tpip install -U pyserial\n", file=sys.stderr)
+        sys.exit(1)
+    data = b''
+    with serial.Serial(port, baud, timeout=1) as s:
+        try:
+            while True:
+                data += s.read(4096)
+        except KeyboardInterrupt:
+            pass
+    return data
+
+
+def main():
+    parser = argparse.ArgumentParser(description='Decode a Tock kernel trace.')
+    parser.add_argument('capture', nargs='?',
+                        help='file with the raw serial output, stdin if omitted')
+    parser.add_argument('--serial', metavar='PORT',
+                        help='read from a serial port until interrupted with ctrl-c')
+    parser.add_argument('--baud', type=int, default=115200,
+                        help='baud rate of the serial port (default 115200)')
+    parser.add_argument('--chrome', metavar='FILE',
+                        help='write a Chrome trace to FILE instead of printing events')
+    args = parser.parse_args()
+
+    if args.serial:
+        data = read_serial(args.serial, args.baud)
+    elif args.capture:
+        with open(args.capture, 'rb') as f:
+            data = f.read()
+    else:
+        data = getattr(sys.stdin, 'buffer', sys.stdin).read()
+
+    decoder = Decoder()
+    if args.chrome:
+        with open(args.chrome, 'w') as f:
+            json.dump(chrome_trace(decoder, data), f)
+    else:
+        print_text(decoder, data, sys.stdout)
+
+
+if __name__ == '__main__':
+    main()
@@ -36,6 +36,56 @@ TOCK_ARCHS ?= cortex-m0 cortex-m4
 ELF2TBF ?= cargo run --manifest-path $(TOCK_USERLAND_BASE_DIR)/tools/elf2tbf/Cargo.toml --
 ELF2TBF_ARGS += --include-pic-info -n $(PACKAGE_NAME)
 
+# FAULT_RESPONSE, if set, overrides how the kernel handles faults in this app
+ifneq ($(FAULT_RESPONSE),)
+ELF2TBF_ARGS += --fault-response $(FAULT_RESPONSE)
+endif
+
+# PRIORITY and TIMESLICE_US, if set, are used by the kernel scheduler
+ifneq ($(PRIORITY),)
+ELF2TBF_ARGS += --priority $(PRIORITY)
+endif
+ifneq ($(TIMESLICE_US),)
+ELF2TBF_ARGS += --timeslice $(TIMESLICE_US)
+endif
+
+# IPC_CLIENTS, if set, is a comma separated list of the package names that may
+# use IPC with this app. A name ending in * matches every name with that prefix.
+ifneq ($(IPC_CLIENTS),)
+ELF2TBF_ARGS += --ipc-clients $(IPC_CLIENTS)
+endif
+
+# SYSCALL_FILTER, if set, is a comma separated list of the drivers this app may
+# use. DRIVER:MIN-MAX limits the app to a range of commands of that driver.
+ifneq ($(SYSCALL_FILTER),)
+ELF2TBF_ARGS += --syscall-filter $(SYSCALL_FILTER)
+endif
+
+# CALLBACK_QUEUE_DEPTH, if set, is how many upcalls to this app can be pending.
+# COALESCE_CALLBACKS, if set to 1, merges upcalls to a callback that already
+# has one pending.
+ifneq ($(CALLBACK_QUEUE_DEPTH),)
+ELF2TBF_ARGS += --callback-queue $(CALLBACK_QUEUE_DEPTH)
+endif
+ifeq ($(COALESCE_CALLBACKS),1)
+ELF2TBF_ARGS += --coalesce-callbacks
+endif
+
+# NONVOLATILE_STORAGE_SIZE, if set, is how many bytes of the nonvolatile
+# storage driver this app gets. Otherwise it gets the default of the board.
+ifneq ($(NONVOLATILE_STORAGE_SIZE),)
+ELF2TBF_ARGS += --nonvolatile-storage $(NONVOLATILE_STORAGE_SIZE)
+endif
+
+# APP_SHA256, if set to 1, appends the SHA-256 hash of the app. APP_SIGNING_KEY,
+# if set, is a file with the Ed25519 secret key to sign the app with.
+ifeq ($(APP_SHA256),1)
+ELF2TBF_ARGS += --sha256
+endif
+ifneq ($(APP_SIGNING_KEY),)
+ELF2TBF_ARGS += --sign $(APP_SIGNING_KEY)
+endif
+
 # Flags for building app Assembly, C, C++ files
 # n.b. make convention is that CPPFLAGS are shared for C and C++ sources
 # [CFLAGS is C only, CXXFLAGS is C++ only]
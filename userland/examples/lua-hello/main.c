@@ -2,6 +2,7 @@
 #include <stdbool.h>
 #include <stdlib.h>
 #include <timer.h>
+#include <tock.h>
 
 #include "lauxlib.h"
 #include "lua.h"
@@ -19,7 +20,22 @@ void delay_ms(int ms) {
 }
 #endif
 
+// Bytes of the grant region to keep free for the drivers this app uses.
+#define GRANT_HEADROOM 1024
+
 int main(void) {
+#if !defined(__unix__)
+  // Cap the heap below the grant region, so that when Lua runs out of memory
+  // its allocations fail rather than the kernel's allocations for the app.
+  tock_app_layout_t layout;
+  if (tock_app_layout(&layout, sizeof(layout)) >= 0) {
+    char* limit = (char*) layout.kernel_break - GRANT_HEADROOM;
+    if (tock_app_set_break_limit(limit) == TOCK_SUCCESS) {
+      printf("Lua heap can grow by %d bytes\n", (int) (limit - (char*) layout.app_break));
+    }
+  }
+#endif
+
   // Open lua
   lua_State *L = luaL_newstate();
 
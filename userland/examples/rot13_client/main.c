@@ -24,6 +24,11 @@ static void rot13_callback(__attribute__ ((unused)) int pid,
 
 int main(void) {
   rot13_svc_num = ipc_discover("org.tockos.examples.rot13");
+  // The service may not have registered yet.
+  while (rot13_svc_num == TOCK_EBUSY) {
+    delay_ms(10);
+    rot13_svc_num = ipc_discover("org.tockos.examples.rot13");
+  }
   if (rot13_svc_num < 0) {
     printf("No rot13 service\n");
     return -1;
@@ -28,6 +28,11 @@ static void ipc_callback(__attribute__ ((unused)) int pid,
 
 int main(void) {
   _svc_num = ipc_discover("org.tockos.services.ble-ess");
+  // The service may not have registered yet.
+  while (_svc_num == TOCK_EBUSY) {
+    delay_ms(10);
+    _svc_num = ipc_discover("org.tockos.services.ble-ess");
+  }
   if (_svc_num < 0) {
     printf("No BLE ESS service installed.\n");
     return -1;
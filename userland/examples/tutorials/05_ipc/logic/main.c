@@ -57,6 +57,11 @@ static uint16_t get_two_random_bytes(void) {
 int main(void) {
   // Retrieve a handle to the LED service.
   _led_service = ipc_discover("org.tockos.tutorials.ipc.led");
+  // The service may not have registered yet.
+  while (_led_service == TOCK_EBUSY) {
+    delay_ms(10);
+    _led_service = ipc_discover("org.tockos.tutorials.ipc.led");
+  }
   if (_led_service < 0) {
     printf("No led service\n");
     return -1;
@@ -68,6 +73,11 @@ int main(void) {
 
   // Retrieve a handle to the RNG service.
   _rng_service = ipc_discover("org.tockos.tutorials.ipc.rng");
+  // The service may not have registered yet.
+  while (_rng_service == TOCK_EBUSY) {
+    delay_ms(10);
+    _rng_service = ipc_discover("org.tockos.tutorials.ipc.rng");
+  }
   if (_rng_service < 0) {
     printf("No rng service\n");
     return -1;
@@ -0,0 +1,74 @@
+#include "app_loader.h"
+
+struct app_loader_data {
+  bool fired;
+  int result;
+};
+
+static struct app_loader_data result = { .fired = false, .result = 0 };
+
+static void app_loader_cb(int ret,
+                          __attribute__ ((unused)) int command_num,
+                          __attribute__ ((unused)) int unused,
+                          void* ud) {
+  struct app_loader_data* data = (struct app_loader_data*) ud;
+  data->fired  = true;
+  data->result = ret;
+}
+
+// Runs `command_num` and waits for its callback.
+static int app_loader_command_sync(int command_num, int arg) {
+  int err = subscribe(APP_LOADER_DRIVER_NUM, 0, app_loader_cb, (void*) &result);
+  if (err < 0) return err;
+
+  result.fired = false;
+  err = command(APP_LOADER_DRIVER_NUM, command_num, arg, 0);
+  if (err < 0) return err;
+
+  yield_for(&result.fired);
+  return result.result;
+}
+
+int app_loader_page_size(void) {
+  return command(APP_LOADER_DRIVER_NUM, 1, 0, 0);
+}
+
+int app_loader_start(size_t len) {
+  return command(APP_LOADER_DRIVER_NUM, 2, len, 0);
+}
+
+int app_loader_write(const uint8_t* chunk, size_t len) {
+  int err = allow(APP_LOADER_DRIVER_NUM, 0, (void*) chunk, len);
+  if (err < 0) return err;
+  return app_loader_command_sync(3, len);
+}
+
+int app_loader_finish(void) {
+  return app_loader_command_sync(4, 0);
+}
+
+int app_loader_abort(void) {
+  return command(APP_LOADER_DRIVER_NUM, 5, 0, 0);
+}
+
+int app_loader_install(const uint8_t* image, size_t len) {
+  int page_size = app_loader_page_size();
+  if (page_size < 0) return page_size;
+
+  int err = app_loader_start(len);
+  if (err < 0) return err;
+
+  for (size_t offset = 0; offset < len; offset += page_size) {
+    err = app_loader_write(image + offset, page_size);
+    if (err < 0) {
+      app_loader_abort();
+      return err;
+    }
+  }
+
+  return app_loader_finish();
+}
+
+int app_loader_uninstall(int index) {
+  return app_loader_command_sync(6, index);
+}
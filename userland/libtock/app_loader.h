@@ -0,0 +1,40 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define APP_LOADER_DRIVER_NUM 0x10003
+
+// All of these functions only work for the process the board designated as
+// the process manager. Everyone else gets TOCK_EPERM. Buffers passed to them
+// must be in RAM.
+
+// Returns the flash page size. Chunks of an image must not cross a page
+// boundary.
+int app_loader_page_size(void);
+
+// Installs a TBF image in pieces, for images that do not fit in RAM at once.
+// `app_loader_start` reserves flash for an image of `len` bytes, which must
+// be a power of two and at least one page, as elf2tbf produces them.
+// `app_loader_write` passes the next chunk and `app_loader_finish` checks
+// the image and starts it, returning the index of the new process.
+// `app_loader_abort` gives up on the install.
+int app_loader_start(size_t len);
+int app_loader_write(const uint8_t* chunk, size_t len);
+int app_loader_finish(void);
+int app_loader_abort(void);
+
+// Installs the TBF `image` of `len` bytes and starts it. Returns the index
+// of the new process.
+int app_loader_install(const uint8_t* image, size_t len);
+
+// Stops the process at `index` and erases its TBF from flash. The caller
+// cannot uninstall itself.
+int app_loader_uninstall(int index);
+
+#ifdef __cplusplus
+}
+#endif
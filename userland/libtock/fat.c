@@ -0,0 +1,88 @@
+#include <string.h>
+
+#include "fat.h"
+
+struct fat_data {
+  bool fired;
+  int result;
+};
+
+static struct fat_data result = { .fired = false, .result = 0 };
+
+static void fat_cb(int ret,
+                   __attribute__ ((unused)) int command_num,
+                   __attribute__ ((unused)) int unused,
+                   void* ud) {
+  struct fat_data* data = (struct fat_data*) ud;
+  data->fired  = true;
+  data->result = ret;
+}
+
+// Runs `command_num` and waits for its callback.
+static int fat_command_sync(int command_num, int arg1, int arg2) {
+  int err = subscribe(FAT_DRIVER_NUM, 0, fat_cb, (void*) &result);
+  if (err < 0) return err;
+
+  result.fired = false;
+  err = command(FAT_DRIVER_NUM, command_num, arg1, arg2);
+  if (err < 0) return err;
+
+  yield_for(&result.fired);
+  return result.result;
+}
+
+int fat_open(const char* path, int flags) {
+  size_t len = strlen(path);
+  int err    = allow(FAT_DRIVER_NUM, 0, (void*) path, len);
+  if (err < 0) return err;
+  return fat_command_sync(1, len, flags);
+}
+
+int fat_close(int fd) {
+  return command(FAT_DRIVER_NUM, 2, fd, 0);
+}
+
+int fat_read(int fd, uint8_t* buf, size_t len) {
+  int err = allow(FAT_DRIVER_NUM, 1, (void*) buf, len);
+  if (err < 0) return err;
+  return fat_command_sync(3, fd, len);
+}
+
+int fat_write(int fd, const uint8_t* buf, size_t len) {
+  int err = allow(FAT_DRIVER_NUM, 1, (void*) buf, len);
+  if (err < 0) return err;
+  return fat_command_sync(4, fd, len);
+}
+
+int fat_seek(int fd, size_t position) {
+  return command(FAT_DRIVER_NUM, 7, fd, position);
+}
+
+int fat_size(int fd) {
+  return command(FAT_DRIVER_NUM, 8, fd, 0);
+}
+
+int fat_readdir(int fd, char* name, size_t len, uint32_t* size, uint8_t* attributes) {
+  // | size: 4 | attributes: 1 | name: up to 64 |
+  static uint8_t entry[5 + 64];
+  int err = allow(FAT_DRIVER_NUM, 1, (void*) entry, sizeof(entry));
+  if (err < 0) return err;
+  int name_len = fat_command_sync(5, fd, 0);
+  if (name_len <= 0) return name_len;
+
+  *size = entry[0] | (entry[1] << 8) | (entry[2] << 16) | ((uint32_t) entry[3] << 24);
+  *attributes = entry[4];
+  if (len > 0) {
+    size_t copy = (size_t) name_len < len - 1 ? (size_t) name_len : len - 1;
+    memcpy(name, &entry[5], copy);
+    name[copy] = '\0';
+  }
+  return name_len;
+}
+
+int fat_delete(const char* path) {
+  size_t len = strlen(path);
+  int err    = allow(FAT_DRIVER_NUM, 0, (void*) path, len);
+  if (err < 0) return err;
+  return fat_command_sync(6, len, 0);
+}
@@ -0,0 +1,55 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define FAT_DRIVER_NUM 0x50004
+
+#define FAT_MAX_OPEN_FILES 4
+
+// Flags for fat_open, or-ed together.
+#define FAT_OPEN_WRITE    1
+#define FAT_OPEN_CREATE   2
+#define FAT_OPEN_APPEND   4
+#define FAT_OPEN_TRUNCATE 8
+
+// Directory entry attribute of a directory.
+#define FAT_ATTR_DIRECTORY 0x10
+
+// Files on an SD card formatted FAT16 or FAT32. All of these wait for the
+// operation to complete, and return a negative TOCK_ error on failure.
+
+// Opens the file or directory at `path`. Returns the file descriptor, or
+// TOCK_FAIL if the path does not exist.
+int fat_open(const char* path, int flags);
+
+int fat_close(int fd);
+
+// Reads up to `len` bytes from the position of `fd`. Returns the number of
+// bytes read, 0 at the end of the file.
+int fat_read(int fd, uint8_t* buf, size_t len);
+
+// Writes `len` bytes at the position of `fd`, or at its end if it was opened
+// with FAT_OPEN_APPEND. Returns the number of bytes written.
+int fat_write(int fd, const uint8_t* buf, size_t len);
+
+// Moves the position of `fd`. Directories can only go back to 0.
+int fat_seek(int fd, size_t position);
+
+// Returns the size of the file.
+int fat_size(int fd);
+
+// Reads the next entry of the directory `fd`. Copies its name to `name`, at
+// most `len` - 1 bytes and terminated with a 0, and sets `size` and
+// `attributes`. Returns the length of the name, 0 after the last entry.
+int fat_readdir(int fd, char* name, size_t len, uint32_t* size, uint8_t* attributes);
+
+// Deletes the file at `path`. Returns TOCK_FAIL if it does not exist.
+int fat_delete(const char* path);
+
+#ifdef __cplusplus
+}
+#endif
@@ -35,3 +35,31 @@ int ipc_share(int pid, void* base, int len) {
   return allow(IPC_DRIVER_NUM, pid, base, len);
 }
 
+
+int ipc_message_receive(void* buf, int len, int depth, subscribe_cb callback, void *ud) {
+  int err = command(IPC_MESSAGE_DRIVER_NUM, 1, depth, 0);
+  if (err < 0) {
+    return err;
+  }
+  err = allow(IPC_MESSAGE_DRIVER_NUM, 0, buf, len);
+  if (err < 0) {
+    return err;
+  }
+  return subscribe(IPC_MESSAGE_DRIVER_NUM, 0, callback, ud);
+}
+
+int ipc_message_release(void) {
+  return command(IPC_MESSAGE_DRIVER_NUM, 2, 0, 0);
+}
+
+int ipc_message_send(int pid, void* buf, int len, int correlation_id) {
+  if (pid <= 0 || pid > 0xffff) {
+    return TOCK_EINVAL;
+  }
+  int err = allow(IPC_MESSAGE_DRIVER_NUM, 1, buf, len);
+  if (err < 0) {
+    return err;
+  }
+  int target = (int) ((unsigned) pid | ((unsigned) correlation_id << 16));
+  return command(IPC_MESSAGE_DRIVER_NUM, 3, target, len);
+}
@@ -10,11 +10,21 @@ extern "C" {
 #endif
 
 #define IPC_DRIVER_NUM 0x10000
+#define IPC_MESSAGE_DRIVER_NUM 0x10002
+
+// Passed as `len` to client callbacks when the service restarted.
+#define IPC_SERVICE_RESTARTED -1
 
 // Performs service discovery
 //
 // Returns the process identifier of the process with the given package name,
-// or a negative value on error.
+// or a negative value on error:
+//
+//   TOCK_ENODEVICE - no process has this package name.
+//   TOCK_EPERM     - the service does not accept this process as a client.
+//   TOCK_EBUSY     - the process has not called `ipc_register_svc` yet. Try
+//                    again later.
+//   TOCK_FAIL      - more than one process has this package name.
 int ipc_discover(const char* pkg_name);
 
 // Registers a service callback for this process.
@@ -35,17 +45,22 @@ int ipc_register_svc(subscribe_cb callback, void *ud);
 // `svc_id` is the (non-zero) process id of the service to subscribe to.
 //
 // Client callbacks are called in response to `notify`s from a particular
-// service and take the following arguments in order:
+// service and when the service restarts. They take the following arguments in
+// order:
 //
 //   int pid   - the notifying service's process id
 //   int len   - the length of the shared buffer or zero if no buffer is shared
-//               from the service.
+//               from the service. IPC_SERVICE_RESTARTED if the service
+//               restarted.
 //   char* buf - the base address of the shared buffer, or NULL if no buffer is
 //               shared from the service.
 //   void* ud  - `userdata`. same as the argument to this function.
 int ipc_register_client_cb(int svc_id, subscribe_cb callback, void *ud);
 
 // Send a notify to the client at the given process id
+//
+// Notifications, shares and messages return TOCK_EPERM if the service does
+// not accept the client.
 int ipc_notify_client(int pid);
 
 // Send a notify to the service at the given process id
@@ -58,6 +73,38 @@ int ipc_notify_svc(int pid);
 // `len` must be a power-of-two larger than 16.
 int ipc_share(int pid, void* base, int len);
 
+// Message passing
+//
+// Messages are copied by the kernel, so the sender and the receiver do not
+// share any memory. Process ids are the same as for the functions above.
+
+// Sets up this process to receive messages.
+//
+// `buf` is split into `depth` equally sized slots, each holding one message.
+// Messages are written to the slots in order, wrapping around at the end of
+// the buffer. The callback is called for every message with the following
+// arguments in order:
+//
+//   int pid            - the sender's process id
+//   int len            - the length of the message
+//   int correlation_id - the 16 bit id the sender attached to the message
+//   void* ud           - `userdata`. same as the argument to this function.
+//
+// Once a message is handled, `ipc_message_release` must be called to free
+// its slot.
+int ipc_message_receive(void* buf, int len, int depth, subscribe_cb callback, void *ud);
+
+// Frees the slot of the oldest received message.
+int ipc_message_release(void);
+
+// Sends `len` bytes from `buf` to the process `pid`. Only the low 16 bits of
+// `correlation_id` are sent.
+//
+// Returns TOCK_EBUSY if the receiver's queue is full, TOCK_ESIZE if the
+// message is larger than one of its slots and TOCK_EPERM if the receiver does
+// not accept messages from this process.
+int ipc_message_send(int pid, void* buf, int len, int correlation_id);
+
 #ifdef __cplusplus
 }
 #endif
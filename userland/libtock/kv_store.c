@@ -0,0 +1,63 @@
+#include "kv_store.h"
+
+struct kv_store_data {
+  bool fired;
+  int result;
+  int cursor;
+};
+
+static struct kv_store_data result = { .fired = false, .result = 0, .cursor = 0 };
+
+static void kv_store_cb(int ret,
+                        __attribute__ ((unused)) int command_num,
+                        int cursor,
+                        void* ud) {
+  struct kv_store_data* data = (struct kv_store_data*) ud;
+  data->fired  = true;
+  data->result = ret;
+  data->cursor = cursor;
+}
+
+// Runs `command_num` and waits for its callback.
+static int kv_store_command_sync(int command_num, int arg1, int arg2) {
+  int err = subscribe(KV_STORE_DRIVER_NUM, 0, kv_store_cb, (void*) &result);
+  if (err < 0) return err;
+
+  result.fired = false;
+  err = command(KV_STORE_DRIVER_NUM, command_num, arg1, arg2);
+  if (err < 0) return err;
+
+  yield_for(&result.fired);
+  return result.result;
+}
+
+int kv_store_get(const uint8_t* key, size_t key_len, uint8_t* value, size_t len) {
+  int err = allow(KV_STORE_DRIVER_NUM, 0, (void*) key, key_len);
+  if (err < 0) return err;
+  err = allow(KV_STORE_DRIVER_NUM, 1, (void*) value, len);
+  if (err < 0) return err;
+  return kv_store_command_sync(1, key_len, 0);
+}
+
+int kv_store_set(const uint8_t* key, size_t key_len, const uint8_t* value, size_t len) {
+  int err = allow(KV_STORE_DRIVER_NUM, 0, (void*) key, key_len);
+  if (err < 0) return err;
+  err = allow(KV_STORE_DRIVER_NUM, 1, (void*) value, len);
+  if (err < 0) return err;
+  return kv_store_command_sync(2, key_len, len);
+}
+
+int kv_store_delete(const uint8_t* key, size_t key_len) {
+  int err = allow(KV_STORE_DRIVER_NUM, 0, (void*) key, key_len);
+  if (err < 0) return err;
+  return kv_store_command_sync(3, key_len, 0);
+}
+
+int kv_store_next_key(int* cursor, uint8_t* key, size_t len) {
+  int err = allow(KV_STORE_DRIVER_NUM, 0, (void*) key, len);
+  if (err < 0) return err;
+  err = kv_store_command_sync(4, *cursor, 0);
+  if (err < 0) return err;
+  *cursor = result.cursor;
+  return err;
+}
@@ -0,0 +1,36 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define KV_STORE_DRIVER_NUM 0x50003
+
+#define KV_STORE_MAX_KEY_LEN 32
+#define KV_STORE_MAX_VALUE_LEN 256
+
+// Keys and values kept in flash across reboots. Every app has its own keys.
+// All of these wait for the operation to complete.
+
+// Copies the value of `key` to `value`, at most `len` bytes. Returns the
+// length of the value, which may be more than `len`, or TOCK_FAIL if the key
+// is not set.
+int kv_store_get(const uint8_t* key, size_t key_len, uint8_t* value, size_t len);
+
+// Sets `key` to `value`.
+int kv_store_set(const uint8_t* key, size_t key_len, const uint8_t* value, size_t len);
+
+// Deletes `key`. Returns TOCK_FAIL if the key is not set.
+int kv_store_delete(const uint8_t* key, size_t key_len);
+
+// Iterates over the keys of the app. Start with `*cursor` 0. Copies the next
+// key to `key`, at most `len` bytes, and returns its length. Returns
+// TOCK_FAIL once there are no more keys, and TOCK_ECANCEL if the store
+// changed too much to carry on, in which case iteration has to start again.
+int kv_store_next_key(int* cursor, uint8_t* key, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
@@ -0,0 +1,93 @@
+#include "process_manager.h"
+
+int process_manager_count(void) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 0, 0, 0);
+}
+
+int process_manager_state(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 1, index, 0);
+}
+
+int process_manager_syscall_count(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 2, index, 0);
+}
+
+int process_manager_memory_size(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 3, index, 0);
+}
+
+int process_manager_memory_used(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 4, index, 0);
+}
+
+int process_manager_name(int index, char* buf, size_t len) {
+  if (len == 0) {
+    return TOCK_EINVAL;
+  }
+  int err = allow(PROCESS_MANAGER_DRIVER_NUM, 0, buf, len - 1);
+  if (err < 0) {
+    return err;
+  }
+  int name_len = command(PROCESS_MANAGER_DRIVER_NUM, 5, index, 0);
+  if (name_len < 0) {
+    return name_len;
+  }
+  buf[(size_t)name_len < len - 1 ? (size_t)name_len : len - 1] = '\0';
+  return name_len;
+}
+
+int process_manager_stop(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 6, index, 0);
+}
+
+int process_manager_resume(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 7, index, 0);
+}
+
+int process_manager_restart(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 8, index, 0);
+}
+
+int process_manager_restart_count(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 9, index, 0);
+}
+
+int process_manager_grant_used(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 10, index, 0);
+}
+
+int process_manager_fault_record(fault_record_t* record) {
+  int err = allow(PROCESS_MANAGER_DRIVER_NUM, 0, record, sizeof(fault_record_t));
+  if (err < 0) {
+    return err;
+  }
+  int len = command(PROCESS_MANAGER_DRIVER_NUM, 12, 0, 0);
+  if (len < 0) {
+    return len;
+  }
+  return len > 0;
+}
+
+int process_manager_clear_fault_record(void) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 13, 0, 0);
+}
+
+int process_manager_dropped_callbacks(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 11, index, 0);
+}
+
+int process_manager_cpu_time_ms(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 14, index, 0);
+}
+
+int process_manager_timeslice_expirations(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 15, index, 0);
+}
+
+int process_manager_scheduled_count(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 16, index, 0);
+}
+
+int process_manager_upcalls_delivered(int index) {
+  return command(PROCESS_MANAGER_DRIVER_NUM, 17, index, 0);
+}
@@ -0,0 +1,100 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PROCESS_MANAGER_DRIVER_NUM 0x10001
+
+// Process states returned by `process_manager_state`.
+#define PROCESS_STATE_UNSTARTED 0
+#define PROCESS_STATE_RUNNING   1
+#define PROCESS_STATE_YIELDED   2
+#define PROCESS_STATE_FAULT     3
+#define PROCESS_STATE_STOPPED   4
+
+// Kinds of fault records.
+#define FAULT_RECORD_PROCESS_FAULT  1
+#define FAULT_RECORD_STACK_OVERFLOW 2
+#define FAULT_RECORD_KERNEL_PANIC   3
+
+// Record of the last process fault or kernel panic. The kernel keeps it
+// across soft resets of the board.
+typedef struct {
+  uint32_t magic;
+  uint32_t checksum;
+  uint32_t kind;           // One of FAULT_RECORD_*
+  uint32_t count;          // Faults since the record was cleared
+  uint32_t panic_line;     // Line of the kernel panic, 0 if none
+  uint32_t pc;
+  uint32_t lr;
+  uint32_t sp;
+  uint32_t xpsr;
+  uint32_t cfsr;
+  uint32_t hfsr;
+  uint32_t mmfar;
+  uint32_t bfar;
+  uint32_t syscall_count;
+  uint32_t last_syscall;   // 0xFFFFFFFF if none
+  char name[20];           // Package name or source file, NUL padded
+} fault_record_t;
+
+// All of these functions only work for the process the board designated as
+// the process manager. Everyone else gets TOCK_EPERM.
+
+// Returns the number of process slots on the board. Valid process indices
+// are 0 up to this number.
+int process_manager_count(void);
+
+// Returns the state of the process at `index`, TOCK_ENODEVICE if there is no
+// process at that index.
+int process_manager_state(int index);
+
+// Returns the number of syscalls the process at `index` has made.
+int process_manager_syscall_count(int index);
+
+// Returns the bytes of RAM assigned to and used by the process at `index`.
+int process_manager_memory_size(int index);
+int process_manager_memory_used(int index);
+
+// Returns the number of times the process at `index` was restarted after a
+// fault.
+int process_manager_restart_count(int index);
+
+// Returns the bytes of grant memory used by the process at `index`.
+int process_manager_grant_used(int index);
+
+// Returns the number of upcalls to the process at `index` that were dropped
+// because its callback queue was full.
+int process_manager_dropped_callbacks(int index);
+
+// CPU usage of the process at `index` since the board booted, across
+// restarts: milliseconds of CPU time, how many times it used up its
+// timeslice, how many times it was scheduled and how many upcalls were
+// delivered to it.
+int process_manager_cpu_time_ms(int index);
+int process_manager_timeslice_expirations(int index);
+int process_manager_scheduled_count(int index);
+int process_manager_upcalls_delivered(int index);
+
+// Copies the package name of the process at `index` into `buf` and NUL
+// terminates it, truncating if needed. Returns the full length of the name.
+int process_manager_name(int index, char* buf, size_t len);
+
+// Stop, resume or restart the process at `index`.
+int process_manager_stop(int index);
+int process_manager_resume(int index);
+int process_manager_restart(int index);
+
+// Copies the record of the last fault into `record`. Returns 1 if there was a
+// record, 0 if there was none.
+int process_manager_fault_record(fault_record_t* record);
+
+// Forgets the record of the last fault.
+int process_manager_clear_fault_record(void);
+
+#ifdef __cplusplus
+}
+#endif
@@ -28,9 +28,9 @@ typedef struct {
 // 1: init_done, intialization completed successfully
 //    arg1 - block_size, block size of SD card in bytes
 //    arg2 - size_in_kB, total size of SD card in kilobytes
-// 2: read_done, read block completed successfully
+// 2: read_done, read blocks completed successfully
 //    arg1 - len, number of bytes read
-// 3: write_done, write block completed successfully
+// 3: write_done, write blocks completed successfully
 //    arg1 - len, number of bytes written
 // 4: error, an error occurred
 //    arg1 - error, number representing the error that occurred
@@ -159,3 +159,64 @@ int sdcard_write_block_sync (uint32_t sector) {
   return result.error;
 }
 
+int sdcard_read_blocks (uint32_t sector, uint32_t count) {
+  return command(DRIVER_NUM_SDCARD, 3, sector, count);
+}
+
+int sdcard_read_blocks_sync (uint32_t sector, uint32_t count) {
+  int err;
+  sdcard_data_t result;
+  result.fired = false;
+  result.error = TOCK_SUCCESS;
+
+  err = sdcard_set_callback(sdcard_cb, (void*) &result);
+  if (err < 0) return err;
+
+  err = sdcard_read_blocks(sector, count);
+  if (err < 0) return err;
+
+  // wait for callback
+  yield_for(&result.fired);
+
+  return result.error;
+}
+
+int sdcard_write_blocks (uint32_t sector, uint32_t count) {
+  return command(DRIVER_NUM_SDCARD, 4, sector, count);
+}
+
+int sdcard_write_blocks_sync (uint32_t sector, uint32_t count) {
+  int err;
+  sdcard_data_t result;
+  result.fired = false;
+  result.error = TOCK_SUCCESS;
+
+  err = sdcard_set_callback(sdcard_cb, (void*) &result);
+  if (err < 0) return err;
+
+  err = sdcard_write_blocks(sector, count);
+  if (err < 0) return err;
+
+  // wait for callback
+  yield_for(&result.fired);
+
+  return result.error;
+}
+
+int sdcard_get_statistics (sdcard_statistics_t* statistics) {
+  int values[4];
+  for (int i = 0; i < 4; i++) {
+    values[i] = command(DRIVER_NUM_SDCARD, 5, i, 0);
+    if (values[i] < 0) return values[i];
+  }
+
+  statistics->blocks_read    = values[0];
+  statistics->blocks_written = values[1];
+  statistics->read_ms        = values[2];
+  statistics->write_ms       = values[3];
+  return TOCK_SUCCESS;
+}
+
+int sdcard_reset_statistics (void) {
+  return command(DRIVER_NUM_SDCARD, 6, 0, 0);
+}
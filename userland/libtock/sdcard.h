@@ -92,6 +92,64 @@ int sdcard_write_block (uint32_t sector);
 // returns 0 if the block has been written, < 0 if an error occurrs
 int sdcard_write_block_sync (uint32_t sector);
 
+// read consecutive blocks from an SD card asynchronously
+// Same as sdcard_read_block, but the card streams all of the blocks after a
+// single command. The read_buffer must hold `count` blocks, and the kernel
+// limits how many blocks one call can read
+//
+// sector - sector address of the first block to be read
+// count - number of blocks to read
+//
+// returns 0 if started successfully, < 0 if an error occurrs
+int sdcard_read_blocks (uint32_t sector, uint32_t count);
+
+// read consecutive blocks from an SD card synchronously
+//
+// sector - sector address of the first block to be read
+// count - number of blocks to read
+//
+// returns 0 if the blocks have been read, < 0 if an error occurrs
+int sdcard_read_blocks_sync (uint32_t sector, uint32_t count);
+
+// write consecutive blocks to an SD card asynchronously
+// Same as sdcard_write_block, but the card receives all of the blocks after
+// a single command and can erase them ahead of the data. The write_buffer
+// must hold `count` blocks
+//
+// sector - sector address of the first block to be written
+// count - number of blocks to write
+//
+// returns 0 if started successfully, < 0 if an error occurrs
+int sdcard_write_blocks (uint32_t sector, uint32_t count);
+
+// write consecutive blocks to an SD card synchronously
+//
+// sector - sector address of the first block to be written
+// count - number of blocks to write
+//
+// returns 0 if the blocks have been written, < 0 if an error occurrs
+int sdcard_write_blocks_sync (uint32_t sector, uint32_t count);
+
+// transfer statistics, counted from boot or the last reset
+typedef struct {
+  uint32_t blocks_read;
+  uint32_t blocks_written;
+  uint32_t read_ms;  // time spent reading, in milliseconds
+  uint32_t write_ms; // time spent writing, in milliseconds
+} sdcard_statistics_t;
+
+// get the transfer statistics of the SD card
+// Completes synchronously. Throughput is blocks * 512 bytes over the time
+//
+// returns 0 if successful, < 0 if an error occurrs
+int sdcard_get_statistics (sdcard_statistics_t* statistics);
+
+// reset the transfer statistics to zero
+// Completes synchronously
+//
+// returns 0 if successful, < 0 if an error occurrs
+int sdcard_reset_statistics (void);
+
 #ifdef __cplusplus
 }
 #endif
@@ -171,6 +171,30 @@ void* tock_app_writeable_flash_region_ends_at(int region_index) {
   return memop(9, region_index);
 }
 
+#pragma GCC diagnostic push
+#pragma GCC diagnostic ignored "-Wbad-function-cast"
+int tock_app_grant_bytes_used(void) {
+  return (int) memop(12, 0);
+}
+
+int tock_app_grant_bytes_for(int grant_num) {
+  return (int) memop(13, grant_num);
+}
+
+int tock_app_dropped_callbacks(void) {
+  return (int) memop(14, 0);
+}
+
+int tock_app_layout(tock_app_layout_t* layout, size_t size) {
+  layout->size = size;
+  return (int) memop(15, (int) layout);
+}
+
+int tock_app_set_break_limit(void* limit) {
+  return (int) memop(16, (int) limit);
+}
+#pragma GCC diagnostic pop
+
 bool driver_exists(uint32_t driver) {
   int ret = command(driver, 0, 0, 0);
   return ret >= 0;
@@ -206,6 +230,8 @@ const char* tock_strerror(int tock_errno) {
       return "Device is not physically installed";
     case TOCK_ENOACK:
       return "Packet transmission not acknowledged";
+    case TOCK_EPERM:
+      return "Not allowed for this process";
   }
   return "Invalid error number";
 }
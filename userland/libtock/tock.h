@@ -39,6 +39,43 @@ void* tock_app_grant_begins_at(void);
 int tock_app_number_writeable_flash_regions(void);
 void* tock_app_writeable_flash_region_begins_at(int region_index);
 void* tock_app_writeable_flash_region_ends_at(int region_index);
+// Bytes of the grant region in use, and bytes used by the grant `grant_num`.
+// The latter is negative once `grant_num` is past the last grant.
+int tock_app_grant_bytes_used(void);
+int tock_app_grant_bytes_for(int grant_num);
+// Number of upcalls to this app the kernel dropped because its callback queue
+// was full.
+int tock_app_dropped_callbacks(void);
+
+// Memory layout of the app, filled in by tock_app_layout(). Addresses the
+// kernel does not know, because the app manages its own stack or heap, are
+// NULL.
+typedef struct {
+  void* start;
+  void* end;
+} tock_flash_region_t;
+
+typedef struct {
+  size_t size;            // Bytes of the struct, set by tock_app_layout().
+  void* stack_start;      // Top of the stack, which grows down to the start
+  size_t stack_size;      // of the app's memory.
+  void* heap_start;
+  void* app_break;        // End of the memory the app can use.
+  void* kernel_break;     // Start of the grant region.
+  void* app_break_limit;  // Set with tock_app_set_break_limit(), or NULL.
+  size_t writeable_flash_regions;
+  tock_flash_region_t writeable_flash[];
+} tock_app_layout_t;
+
+// Fill in `layout`, which is `size` bytes and has room for
+// (size - sizeof(tock_app_layout_t)) / sizeof(tock_flash_region_t) writeable
+// flash regions. Returns the size needed for all regions, or a negative error.
+int tock_app_layout(tock_app_layout_t* layout, size_t size);
+
+// Keep sbrk and brk from moving the app break above `limit`, so the heap does
+// not take memory the kernel needs for grants. The limit can only be lowered,
+// and not below the current break.
+int tock_app_set_break_limit(void* limit);
 
 
 // Checks to see if the given driver number exists on this platform.
@@ -58,6 +95,7 @@ bool driver_exists(uint32_t driver);
 #define TOCK_ENODEVICE    -11
 #define TOCK_EUNINSTALLED -12
 #define TOCK_ENOACK       -13
+#define TOCK_EPERM        -14
 
 const char* tock_strerror(int tock_errno);
 
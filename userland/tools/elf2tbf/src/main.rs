@@ -1,6 +1,14 @@
 extern crate elf;
 extern crate getopts;
 
+// The kernel checks app images with the same code elf2tbf signs them with.
+#[path = "../../../../kernel/src/crypto/ed25519.rs"]
+mod ed25519;
+#[path = "../../../../kernel/src/crypto/sha256.rs"]
+mod sha256;
+#[path = "../../../../kernel/src/crypto/sha512.rs"]
+mod sha512;
+
 use getopts::Options;
 use std::cmp;
 use std::env;
@@ -30,6 +38,28 @@ enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderPicOption1 = 4,
+    TbfHeaderFaultResponse = 5,
+    TbfHeaderScheduling = 6,
+    TbfHeaderIpcClients = 7,
+    TbfHeaderSyscallFilter = 8,
+    TbfHeaderFooterOffset = 9,
+    TbfHeaderCallbackQueue = 10,
+    TbfHeaderNonvolatileStorage = 11,
+}
+
+/// Types of the TLV elements after the app binary.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug)]
+enum TbfFooterTypes {
+    TbfFooterSha256 = 128,
+    TbfFooterEd25519 = 129,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfFooterTlv {
+    tipe: TbfFooterTypes,
+    length: u16,
 }
 
 #[repr(C)]
@@ -81,6 +111,14 @@ struct TbfHeaderWriteableFlashRegion {
     size: u32,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderFaultResponse {
+    base: TbfHeaderTlv,
+    response: u32,
+    max_restarts: u32,
+}
+
 impl fmt::Display for TbfHeaderBase {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "
@@ -152,6 +190,191 @@ impl fmt::Display for TbfHeaderWriteableFlashRegion {
     }
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderScheduling {
+    base: TbfHeaderTlv,
+    priority: u32,
+    timeslice_us: u32,
+}
+
+impl fmt::Display for TbfHeaderScheduling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "
+              priority: {:>8} {:>#10X}
+          timeslice_us: {:>8} {:>#10X}
+",
+        self.priority, self.priority,
+        self.timeslice_us, self.timeslice_us,
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderCallbackQueue {
+    base: TbfHeaderTlv,
+    queue_depth: u32,
+    flags: u32,
+}
+
+impl fmt::Display for TbfHeaderCallbackQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "
+           queue_depth: {:>8} {:>#10X}
+  callback_queue_flags: {:>8} {:>#10X}
+",
+        self.queue_depth, self.queue_depth,
+        self.flags, self.flags,
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderNonvolatileStorage {
+    base: TbfHeaderTlv,
+    size: u32,
+}
+
+impl fmt::Display for TbfHeaderNonvolatileStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "
+   nonvolatile_storage: {:>8} {:>#10X}
+",
+        self.size, self.size,
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderFooterOffset {
+    base: TbfHeaderTlv,
+    binary_end_offset: u32,
+}
+
+impl fmt::Display for TbfHeaderFooterOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "
+     binary_end_offset: {:>8} {:>#10X}
+",
+        self.binary_end_offset, self.binary_end_offset,
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderDriverPermission {
+    driver_num: u32,
+    min_command: u32,
+    max_command: u32,
+}
+
+impl fmt::Display for TbfHeaderDriverPermission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "
+            driver_num: {:>8} {:>#10X}
+           min_command: {:>8} {:>#10X}
+           max_command: {:>8} {:>#10X}
+",
+        self.driver_num, self.driver_num,
+        self.min_command, self.min_command,
+        self.max_command, self.max_command,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderFaultResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "
+        fault_response: {:>8} {:>#10X}
+          max_restarts: {:>8} {:>#10X}
+",
+        self.response, self.response,
+        self.max_restarts, self.max_restarts,
+        )
+    }
+}
+
+/// Parse the argument to `--fault-response` into the `response` and
+/// `max_restarts` fields of the fault response TLV.
+fn parse_fault_response(arg: &str) -> Option<(u32, u32)> {
+    match arg {
+        "panic" => Some((0, 0)),
+        "restart" => Some((1, 0)),
+        "stop" => Some((3, 0)),
+        _ => {
+            if arg.starts_with("restart:") {
+                arg["restart:".len()..].parse::<u32>().ok().map(|n| (2, n))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(arg: &str) -> Option<u32> {
+    if arg.starts_with("0x") {
+        u32::from_str_radix(&arg[2..], 16).ok()
+    } else {
+        arg.parse::<u32>().ok()
+    }
+}
+
+/// Parse the argument to `--syscall-filter`, a comma separated list of
+/// `DRIVER` or `DRIVER:MIN-MAX` entries, into syscall filter entries.
+fn parse_syscall_filter(arg: &str) -> Option<Vec<TbfHeaderDriverPermission>> {
+    arg.split(',')
+        .map(|entry| {
+            let mut parts = entry.splitn(2, ':');
+            let driver_num = parts.next().and_then(parse_number);
+            let commands = match parts.next() {
+                None => Some((0, u32::max_value())),
+                Some(range) => {
+                    let mut bounds = range.splitn(2, '-');
+                    let min = bounds.next().and_then(parse_number);
+                    let max = bounds.next().map_or(min, parse_number);
+                    min.and_then(|min| max.map(|max| (min, max)))
+                }
+            };
+            driver_num.and_then(|driver_num| {
+                commands.map(|(min_command, max_command)| {
+                    TbfHeaderDriverPermission {
+                        driver_num: driver_num,
+                        min_command: min_command,
+                        max_command: max_command,
+                    }
+                })
+            })
+        })
+        .collect()
+}
+
+/// Read an Ed25519 secret key from `path`. The file holds either the 32 raw
+/// bytes of the key or the key as 64 hexadecimal digits.
+fn read_secret_key(path: &str) -> io::Result<[u8; 32]> {
+    let mut contents = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut contents));
+    let mut key = [0; 32];
+    if contents.len() == key.len() {
+        key.copy_from_slice(&contents);
+        return Ok(key);
+    }
+    let hex = String::from_utf8_lossy(&contents);
+    let hex = hex.trim();
+    if hex.len() != 2 * key.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "key must be 32 bytes"));
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = try!(u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid hex in key")));
+    }
+    Ok(key)
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
@@ -160,6 +383,42 @@ fn main() {
     opts.optopt("n", "", "set package name", "PACKAGE_NAME");
     opts.optflag("v", "verbose", "be verbose");
     opts.optflag("p", "include-pic-info", "include PIC information");
+    opts.optopt("",
+                "fault-response",
+                "how the kernel should respond when the app faults: panic, restart, \
+                 restart:N (restart at most N times) or stop",
+                "RESPONSE");
+    opts.optopt("", "priority", "set scheduling priority, higher runs first", "PRIORITY");
+    opts.optopt("",
+                "timeslice",
+                "set scheduling timeslice in microseconds",
+                "MICROSECONDS");
+    opts.optopt("",
+                "ipc-clients",
+                "comma separated package names of the apps that may use IPC with this \
+                 app, a name ending in * matches all names with that prefix",
+                "NAMES");
+    opts.optopt("",
+                "syscall-filter",
+                "comma separated drivers the app may use, each optionally limited to a \
+                 range of commands as DRIVER:MIN-MAX",
+                "DRIVERS");
+    opts.optopt("",
+                "callback-queue",
+                "how many upcalls the app can have pending, 0 for the kernel default",
+                "DEPTH");
+    opts.optflag("",
+                 "coalesce-callbacks",
+                 "merge an upcall into one that is already pending for the same callback");
+    opts.optopt("",
+                "nonvolatile-storage",
+                "bytes of nonvolatile storage the kernel should reserve for the app",
+                "BYTES");
+    opts.optflag("", "sha256", "append the SHA-256 hash of the app");
+    opts.optopt("",
+                "sign",
+                "sign the app with the Ed25519 secret key in KEYFILE",
+                "KEYFILE");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -169,6 +428,40 @@ fn main() {
     let package_name = matches.opt_str("n");
     let verbose = matches.opt_present("v");
     let pic = matches.opt_present("p");
+    let fault_response = matches.opt_str("fault-response").map(|arg| {
+        parse_fault_response(&arg).unwrap_or_else(|| panic!("Invalid fault response: {}", arg))
+    });
+    let priority = matches.opt_str("priority").map(|arg| {
+        arg.parse::<u32>().unwrap_or_else(|_| panic!("Invalid priority: {}", arg))
+    });
+    let timeslice = matches.opt_str("timeslice").map(|arg| {
+        arg.parse::<u32>().unwrap_or_else(|_| panic!("Invalid timeslice: {}", arg))
+    });
+    let scheduling = if priority.is_some() || timeslice.is_some() {
+        Some((priority.unwrap_or(0), timeslice.unwrap_or(0)))
+    } else {
+        None
+    };
+    let ipc_clients = matches.opt_str("ipc-clients").map(|arg| arg.replace(",", "\0"));
+    let syscall_filter = matches.opt_str("syscall-filter").map(|arg| {
+        parse_syscall_filter(&arg).unwrap_or_else(|| panic!("Invalid syscall filter: {}", arg))
+    });
+    let queue_depth = matches.opt_str("callback-queue").map(|arg| {
+        arg.parse::<u32>().unwrap_or_else(|_| panic!("Invalid callback queue depth: {}", arg))
+    });
+    let coalesce_callbacks = matches.opt_present("coalesce-callbacks");
+    let callback_queue = if queue_depth.is_some() || coalesce_callbacks {
+        Some((queue_depth.unwrap_or(0), if coalesce_callbacks { 1 } else { 0 }))
+    } else {
+        None
+    };
+    let nonvolatile_storage = matches.opt_str("nonvolatile-storage").map(|arg| {
+        arg.parse::<u32>().unwrap_or_else(|_| panic!("Invalid nonvolatile storage size: {}", arg))
+    });
+    let append_hash = matches.opt_present("sha256");
+    let signing_key = matches.opt_str("sign").map(|path| {
+        read_secret_key(&path).unwrap_or_else(|e| panic!("Cannot read key {}: {}", path, e))
+    });
     let input = if !matches.free.is_empty() {
         matches.free[0].clone()
     } else {
@@ -186,11 +479,37 @@ fn main() {
     match output {
             None => {
                 let mut out = io::stdout();
-                do_work(&file, &mut out, package_name, verbose, pic)
+                do_work(&file,
+                        &mut out,
+                        package_name,
+                        verbose,
+                        pic,
+                        fault_response,
+                        scheduling,
+                        ipc_clients,
+                        syscall_filter,
+                        callback_queue,
+                        nonvolatile_storage,
+                        append_hash,
+                        signing_key)
             }
             Some(name) => {
                 match File::create(Path::new(&name)) {
-                    Ok(mut f) => do_work(&file, &mut f, package_name, verbose, pic),
+                    Ok(mut f) => {
+                        do_work(&file,
+                                &mut f,
+                                package_name,
+                                verbose,
+                                pic,
+                                fault_response,
+                                scheduling,
+                                ipc_clients,
+                                syscall_filter,
+                                callback_queue,
+                                nonvolatile_storage,
+                                append_hash,
+                                signing_key)
+                    }
                     Err(e) => panic!("Error: {:?}", e),
                 }
             }
@@ -239,7 +558,15 @@ fn do_work(input: &elf::File,
            output: &mut Write,
            package_name: Option<String>,
            verbose: bool,
-           pic: bool)
+           pic: bool,
+           fault_response: Option<(u32, u32)>,
+           scheduling: Option<(u32, u32)>,
+           ipc_clients: Option<String>,
+           syscall_filter: Option<Vec<TbfHeaderDriverPermission>>,
+           callback_queue: Option<(u32, u32)>,
+           nonvolatile_storage: Option<u32>,
+           append_hash: bool,
+           signing_key: Option<[u8; 32]>)
            -> io::Result<()> {
     let package_name = package_name.unwrap_or(String::new());
     let (relocation_data_size, rel_data) = match input.sections
@@ -286,18 +613,54 @@ fn do_work(input: &elf::File,
                          mem::size_of::<TbfHeaderWriteableFlashRegion>();
     }
 
-    // Now we can calculate the entire size of the app in flash.
-    let mut total_size = (header_length + rel_data.len() + text.data.len() + got.data.len() +
-                          data.data.len() +
-                          appstate.data.len()) as u32;
+    // Only tell the kernel how to handle faults if asked to, otherwise the
+    // board default applies.
+    if fault_response.is_some() {
+        header_length += mem::size_of::<TbfHeaderFaultResponse>();
+    }
 
-    let ending_pad = if total_size.count_ones() > 1 {
-        let power2len = cmp::max(1 << (32 - total_size.leading_zeros()), 512);
-        power2len - total_size
-    } else {
-        0
-    };
-    total_size += ending_pad;
+    // Same for the scheduling parameters.
+    if scheduling.is_some() {
+        header_length += mem::size_of::<TbfHeaderScheduling>();
+    }
+
+    // The list of IPC clients is padded like the package name. An empty list
+    // is still written, it means that no app may use IPC with this one.
+    let mut post_ipc_clients_pad = 0;
+    if let Some(ref clients) = ipc_clients {
+        let ipc_clients_total_size = align4!(mem::size_of::<TbfHeaderTlv>() + clients.len());
+        header_length += ipc_clients_total_size;
+        post_ipc_clients_pad = ipc_clients_total_size -
+                               (mem::size_of::<TbfHeaderTlv>() + clients.len());
+    }
+
+    // Apps without a syscall filter may use every driver.
+    if let Some(ref filter) = syscall_filter {
+        header_length += mem::size_of::<TbfHeaderTlv>() +
+                         filter.len() * mem::size_of::<TbfHeaderDriverPermission>();
+    }
+
+    // Apps without a callback queue element get the kernel default depth.
+    if callback_queue.is_some() {
+        header_length += mem::size_of::<TbfHeaderCallbackQueue>();
+    }
+
+    // Apps without a nonvolatile storage element get the board default.
+    if nonvolatile_storage.is_some() {
+        header_length += mem::size_of::<TbfHeaderNonvolatileStorage>();
+    }
+
+    // Footers are found through the offset in the header.
+    let mut footers_length = 0;
+    if append_hash {
+        footers_length += mem::size_of::<TbfFooterTlv>() + 32;
+    }
+    if signing_key.is_some() {
+        footers_length += mem::size_of::<TbfFooterTlv>() + 96;
+    }
+    if footers_length > 0 {
+        header_length += mem::size_of::<TbfHeaderFooterOffset>();
+    }
 
     // Calculate the offset between the start of the flash region and the actual
     // app code. Also need to get the padding size.
@@ -328,6 +691,21 @@ fn do_work(input: &elf::File,
     let minimum_ram_size = stack_len + app_heap_len + kernel_heap_len + got_size + data_size +
                            bss_size;
 
+    // Footers start on a four byte boundary after the app binary.
+    let binary_end_offset = align4!(data_offset + data_size);
+    let post_binary_pad = binary_end_offset - (data_offset + data_size);
+
+    // Now we can calculate the entire size of the app in flash.
+    let mut total_size = binary_end_offset + footers_length as u32;
+
+    let ending_pad = if total_size.count_ones() > 1 {
+        let power2len = cmp::max(1 << (32 - total_size.leading_zeros()), 512);
+        power2len - total_size
+    } else {
+        0
+    };
+    total_size += ending_pad;
+
     // Flags default to app is enabled.
     let flags = 0x00000001;
 
@@ -384,6 +762,65 @@ fn do_work(input: &elf::File,
         size: appstate_size,
     };
 
+    let (response, max_restarts) = fault_response.unwrap_or((0, 0));
+    let tbf_fault_response = TbfHeaderFaultResponse {
+        base: TbfHeaderTlv {
+            tipe: TbfHeaderTypes::TbfHeaderFaultResponse,
+            length: (mem::size_of::<TbfHeaderFaultResponse>() -
+                     mem::size_of::<TbfHeaderTlv>()) as u16,
+        },
+        response: response,
+        max_restarts: max_restarts,
+    };
+
+    let (priority, timeslice_us) = scheduling.unwrap_or((0, 0));
+    let tbf_scheduling = TbfHeaderScheduling {
+        base: TbfHeaderTlv {
+            tipe: TbfHeaderTypes::TbfHeaderScheduling,
+            length: (mem::size_of::<TbfHeaderScheduling>() - mem::size_of::<TbfHeaderTlv>()) as u16,
+        },
+        priority: priority,
+        timeslice_us: timeslice_us,
+    };
+
+    let (queue_depth, callback_queue_flags) = callback_queue.unwrap_or((0, 0));
+    let tbf_callback_queue = TbfHeaderCallbackQueue {
+        base: TbfHeaderTlv {
+            tipe: TbfHeaderTypes::TbfHeaderCallbackQueue,
+            length: (mem::size_of::<TbfHeaderCallbackQueue>() - mem::size_of::<TbfHeaderTlv>()) as u16,
+        },
+        queue_depth: queue_depth,
+        flags: callback_queue_flags,
+    };
+
+    let tbf_nonvolatile_storage = TbfHeaderNonvolatileStorage {
+        base: TbfHeaderTlv {
+            tipe: TbfHeaderTypes::TbfHeaderNonvolatileStorage,
+            length: (mem::size_of::<TbfHeaderNonvolatileStorage>() - mem::size_of::<TbfHeaderTlv>()) as u16,
+        },
+        size: nonvolatile_storage.unwrap_or(0),
+    };
+
+    let tbf_ipc_clients_tlv = TbfHeaderTlv {
+        tipe: TbfHeaderTypes::TbfHeaderIpcClients,
+        length: ipc_clients.as_ref().map_or(0, |clients| clients.len()) as u16,
+    };
+
+    let tbf_footer_offset = TbfHeaderFooterOffset {
+        base: TbfHeaderTlv {
+            tipe: TbfHeaderTypes::TbfHeaderFooterOffset,
+            length: (mem::size_of::<TbfHeaderFooterOffset>() - mem::size_of::<TbfHeaderTlv>()) as u16,
+        },
+        binary_end_offset: binary_end_offset,
+    };
+
+    let tbf_syscall_filter_tlv = TbfHeaderTlv {
+        tipe: TbfHeaderTypes::TbfHeaderSyscallFilter,
+        length: syscall_filter.as_ref().map_or(0, |filter| {
+            filter.len() * mem::size_of::<TbfHeaderDriverPermission>()
+        }) as u16,
+    };
+
     if verbose {
         print!("{}", tbf_header);
         print!("{}", tbf_main);
@@ -391,6 +828,33 @@ fn do_work(input: &elf::File,
             print!("{}", tbf_pic);
         }
         print!("{}", tbf_flash_region);
+        if fault_response.is_some() {
+            print!("{}", tbf_fault_response);
+        }
+        if scheduling.is_some() {
+            print!("{}", tbf_scheduling);
+        }
+        if let Some(ref clients) = ipc_clients {
+            println!("           ipc_clients: {}", clients.replace("\0", ","));
+        }
+        if let Some(ref filter) = syscall_filter {
+            for entry in filter {
+                print!("{}", entry);
+            }
+        }
+        if callback_queue.is_some() {
+            print!("{}", tbf_callback_queue);
+        }
+        if nonvolatile_storage.is_some() {
+            print!("{}", tbf_nonvolatile_storage);
+        }
+        if footers_length > 0 {
+            print!("{}", tbf_footer_offset);
+        }
+        if let Some(ref key) = signing_key {
+            // Boards need this to trust the signature.
+            println!("    signing public key: {:?}", ed25519::public_key(key));
+        }
     }
 
     // Calculate the header checksum.
@@ -412,6 +876,39 @@ fn do_work(input: &elf::File,
         try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_flash_region) }));
     }
 
+    if fault_response.is_some() {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_fault_response) }));
+    }
+
+    if scheduling.is_some() {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_scheduling) }));
+    }
+
+    if let Some(ref clients) = ipc_clients {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_ipc_clients_tlv) }));
+        try!(header_buf.write_all(clients.as_ref()));
+        try!(do_pad(&mut header_buf, post_ipc_clients_pad));
+    }
+
+    if let Some(ref filter) = syscall_filter {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_syscall_filter_tlv) }));
+        for entry in filter {
+            try!(header_buf.write_all(unsafe { as_byte_slice(entry) }));
+        }
+    }
+
+    if callback_queue.is_some() {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_callback_queue) }));
+    }
+
+    if nonvolatile_storage.is_some() {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_nonvolatile_storage) }));
+    }
+
+    if footers_length > 0 {
+        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_footer_offset) }));
+    }
+
     // Start from the beginning and iterate through the buffer as words.
     try!(header_buf.seek(SeekFrom::Start(0)));
     let mut wordbuf = [0u8; 4];
@@ -455,17 +952,47 @@ fn do_work(input: &elf::File,
         Ok(())
     }
 
-    // Write the header and actual app to a binary file.
-    try!(output.write_all(header_buf.get_ref()));
-    try!(do_pad(output, post_header_pad as usize));
-    try!(output.write_all(appstate.data.as_ref()));
-    try!(do_pad(output, post_appstate_pad as usize));
-    try!(output.write_all(rel_data.as_ref()));
-    try!(output.write_all(text.data.as_ref()));
-    try!(output.write_all(got.data.as_ref()));
-    try!(output.write_all(data.data.as_ref()));
+    // Put the header and actual app together, the footers cover both.
+    let mut binary = Vec::new();
+    try!(binary.write_all(header_buf.get_ref()));
+    try!(do_pad(&mut binary, post_header_pad as usize));
+    try!(binary.write_all(appstate.data.as_ref()));
+    try!(do_pad(&mut binary, post_appstate_pad as usize));
+    try!(binary.write_all(rel_data.as_ref()));
+    try!(binary.write_all(text.data.as_ref()));
+    try!(binary.write_all(got.data.as_ref()));
+    try!(binary.write_all(data.data.as_ref()));
+    try!(do_pad(&mut binary, post_binary_pad as usize));
+
+    let mut hasher = sha256::Sha256::new();
+    hasher.update(&binary);
+    let digest = hasher.finish();
+
+    if append_hash {
+        let tlv = TbfFooterTlv {
+            tipe: TbfFooterTypes::TbfFooterSha256,
+            length: digest.len() as u16,
+        };
+        try!(binary.write_all(unsafe { as_byte_slice(&tlv) }));
+        try!(binary.write_all(&digest));
+    }
+
+    if let Some(ref key) = signing_key {
+        let public_key = ed25519::public_key(key);
+        let signature = ed25519::sign(key, &digest);
+        assert!(ed25519::verify(&public_key, &digest, &signature));
+        let tlv = TbfFooterTlv {
+            tipe: TbfFooterTypes::TbfFooterEd25519,
+            length: (public_key.len() + signature.len()) as u16,
+        };
+        try!(binary.write_all(unsafe { as_byte_slice(&tlv) }));
+        try!(binary.write_all(&public_key));
+        try!(binary.write_all(&signature));
+    }
 
-    // Pad to get a power of 2 sized flash app.
+    // Write the app to a binary file, padded to get a power of 2 sized flash
+    // app.
+    try!(output.write_all(&binary));
     try!(do_pad(output, ending_pad as usize));
 
     Ok(())